@@ -1,5 +1,5 @@
-use std::f32::consts::PI;
-
+use dragknife_repath::input;
+use dragknife_repath::output::{self, OutputConfig};
 use dragknife_repath::types::{DragknifeConfig, LiftConfig};
 use dragknife_repath::DragknifePath;
 
@@ -8,13 +8,18 @@ fn main() {
         .target(env_logger::Target::Stdout)
         .init();
     let fc: String = std::fs::read_to_string("test_input2.cnc").unwrap();
-    let got: Vec<_> = gcode::parse(&fc).collect();
-    let path = DragknifePath::from_gcode(got.iter());
-    let config = DragknifeConfig::new(0.35, LiftConfig::RelativeHeight(0.5), 10. * PI / 180., 300.);
+    let program = input::parse_program(&fc);
+    let path = DragknifePath::from_gcode(program.gcodes.iter());
+    let config = DragknifeConfig::builder()
+        .knife_offset(0.35)
+        .lift(LiftConfig::RelativeHeight(0.5))
+        .sharp_angle_threshold(10f64.to_radians())
+        .build()
+        .unwrap();
     let fixed = path.to_fixed_gcode(&config);
     std::fs::write(
         "output.cnc",
-        fixed.iter().map(|g| format!("{}\n", g)).collect::<String>(),
+        output::write_program(&program, fixed, &OutputConfig::default()).text,
     )
     .unwrap();
 }
@@ -1,51 +1,250 @@
-use std::fs::File;
-use std::io::{prelude::*, Result};
-use std::{f32::consts::PI, path::PathBuf};
+// The web build leaves out the parts of the app which need the file system, threads or a
+// serial port
+#![cfg_attr(
+    target_arch = "wasm32",
+    allow(dead_code, unused_imports, unused_variables)
+)]
+
+use std::io::Result;
+use std::{
+    f64::consts::PI,
+    ops::{Range, RangeInclusive},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
 
 use eframe::CreationContext;
-use gcode::Mnemonic;
+use egui::{Key, KeyboardShortcut, Modifiers};
 use serde::{Deserialize, Serialize};
 
-use crate::types::LiftConfig;
-use crate::{types::DragknifeConfig, DragknifePath};
+use crate::config_file::{ConfigFile, CONFIG_FILE_NAME};
+use crate::dialect::{CodeRange, Dialect};
+use crate::diff::{self, DiffKind, DiffRow};
+use crate::grbl::{GrblConnection, GrblEvent, MachineStatus};
+use crate::i18n::{self, tr, tr_args, Language};
+use crate::job::{self, JobStats};
+use crate::operations::{Operation, DEFAULT_OPERATION_MARKER};
+use crate::output::{
+    LineEnding, LineOrigin, MaterialDepth, OutputConfig, OutputFilter, OutputWarning, ProgramOutput,
+};
+use crate::preset::{Preset, PresetLibrary};
+use crate::preview::{KnifePosition, MoveKind, Preview, PreviewCorner};
+use crate::registration::MarkStyle;
+use crate::toolpath::{ExportFormat, Toolpath};
+use crate::types::{CornerRounding, DegenerateArcs, DragknifeConfig, LiftConfig, UnsupportedCodes};
+
+/// How many input files the "Recent" menu remembers.
+const MAX_RECENT_FILES: usize = 10;
+
+/// Shortcuts of the main actions. Copying the output takes Shift too, so copying selected text
+/// still works.
+const OPEN_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::O);
+const REPATH_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::R);
+const SAVE_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::S);
+const PREVIEW_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::P);
+const COPY_SHORTCUT: KeyboardShortcut =
+    KeyboardShortcut::new(Modifiers::COMMAND.plus(Modifiers::SHIFT), Key::C);
+
+/// How long the settings have to stay the same before a live repath starts, in seconds.
+const LIVE_REPATH_DELAY: f64 = 0.3;
 
 #[derive(Deserialize, Serialize)]
 #[serde(default)]
 pub struct DragknifeApp {
     config: DragknifeConfig,
-    output_name: String,
     input_file: Option<PathBuf>,
-    skip_m3: bool,
+    /// The contents of the input file if it was uploaded in the browser, where it can not be read
+    /// from `input_file`.
+    #[serde(skip)]
+    input_source: Option<Arc<str>>,
+    /// A file being uploaded in the browser, see `web::upload_file`.
+    #[serde(skip)]
+    upload: Option<mpsc::Receiver<(String, String)>>,
+    /// The last opened input files, most recent first.
+    recent_files: Vec<PathBuf>,
+    output_config: OutputConfig,
+    filter_text: String,
+    presets: PresetLibrary,
+    preset_name: String,
+    /// Stop on parameters which are used before they are set, instead of using 0.
+    strict_parameters: bool,
+    /// Parse the written program again and stop if it does not make the cuts of the input.
+    verify_output: bool,
+    /// The speed rapid moves are assumed to run at in the preview, in mm/min.
+    rapid_feedrate: f64,
+    /// Repath with `config_b` too, to compare the two.
+    compare: bool,
+    config_b: DragknifeConfig,
+    /// The knife settings edit `config_b` instead of `config`.
+    #[serde(skip)]
+    editing_b: bool,
+    /// The preview and stats of `config_b`, if comparing.
+    #[serde(skip)]
+    compared: Option<(Preview, JobStats)>,
+    /// The preview panel is shown.
+    preview_shown: bool,
+    /// The language the app is shown in.
+    language: Language,
+    /// The units lengths and feedrates are shown and entered in.
+    units: DisplayUnits,
+    /// The resolution of exported preview images, in pixels per mm.
+    image_scale: f64,
+    #[serde(skip)]
+    image_status: Option<String>,
+    /// The serial port of the machine, e.g. `/dev/ttyUSB0` or `\\.\COM3`.
+    port_path: String,
+    baud_rate: u32,
+    #[serde(skip)]
+    machine: Machine,
+    /// The output file names, see `job::output_path`.
+    name_template: String,
+    /// The folder the outputs are written to, or `None` to write them next to their input.
+    output_dir: Option<PathBuf>,
+    /// A repath waiting for the user to confirm overwriting its output file.
+    #[serde(skip)]
+    pending_overwrite: Option<(RepathInputs, PathBuf)>,
+    /// The output file written last, which is overwritten without asking again.
+    #[serde(skip)]
+    confirmed_output: Option<PathBuf>,
+    #[serde(skip)]
+    output_status: Option<String>,
+    /// Repath the picked file in the background whenever the settings change, without writing
+    /// the output file.
+    live_repath: bool,
+    #[serde(skip)]
+    live: LiveRepath,
+    #[serde(skip)]
+    batch: Vec<BatchFile>,
+    #[serde(skip)]
+    preset_status: Option<String>,
     #[serde(skip)]
     output_contents: Result<Option<String>>,
     #[serde(skip)]
+    output_warnings: Vec<OutputWarning>,
+    #[serde(skip)]
+    output_origins: Vec<LineOrigin>,
+    #[serde(skip)]
+    stats: Option<JobStats>,
+    output_view: OutputView,
+    /// The output as edited in the app, if it was.
+    #[serde(skip)]
+    edited_output: Option<String>,
+    #[serde(skip)]
+    edit_status: Option<String>,
+    #[serde(skip)]
+    input_contents: String,
+    #[serde(skip)]
+    diff_rows: Vec<DiffRow>,
+    /// Text to find in the output, ignoring ASCII case.
+    #[serde(skip)]
+    find_text: String,
+    /// The zero-based output line to highlight, and whether it still has to be scrolled to.
+    #[serde(skip)]
+    output_line: Option<(usize, bool)>,
+    #[serde(skip)]
     output_file: Option<PathBuf>,
+    #[serde(skip)]
+    preview: Option<Preview>,
+    /// The repathed moves, for exporting them in other formats than G-code.
+    #[serde(skip)]
+    toolpath: Option<Toolpath>,
+    /// Index into the corners of the preview.
+    #[serde(skip)]
+    selected_corner: Option<usize>,
+    #[serde(skip)]
+    preview_view: PreviewView,
+    #[serde(skip)]
+    playback: Playback,
 }
 
 impl Default for DragknifeApp {
     fn default() -> Self {
+        let config = DragknifeConfig {
+            knife_offset: 1.,
+            lift_config: LiftConfig::RelativeHeight(1.0),
+            sharp_angle_threshold: 10. * PI / 180.,
+            swivel_feedrate: 300.,
+            reversal_lift: LiftConfig::RelativeHeight(5.0),
+            max_cut_depth: Some(3.),
+            ..Default::default()
+        };
         Self {
-            config: DragknifeConfig {
-                knife_offset: 1.,
-                lift_config: LiftConfig::RelativeHeight(1.0),
-                sharp_angle_threshold: 10. * PI / 180.,
-                swivel_feedrate: 300.,
-            },
+            config_b: config.clone(),
+            config,
             input_file: None,
+            recent_files: vec![],
             output_file: None,
-            skip_m3: true,
+            output_config: OutputConfig {
+                filters: vec![OutputFilter::Code {
+                    letter: 'M',
+                    major_number: 3,
+                }],
+                format_numbers: true,
+                ..Default::default()
+            },
+            filter_text: "M3".to_string(),
+            presets: PresetLibrary::builtin(),
+            preset_name: "".to_string(),
+            strict_parameters: false,
+            verify_output: true,
+            rapid_feedrate: 5000.,
+            compare: false,
+            editing_b: false,
+            compared: None,
+            preview_shown: true,
+            language: Language::default(),
+            units: DisplayUnits::default(),
+            image_scale: 10.,
+            image_status: None,
+            port_path: String::new(),
+            baud_rate: 115200,
+            machine: Machine::default(),
+            name_template: job::DEFAULT_NAME_TEMPLATE.to_string(),
+            input_source: None,
+            upload: None,
+            output_dir: None,
+            pending_overwrite: None,
+            confirmed_output: None,
+            output_status: None,
+            live_repath: false,
+            live: LiveRepath::default(),
+            batch: vec![],
+            preset_status: None,
             output_contents: Ok(None),
-            output_name: "".to_string(),
+            output_warnings: vec![],
+            output_origins: vec![],
+            stats: None,
+            output_view: OutputView::default(),
+            edited_output: None,
+            edit_status: None,
+            input_contents: String::new(),
+            diff_rows: vec![],
+            find_text: String::new(),
+            output_line: None,
+            preview: None,
+            toolpath: None,
+            selected_corner: None,
+            preview_view: PreviewView::default(),
+            playback: Playback::default(),
         }
     }
 }
 
 impl DragknifeApp {
     pub fn new(cc: &CreationContext<'_>) -> Self {
-        if let Some(storage) = cc.storage {
-            return eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+        #[allow(unused_mut)]
+        let mut app: Self = cc
+            .storage
+            .and_then(|storage| eframe::get_value(storage, eframe::APP_KEY))
+            .unwrap_or_default();
+        // The browser only remembers the name of an uploaded file, not its contents
+        #[cfg(target_arch = "wasm32")]
+        {
+            app.input_file = None;
         }
-        Default::default()
+        app
     }
 }
 
@@ -54,135 +253,3083 @@ impl eframe::App for DragknifeApp {
         eframe::set_value(storage, eframe::APP_KEY, self);
     }
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        i18n::set_language(self.language);
+        self.poll_upload();
+        let mut repathed = self.poll_live_repath(ctx).map(|result| (result, true));
+        let shortcuts = Shortcuts::consume(ctx);
+        self.machine.poll();
         let Self {
             config,
             input_file,
+            recent_files,
             output_file,
-            skip_m3,
-            output_name,
+            output_config,
+            filter_text,
+            presets,
+            preset_name,
+            preset_status,
+            strict_parameters,
+            verify_output,
             output_contents,
+            output_warnings,
+            output_origins,
+            stats,
+            output_view,
+            edited_output,
+            edit_status,
+            input_contents,
+            diff_rows,
+            find_text,
+            output_line,
+            preview,
+            toolpath,
+            selected_corner,
+            preview_view,
+            rapid_feedrate,
+            compare,
+            config_b,
+            editing_b,
+            compared,
+            preview_shown,
+            language,
+            units,
+            image_scale,
+            image_status,
+            port_path,
+            baud_rate,
+            machine,
+            name_template,
+            input_source,
+            #[cfg(target_arch = "wasm32")]
+            upload,
+            #[cfg(not(target_arch = "wasm32"))]
+                upload: _,
+            output_dir,
+            pending_overwrite,
+            confirmed_output,
+            output_status,
+            live_repath,
+            live,
+            batch,
+            playback,
         } = self;
 
+        if shortcuts.toggle_preview {
+            *preview_shown = !*preview_shown;
+        }
+        egui::SidePanel::right("preview panel")
+            .default_width(400.)
+            .show_animated(ctx, *preview_shown, |ui| {
+                ui.heading(tr("Preview"));
+                let Some(preview) = preview else {
+                    ui.label(tr("Repath a file to see the path."));
+                    return;
+                };
+                ui.horizontal(|ui| {
+                    if ui
+                        .button(tr("Fit"))
+                        .on_hover_text(tr("Show the whole path"))
+                        .clicked()
+                    {
+                        *preview_view = PreviewView::default();
+                    }
+                    ui.label(tr("Scroll to zoom, drag to pan"));
+                });
+                #[cfg(not(target_arch = "wasm32"))]
+                ui.horizontal(|ui| {
+                    if ui
+                        .button(tr("Export image…"))
+                        .on_hover_text(tr("Save the path as a PNG or SVG image"))
+                        .clicked()
+                    {
+                        let name = input_file
+                            .as_ref()
+                            .and_then(|file| file.file_stem())
+                            .map_or("preview.png".to_string(), |stem| {
+                                format!("{}_preview.png", stem.to_string_lossy())
+                            });
+                        if let Some(path) = rfd::FileDialog::new()
+                            .add_filter(tr("PNG image"), &["png"])
+                            .add_filter(tr("SVG image"), &["svg"])
+                            .set_file_name(&name)
+                            .save_file()
+                        {
+                            *image_status = match preview.save_image(&path, *image_scale) {
+                                Ok(()) => {
+                                    Some(tr_args("Saved to {path}", &[("path", &path.display())]))
+                                }
+                                Err(err) => Some(format!("{err}")),
+                            };
+                        }
+                    }
+                    ui.add(
+                        egui::DragValue::new(image_scale)
+                            .clamp_range(0.1..=1000.0)
+                            .speed(0.1)
+                            .suffix(" px/mm"),
+                    );
+                    if let Some(toolpath) = toolpath {
+                        if ui
+                            .button(tr("Export path…"))
+                            .on_hover_text(tr(
+                                "Save the corrected path as HPGL for vinyl cutters and plotters, \
+                                 as an SVG image at real size with a layer for each kind of move, \
+                                 or as JSON for other tools",
+                            ))
+                            .clicked()
+                        {
+                            *image_status = export_toolpath(toolpath, input_file.as_deref());
+                        }
+                    }
+                    if let Some(status) = image_status {
+                        ui.label(status.as_str());
+                    }
+                });
+                let duration = preview.duration();
+                ui.horizontal(|ui| {
+                    let play = if playback.playing { "⏸" } else { "▶" };
+                    if ui.button(play).clicked() {
+                        if !playback.playing && playback.time >= duration {
+                            playback.time = 0.;
+                        }
+                        playback.playing = !playback.playing;
+                    }
+                    ui.add(egui::Slider::new(&mut playback.time, 0.0..=duration).show_value(false));
+                    ui.label(format!(
+                        "{} / {}",
+                        format_time(playback.time),
+                        format_time(duration)
+                    ));
+                    egui::ComboBox::from_id_source("playback speed")
+                        .selected_text(format!("{}×", playback.speed))
+                        .width(50.)
+                        .show_ui(ui, |ui| {
+                            for speed in [1., 2., 5., 10., 50., 100.] {
+                                ui.selectable_value(
+                                    &mut playback.speed,
+                                    speed,
+                                    format!("{speed}×"),
+                                );
+                            }
+                        });
+                });
+                if playback.playing {
+                    playback.time += ui.input(|i| i.stable_dt) as f64 * playback.speed;
+                    if playback.time >= duration {
+                        playback.time = duration;
+                        playback.playing = false;
+                    }
+                    ctx.request_repaint();
+                }
+                let knife = preview.knife_at(playback.time);
+                let compared = compared.as_ref().map(|(preview, _)| preview);
+                if compared.is_some() {
+                    ui.colored_label(COMPARED_COLOR, tr("Configuration B is drawn in blue"));
+                }
+                show_preview(ui, preview, compared, knife, selected_corner, preview_view);
+                if let (Some(corner), Ok(Some(output))) = (
+                    selected_corner.and_then(|i| preview.corners.get(i)),
+                    &output_contents,
+                ) {
+                    ui.separator();
+                    show_corner(
+                        ui,
+                        corner,
+                        preview.knife_offset,
+                        output,
+                        output_origins,
+                        output_line,
+                    );
+                }
+                ui.separator();
+                let swivels = preview.corners.iter().filter(|c| c.swivel.is_some());
+                ui.label(tr_args(
+                    "{count} inserted swivels",
+                    &[("count", &swivels.count())],
+                ));
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for (i, corner) in preview.corners.iter().enumerate() {
+                        let Some(swivel) = &corner.swivel else {
+                            continue;
+                        };
+                        let kind = if swivel.reversal {
+                            tr("reversal")
+                        } else {
+                            tr("swivel")
+                        };
+                        let text = format!(
+                            "{}: {} {kind}",
+                            line_text(corner.line),
+                            turn_text(corner.angle)
+                        );
+                        if ui
+                            .selectable_label(*selected_corner == Some(i), text)
+                            .clicked()
+                        {
+                            *selected_corner = Some(i);
+                        }
+                    }
+                });
+            });
+
         egui::CentralPanel::default().show(ctx, |ui| {
-            ui.heading("Dragknife settings");
+            ui.horizontal(|ui| {
+                ui.heading(tr("Dragknife settings"));
+                ui.toggle_value(preview_shown, tr("Preview"))
+                    .on_hover_text(ctx.format_shortcut(&PREVIEW_SHORTCUT));
+            });
             egui::warn_if_debug_build(ui);
 
-            ui.add(
-                egui::Slider::new(&mut config.knife_offset, 0.0..=50.0)
-                    .text("Dragknife offset (mm)"),
-            );
-            let selected_height = *config.lift_config.get_height_mut();
             ui.horizontal(|ui| {
-                ui.label("Swivel lift type");
+                ui.checkbox(compare, tr("Compare with a second configuration"));
+                if *compare {
+                    ui.selectable_value(editing_b, false, tr("Edit A"));
+                    ui.selectable_value(editing_b, true, tr("Edit B"));
+                    if ui.button(tr("Copy A to B")).clicked() {
+                        *config_b = config.clone();
+                    }
+                }
+            });
+            // While comparing, the knife settings below can edit configuration B instead
+            let edited_config = if *compare && *editing_b {
+                &mut *config_b
+            } else {
+                &mut *config
+            };
+            // The selected preset, if the settings were changed since it was picked
+            let modified = presets
+                .get(preset_name)
+                .filter(|preset| preset.config != *edited_config)
+                .cloned();
+            ui.horizontal(|ui| {
+                let selected_text = if modified.is_some() {
+                    tr_args("{preset} (modified)", &[("preset", preset_name)])
+                } else {
+                    preset_name.clone()
+                };
+                egui::ComboBox::from_label(tr("Preset"))
+                    .selected_text(selected_text)
+                    .show_ui(ui, |ui| {
+                        for preset in presets.presets.iter() {
+                            if ui
+                                .selectable_label(*preset_name == preset.name, &preset.name)
+                                .clicked()
+                            {
+                                *preset_name = preset.name.clone();
+                                *edited_config = preset.config.clone();
+                            }
+                        }
+                    });
+            });
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(preset_name).hint_text(tr("Preset name")));
+                if ui.button(tr("Save preset")).clicked() && !preset_name.is_empty() {
+                    presets.insert(Preset::new(preset_name.clone(), edited_config.clone()));
+                }
+                if ui.button(tr("Delete preset")).clicked() {
+                    presets.remove(preset_name);
+                }
+                if let Some(preset) = modified {
+                    if ui
+                        .button(tr("Revert"))
+                        .on_hover_text(tr("Go back to the settings of the preset"))
+                        .clicked()
+                    {
+                        *edited_config = preset.config;
+                    }
+                }
+            });
+            #[cfg(not(target_arch = "wasm32"))]
+            ui.horizontal(|ui| {
+                if ui.button(tr("Import presets…")).clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter(tr("Presets"), &["json"])
+                        .pick_file()
+                    {
+                        *preset_status = match PresetLibrary::load(&path) {
+                            Ok(imported) => {
+                                let count = imported.presets.len();
+                                presets.merge(imported);
+                                Some(tr_args("Imported {count} presets.", &[("count", &count)]))
+                            }
+                            Err(err) => Some(format!("{err}")),
+                        };
+                    }
+                }
+                if let Some(preset) = presets.get(preset_name) {
+                    if ui
+                        .button(tr("Share preset…"))
+                        .on_hover_text(tr("Export only this preset, to import on another machine"))
+                        .clicked()
+                    {
+                        if let Some(path) = rfd::FileDialog::new()
+                            .add_filter(tr("Presets"), &["json"])
+                            .set_file_name(&format!("{}.json", preset.name))
+                            .save_file()
+                        {
+                            let shared = PresetLibrary {
+                                presets: vec![preset.clone()],
+                            };
+                            *preset_status = match shared.save(&path) {
+                                Ok(()) => {
+                                    Some(tr_args("Shared to {path}", &[("path", &path.display())]))
+                                }
+                                Err(err) => Some(format!("{err}")),
+                            };
+                        }
+                    }
+                }
+                if ui.button(tr("Export presets…")).clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter(tr("Presets"), &["json"])
+                        .save_file()
+                    {
+                        *preset_status = match presets.save(&path) {
+                            Ok(()) => {
+                                Some(tr_args("Exported to {path}", &[("path", &path.display())]))
+                            }
+                            Err(err) => Some(format!("{err}")),
+                        };
+                    }
+                }
+            });
+            #[cfg(not(target_arch = "wasm32"))]
+            ui.horizontal(|ui| {
+                if ui
+                    .button(tr("Import settings…"))
+                    .on_hover_text(tr("Load the knife, output and check settings from a file"))
+                    .clicked()
+                {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter(tr("Settings"), &["toml", "json"])
+                        .pick_file()
+                    {
+                        *preset_status = match ConfigFile::load(&path) {
+                            Ok(file) => {
+                                *edited_config = file.knife;
+                                *output_config = file.output;
+                                *strict_parameters = file.strict_parameters;
+                                *verify_output = file.verify_output;
+                                Some(tr_args("Imported {path}", &[("path", &path.display())]))
+                            }
+                            Err(err) => Some(format!("{err}")),
+                        };
+                    }
+                }
+                if ui
+                    .button(tr("Export settings…"))
+                    .on_hover_text(tr(
+                        "Save the knife, output and check settings to share a machine setup. \
+                        dragknife-cli reads the same file.",
+                    ))
+                    .clicked()
+                {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter(tr("Settings"), &["toml", "json"])
+                        .set_file_name(CONFIG_FILE_NAME)
+                        .save_file()
+                    {
+                        let file = ConfigFile {
+                            strict_parameters: *strict_parameters,
+                            verify_output: *verify_output,
+                            knife: edited_config.clone(),
+                            output: output_config.clone(),
+                        };
+                        *preset_status = match file.save(&path) {
+                            Ok(()) => {
+                                Some(tr_args("Exported to {path}", &[("path", &path.display())]))
+                            }
+                            Err(err) => Some(format!("{err}")),
+                        };
+                    }
+                }
+            });
+            if let Some(status) = preset_status {
+                ui.label(status.as_str());
+            }
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                ui.label(tr("Language"));
+                egui::ComboBox::from_id_source("language")
+                    .selected_text(language.name())
+                    .show_ui(ui, |ui| {
+                        for option in Language::ALL {
+                            ui.selectable_value(language, option, option.name());
+                        }
+                    });
+            });
+            ui.horizontal(|ui| {
+                ui.label(tr("Units"));
+                ui.selectable_value(units, DisplayUnits::Millimeters, "mm");
+                ui.selectable_value(units, DisplayUnits::Inches, "inch");
+            });
+            ui.add(units.length_slider(
+                &mut edited_config.knife_offset,
+                0.0..=50.0,
+                tr("Dragknife offset"),
+            ));
+            let selected_height = *edited_config.lift_config.get_height_mut();
+            ui.horizontal(|ui| {
+                ui.label(tr("Swivel lift type"));
                 ui.selectable_value(
-                    &mut config.lift_config,
+                    &mut edited_config.lift_config,
                     LiftConfig::RelativeHeight(selected_height),
-                    "Relative",
+                    tr("Relative"),
                 );
                 ui.selectable_value(
-                    &mut config.lift_config,
+                    &mut edited_config.lift_config,
                     LiftConfig::AbsoluteHeight(selected_height),
-                    "Absolute",
+                    tr("Absolute"),
                 );
             });
+            ui.add(units.length_slider(
+                edited_config.lift_config.get_height_mut(),
+                0.0..=50.0,
+                tr("Swivel lift height"),
+            ));
+            ui.horizontal(|ui| {
+                let mut relative = edited_config.swivel_feedrate_percentage.is_some();
+                ui.checkbox(&mut relative, tr("Relative swivel feedrate"));
+                match (relative, &mut edited_config.swivel_feedrate_percentage) {
+                    (true, Some(percentage)) => {
+                        ui.add(
+                            egui::Slider::new(percentage, 1.0..=100.0)
+                                .text(tr("Swivel feedrate (% of cutting feedrate)")),
+                        );
+                    }
+                    (true, percentage @ None) => *percentage = Some(25.),
+                    (false, percentage) => {
+                        *percentage = None;
+                        ui.add(units.feedrate_slider(
+                            &mut edited_config.swivel_feedrate,
+                            0.0..=2000.0,
+                            tr("Swivel feedrate"),
+                        ));
+                    }
+                }
+            });
             ui.add(
-                egui::Slider::new(config.lift_config.get_height_mut(), 0.0..=50.0)
-                    .text("Swivel lift height (mm)"),
-            );
-            ui.add(
-                egui::Slider::new(&mut config.swivel_feedrate, 0.0..=2000.0)
-                    .text("Swivel feedrate (mm/min)"),
+                egui::Slider::new(&mut edited_config.swivel_dwell, 0.0..=5.0)
+                    .text(tr("Dwell after swivel (s)")),
             );
             ui.add(
                 egui::Slider::from_get_set(0.0..=180.0, |optional| {
                     if let Some(v) = optional {
-                        config.sharp_angle_threshold = v as f32 * PI / 180.;
+                        edited_config.sharp_angle_threshold = v * PI / 180.;
                     }
-                    (config.sharp_angle_threshold * 180. / PI).into()
+                    edited_config.sharp_angle_threshold * 180. / PI
                 })
-                .text("Sharp corner threshold (°)"),
+                .text(if edited_config.right_turn_threshold.is_some() {
+                    tr("Left turn threshold (°)")
+                } else {
+                    tr("Sharp corner threshold (°)")
+                }),
             );
-            ui.checkbox(skip_m3, "Remove M3 commands from output");
-            ui.separator();
-            ui.add(egui::TextEdit::singleline(output_name).hint_text("Output filename"));
-            if ui.button("Open file…").clicked() {
-                if let Some(path) = rfd::FileDialog::new().pick_file() {
-                    *input_file = Some(path);
+            ui.horizontal(|ui| {
+                let mut separate = edited_config.right_turn_threshold.is_some();
+                ui.checkbox(&mut separate, tr("Separate threshold for right turns"));
+                match (separate, &mut edited_config.right_turn_threshold) {
+                    (true, Some(threshold)) => {
+                        ui.add(
+                            egui::Slider::from_get_set(0.0..=180.0, |optional| {
+                                if let Some(v) = optional {
+                                    *threshold = v * PI / 180.;
+                                }
+                                *threshold * 180. / PI
+                            })
+                            .text(tr("Right turn threshold (°)")),
+                        );
+                    }
+                    (true, threshold @ None) => {
+                        *threshold = Some(edited_config.sharp_angle_threshold)
+                    }
+                    (false, threshold) => *threshold = None,
                 }
-            }
-            if let Some(picked_path) = input_file {
+            });
+            ui.horizontal(|ui| {
+                let mut lift = edited_config.reversal_angle.is_some();
+                ui.checkbox(&mut lift, tr("Lift at reversals"));
+                match (lift, &mut edited_config.reversal_angle) {
+                    (true, Some(angle)) => {
+                        ui.add(
+                            egui::Slider::from_get_set(90.0..=180.0, |optional| {
+                                if let Some(v) = optional {
+                                    *angle = v * PI / 180.;
+                                }
+                                *angle * 180. / PI
+                            })
+                            .text(tr("Reversal angle (°)")),
+                        );
+                        ui.add(units.length_slider(
+                            edited_config.reversal_lift.get_height_mut(),
+                            0.0..=50.0,
+                            tr("Reversal lift height"),
+                        ));
+                    }
+                    (true, angle @ None) => *angle = Some(170f64.to_radians()),
+                    (false, angle) => *angle = None,
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label(tr("Arcs without a consistent radius"));
+                ui.selectable_value(
+                    &mut edited_config.degenerate_arcs,
+                    DegenerateArcs::Linear,
+                    tr("Cut as line"),
+                );
+                ui.selectable_value(
+                    &mut edited_config.degenerate_arcs,
+                    DegenerateArcs::Drop,
+                    tr("Drop"),
+                );
+                ui.selectable_value(
+                    &mut edited_config.degenerate_arcs,
+                    DegenerateArcs::Error,
+                    tr("Stop"),
+                );
+            });
+            ui.horizontal(|ui| {
+                let mut check = edited_config.max_cut_depth.is_some();
+                ui.checkbox(&mut check, tr("Warn about deep cuts"));
+                match (check, &mut edited_config.max_cut_depth) {
+                    (true, Some(depth)) => {
+                        ui.add(units.length_slider(depth, 0.0..=20.0, tr("Max depth below zero")));
+                    }
+                    (true, depth @ None) => *depth = Some(3.),
+                    (false, depth) => *depth = None,
+                }
+            });
+            ui.horizontal(|ui| {
+                let mut check = edited_config.min_height.is_some();
+                ui.checkbox(&mut check, tr("Protect the table"));
+                match (check, &mut edited_config.min_height) {
+                    (true, Some(height)) => {
+                        ui.add(units.length_drag(height).speed(0.05));
+                        ui.label(tr("Lowest allowed height"));
+                    }
+                    (true, height @ None) => *height = Some(-0.2),
+                    (false, height) => *height = None,
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label(tr("Codes which are not understood"))
+                    .on_hover_text(tr(
+                        "G and M codes which may change the path, like a drilling cycle (G83), \
+                        are copied to the output as they are",
+                    ));
+                ui.selectable_value(
+                    &mut edited_config.unsupported_codes,
+                    UnsupportedCodes::Pass,
+                    tr("Copy"),
+                );
+                ui.selectable_value(
+                    &mut edited_config.unsupported_codes,
+                    UnsupportedCodes::Warn,
+                    tr("Copy and warn"),
+                );
+                ui.selectable_value(
+                    &mut edited_config.unsupported_codes,
+                    UnsupportedCodes::Error,
+                    tr("Stop"),
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.label(tr("Allowed codes"))
+                    .on_hover_text(tr("Copied without a warning"));
+                show_code_list(
+                    ui,
+                    "allowed codes",
+                    &mut edited_config.allowed_codes,
+                    "e.g. M62-65",
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.label(tr("Blocked codes"))
+                    .on_hover_text(tr("Stop the repath, also codes which are understood"));
+                show_code_list(
+                    ui,
+                    "blocked codes",
+                    &mut edited_config.blocked_codes,
+                    "e.g. M3",
+                );
+            });
+            ui.horizontal(|ui| {
+                let mut lift = edited_config.crossing_lift.is_some();
+                ui.checkbox(&mut lift, tr("Lift higher for swivels across earlier cuts"));
+                match (lift, &mut edited_config.crossing_lift) {
+                    (true, Some(crossing_lift)) => {
+                        ui.add(units.length_slider(
+                            crossing_lift.get_height_mut(),
+                            0.0..=50.0,
+                            tr("Crossing lift height"),
+                        ));
+                    }
+                    (true, crossing_lift @ None) => {
+                        *crossing_lift = Some(LiftConfig::RelativeHeight(5.0))
+                    }
+                    (false, crossing_lift) => *crossing_lift = None,
+                }
+            });
+            ui.checkbox(
+                &mut edited_config.reverse_pushed_contours,
+                tr("Reverse contours with fewer unswiveled corners the other way"),
+            );
+            ui.add(units.length_slider(
+                &mut edited_config.compensation_radius,
+                0.0..=10.0,
+                tr("Cutter compensation radius (G41/G42)"),
+            ))
+            .on_hover_text(tr(
+                "The path is offset by this much where the program turns on cutter compensation, \
+                unless G41.1 or G42.1 give a diameter with D",
+            ));
+            ui.add(units.length_slider(
+                &mut edited_config.kerf_width,
+                0.0..=2.0,
+                tr("Blade kerf width"),
+            ))
+            .on_hover_text(tr(
+                "Closed contours are offset by half of this, outward around parts and inward in \
+                holes, so they are cut at the size they were drawn",
+            ));
+            ui.add(units.length_slider(
+                &mut edited_config.corner_radius,
+                0.0..=5.0,
+                tr("Corner radius"),
+            ))
+            .on_hover_text(tr(
+                "Corners between cuts are replaced by arcs of this radius, which the blade \
+                follows without a swivel",
+            ));
+            ui.horizontal(|ui| {
+                ui.label(tr("Round"));
+                ui.selectable_value(
+                    &mut edited_config.corner_rounding,
+                    CornerRounding::All,
+                    tr("Every corner"),
+                );
+                ui.selectable_value(
+                    &mut edited_config.corner_rounding,
+                    CornerRounding::Unswiveled,
+                    tr("Corners without a swivel"),
+                )
+                .on_hover_text(tr("Sharp corners are still swiveled at"));
+            });
+            ui.collapsing(tr("Work offsets"), |ui| {
+                egui::Grid::new("work offsets").show(ui, |ui| {
+                    for (i, offset) in edited_config.work_offsets.iter_mut().enumerate() {
+                        ui.label(format!("G{}", 54 + i));
+                        ui.add(units.length_drag(&mut offset.x).prefix("X ").speed(0.1));
+                        ui.add(units.length_drag(&mut offset.y).prefix("Y ").speed(0.1));
+                        ui.add(units.length_drag(&mut offset.z).prefix("Z ").speed(0.1));
+                        ui.end_row();
+                    }
+                });
+            });
+            ui.collapsing(tr("Operations"), |ui| {
+                ui.label(tr(
+                    "Cut the parts of the program after a marker comment with other settings, \
+                    e.g. crease lines with a creasing wheel",
+                ));
                 ui.horizontal(|ui| {
-                    ui.label("Picked file:");
-                    egui::ScrollArea::horizontal()
-                        .stick_to_right(true)
-                        .show(ui, |ui| ui.monospace(picked_path.display().to_string()));
+                    ui.label(tr("Marker"));
+                    ui.add(
+                        egui::TextEdit::singleline(&mut edited_config.operation_marker)
+                            .hint_text(DEFAULT_OPERATION_MARKER),
+                    )
+                    .on_hover_text(tr(
+                        "A regex for the comments which start an operation, with its name as the \
+                        first group",
+                    ));
                 });
-                if ui.button("Repath").clicked() {
-                    match repath_and_write(picked_path, &config, &output_name, *skip_m3) {
-                        Ok((output, output_file_opt)) => {
-                            *output_contents = Ok(Some(output));
-                            *output_file = output_file_opt
+                let mut remove = None;
+                egui::Grid::new("operations").show(ui, |ui| {
+                    ui.label(tr("Name"));
+                    ui.label(tr("Depth"));
+                    ui.label(tr("Dragknife offset"));
+                    ui.label(tr("Tool change"));
+                    ui.end_row();
+                    for (i, operation) in edited_config.operations.iter_mut().enumerate() {
+                        ui.add(egui::TextEdit::singleline(&mut operation.name).desired_width(80.));
+                        ui.horizontal(|ui| {
+                            let mut set = operation.depth.is_some();
+                            ui.checkbox(&mut set, "");
+                            match (set, &mut operation.depth) {
+                                (true, Some(depth)) => {
+                                    ui.add(units.length_drag(depth).speed(0.05));
+                                }
+                                (true, depth @ None) => *depth = Some(-0.5),
+                                (false, depth) => *depth = None,
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            let mut set = operation.knife_offset.is_some();
+                            ui.checkbox(&mut set, "");
+                            match (set, &mut operation.knife_offset) {
+                                (true, Some(offset)) => {
+                                    ui.add(units.length_drag(offset).speed(0.01));
+                                }
+                                (true, offset @ None) => *offset = Some(0.),
+                                (false, offset) => *offset = None,
+                            }
+                        });
+                        ui.add(
+                            egui::TextEdit::singleline(&mut operation.tool_change)
+                                .hint_text("e.g. M0")
+                                .desired_width(80.),
+                        );
+                        if ui.button(tr("Remove")).clicked() {
+                            remove = Some(i);
                         }
-                        Err(err) => *output_contents = Err(err),
+                        ui.end_row();
                     }
+                });
+                if let Some(i) = remove {
+                    edited_config.operations.remove(i);
+                }
+                if ui
+                    .button(tr("Add operation"))
+                    .on_hover_text(tr(
+                        "Other settings, like swivels and lift, can be set in a config file",
+                    ))
+                    .clicked()
+                {
+                    edited_config.operations.push(Operation {
+                        name: format!("operation{}", edited_config.operations.len() + 1),
+                        ..Default::default()
+                    });
+                }
+            });
+            ui.collapsing(tr("Registration marks"), |ui| {
+                ui.label(tr(
+                    "Line the path up with a printed design: measure where the first two marks \
+                    are on the machine, and the path is moved and rotated onto them",
+                ));
+                let registration = &mut edited_config.registration;
+                let mut remove = None;
+                egui::Grid::new("registration_marks").show(ui, |ui| {
+                    ui.label(tr("Mark"));
+                    ui.label(tr("In the design"));
+                    ui.label(tr("Measured"));
+                    ui.end_row();
+                    for (i, mark) in registration.marks.iter_mut().enumerate() {
+                        ui.label(format!("{}", i + 1));
+                        ui.horizontal(|ui| {
+                            ui.add(units.length_drag(&mut mark[0]).prefix("X ").speed(0.1));
+                            ui.add(units.length_drag(&mut mark[1]).prefix("Y ").speed(0.1));
+                        });
+                        ui.horizontal(|ui| {
+                            if let Some(measured) = registration.measured.get_mut(i) {
+                                ui.add(units.length_drag(&mut measured[0]).prefix("X ").speed(0.1));
+                                ui.add(units.length_drag(&mut measured[1]).prefix("Y ").speed(0.1));
+                            }
+                        });
+                        if ui.button(tr("Remove")).clicked() {
+                            remove = Some(i);
+                        }
+                        ui.end_row();
+                    }
+                });
+                if let Some(i) = remove {
+                    registration.marks.remove(i);
+                    registration.measured.clear();
                 }
-            }
-            if let Ok(Some(output)) = output_contents {
                 ui.horizontal(|ui| {
-                    if ui.button("📋").on_hover_text("Click to copy").clicked() {
-                        ui.output_mut(|o| o.copied_text = output.clone());
+                    if ui.button(tr("Add mark")).clicked() {
+                        registration.marks.push([0., 0.]);
                     }
-                    if let Some(output_file_actual) = output_file {
-                        egui::ScrollArea::horizontal()
-                            .stick_to_right(true)
-                            .id_source("second scroll area")
-                            .show(ui, |ui| {
-                                ui.monospace(output_file_actual.display().to_string())
-                            });
-                    } else {
-                        ui.label("Output file name was empty: did not write to file.");
+                    let mut measured = !registration.measured.is_empty();
+                    ui.add_enabled_ui(registration.marks.len() >= 2, |ui| {
+                        ui.checkbox(&mut measured, tr("Measured"));
+                    });
+                    match (measured, registration.measured.is_empty()) {
+                        // Start from the design, so only the difference has to be entered
+                        (true, true) => registration.measured = registration.marks[..2].to_vec(),
+                        (false, false) => registration.measured.clear(),
+                        _ => {}
+                    }
+                    if let Some(stretch) = registration.stretch() {
+                        ui.label(tr_args(
+                            "{stretch} mm further apart than in the design",
+                            &[("stretch", &format!("{stretch:.3}"))],
+                        ));
                     }
                 });
-                egui::ScrollArea::vertical().show(ui, |ui| {
-                    ui.label(output.as_str());
+                ui.horizontal(|ui| {
+                    ui.label(tr("Make marks by"));
+                    ui.radio_value(
+                        &mut registration.mark_style,
+                        MarkStyle::Drill,
+                        tr("plunging"),
+                    );
+                    ui.radio_value(
+                        &mut registration.mark_style,
+                        MarkStyle::Cross,
+                        tr("a cross"),
+                    );
+                    ui.add_enabled(
+                        registration.mark_style == MarkStyle::Cross,
+                        units
+                            .length_drag(&mut registration.mark_size)
+                            .clamp_range(0.1..=100.0)
+                            .speed(0.1),
+                    );
+                    ui.add(
+                        units
+                            .length_drag(&mut registration.mark_depth)
+                            .prefix("depth ")
+                            .speed(0.05),
+                    );
                 });
-            } else if let Err(e) = output_contents {
-                ui.label(format!("{e}"));
-            } else {
-                ui.label("No output");
+                #[cfg(not(target_arch = "wasm32"))]
+                if ui
+                    .add_enabled(
+                        !registration.marks.is_empty(),
+                        egui::Button::new(tr("Save mark program…")),
+                    )
+                    .on_hover_text(tr(
+                        "A program which makes the marks, with the output settings",
+                    ))
+                    .clicked()
+                {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter(tr("GCode"), &["nc", "cnc", "gcode", "ngc", "tap"])
+                        .set_file_name("marks.nc")
+                        .save_file()
+                    {
+                        let program = registration.mark_program(output_config);
+                        *preset_status = match std::fs::write(&path, program.text) {
+                            Ok(()) => {
+                                Some(tr_args("Saved to {path}", &[("path", &path.display())]))
+                            }
+                            Err(err) => Some(format!("{err}")),
+                        };
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label(tr("Lift knife with"));
+                ui.add(
+                    egui::TextEdit::singleline(&mut edited_config.lift_template)
+                        .hint_text(tr("Z move, or e.g. M5 G4 P0.1")),
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.label(tr("Lower knife with"));
+                ui.add(
+                    egui::TextEdit::singleline(&mut edited_config.lower_template)
+                        .hint_text(tr("Z move, or e.g. M3 G4 P0.1")),
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.label(tr("Controller"));
+                let mut dialects = Dialect::builtin().to_vec();
+                if !output_config.dialect.is_builtin() {
+                    dialects.push(output_config.dialect.clone());
+                }
+                egui::ComboBox::from_id_source("dialect")
+                    .selected_text(output_config.dialect.name())
+                    .show_ui(ui, |ui| {
+                        for dialect in dialects {
+                            let name = dialect.name.clone();
+                            ui.selectable_value(&mut output_config.dialect, dialect, name);
+                        }
+                    })
+                    .response
+                    .on_hover_text(tr(
+                        "Leave out the commands and words the controller does not accept",
+                    ));
+                #[cfg(not(target_arch = "wasm32"))]
+                if ui
+                    .button(tr("Load dialect…"))
+                    .on_hover_text(tr(
+                        "Read the codes, words and preamble of another controller from a file",
+                    ))
+                    .clicked()
+                {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter(tr("Settings"), &["toml", "json"])
+                        .pick_file()
+                    {
+                        *preset_status = match Dialect::load(&path) {
+                            Ok(dialect) => {
+                                output_config.dialect = dialect;
+                                Some(tr_args("Imported {path}", &[("path", &path.display())]))
+                            }
+                            Err(err) => Some(format!("{err}")),
+                        };
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label(tr("Material"));
+                let selected = match output_config.material_depth() {
+                    Some(material) => material.name.clone(),
+                    None => tr("Depths of the program").to_string(),
+                };
+                let names: Vec<_> = output_config
+                    .materials
+                    .iter()
+                    .map(|material| material.name.clone())
+                    .collect();
+                egui::ComboBox::from_id_source("material")
+                    .selected_text(selected)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(
+                            &mut output_config.material,
+                            String::new(),
+                            tr("Depths of the program"),
+                        );
+                        for name in names {
+                            ui.selectable_value(&mut output_config.material, name.clone(), name);
+                        }
+                    })
+                    .response
+                    .on_hover_text(tr(
+                        "Scale the cut depths of the program to those of the material",
+                    ));
+            });
+            ui.collapsing(tr("Materials"), |ui| {
+                let mut remove = None;
+                egui::Grid::new("materials").show(ui, |ui| {
+                    ui.label(tr("Name"));
+                    ui.label(tr("Depth in the program"));
+                    ui.label(tr("Depth to cut"));
+                    ui.end_row();
+                    for (i, material) in output_config.materials.iter_mut().enumerate() {
+                        ui.add(egui::TextEdit::singleline(&mut material.name).desired_width(80.));
+                        ui.add(
+                            units
+                                .length_drag(&mut material.nominal_depth)
+                                .clamp_range(0.001..=f32::MAX)
+                                .speed(0.05),
+                        );
+                        ui.add(
+                            units
+                                .length_drag(&mut material.depth)
+                                .clamp_range(0.0..=f32::MAX)
+                                .speed(0.05),
+                        );
+                        if ui.button(tr("Remove")).clicked() {
+                            remove = Some(i);
+                        }
+                        ui.end_row();
+                    }
+                });
+                if let Some(i) = remove {
+                    output_config.materials.remove(i);
+                }
+                if ui.button(tr("Add material")).clicked() {
+                    output_config.materials.push(MaterialDepth {
+                        name: format!("material{}", output_config.materials.len() + 1),
+                        ..Default::default()
+                    });
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label(tr("Remove from output"));
+                ui.add(egui::TextEdit::singleline(filter_text).hint_text("e.g. M3, M7, M8, S"));
+            });
+            match OutputFilter::parse_list(filter_text) {
+                Ok(filters) => output_config.filters = filters,
+                Err(err) => {
+                    ui.label(format!("{err}"));
+                }
             }
-        });
-    }
-}
-
-fn repath_and_write(
-    input_file: &PathBuf,
-    config: &DragknifeConfig,
-    output_name: &str,
-    skip_m3: bool,
-) -> Result<(String, Option<PathBuf>)> {
-    let fc = std::fs::read_to_string(input_file)?;
-    let got: Vec<_> = gcode::parse(&fc).collect();
-    let path = DragknifePath::from_gcode(got.iter());
-    let fixed = path.to_fixed_gcode(config);
-    let output = fixed
-        .iter()
-        .filter(|g| {
-            !skip_m3
-                || match (g.mnemonic(), g.major_number()) {
-                    (Mnemonic::Miscellaneous, 3) => false,
-                    _ => true,
+            ui.checkbox(
+                &mut output_config.gate_knife,
+                tr("Insert M3/M5 around cuts (solenoid knife)"),
+            );
+            ui.horizontal(|ui| {
+                ui.label(tr("Height map"));
+                match &output_config.height_map {
+                    Some(map) => {
+                        let (min, max) = map.range();
+                        ui.label(tr_args(
+                            "{columns} × {rows} points, {min} to {max} mm",
+                            &[
+                                ("columns", &map.x.len()),
+                                ("rows", &map.y.len()),
+                                ("min", &format!("{min:.2}")),
+                                ("max", &format!("{max:.2}")),
+                            ],
+                        ));
+                        if ui.button(tr("Remove")).clicked() {
+                            output_config.height_map = None;
+                        }
+                    }
+                    None => {
+                        ui.label(tr("None"));
+                    }
                 }
-        })
-        .map(|g| format!("{}\n", g))
-        .collect::<String>();
-    let output_file = if !output_name.is_empty() {
-        let output_file = input_file.with_file_name(output_name);
-        let file = File::create(&output_file)?;
-        write!(&file, "{output}")?;
-        Some(output_file)
-    } else {
-        None
-    };
-    Ok((output, output_file))
+                #[cfg(not(target_arch = "wasm32"))]
+                if ui
+                    .button(tr("Load height map…"))
+                    .on_hover_text(tr(
+                        "Read Z offsets measured over the bed from a CSV file with x,y,z lines, \
+                        or JSON",
+                    ))
+                    .clicked()
+                {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter(tr("Height map"), &["csv", "txt", "json"])
+                        .pick_file()
+                    {
+                        *preset_status = match crate::height_map::HeightMap::load(&path) {
+                            Ok(map) => {
+                                output_config.height_map = Some(map);
+                                Some(tr_args("Imported {path}", &[("path", &path.display())]))
+                            }
+                            Err(err) => Some(format!("{err}")),
+                        };
+                    }
+                }
+                ui.add_enabled(
+                    output_config.height_map.is_some(),
+                    units
+                        .length_drag(&mut output_config.height_map_segment)
+                        .prefix("segments ")
+                        .clamp_range(0.1..=1000.0)
+                        .speed(0.1),
+                )
+                .on_hover_text(tr("Longer moves are split to follow the height map"));
+            });
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut output_config.probe_start, tr("Probe the surface"))
+                    .on_hover_text(tr(
+                        "Probe with G38.2 before the first cut and make the surface Z zero",
+                    ));
+                ui.checkbox(&mut output_config.probe_contours, tr("before every cut"));
+                ui.add_enabled_ui(output_config.probes(), |ui| {
+                    ui.add(
+                        units
+                            .feedrate_drag(&mut output_config.probe_feedrate)
+                            .clamp_range(1.0..=f32::MAX)
+                            .prefix("F "),
+                    );
+                    ui.add(
+                        units
+                            .length_drag(&mut output_config.probe_depth)
+                            .clamp_range(0.0..=f32::MAX)
+                            .speed(0.1)
+                            .prefix("depth "),
+                    );
+                    ui.add(
+                        units
+                            .length_drag(&mut output_config.probe_clearance)
+                            .clamp_range(0.0..=f32::MAX)
+                            .speed(0.1)
+                            .prefix("retract "),
+                    );
+                });
+            });
+            ui.checkbox(&mut output_config.strip_comments, tr("Remove comments"));
+            ui.checkbox(
+                &mut output_config.annotate,
+                tr("Comment inserted lines (e.g. swivels)"),
+            );
+            ui.checkbox(
+                &mut output_config.original_comments,
+                tr("Keep changed input lines as comments"),
+            );
+            ui.add_enabled(
+                !output_config.format_numbers,
+                egui::Checkbox::new(
+                    &mut output_config.keep_original_text,
+                    tr("Copy unchanged lines as written"),
+                ),
+            );
+            ui.add_enabled(
+                output_config.keep_original_text && !output_config.format_numbers,
+                egui::Checkbox::new(
+                    &mut output_config.normalize_original_text,
+                    tr("Uppercase and space copied lines"),
+                ),
+            );
+            ui.horizontal(|ui| {
+                ui.label(tr("Line endings"));
+                ui.radio_value(&mut output_config.line_ending, LineEnding::Lf, "LF");
+                ui.radio_value(&mut output_config.line_ending, LineEnding::CrLf, "CR LF");
+                ui.checkbox(&mut output_config.ascii_only, tr("ASCII only"));
+            });
+            ui.checkbox(
+                &mut output_config.percent_delimiters,
+                tr("Start and end with % (Fanuc)"),
+            );
+            ui.checkbox(
+                &mut output_config.incremental,
+                tr("Incremental output (G91)"),
+            );
+            ui.checkbox(
+                &mut output_config.absolute_arc_centers,
+                tr("Absolute arc centers (G90.1)"),
+            );
+            ui.checkbox(&mut output_config.radius_arcs, tr("Arcs with radius (R)"));
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut output_config.arcs_as_lines, tr("Arcs as lines"))
+                    .on_hover_text(tr(
+                        "For controllers without arcs, e.g. Marlin without ARC_SUPPORT",
+                    ));
+                ui.add_enabled(
+                    output_config.arcs_as_lines,
+                    units
+                        .length_drag(&mut output_config.arc_tolerance)
+                        .prefix("tolerance ")
+                        .clamp_range(0.001..=1.0)
+                        .speed(0.001),
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut output_config.line_numbers, tr("Number lines"));
+                ui.add_enabled(
+                    output_config.line_numbers,
+                    egui::DragValue::new(&mut output_config.line_number_start).prefix("from N"),
+                );
+                ui.add_enabled(
+                    output_config.line_numbers,
+                    egui::DragValue::new(&mut output_config.line_number_step)
+                        .prefix("step ")
+                        .clamp_range(1..=1000),
+                );
+            });
+            ui.checkbox(
+                &mut output_config.checksums,
+                tr("Add checksums (Marlin/RepRap)"),
+            );
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut output_config.format_numbers, tr("Round numbers"));
+                ui.add_enabled_ui(output_config.format_numbers, |ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut output_config.coordinate_decimals)
+                            .clamp_range(0..=6)
+                            .prefix("coordinates: "),
+                    );
+                    ui.add(
+                        egui::DragValue::new(&mut output_config.feedrate_decimals)
+                            .clamp_range(0..=6)
+                            .prefix("feedrate: "),
+                    );
+                    ui.checkbox(&mut output_config.trim_zeros, tr("Trim zeros"));
+                });
+            });
+            ui.horizontal(|ui| {
+                ui.checkbox(
+                    &mut output_config.limit_line_length,
+                    tr("Maximum line length"),
+                );
+                ui.add_enabled(
+                    output_config.limit_line_length,
+                    egui::DragValue::new(&mut output_config.max_line_length).clamp_range(10..=1000),
+                );
+            });
+            ui.collapsing(tr("Header and footer"), |ui| {
+                ui.label(tr("Placeholders: {date}, {time}, {safe_height}"));
+                ui.add(
+                    egui::TextEdit::multiline(&mut output_config.header)
+                        .hint_text(tr("Header, e.g. G21 G90 G54"))
+                        .desired_rows(3),
+                );
+                ui.add(
+                    egui::TextEdit::multiline(&mut output_config.footer)
+                        .hint_text(tr("Footer, e.g. G0 Z{safe_height} M2"))
+                        .desired_rows(3),
+                );
+            });
+            ui.checkbox(
+                &mut output_config.split_files,
+                tr("Split into a file per operation and tool change"),
+            )
+            .on_hover_text(tr(
+                "Files are named after the output, with their number and operation",
+            ));
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut output_config.dry_run, tr("Dry run"));
+                ui.add_enabled(
+                    output_config.dry_run,
+                    units.length_slider(
+                        &mut output_config.dry_run_height,
+                        0.0..=50.0,
+                        tr("Dry run height"),
+                    ),
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.checkbox(
+                    &mut output_config.check_clearance,
+                    tr("Check rapid clearance"),
+                );
+                ui.add_enabled(
+                    output_config.check_clearance,
+                    units.length_slider(
+                        &mut output_config.clearance_height,
+                        0.0..=50.0,
+                        tr("Clearance height"),
+                    ),
+                );
+            });
+            ui.add_enabled(
+                output_config.check_clearance,
+                egui::Checkbox::new(
+                    &mut output_config.enforce_clearance,
+                    tr("Lift low rapid moves to clearance height"),
+                ),
+            );
+            ui.checkbox(
+                &mut output_config.check_soft_limits,
+                tr("Check soft limits (from program zero)"),
+            );
+            ui.add_enabled_ui(output_config.check_soft_limits, |ui| {
+                egui::Grid::new("soft limits").show(ui, |ui| {
+                    let limits = &mut output_config.soft_limits;
+                    for (i, axis) in ["X", "Y", "Z"].into_iter().enumerate() {
+                        ui.label(axis);
+                        ui.add(units.length_drag(&mut limits.min[i]).prefix("min "));
+                        ui.add(units.length_drag(&mut limits.max[i]).prefix("max "));
+                        ui.end_row();
+                    }
+                });
+            });
+            ui.horizontal(|ui| {
+                ui.checkbox(
+                    &mut output_config.check_max_feedrate,
+                    tr("Check maximum feedrate"),
+                );
+                ui.add_enabled(
+                    output_config.check_max_feedrate,
+                    units
+                        .feedrate_drag(&mut output_config.max_feedrate)
+                        .clamp_range(1.0..=100000.0),
+                );
+                ui.add_enabled(
+                    output_config.check_max_feedrate,
+                    egui::Checkbox::new(&mut output_config.clamp_feedrate, tr("Lower to maximum")),
+                );
+            });
+            ui.separator();
+            ui.checkbox(
+                strict_parameters,
+                tr("Stop on unset #parameters instead of using 0"),
+            );
+            ui.checkbox(
+                verify_output,
+                tr("Check the written program against the input"),
+            );
+            ui.horizontal(|ui| {
+                ui.add(
+                    units
+                        .feedrate_drag(rapid_feedrate)
+                        .clamp_range(1.0..=100000.0),
+                );
+                ui.label(tr("Rapid feedrate for the preview"));
+            });
+            ui.horizontal(|ui| {
+                ui.label(tr("Output names"));
+                ui.add(
+                    egui::TextEdit::singleline(name_template).hint_text(job::DEFAULT_NAME_TEMPLATE),
+                );
+            });
+            ui.label(tr(
+                "{stem}, {ext} and {name} are the parts of the input file name.",
+            ));
+            #[cfg(not(target_arch = "wasm32"))]
+            ui.horizontal(|ui| {
+                ui.label(tr("Output folder"));
+                match output_dir {
+                    Some(dir) => ui.monospace(dir.display().to_string()),
+                    None => ui.label(tr("next to the input")),
+                };
+                if ui.button(tr("Choose…")).clicked() {
+                    if let Some(dir) = rfd::FileDialog::new().pick_folder() {
+                        *output_dir = Some(dir);
+                    }
+                }
+                if output_dir.is_some() && ui.button(tr("Next to the input")).clicked() {
+                    *output_dir = None;
+                }
+            });
+            #[cfg(target_arch = "wasm32")]
+            ui.horizontal(|ui| {
+                let button = ui.button(tr("Upload file…"));
+                let button = button.on_hover_text(ctx.format_shortcut(&OPEN_SHORTCUT));
+                if button.clicked() || shortcuts.open {
+                    *upload = Some(crate::web::upload_file(ctx));
+                }
+                if upload.is_some() {
+                    ui.spinner();
+                }
+            });
+            #[cfg(not(target_arch = "wasm32"))]
+            ui.horizontal(|ui| {
+                let button = ui.button(tr("Open file…"));
+                let button = button.on_hover_text(ctx.format_shortcut(&OPEN_SHORTCUT));
+                if button.clicked() || shortcuts.open {
+                    if let Some(path) = rfd::FileDialog::new().pick_file() {
+                        remember_file(recent_files, &path);
+                        *input_file = Some(path);
+                    }
+                }
+                ui.add_enabled_ui(!recent_files.is_empty(), |ui| {
+                    ui.menu_button(tr("Recent"), |ui| {
+                        let mut picked = None;
+                        for path in recent_files.iter() {
+                            let name = path.file_name().unwrap_or(path.as_os_str());
+                            let button = ui.add_enabled(
+                                path.exists(),
+                                egui::Button::new(name.to_string_lossy()),
+                            );
+                            if button.on_hover_text(path.display().to_string()).clicked() {
+                                picked = Some(path.clone());
+                                ui.close_menu();
+                            }
+                        }
+                        ui.separator();
+                        if ui.button(tr("Clear")).clicked() {
+                            recent_files.clear();
+                            ui.close_menu();
+                        }
+                        if let Some(path) = picked {
+                            remember_file(recent_files, &path);
+                            *input_file = Some(path);
+                        }
+                    });
+                });
+            });
+            if let Some(picked_path) = input_file {
+                ui.horizontal(|ui| {
+                    ui.label(tr("Picked file:"));
+                    egui::ScrollArea::horizontal()
+                        .stick_to_right(true)
+                        .show(ui, |ui| ui.monospace(picked_path.display().to_string()));
+                });
+                #[cfg(not(target_arch = "wasm32"))]
+                let target = output_target(picked_path, output_dir.as_deref(), name_template);
+                #[cfg(not(target_arch = "wasm32"))]
+                {
+                    ui.horizontal(|ui| {
+                        ui.label(tr("Writes to:"));
+                        egui::ScrollArea::horizontal()
+                            .stick_to_right(true)
+                            .id_source("target scroll area")
+                            .show(ui, |ui| ui.monospace(target.display().to_string()));
+                    });
+                    if target == *picked_path {
+                        ui.colored_label(
+                            ui.visuals().error_fg_color,
+                            tr("The output names would overwrite the input."),
+                        );
+                    } else if target.exists() {
+                        ui.colored_label(
+                            ui.visuals().warn_fg_color,
+                            tr("The output file exists and will be overwritten."),
+                        );
+                    }
+                }
+                ui.horizontal(|ui| {
+                    let button = ui.button(tr("Repath"));
+                    let button = button.on_hover_text(ctx.format_shortcut(&REPATH_SHORTCUT));
+                    // Saving before anything is written writes the output like repathing
+                    let save =
+                        shortcuts.save && output_file.is_none() && !cfg!(target_arch = "wasm32");
+                    if button.clicked() || shortcuts.repath || save {
+                        let inputs = RepathInputs {
+                            input_file: picked_path.clone(),
+                            input_source: input_source.clone(),
+                            config: config.clone(),
+                            output_config: output_config.clone(),
+                            strict_parameters: *strict_parameters,
+                            verify_output: *verify_output,
+                            rapid_feedrate: *rapid_feedrate,
+                            config_b: compare.then(|| config_b.clone()),
+                        };
+                        // Nothing is written in the browser, the output is downloaded instead
+                        #[cfg(target_arch = "wasm32")]
+                        {
+                            repathed = Some((repath(&inputs), false));
+                        }
+                        #[cfg(not(target_arch = "wasm32"))]
+                        if target.exists() && confirmed_output.as_ref() != Some(&target) {
+                            *pending_overwrite = Some((inputs, target));
+                        } else {
+                            *confirmed_output = Some(target.clone());
+                            repathed = Some((repath_and_write(&inputs, &target), false));
+                        }
+                    }
+                    ui.checkbox(live_repath, tr("Live")).on_hover_text(tr(
+                        "Repath whenever the settings change. Repath writes the output file.",
+                    ));
+                    if live.running.is_some() {
+                        ui.spinner();
+                    }
+                });
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            ui.collapsing(tr("Batch"), |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button(tr("Add files…")).clicked() {
+                        if let Some(paths) = rfd::FileDialog::new().pick_files() {
+                            add_to_batch(batch, paths, output_dir.as_deref(), name_template);
+                        }
+                    }
+                    if ui.button(tr("Add folder…")).clicked() {
+                        if let Some(folder) = rfd::FileDialog::new().pick_folder() {
+                            match folder_files(&folder) {
+                                Ok(paths) => {
+                                    add_to_batch(batch, paths, output_dir.as_deref(), name_template)
+                                }
+                                Err(err) => batch.push(BatchFile {
+                                    input: folder,
+                                    status: BatchStatus::Failed(err.to_string()),
+                                }),
+                            }
+                        }
+                    }
+                    if ui.button(tr("Clear")).clicked() {
+                        batch.clear();
+                    }
+                });
+                ui.label(tr(
+                    "The files are written with the output names and folder above.",
+                ));
+                egui::Grid::new("batch").striped(true).show(ui, |ui| {
+                    for file in batch.iter() {
+                        ui.label(file.input.display().to_string());
+                        match &file.status {
+                            BatchStatus::Waiting => {
+                                ui.label(tr("Waiting"));
+                            }
+                            BatchStatus::Done { output, warnings } => {
+                                let name = output.file_name().unwrap_or_default().to_string_lossy();
+                                if *warnings > 0 {
+                                    ui.colored_label(
+                                        ui.visuals().warn_fg_color,
+                                        tr_args(
+                                            "Written to {name}, {warnings} warnings",
+                                            &[("name", &name), ("warnings", warnings)],
+                                        ),
+                                    );
+                                } else {
+                                    ui.label(tr_args("Written to {name}", &[("name", &name)]));
+                                }
+                            }
+                            BatchStatus::Failed(err) => {
+                                ui.colored_label(ui.visuals().error_fg_color, err);
+                            }
+                        }
+                        ui.end_row();
+                    }
+                });
+                if ui
+                    .add_enabled(!batch.is_empty(), egui::Button::new(tr("Process all")))
+                    .clicked()
+                {
+                    for file in batch.iter_mut() {
+                        file.status = process_batch_file(
+                            &file.input,
+                            &output_target(&file.input, output_dir.as_deref(), name_template),
+                            config,
+                            output_config,
+                            *strict_parameters,
+                            *verify_output,
+                        );
+                    }
+                }
+            });
+            #[cfg(not(target_arch = "wasm32"))]
+            ui.collapsing(tr("Send to machine"), |ui| {
+                // Edits are sent as they are shown
+                let program = match (&edited_output, &output_contents) {
+                    (Some(edited), _) => Some(edited.as_str()),
+                    (None, Ok(Some(output))) => Some(output.as_str()),
+                    _ => None,
+                };
+                show_machine(ui, machine, port_path, baud_rate, program, output_line);
+            });
+            if let (Ok(Some(_)), Some(stats)) = (&output_contents, stats) {
+                egui::CollapsingHeader::new(tr("Statistics"))
+                    .default_open(true)
+                    .show(ui, |ui| {
+                        let compared = compared.as_ref().map(|(_, stats)| stats);
+                        show_stats(ui, stats, compared, output_origins.len())
+                    });
+            }
+            if !output_warnings.is_empty() {
+                egui::CollapsingHeader::new(tr_args(
+                    "Warnings ({count})",
+                    &[("count", &output_warnings.len())],
+                ))
+                .default_open(true)
+                .show(ui, |ui| {
+                    show_warnings(ui, output_warnings, output_origins, output_line)
+                });
+            }
+            if let Ok(Some(output)) = output_contents {
+                ui.horizontal(|ui| {
+                    let hover_text = format!(
+                        "{} ({})",
+                        tr("Click to copy"),
+                        ctx.format_shortcut(&COPY_SHORTCUT)
+                    );
+                    if ui.button("📋").on_hover_text(hover_text).clicked() || shortcuts.copy {
+                        let text = edited_output.as_ref().unwrap_or(output);
+                        ui.output_mut(|o| o.copied_text = text.clone());
+                    }
+                    #[cfg(not(target_arch = "wasm32"))]
+                    if let (true, Some(file)) = (shortcuts.save, output_file.as_ref()) {
+                        let text = edited_output.as_ref().unwrap_or(output);
+                        *output_status = Some(match std::fs::write(file, text) {
+                            Ok(()) => tr_args("Saved to {path}", &[("path", &file.display())]),
+                            Err(err) => format!("{}: {err}", file.display()),
+                        });
+                    }
+                    #[cfg(target_arch = "wasm32")]
+                    if let Some(input) = input_file {
+                        let button = ui.button(tr("Download"));
+                        let button = button.on_hover_text(ctx.format_shortcut(&SAVE_SHORTCUT));
+                        if button.clicked() || shortcuts.save {
+                            let text = edited_output.as_ref().unwrap_or(output);
+                            let name = output_target(input, None, name_template);
+                            let name = name.file_name().unwrap_or_default().to_string_lossy();
+                            *output_status = crate::web::download(&name, text).err().map(|err| {
+                                tr_args(
+                                    "Could not download the output: {error}",
+                                    &[("error", &format!("{err:?}"))],
+                                )
+                            });
+                        }
+                    }
+                    if let Some(output_file_actual) = output_file {
+                        if ui
+                            .button("📂")
+                            .on_hover_text(tr("Open the containing folder"))
+                            .clicked()
+                        {
+                            *output_status =
+                                open_containing_folder(output_file_actual).err().map(|err| {
+                                    tr_args(
+                                        "Could not open the folder: {error}",
+                                        &[("error", &err)],
+                                    )
+                                });
+                        }
+                        egui::ScrollArea::horizontal()
+                            .stick_to_right(true)
+                            .id_source("second scroll area")
+                            .show(ui, |ui| {
+                                ui.monospace(output_file_actual.display().to_string())
+                            });
+                    } else if *live_repath && !cfg!(target_arch = "wasm32") {
+                        ui.label(tr("Repathed live: click Repath to write to file."));
+                    }
+                });
+                if let Some(status) = output_status {
+                    ui.label(status.as_str());
+                }
+                ui.horizontal(|ui| {
+                    ui.selectable_value(output_view, OutputView::Output, tr("Output"));
+                    ui.selectable_value(output_view, OutputView::Changes, tr("Changes"));
+                    ui.selectable_value(output_view, OutputView::Edit, tr("Edit"));
+                    if *output_view != OutputView::Edit {
+                        ui.separator();
+                        show_find(ui, output, find_text, output_line);
+                    }
+                });
+                match output_view {
+                    OutputView::Output => {
+                        show_output(ui, output, output_origins, find_text, output_line)
+                    }
+                    OutputView::Changes => {
+                        show_changes_view(ui, input_contents, output, diff_rows, output_line)
+                    }
+                    OutputView::Edit => {
+                        show_edit(ui, output, edited_output, output_file, edit_status)
+                    }
+                }
+            } else if let Err(e) = output_contents {
+                ui.label(format!("{e}"));
+            } else {
+                ui.label(tr("No output"));
+            }
+        });
+
+        if let Some((_, target)) = pending_overwrite {
+            let mut overwrite = None;
+            egui::Window::new(tr("Overwrite file?"))
+                .collapsible(false)
+                .resizable(false)
+                .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+                .show(ctx, |ui| {
+                    ui.label(tr_args(
+                        "{path} exists. Overwrite it?",
+                        &[("path", &target.display())],
+                    ));
+                    ui.horizontal(|ui| {
+                        if ui.button(tr("Overwrite")).clicked() {
+                            overwrite = Some(true);
+                        }
+                        if ui.button(tr("Cancel")).clicked() {
+                            overwrite = Some(false);
+                        }
+                    });
+                });
+            if let Some(overwrite) = overwrite {
+                if let Some((inputs, target)) = pending_overwrite.take().filter(|_| overwrite) {
+                    repathed = Some((repath_and_write(&inputs, &target), false));
+                    *confirmed_output = Some(target);
+                }
+            }
+        }
+
+        if let Some((result, live)) = repathed {
+            self.show_repathed(result, live);
+        }
+    }
+}
+
+impl DragknifeApp {
+    /// Shows the result of a repath. A `live` repath keeps the view of the preview and the
+    /// selected corner, which stay where they were as the settings are changed.
+    fn show_repathed(&mut self, result: Result<Repathed>, live: bool) {
+        let repathed = match result {
+            Ok(repathed) => repathed,
+            Err(err) => {
+                self.output_contents = Err(err);
+                self.preview = None;
+                self.toolpath = None;
+                self.stats = None;
+                self.compared = None;
+                return;
+            }
+        };
+        let output = repathed.output;
+        self.diff_rows = diff::diff_lines(&repathed.source, &output.text, &output.origins);
+        self.input_contents = repathed.source;
+        self.output_contents = Ok(Some(output.text));
+        self.output_warnings = output.warnings;
+        self.output_origins = output.origins;
+        self.output_line = None;
+        self.edited_output = None;
+        self.edit_status = None;
+        self.output_file = repathed.output_file;
+        self.stats = Some(repathed.stats);
+        self.compared = repathed.compared;
+        let corners = repathed.preview.corners.len();
+        self.preview = Some(repathed.preview);
+        self.toolpath = Some(repathed.toolpath);
+        if live {
+            self.selected_corner = self.selected_corner.filter(|&i| i < corners);
+        } else {
+            self.selected_corner = None;
+            self.preview_view = PreviewView::default();
+            self.playback = Playback::default();
+        }
+    }
+
+    /// Takes the file uploaded in the browser as the input once it is read.
+    fn poll_upload(&mut self) {
+        let Some(upload) = &self.upload else {
+            return;
+        };
+        match upload.try_recv() {
+            Ok((name, source)) => {
+                self.input_file = Some(PathBuf::from(name));
+                self.input_source = Some(source.into());
+                self.upload = None;
+            }
+            // The upload was cancelled
+            Err(mpsc::TryRecvError::Disconnected) => self.upload = None,
+            Err(mpsc::TryRecvError::Empty) => {}
+        }
+    }
+
+    /// Starts a repath in the background once the settings have not changed for
+    /// [`LIVE_REPATH_DELAY`], and returns the result of the last one when it is done.
+    fn poll_live_repath(&mut self, ctx: &egui::Context) -> Option<Result<Repathed>> {
+        let live = &mut self.live;
+        let mut finished = None;
+        if let Some(running) = &live.running {
+            match running.try_recv() {
+                Ok(result) => {
+                    finished = Some(result);
+                    live.running = None;
+                }
+                Err(mpsc::TryRecvError::Disconnected) => live.running = None,
+                Err(mpsc::TryRecvError::Empty) => {}
+            }
+        }
+        let (true, Some(input_file)) = (self.live_repath, &self.input_file) else {
+            return finished;
+        };
+        let inputs = RepathInputs {
+            input_file: input_file.clone(),
+            input_source: self.input_source.clone(),
+            config: self.config.clone(),
+            output_config: self.output_config.clone(),
+            strict_parameters: self.strict_parameters,
+            verify_output: self.verify_output,
+            rapid_feedrate: self.rapid_feedrate,
+            config_b: self.compare.then(|| self.config_b.clone()),
+        };
+        let now = ctx.input(|i| i.time);
+        if live.inputs.as_ref() != Some(&inputs) {
+            live.inputs = Some(inputs.clone());
+            live.changed_at = Some(now);
+        }
+        if let Some(changed_at) = live.changed_at {
+            let wait = LIVE_REPATH_DELAY - (now - changed_at);
+            if wait > 0. {
+                ctx.request_repaint_after(Duration::from_secs_f64(wait));
+            } else if live.running.is_none() {
+                // Started again when the running repath is done, as that repaints
+                live.changed_at = None;
+                // There are no threads in the browser
+                #[cfg(target_arch = "wasm32")]
+                {
+                    finished = Some(repath(&inputs));
+                }
+                #[cfg(not(target_arch = "wasm32"))]
+                {
+                    let (sender, receiver) = mpsc::channel();
+                    let ctx = ctx.clone();
+                    thread::spawn(move || {
+                        // The app may have been closed in the meantime
+                        let _ = sender.send(repath(&inputs));
+                        ctx.request_repaint();
+                    });
+                    live.running = Some(receiver);
+                }
+            }
+        }
+        finished
+    }
+}
+
+/// The shortcuts of the main actions pressed this frame.
+#[derive(Debug, Default, Clone, Copy)]
+struct Shortcuts {
+    open: bool,
+    repath: bool,
+    /// Writes the shown output, with its edits, to the output file.
+    save: bool,
+    toggle_preview: bool,
+    copy: bool,
+}
+
+impl Shortcuts {
+    /// Takes the shortcuts from the input, so text fields do not get them too.
+    fn consume(ctx: &egui::Context) -> Self {
+        ctx.input_mut(|i| Self {
+            open: i.consume_shortcut(&OPEN_SHORTCUT),
+            repath: i.consume_shortcut(&REPATH_SHORTCUT),
+            save: i.consume_shortcut(&SAVE_SHORTCUT),
+            toggle_preview: i.consume_shortcut(&PREVIEW_SHORTCUT),
+            copy: i.consume_shortcut(&COPY_SHORTCUT),
+        })
+    }
+}
+
+/// Puts `path` at the front of the recent files, keeping at most [`MAX_RECENT_FILES`].
+fn remember_file(recent_files: &mut Vec<PathBuf>, path: &Path) {
+    recent_files.retain(|recent| recent != path);
+    recent_files.insert(0, path.to_path_buf());
+    recent_files.truncate(MAX_RECENT_FILES);
+}
+
+/// Repaths the input file without writing the output.
+fn repath(inputs: &RepathInputs) -> Result<Repathed> {
+    let fc = match &inputs.input_source {
+        Some(source) => source.to_string(),
+        None => std::fs::read_to_string(&inputs.input_file)?,
+    };
+    let invalid = |err| std::io::Error::new(std::io::ErrorKind::InvalidData, err);
+    let prepared =
+        job::prepare_path(&fc, &inputs.config, inputs.strict_parameters).map_err(invalid)?;
+    let output = prepared
+        .write(&inputs.config, &inputs.output_config, inputs.verify_output)
+        .map_err(invalid)?;
+    let preview = prepared.preview(&inputs.config, inputs.rapid_feedrate);
+    let toolpath = prepared.toolpath();
+    let stats = prepared.stats(&inputs.config, &output.text, inputs.rapid_feedrate);
+    let compared = match &inputs.config_b {
+        Some(config_b) => {
+            let prepared =
+                job::prepare_path(&fc, config_b, inputs.strict_parameters).map_err(invalid)?;
+            let written = prepared
+                .write(config_b, &inputs.output_config, false)
+                .map_err(invalid)?;
+            Some((
+                prepared.preview(config_b, inputs.rapid_feedrate),
+                prepared.stats(config_b, &written.text, inputs.rapid_feedrate),
+            ))
+        }
+        None => None,
+    };
+    Ok(Repathed {
+        source: fc,
+        output,
+        preview,
+        toolpath,
+        stats,
+        compared,
+        output_file: None,
+    })
+}
+
+/// Asks where to save `toolpath` and writes it in the format of the chosen extension, HPGL if it
+/// has none, and returns what happened, if anything.
+#[cfg(not(target_arch = "wasm32"))]
+fn export_toolpath(toolpath: &Toolpath, input_file: Option<&Path>) -> Option<String> {
+    let name = input_file
+        .and_then(|file| file.file_stem())
+        .map_or("output".into(), |stem| stem.to_string_lossy())
+        + "_dragknife."
+        + ExportFormat::Hpgl.extension();
+    let path = rfd::FileDialog::new()
+        .add_filter(tr("HPGL file"), &[ExportFormat::Hpgl.extension(), "hpgl"])
+        .add_filter(tr("SVG image"), &[ExportFormat::Svg.extension()])
+        .add_filter(tr("JSON file"), &[ExportFormat::Json.extension()])
+        .set_file_name(&name)
+        .save_file()?;
+    let format = path
+        .extension()
+        .and_then(|ext| ExportFormat::from_extension(&ext.to_string_lossy()))
+        .unwrap_or(ExportFormat::Hpgl);
+    Some(match std::fs::write(&path, format.write(toolpath)) {
+        Ok(()) => tr_args("Saved to {path}", &[("path", &path.display())]),
+        Err(err) => format!("{}: {err}", path.display()),
+    })
+}
+
+fn repath_and_write(inputs: &RepathInputs, output_file: &Path) -> Result<Repathed> {
+    if output_file == inputs.input_file {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "the output names would overwrite the input",
+        ));
+    }
+    let mut repathed = repath(inputs)?;
+    let written = job::write_output(output_file, &repathed.output)?;
+    repathed.output_file = written.into_iter().next();
+    Ok(repathed)
+}
+
+/// The file to write the output of `input` to: named after `name_template`, or the default
+/// template if it is empty, in `output_dir` or else next to the input.
+fn output_target(input: &Path, output_dir: Option<&Path>, name_template: &str) -> PathBuf {
+    let template = if name_template.is_empty() {
+        job::DEFAULT_NAME_TEMPLATE
+    } else {
+        name_template
+    };
+    let path = job::output_path(input, template);
+    match (output_dir, path.file_name()) {
+        (Some(dir), Some(name)) => dir.join(name),
+        _ => path,
+    }
+}
+
+/// Opens the folder containing `path` in the file manager of the system.
+fn open_containing_folder(path: &Path) -> Result<()> {
+    let folder = path.parent().unwrap_or(Path::new("."));
+    #[cfg(target_os = "windows")]
+    let program = "explorer";
+    #[cfg(target_os = "macos")]
+    let program = "open";
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let program = "xdg-open";
+    std::process::Command::new(program).arg(folder).spawn()?;
+    Ok(())
+}
+
+/// The connection to a GRBL controller and what it reported.
+#[derive(Default)]
+struct Machine {
+    connection: Option<GrblConnection>,
+    status: Option<MachineStatus>,
+    /// Lines of the program which were answered, of all its lines.
+    progress: Option<(usize, usize)>,
+    streaming: bool,
+    /// Errors, alarms and messages of the machine, with the zero-based program line they are
+    /// about. Newest last.
+    log: Vec<(Option<usize>, String)>,
+}
+
+/// How many messages of the machine are kept.
+const MACHINE_LOG_SIZE: usize = 200;
+
+impl Machine {
+    /// Takes in what happened on the connection since the last frame.
+    fn poll(&mut self) {
+        let Some(connection) = &self.connection else {
+            return;
+        };
+        let mut disconnected = false;
+        for event in connection.events() {
+            match event {
+                GrblEvent::Status(status) => self.status = Some(status),
+                GrblEvent::Progress {
+                    acknowledged,
+                    total,
+                } => self.progress = Some((acknowledged, total)),
+                GrblEvent::Error { line, message } => self.log.push((line, message)),
+                GrblEvent::Alarm(alarm) => {
+                    self.streaming = false;
+                    self.log.push((None, alarm));
+                }
+                GrblEvent::Message(message) => self.log.push((None, message)),
+                GrblEvent::Done => {
+                    self.streaming = false;
+                    self.log.push((None, tr("Program sent").to_string()));
+                }
+                GrblEvent::Disconnected(err) => {
+                    self.log.push((None, format!("Disconnected: {err}")));
+                    disconnected = true;
+                }
+            }
+        }
+        if disconnected {
+            self.connection = None;
+            self.streaming = false;
+        }
+        let excess = self.log.len().saturating_sub(MACHINE_LOG_SIZE);
+        self.log.drain(..excess);
+    }
+}
+
+/// Connects to the machine and sends `program` to it, with buttons to pause, resume and stop
+/// and the state the machine reports. Errors link to the output line they are about.
+fn show_machine(
+    ui: &mut egui::Ui,
+    machine: &mut Machine,
+    port_path: &mut String,
+    baud_rate: &mut u32,
+    program: Option<&str>,
+    output_line: &mut Option<(usize, bool)>,
+) {
+    match &machine.connection {
+        None => {
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::TextEdit::singleline(port_path)
+                        .hint_text(r"Serial port, e.g. /dev/ttyUSB0 or \\.\COM3"),
+                );
+                egui::ComboBox::from_id_source("baud rate")
+                    .selected_text(format!("{baud_rate} baud"))
+                    .show_ui(ui, |ui| {
+                        for rate in [9600, 19200, 38400, 57600, 115200, 230400] {
+                            ui.selectable_value(baud_rate, rate, rate.to_string());
+                        }
+                    });
+                if ui.button(tr("Connect")).clicked() {
+                    let ctx = ui.ctx().clone();
+                    match GrblConnection::open(&*port_path, *baud_rate, move || {
+                        ctx.request_repaint()
+                    }) {
+                        Ok(connection) => {
+                            *machine = Machine {
+                                connection: Some(connection),
+                                ..Default::default()
+                            }
+                        }
+                        Err(err) => machine.log.push((None, format!("{port_path}: {err}"))),
+                    }
+                }
+            });
+        }
+        Some(connection) => {
+            let mut disconnect = false;
+            ui.horizontal(|ui| {
+                disconnect = ui.button(tr("Disconnect")).clicked();
+                match &machine.status {
+                    Some(MachineStatus {
+                        state,
+                        position: Some([x, y, z]),
+                        work_position,
+                    }) => {
+                        let coordinates = if *work_position {
+                            tr("work")
+                        } else {
+                            tr("machine")
+                        };
+                        ui.label(format!(
+                            "{state} at X {x:.3} Y {y:.3} Z {z:.3} ({coordinates})"
+                        ));
+                    }
+                    Some(status) => {
+                        ui.label(&status.state);
+                    }
+                    None => {
+                        ui.label(tr("Waiting for the machine…"));
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                let can_send = program.is_some() && !machine.streaming;
+                if ui
+                    .add_enabled(can_send, egui::Button::new(tr("Send program")))
+                    .clicked()
+                {
+                    if let Some(program) = program {
+                        connection.send_program(program);
+                        machine.streaming = true;
+                    }
+                }
+                if ui.button(tr("Pause")).clicked() {
+                    connection.feed_hold();
+                }
+                if ui.button(tr("Resume")).clicked() {
+                    connection.resume();
+                }
+                if ui
+                    .button(tr("Stop"))
+                    .on_hover_text(tr("Reset the controller, stopping right away"))
+                    .clicked()
+                {
+                    connection.reset();
+                    machine.streaming = false;
+                }
+                if ui.button(tr("Unlock")).on_hover_text("$X").clicked() {
+                    connection.send_command("$X");
+                }
+                if ui.button(tr("Home")).on_hover_text("$H").clicked() {
+                    connection.send_command("$H");
+                }
+            });
+            if let Some((acknowledged, total)) = machine.progress {
+                let fraction = if total > 0 {
+                    acknowledged as f32 / total as f32
+                } else {
+                    1.
+                };
+                ui.add(egui::ProgressBar::new(fraction).text(tr_args(
+                    "{acknowledged} of {total} lines",
+                    &[("acknowledged", &acknowledged), ("total", &total)],
+                )));
+            }
+            if disconnect {
+                machine.connection = None;
+                machine.streaming = false;
+            }
+        }
+    }
+    egui::ScrollArea::vertical()
+        .id_source("machine log")
+        .max_height(120.)
+        .stick_to_bottom(true)
+        .show(ui, |ui| {
+            for (line, message) in &machine.log {
+                match line {
+                    Some(line) => {
+                        let text = tr_args(
+                            "Line {line}: {message}",
+                            &[("line", &(line + 1)), ("message", message)],
+                        );
+                        if ui.link(text).clicked() {
+                            *output_line = Some((*line, true));
+                        }
+                    }
+                    None => {
+                        ui.label(message);
+                    }
+                }
+            }
+        });
+}
+
+/// A file of the batch and how processing it went.
+struct BatchFile {
+    input: PathBuf,
+    status: BatchStatus,
+}
+
+enum BatchStatus {
+    Waiting,
+    Done { output: PathBuf, warnings: usize },
+    Failed(String),
+}
+
+/// Queues the `paths` which are not queued yet, and drops queued files which are the output of
+/// another one, so processing a folder twice does not repath its own outputs.
+fn add_to_batch(
+    batch: &mut Vec<BatchFile>,
+    paths: Vec<PathBuf>,
+    output_dir: Option<&Path>,
+    name_template: &str,
+) {
+    for path in paths {
+        if !batch.iter().any(|file| file.input == path) {
+            batch.push(BatchFile {
+                input: path,
+                status: BatchStatus::Waiting,
+            });
+        }
+    }
+    let outputs: Vec<_> = batch
+        .iter()
+        .map(|file| output_target(&file.input, output_dir, name_template))
+        .collect();
+    batch.retain(|file| !outputs.contains(&file.input));
+}
+
+/// The files in `folder`, sorted by name.
+fn folder_files(folder: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(folder)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Repaths `input` and writes it to `output_path`.
+fn process_batch_file(
+    input: &Path,
+    output_path: &Path,
+    config: &DragknifeConfig,
+    output_config: &OutputConfig,
+    strict_parameters: bool,
+    verify_output: bool,
+) -> BatchStatus {
+    let source = match std::fs::read_to_string(input) {
+        Ok(source) => source,
+        Err(err) => return BatchStatus::Failed(err.to_string()),
+    };
+    let output = match job::repath_source(
+        &source,
+        config,
+        output_config,
+        strict_parameters,
+        verify_output,
+    ) {
+        Ok(output) => output,
+        Err(err) => return BatchStatus::Failed(err.to_string()),
+    };
+    if output_path == input {
+        return BatchStatus::Failed("the output names would overwrite the input".to_string());
+    }
+    match job::write_output(output_path, &output) {
+        Ok(written) => BatchStatus::Done {
+            output: written
+                .into_iter()
+                .next()
+                .unwrap_or(output_path.to_path_buf()),
+            warnings: output.warnings.len(),
+        },
+        Err(err) => BatchStatus::Failed(format!("{}: {err}", output_path.display())),
+    }
+}
+
+/// Everything a repath of the picked file depends on.
+#[derive(Debug, Clone, PartialEq)]
+struct RepathInputs {
+    input_file: PathBuf,
+    /// The uploaded contents of `input_file`, see `DragknifeApp::input_source`.
+    input_source: Option<Arc<str>>,
+    config: DragknifeConfig,
+    output_config: OutputConfig,
+    strict_parameters: bool,
+    verify_output: bool,
+    rapid_feedrate: f64,
+    /// A second configuration to compare with.
+    config_b: Option<DragknifeConfig>,
+}
+
+/// The state of repathing live, see `DragknifeApp::live_repath`.
+#[derive(Default)]
+struct LiveRepath {
+    /// The inputs of the last live repath, started or waiting for the settings to settle.
+    inputs: Option<RepathInputs>,
+    /// When the inputs last changed, in seconds of `egui::InputState::time`, until the repath
+    /// is started.
+    changed_at: Option<f64>,
+    running: Option<mpsc::Receiver<Result<Repathed>>>,
+}
+
+/// The results of repathing a file, to show in the app.
+struct Repathed {
+    source: String,
+    output: ProgramOutput,
+    preview: Preview,
+    toolpath: Toolpath,
+    stats: JobStats,
+    /// The preview and stats of `RepathInputs::config_b`.
+    compared: Option<(Preview, JobStats)>,
+    output_file: Option<PathBuf>,
+}
+
+/// Zoom and pan of the preview, on top of fitting the whole path in view.
+#[derive(Debug, Clone, Copy)]
+struct PreviewView {
+    zoom: f32,
+    /// In points on screen.
+    pan: egui::Vec2,
+}
+
+impl Default for PreviewView {
+    fn default() -> Self {
+        Self {
+            zoom: 1.,
+            pan: egui::Vec2::ZERO,
+        }
+    }
+}
+
+/// What the output part of the app shows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+enum OutputView {
+    #[default]
+    Output,
+    /// The input and output side by side.
+    Changes,
+    /// The output in an editor, to change it by hand before saving it.
+    Edit,
+}
+
+/// The units lengths and feedrates are shown and entered in. The settings are always in mm, so
+/// only the widgets convert.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+enum DisplayUnits {
+    #[default]
+    Millimeters,
+    Inches,
+}
+
+impl DisplayUnits {
+    /// Millimeters per unit.
+    fn factor(self) -> f64 {
+        match self {
+            DisplayUnits::Millimeters => 1.,
+            DisplayUnits::Inches => 25.4,
+        }
+    }
+
+    fn length_unit(self) -> &'static str {
+        match self {
+            DisplayUnits::Millimeters => "mm",
+            DisplayUnits::Inches => "in",
+        }
+    }
+
+    fn feedrate_unit(self) -> &'static str {
+        match self {
+            DisplayUnits::Millimeters => "mm/min",
+            DisplayUnits::Inches => "in/min",
+        }
+    }
+
+    /// A slider for a length in mm, shown in these units. The unit is added to `text`.
+    fn length_slider<'a, Num: egui::emath::Numeric>(
+        self,
+        value: &'a mut Num,
+        range: RangeInclusive<Num>,
+        text: &str,
+    ) -> egui::Slider<'a> {
+        let slider = egui::Slider::new(value, range);
+        self.convert_slider(slider, false)
+            .text(format!("{text} ({})", self.length_unit()))
+    }
+
+    /// A slider for a feedrate in mm/min, shown in these units. The unit is added to `text`.
+    fn feedrate_slider<'a, Num: egui::emath::Numeric>(
+        self,
+        value: &'a mut Num,
+        range: RangeInclusive<Num>,
+        text: &str,
+    ) -> egui::Slider<'a> {
+        let slider = egui::Slider::new(value, range);
+        self.convert_slider(slider, true)
+            .text(format!("{text} ({})", self.feedrate_unit()))
+    }
+
+    /// A drag value for a length in mm, shown in these units.
+    fn length_drag<'a, Num: egui::emath::Numeric>(self, value: &'a mut Num) -> egui::DragValue<'a> {
+        let drag = egui::DragValue::new(value).suffix(format!(" {}", self.length_unit()));
+        self.convert_drag(drag, false)
+    }
+
+    /// A drag value for a feedrate in mm/min, shown in these units.
+    fn feedrate_drag<'a, Num: egui::emath::Numeric>(
+        self,
+        value: &'a mut Num,
+    ) -> egui::DragValue<'a> {
+        let drag = egui::DragValue::new(value).suffix(format!(" {}", self.feedrate_unit()));
+        self.convert_drag(drag, true)
+    }
+
+    fn convert_slider(self, slider: egui::Slider<'_>, feedrate: bool) -> egui::Slider<'_> {
+        if self == DisplayUnits::Millimeters {
+            return slider;
+        }
+        // Inches need more decimals than the default for mm
+        let (factor, decimals) = (self.factor(), if feedrate { 1 } else { 4 });
+        slider
+            .custom_formatter(move |value, _| format!("{:.*}", decimals, value / factor))
+            .custom_parser(move |text| text.trim().parse::<f64>().ok().map(|v| v * factor))
+    }
+
+    fn convert_drag(self, drag: egui::DragValue<'_>, feedrate: bool) -> egui::DragValue<'_> {
+        if self == DisplayUnits::Millimeters {
+            return drag;
+        }
+        // Inches need more decimals than the default for mm
+        let (factor, decimals) = (self.factor(), if feedrate { 1 } else { 4 });
+        drag.custom_formatter(move |value, _| format!("{:.*}", decimals, value / factor))
+            .custom_parser(move |text| text.trim().parse::<f64>().ok().map(|v| v * factor))
+    }
+}
+
+/// Playback of the knife moving along the previewed path.
+#[derive(Debug, Clone, Copy)]
+struct Playback {
+    playing: bool,
+    /// Seconds into the program.
+    time: f64,
+    /// How many times faster than the machine.
+    speed: f64,
+}
+
+impl Default for Playback {
+    fn default() -> Self {
+        Self {
+            playing: false,
+            time: 0.,
+            speed: 1.,
+        }
+    }
+}
+
+/// Minutes and seconds, e.g. `2:05.3`.
+/// A text field for a list of codes like `G64, M62-65`, which sets `codes` while the list is
+/// valid. The text is kept while it is typed, and follows `codes` when they are changed
+/// elsewhere, e.g. by a preset.
+fn show_code_list(ui: &mut egui::Ui, id: &str, codes: &mut Vec<CodeRange>, hint: &str) {
+    let id = ui.make_persistent_id(id);
+    let mut text = ui
+        .data_mut(|data| data.get_temp::<String>(id))
+        .filter(|text| CodeRange::parse_list(text).map_or(true, |parsed| parsed == *codes))
+        .unwrap_or_else(|| CodeRange::format_list(codes));
+    ui.add(egui::TextEdit::singleline(&mut text).hint_text(hint));
+    match CodeRange::parse_list(&text) {
+        Ok(parsed) => *codes = parsed,
+        Err(err) => {
+            ui.label(format!("{err}"));
+        }
+    }
+    ui.data_mut(|data| data.insert_temp(id, text));
+}
+
+fn format_time(seconds: f64) -> String {
+    format!("{}:{:04.1}", (seconds / 60.).floor(), seconds % 60.)
+}
+
+/// The stats of the input and the repathed program next to each other, with the number of lines
+/// written. The stats of the program repathed with configuration B are added if comparing.
+fn show_stats(
+    ui: &mut egui::Ui,
+    stats: &JobStats,
+    compared: Option<&JobStats>,
+    output_lines: usize,
+) {
+    let (input, output) = (&stats.input, &stats.output);
+    // A row of the grid, with the value for B if comparing
+    let row = |ui: &mut egui::Ui, name: &str, input: String, output: String, b: String| {
+        ui.label(name);
+        ui.label(input);
+        ui.label(output);
+        if compared.is_some() {
+            ui.colored_label(COMPARED_COLOR, b);
+        }
+        ui.end_row();
+    };
+    let b = |value: &dyn Fn(&JobStats) -> String| compared.map_or(String::new(), value);
+    egui::Grid::new("stats").striped(true).show(ui, |ui| {
+        row(
+            ui,
+            "",
+            tr("Input").to_string(),
+            tr("Repathed").to_string(),
+            tr("Repathed B").to_string(),
+        );
+        row(
+            ui,
+            tr("Contours"),
+            input.contours.to_string(),
+            String::new(),
+            String::new(),
+        );
+        row(
+            ui,
+            tr("Cut length"),
+            format!("{:.1} mm", input.cut_length),
+            format!("{:.1} mm", output.cut_length),
+            b(&|stats| format!("{:.1} mm", stats.output.cut_length)),
+        );
+        row(
+            ui,
+            tr("Rapid length"),
+            format!("{:.1} mm", input.rapid_length),
+            format!("{:.1} mm", output.rapid_length),
+            b(&|stats| format!("{:.1} mm", stats.output.rapid_length)),
+        );
+        row(
+            ui,
+            tr("Corners"),
+            input.corners.to_string(),
+            String::new(),
+            String::new(),
+        );
+        row(
+            ui,
+            tr("Swivels inserted"),
+            String::new(),
+            input.swivels.to_string(),
+            b(&|stats| stats.input.swivels.to_string()),
+        );
+        row(
+            ui,
+            tr("Reversals"),
+            String::new(),
+            input.reversals.to_string(),
+            b(&|stats| stats.input.reversals.to_string()),
+        );
+        row(
+            ui,
+            tr("Estimated time"),
+            format_time(input.run_time * 60.),
+            format_time(output.run_time * 60.),
+            b(&|stats| format_time(stats.output.run_time * 60.)),
+        );
+        row(
+            ui,
+            tr("Lines"),
+            String::new(),
+            output_lines.to_string(),
+            String::new(),
+        );
+    });
+}
+
+/// `Line 12`, for a zero-based input line.
+fn line_text(line: Option<usize>) -> String {
+    line.map_or(tr("Line ?").to_string(), |line| {
+        tr_args("Line {line}", &[("line", &(line + 1))])
+    })
+}
+
+/// The size and direction of a turn, e.g. `90° left`.
+fn turn_text(angle: f64) -> String {
+    let turn = if angle > 0. { tr("left") } else { tr("right") };
+    format!("{:.0}° {turn}", angle.abs().to_degrees())
+}
+
+/// The output program with a line per row, scrolled to `output_line` if it was just set.
+fn show_output(
+    ui: &mut egui::Ui,
+    output: &str,
+    origins: &[LineOrigin],
+    find_text: &str,
+    output_line: &mut Option<(usize, bool)>,
+) {
+    let lines: Vec<&str> = output.lines().collect();
+    let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+    let mut scroll_area = egui::ScrollArea::both().id_source("output");
+    if let Some((line, scroll)) = output_line {
+        if *scroll {
+            let row = row_height + ui.spacing().item_spacing.y;
+            // Leave a few lines above it
+            scroll_area = scroll_area.vertical_scroll_offset(line.saturating_sub(3) as f32 * row);
+            *scroll = false;
+        }
+    }
+    scroll_area.show_rows(ui, row_height, lines.len(), |ui, rows| {
+        for i in rows {
+            let background = if output_line.is_some_and(|(line, _)| line == i) {
+                ui.visuals().selection.bg_fill
+            } else if origins.get(i).is_some_and(|origin| origin.inserted) {
+                diff_color(DiffKind::Inserted)
+            } else {
+                egui::Color32::TRANSPARENT
+            };
+            let mut job = egui::text::LayoutJob::default();
+            highlight_line(&mut job, ui, lines[i], find_text, background);
+            ui.add(egui::Label::new(job).wrap(false));
+        }
+    });
+}
+
+/// An editor for the output, with buttons to save the edits and to go back to the generated
+/// output. Repathing again replaces the edits.
+fn show_edit(
+    ui: &mut egui::Ui,
+    generated: &str,
+    edited_output: &mut Option<String>,
+    output_file: &mut Option<PathBuf>,
+    edit_status: &mut Option<String>,
+) {
+    let edited = edited_output.get_or_insert_with(|| generated.to_string());
+    let changed = edited != generated;
+    ui.horizontal(|ui| {
+        let mut save_to = None;
+        if let Some(file) = output_file.as_ref() {
+            if ui
+                .button(tr("Save"))
+                .on_hover_text(tr_args("Overwrite {path}", &[("path", &file.display())]))
+                .clicked()
+            {
+                save_to = Some(file.clone());
+            }
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if ui.button(tr("Save as…")).clicked() {
+            let mut dialog = rfd::FileDialog::new();
+            if let Some(file) = output_file.as_ref() {
+                if let Some(folder) = file.parent() {
+                    dialog = dialog.set_directory(folder);
+                }
+            }
+            save_to = dialog.save_file();
+        }
+        if let Some(path) = save_to {
+            *edit_status = match std::fs::write(&path, edited.as_bytes()) {
+                Ok(()) => Some(tr_args("Saved to {path}", &[("path", &path.display())])),
+                Err(err) => Some(format!("{}: {err}", path.display())),
+            };
+            *output_file = Some(path);
+        }
+        if ui
+            .add_enabled(changed, egui::Button::new(tr("Revert to generated")))
+            .clicked()
+        {
+            *edited = generated.to_string();
+            *edit_status = None;
+        }
+        if changed {
+            ui.label(tr("Edited"));
+        }
+        if let Some(status) = edit_status {
+            ui.label(status.as_str());
+        }
+    });
+    let mut layouter = |ui: &egui::Ui, text: &str, _wrap_width: f32| {
+        let newline = egui::TextFormat {
+            font_id: egui::TextStyle::Monospace.resolve(ui.style()),
+            ..Default::default()
+        };
+        let mut job = egui::text::LayoutJob::default();
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                job.append("\n", 0., newline.clone());
+            }
+            highlight_line(&mut job, ui, line, "", egui::Color32::TRANSPARENT);
+        }
+        // Lines are not wrapped, like in the output view
+        ui.fonts(|fonts| fonts.layout_job(job))
+    };
+    egui::ScrollArea::both().id_source("edit").show(ui, |ui| {
+        ui.add(
+            egui::TextEdit::multiline(edited)
+                .code_editor()
+                .desired_width(f32::INFINITY)
+                .layouter(&mut layouter),
+        );
+    });
+}
+
+/// A find box for the output, with the number of matching lines and buttons to go to the
+/// previous and next one. Enter goes to the next one.
+fn show_find(
+    ui: &mut egui::Ui,
+    output: &str,
+    find_text: &mut String,
+    output_line: &mut Option<(usize, bool)>,
+) {
+    let response = ui.add(
+        egui::TextEdit::singleline(find_text)
+            .hint_text(tr("Find"))
+            .desired_width(150.),
+    );
+    let enter = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+    let needle = find_text.to_ascii_lowercase();
+    let matches: Vec<usize> = if needle.is_empty() {
+        vec![]
+    } else {
+        output
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| line.to_ascii_lowercase().contains(&needle))
+            .map(|(i, _)| i)
+            .collect()
+    };
+    let current = output_line.map(|(line, _)| line);
+    let previous = ui
+        .add_enabled(!matches.is_empty(), egui::Button::new("⏶"))
+        .on_hover_text(tr("Previous match"))
+        .clicked();
+    let next = ui
+        .add_enabled(!matches.is_empty(), egui::Button::new("⏷"))
+        .on_hover_text(tr("Next match"))
+        .clicked()
+        || enter;
+    if !needle.is_empty() {
+        match current.and_then(|line| matches.iter().position(|&m| m == line)) {
+            Some(i) => ui.label(tr_args(
+                "{index} of {count}",
+                &[("index", &(i + 1)), ("count", &matches.len())],
+            )),
+            None => ui.label(tr_args("{count} lines", &[("count", &matches.len())])),
+        };
+    }
+    let target = if next {
+        let after = matches
+            .iter()
+            .find(|&&m| current.is_none_or(|line| m > line));
+        after.or(matches.first())
+    } else if previous {
+        let before = matches
+            .iter()
+            .rev()
+            .find(|&&m| current.is_none_or(|line| m < line));
+        before.or(matches.last())
+    } else {
+        None
+    };
+    if let Some(&line) = target {
+        *output_line = Some((line, true));
+    }
+    if enter {
+        response.request_focus();
+    }
+}
+
+/// Appends a line of G-code to `job`, colored by what its words do: `G` and `M` codes,
+/// coordinates, feedrates and comments each get their own color. Where `find_text` is found,
+/// ignoring ASCII case, it is marked.
+fn highlight_line(
+    job: &mut egui::text::LayoutJob,
+    ui: &egui::Ui,
+    line: &str,
+    find_text: &str,
+    background: egui::Color32,
+) {
+    let font_id = egui::TextStyle::Monospace.resolve(ui.style());
+    let plain = ui.visuals().text_color();
+    let weak = ui.visuals().weak_text_color();
+    let found: Vec<Range<usize>> = if find_text.is_empty() {
+        vec![]
+    } else {
+        line.to_ascii_lowercase()
+            .match_indices(&find_text.to_ascii_lowercase())
+            .map(|(start, text)| start..start + text.len())
+            .collect()
+    };
+    let is_found = |i: usize| found.iter().any(|range| range.contains(&i));
+    let mut offset = 0;
+    let mut append = |text: &str, color: egui::Color32, italics: bool| {
+        let mut start = 0;
+        while start < text.len() {
+            let marked = is_found(offset + start);
+            let end = (start + 1..text.len())
+                .find(|&i| text.is_char_boundary(i) && is_found(offset + i) != marked)
+                .unwrap_or(text.len());
+            job.append(
+                &text[start..end],
+                0.,
+                egui::TextFormat {
+                    font_id: font_id.clone(),
+                    color,
+                    background: if marked {
+                        ui.visuals().warn_fg_color.linear_multiply(0.4)
+                    } else {
+                        background
+                    },
+                    italics,
+                    ..Default::default()
+                },
+            );
+            start = end;
+        }
+        offset += text.len();
+    };
+    let mut rest = line;
+    while let Some(first) = rest.chars().next() {
+        let end = match first {
+            '(' => rest.find(')').map_or(rest.len(), |end| end + 1),
+            ';' => rest.len(),
+            letter if letter.is_ascii_alphabetic() => rest[1..]
+                .find(|c: char| !(c.is_ascii_digit() || ".+-".contains(c)))
+                .map_or(rest.len(), |end| end + 1),
+            _ => first.len_utf8(),
+        };
+        let (token, after) = rest.split_at(end);
+        let color = match first.to_ascii_uppercase() {
+            '(' | ';' => weak,
+            'G' => egui::Color32::from_rgb(80, 140, 230),
+            'M' => egui::Color32::from_rgb(190, 100, 210),
+            'F' => egui::Color32::from_rgb(220, 140, 40),
+            'X' | 'Y' | 'Z' | 'I' | 'J' | 'K' | 'R' => egui::Color32::from_rgb(80, 170, 100),
+            'N' => weak,
+            _ => plain,
+        };
+        append(token, color, first == '(' || first == ';');
+        rest = after;
+    }
+}
+
+/// The background of a row of [`show_changes_view`], also used for inserted output lines.
+fn diff_color(kind: DiffKind) -> egui::Color32 {
+    match kind {
+        DiffKind::Unchanged => egui::Color32::TRANSPARENT,
+        DiffKind::Changed => egui::Color32::from_rgba_unmultiplied(200, 160, 0, 40),
+        DiffKind::Inserted => egui::Color32::from_rgba_unmultiplied(0, 180, 0, 40),
+        DiffKind::Removed => egui::Color32::from_rgba_unmultiplied(220, 0, 0, 40),
+    }
+}
+
+/// The input and output side by side, with the changed, inserted and removed lines colored.
+/// The row of `output_line` is highlighted and scrolled to like in [`show_output`].
+fn show_changes_view(
+    ui: &mut egui::Ui,
+    input: &str,
+    output: &str,
+    rows: &[DiffRow],
+    output_line: &mut Option<(usize, bool)>,
+) {
+    let input: Vec<&str> = input.lines().collect();
+    let output: Vec<&str> = output.lines().collect();
+    let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+    let column_width = (ui.available_width() - ui.spacing().item_spacing.x) / 2.;
+    let text = |lines: &[&str], line: Option<usize>| {
+        line.map_or(String::new(), |line| {
+            format!("{:>5} {}", line + 1, lines.get(line).unwrap_or(&""))
+        })
+    };
+    let selected_row =
+        output_line.and_then(|(line, _)| rows.iter().position(|row| row.output == Some(line)));
+    let mut scroll_area = egui::ScrollArea::vertical().id_source("changes");
+    if let (Some((_, scroll)), Some(selected_row)) = (output_line.as_mut(), selected_row) {
+        if *scroll {
+            let row = row_height + ui.spacing().item_spacing.y;
+            scroll_area =
+                scroll_area.vertical_scroll_offset(selected_row.saturating_sub(3) as f32 * row);
+            *scroll = false;
+        }
+    }
+    scroll_area.show_rows(ui, row_height, rows.len(), |ui, range| {
+        for (i, row) in rows.iter().enumerate().take(range.end).skip(range.start) {
+            let color = diff_color(row.kind);
+            ui.horizontal(|ui| {
+                for (lines, line) in [(&input, row.input), (&output, row.output)] {
+                    let color = if line.is_some() {
+                        color
+                    } else {
+                        egui::Color32::TRANSPARENT
+                    };
+                    let size = egui::vec2(column_width, row_height);
+                    let layout = egui::Layout::left_to_right(egui::Align::Center);
+                    ui.allocate_ui_with_layout(size, layout, |ui| {
+                        ui.set_width(column_width);
+                        ui.set_clip_rect(ui.max_rect().intersect(ui.clip_rect()));
+                        ui.painter().rect_filled(ui.max_rect(), 0., color);
+                        if selected_row == Some(i) {
+                            let stroke = ui.visuals().selection.stroke;
+                            ui.painter().rect_stroke(ui.max_rect(), 0., stroke);
+                        }
+                        ui.add(
+                            egui::Label::new(egui::RichText::new(text(lines, line)).monospace())
+                                .wrap(false),
+                        );
+                    });
+                }
+            });
+        }
+    });
+}
+
+/// The warnings of the repath. Clicking one shows the output line it is about.
+fn show_warnings(
+    ui: &mut egui::Ui,
+    warnings: &[OutputWarning],
+    origins: &[LineOrigin],
+    output_line: &mut Option<(usize, bool)>,
+) {
+    egui::ScrollArea::vertical()
+        .id_source("warnings")
+        .max_height(150.)
+        .show(ui, |ui| {
+            for warning in warnings {
+                let line = match warning {
+                    OutputWarning::LineTooLong { output_line, .. } => Some(*output_line),
+                    _ => warning
+                        .line()
+                        .and_then(|line| output_line_of(origins, line)),
+                };
+                // Skipped input may explain the other warnings
+                let color = match warning {
+                    OutputWarning::Parse(_) => ui.visuals().error_fg_color,
+                    _ => ui.visuals().warn_fg_color,
+                };
+                let text = egui::RichText::new(warning.to_string()).color(color);
+                let selected = line.is_some() && output_line.map(|(line, _)| line) == line;
+                let label =
+                    ui.add_enabled(line.is_some(), egui::SelectableLabel::new(selected, text));
+                if let Some(line) = line {
+                    if label
+                        .on_hover_text(tr_args("Show output line {line}", &[("line", &(line + 1))]))
+                        .clicked()
+                    {
+                        *output_line = Some((line, true));
+                    }
+                }
+            }
+        });
+}
+
+/// The zero-based output line written for an input line, preferring the line it was copied or
+/// changed to over the lines inserted before it.
+fn output_line_of(origins: &[LineOrigin], input_line: usize) -> Option<usize> {
+    let for_line = |origin: &LineOrigin| origin.input_line == Some(input_line);
+    origins
+        .iter()
+        .position(|origin| for_line(origin) && !origin.inserted)
+        .or_else(|| origins.iter().position(for_line))
+}
+
+/// What was computed for a corner, and the output lines made for it with links to them.
+fn show_corner(
+    ui: &mut egui::Ui,
+    corner: &PreviewCorner,
+    knife_offset: f64,
+    output: &str,
+    origins: &[LineOrigin],
+    output_line: &mut Option<(usize, bool)>,
+) {
+    ui.strong(match corner.line {
+        Some(line) => tr_args("Corner at line {line}", &[("line", &(line + 1))]),
+        None => tr("Corner").to_string(),
+    });
+    egui::Grid::new("corner").num_columns(2).show(ui, |ui| {
+        ui.label(tr("Angle"));
+        ui.label(turn_text(corner.angle));
+        ui.end_row();
+        ui.label(tr("Threshold"));
+        let exceeded = if corner.angle.abs() > corner.threshold {
+            tr("exceeded")
+        } else {
+            tr("not exceeded")
+        };
+        ui.label(format!("{:.0}°, {exceeded}", corner.threshold.to_degrees()));
+        ui.end_row();
+        ui.label(tr("Position"));
+        ui.label(format!(
+            "X {:.3} Y {:.3}",
+            corner.position.x, corner.position.y
+        ));
+        ui.end_row();
+        ui.label(tr("Inserted"));
+        ui.label(match &corner.swivel {
+            None => tr("Nothing").to_string(),
+            Some(swivel) if swivel.reversal => tr("Turn beside the corner (reversal)").to_string(),
+            // Left turns swivel the holder clockwise around the blade
+            Some(_) if corner.angle > 0. => tr_args(
+                "G2 swivel (clockwise), radius {radius} mm",
+                &[("radius", &format!("{knife_offset:.3}"))],
+            ),
+            Some(_) => tr_args(
+                "G3 swivel (counterclockwise), radius {radius} mm",
+                &[("radius", &format!("{knife_offset:.3}"))],
+            ),
+        });
+        ui.end_row();
+    });
+    if corner.line.is_none() {
+        return;
+    }
+    ui.label(tr("Output lines:"));
+    egui::ScrollArea::vertical()
+        .id_source("corner lines")
+        .max_height(150.)
+        .show(ui, |ui| {
+            for (i, (text, origin)) in output.lines().zip(origins).enumerate() {
+                if origin.input_line != corner.line {
+                    continue;
+                }
+                ui.horizontal(|ui| {
+                    if ui.link(format!("{:>5}", i + 1)).clicked() {
+                        *output_line = Some((i, true));
+                    }
+                    let marker = if origin.inserted { "+" } else { " " };
+                    ui.monospace(format!("{marker} {text}"));
+                });
+            }
+        });
+}
+
+/// Draws the path from above, with the inserted swivels dashed and the corners marked, and
+/// the knife if it is given, with a line from the holder to the blade tip.
+/// Clicking a marker selects its corner. Scrolling zooms in on the pointer and dragging pans.
+fn show_preview(
+    ui: &mut egui::Ui,
+    preview: &Preview,
+    compared: Option<&Preview>,
+    knife: Option<KnifePosition>,
+    selected_corner: &mut Option<usize>,
+    view: &mut PreviewView,
+) {
+    let size = egui::vec2(ui.available_width(), ui.available_width());
+    let (response, painter) = ui.allocate_painter(size, egui::Sense::click_and_drag());
+    let rect = response.rect;
+    painter.rect_filled(rect, 0., ui.visuals().extreme_bg_color);
+    let Some((min, max)) = preview.bounds() else {
+        return;
+    };
+    if response.hovered() {
+        let (scroll, zoom) = ui.input(|i| (i.scroll_delta.y, i.zoom_delta()));
+        let factor = (zoom * (scroll / 200.).exp()).clamp(0.5, 2.);
+        let new_zoom = (view.zoom * factor).clamp(0.5, 10000.);
+        if let Some(pointer) = response.hover_pos() {
+            // Keep the point under the pointer where it is
+            let from_center = pointer - rect.center();
+            view.pan = from_center - (from_center - view.pan) * (new_zoom / view.zoom);
+        }
+        view.zoom = new_zoom;
+    }
+    view.pan += response.drag_delta();
+    let margin = 10.;
+    let width = (max.x - min.x).max(max.y - min.y).max(1e-3) as f32;
+    // Points on screen per mm
+    let scale = (rect.width() - 2. * margin) / width * view.zoom;
+    let middle = ((min.x + max.x) / 2., (min.y + max.y) / 2.);
+    let to_screen = |x: f64, y: f64| {
+        rect.center() + view.pan + egui::vec2((x - middle.0) as f32, (middle.1 - y) as f32) * scale
+    };
+    let cut_color = ui.visuals().text_color();
+    let swivel_color = ui.visuals().warn_fg_color;
+    let selected_color = ui.visuals().selection.stroke.color;
+    let selected_moves = selected_corner
+        .and_then(|i| preview.corners.get(i)?.swivel.as_ref())
+        .map_or(0..0, |swivel| swivel.moves.clone());
+    // Underneath, so where both are the same only A shows
+    for preview_move in compared.iter().flat_map(|compared| &compared.moves) {
+        let points: Vec<egui::Pos2> = preview_move
+            .points
+            .iter()
+            .map(|point| to_screen(point.x, point.y))
+            .collect();
+        let stroke = egui::Stroke::new(3., COMPARED_COLOR.linear_multiply(0.6));
+        match preview_move.kind {
+            MoveKind::Cut => {
+                painter.add(egui::Shape::line(points, stroke));
+            }
+            MoveKind::Swivel => painter.extend(egui::Shape::dashed_line(&points, stroke, 3., 2.)),
+            MoveKind::Rapid | MoveKind::Dwell => {}
+        }
+    }
+    for (i, preview_move) in preview.moves.iter().enumerate() {
+        let points: Vec<egui::Pos2> = preview_move
+            .points
+            .iter()
+            .map(|point| to_screen(point.x, point.y))
+            .collect();
+        match preview_move.kind {
+            MoveKind::Cut => {
+                painter.add(egui::Shape::line(points, egui::Stroke::new(1.5, cut_color)));
+            }
+            MoveKind::Rapid => {
+                painter.add(egui::Shape::line(
+                    points,
+                    egui::Stroke::new(0.5, ui.visuals().weak_text_color()),
+                ));
+            }
+            MoveKind::Dwell => {}
+            MoveKind::Swivel => {
+                let color = if selected_moves.contains(&i) {
+                    selected_color
+                } else {
+                    swivel_color
+                };
+                painter.extend(egui::Shape::dashed_line(
+                    &points,
+                    egui::Stroke::new(1.5, color),
+                    3.,
+                    2.,
+                ));
+            }
+        }
+    }
+    let corners: Vec<egui::Pos2> = preview
+        .corners
+        .iter()
+        .map(|corner| to_screen(corner.position.x, corner.position.y))
+        .collect();
+    for (i, (corner, pos)) in preview.corners.iter().zip(&corners).enumerate() {
+        let selected = *selected_corner == Some(i);
+        match &corner.swivel {
+            Some(swivel) => {
+                let color = match (selected, swivel.reversal) {
+                    (true, _) => selected_color,
+                    (false, true) => ui.visuals().error_fg_color,
+                    (false, false) => swivel_color,
+                };
+                painter.circle_stroke(*pos, 4., egui::Stroke::new(1.5, color));
+            }
+            None if selected => {
+                painter.circle_stroke(*pos, 4., egui::Stroke::new(1.5, selected_color));
+            }
+            None => painter.circle_filled(*pos, 2., ui.visuals().weak_text_color()),
+        }
+    }
+    if let Some(knife) = knife {
+        let color = ui.visuals().selection.bg_fill;
+        let holder = to_screen(knife.holder.x, knife.holder.y);
+        let tip = to_screen(knife.tip.x, knife.tip.y);
+        painter.line_segment([holder, tip], egui::Stroke::new(2., color));
+        painter.circle_stroke(holder, 6., egui::Stroke::new(2., color));
+        painter.circle_filled(tip, 2.5, color);
+    }
+    show_scale(ui, &painter, rect, scale);
+    if let Some(pointer) = response
+        .interact_pointer_pos()
+        .filter(|_| response.clicked())
+    {
+        *selected_corner = corners
+            .iter()
+            .enumerate()
+            .map(|(i, corner)| (i, corner.distance(pointer)))
+            .filter(|(_, distance)| *distance < 8.)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i);
+    }
+}
+
+/// The color of configuration B when comparing.
+const COMPARED_COLOR: egui::Color32 = egui::Color32::from_rgb(80, 140, 230);
+
+/// Draws a bar of a round length in mm in the lower left corner of the preview.
+fn show_scale(ui: &egui::Ui, painter: &egui::Painter, rect: egui::Rect, scale: f32) {
+    // The longest round length which fits in about 100 points
+    let target = 100. / scale as f64;
+    let exponent = target.log10().floor() as i32;
+    let power = 10f64.powi(exponent);
+    let step = [5., 2., 1.]
+        .into_iter()
+        .find(|step| step * power <= target)
+        .unwrap_or(1.);
+    let length = step * power;
+    let start = rect.left_bottom() + egui::vec2(10., -10.);
+    let end = start + egui::vec2(length as f32 * scale, 0.);
+    let stroke = egui::Stroke::new(1.5, ui.visuals().text_color());
+    painter.line_segment([start, end], stroke);
+    for end in [start, end] {
+        painter.line_segment([end, end - egui::vec2(0., 5.)], stroke);
+    }
+    painter.text(
+        start - egui::vec2(0., 7.),
+        egui::Align2::LEFT_BOTTOM,
+        format!("{:.*} mm", (-exponent).max(0) as usize, length),
+        egui::FontId::proportional(12.),
+        ui.visuals().text_color(),
+    );
 }
@@ -0,0 +1,893 @@
+#![warn(clippy::all, rust_2018_idioms)]
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::Write,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
+
+use dragknife_repath::{
+    config_file::{ConfigFile, CONFIG_FILE_NAME},
+    dialect::{CodeRange, Dialect},
+    height_map::HeightMap,
+    job::{self, JobError, JobStats},
+    output::{OutputFilter, OutputWarning},
+    preset::PresetLibrary,
+    resume,
+    toolpath::ExportFormat,
+    types::{CornerRounding, DragknifeConfig, RepathError, UnsupportedCodes},
+};
+
+const USAGE: &str = "\
+Usage: dragknife-cli [repath] <inputs>... [-o <output> | --name <template>] [options]
+       dragknife-cli validate <inputs>... [options]
+       dragknife-cli stats <inputs>... [options]
+       dragknife-cli marks [-o <output>] [options]
+       dragknife-cli resume <program> --line <n> [-o <output>] [options]
+
+Inputs can contain * and ? in the file name to select several files, or be a folder to select
+all files in it. An input of - reads the program from stdin and writes it to stdout, and -o -
+writes to stdout. Warnings and errors are written to stderr.
+
+Commands:
+  repath                    Repath the input and write the program (default)
+  validate                  Run the checks without writing anything, and fail on any warning
+  stats                     Print the cut length, corners, swivels, bounds and run time
+  marks                     Write a program which makes the registration marks, to stdout
+                            without -o
+  resume                    Write the program from --line on, for a job which stopped there,
+                            to stdout without -o. The lines are copied as they are, so give
+                            it the repathed program which was running. It starts by restoring
+                            the units, modes, feedrate and position of that line, and turns
+                            the blade back into the direction it was cutting
+
+Options:
+      --config <file>       Load the settings from this file instead of dragknife.toml in the
+                            working directory, as JSON if it ends in .json. The options
+                            below override them
+  -o, --output <file>       Where to write the program, for a single input
+      --name <template>     File name for the output next to each input, where {stem}, {ext}
+                            and {name} are replaced by those of the input, default
+                            {stem}_dragknife.{ext}, or with the extension of the format
+      --format <format>     Write the program as gcode (default), hpgl for vinyl cutters and
+                            plotters, svg for an image of the path at real size, or json
+                            with the lines and arcs of the path for other tools
+      --preset <name>       Start from a built-in preset instead of the defaults
+      --offset <mm>         Knife offset
+      --max-depth <mm>      Warn about cuts deeper than this
+      --min-height <mm>     Warn about cuts and swivels below this height
+      --compensation-radius <mm>
+                            Offset the path by this radius where G41 or G42 turn on cutter
+                            compensation, unless G41.1 or G42.1 give a diameter with D
+      --kerf-width <mm>     Offset closed contours by half this width, outward around parts
+                            and inward in holes, so they are cut at the size drawn
+      --corner-radius <mm>  Replace corners between cuts by arcs of this radius, which the
+                            blade follows without a swivel
+      --round-corners <all|unswiveled>
+                            Round every corner instead of swiveling (default), or only the
+                            ones not sharp enough for a swivel and swivel at the others
+      --unsupported-codes <pass|warn|error>
+                            What to do with G and M codes which are not understood, like
+                            G83: copy them, copy them with a warning (default) or stop
+      --allow-codes <codes> Copy these codes without a warning, e.g. M62-65,G64
+      --block-codes <codes> Stop at these codes, also ones which are understood, e.g. M3
+      --soft-limits <x,y,z,x,y,z>
+                            Warn about moves outside these limits, minimum then maximum
+      --max-feedrate <mm/min>
+                            Warn about feedrates above this
+      --rapid-feedrate <mm/min>
+                            Rapid speed of the machine for the run time, default 5000
+      --filter <codes>      Remove these commands and words from the output, e.g. M3,S
+      --dialect <name|file> Only write what this controller accepts: linuxcnc (default),
+                            mach3, grbl, marlin or a dialect defined in a .toml or .json file
+      --material <name>     Scale the cut depths to those of this material in the config file
+      --height-map <file>   Follow a bed which is not flat: add the Z offsets of a grid of x,y,z
+                            points in a .csv or .json file to every height
+      --split               Write a file for every part between tool changes, pauses and
+                            operations, named like part_dragknife_2_crease.nc, for controllers
+                            which can not pause for a tool swap
+      --probe               Probe the surface with G38.2 before the first cut and make it Z zero
+      --probe-contours      Probe again before every cut after a rapid move
+      --probe-feedrate <mm/min>
+                            Feedrate to probe at, default 50
+      --probe-clearance <mm>
+                            Height to retract to after probing, default 2
+      --marks <x,y,...>     Where the registration marks are in the design, in mm
+      --measured <x,y,x,y>  Where the first two marks were measured on the machine. The path is
+                            moved and rotated so the marks of the design land there
+      --line <n>            The line to resume at, counted from 1 like in an editor
+      --strict-parameters   Stop on unset #parameters instead of using 0
+      --no-verify           Do not check the written program against the input
+      --json                Print a report for every input as a line of JSON, with the status,
+                            error, warnings, stats and settings
+      --watch               Keep running, and run again for every input which is changed or
+                            added, e.g. when the CAM software exports it again
+  -h, --help                Print this help
+
+Exit codes:
+  0  Success
+  1  Repathed, but with warnings
+  2  Invalid command line
+  3  An input could not be read or parsed
+  4  The repath stopped on a problem with the input, or validate found warnings
+  5  An output could not be written
+With several inputs, the highest code of them is returned.";
+
+/// Stands for stdin as input and stdout as output.
+const STDIO: &str = "-";
+
+/// How often the inputs are checked for changes with `--watch`.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+enum Subcommand {
+    Repath {
+        /// Where to write the program, for a single input.
+        output: Option<PathBuf>,
+        /// File name for the output next to each input, see `job::output_path`.
+        name_template: String,
+        format: Format,
+    },
+    Validate,
+    Stats,
+    /// Write the program which makes the registration marks, see `Registration::mark_program`.
+    Marks {
+        output: Option<PathBuf>,
+    },
+    /// Write the program from a line on, see `resume::resume_program`.
+    Resume {
+        output: Option<PathBuf>,
+        line: usize,
+    },
+}
+
+/// What the repathed program is written as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    GCode,
+    /// The moves with the knife correction applied, see `job::PreparedPath::toolpath`.
+    Toolpath(ExportFormat),
+}
+
+impl Format {
+    fn parse(text: &str) -> Result<Self, String> {
+        if text == "gcode" {
+            return Ok(Format::GCode);
+        }
+        ExportFormat::ALL
+            .into_iter()
+            .find(|format| format.name() == text)
+            .map(Format::Toolpath)
+            .ok_or_else(|| {
+                let names: Vec<_> = ExportFormat::ALL.iter().map(|f| f.name()).collect();
+                format!(
+                    "Unknown format {text}, expected gcode, {}",
+                    names.join(", ")
+                )
+            })
+    }
+
+    /// The output name template used if no other is given, see `job::output_path`.
+    fn default_name_template(self) -> String {
+        match self {
+            Format::GCode => job::DEFAULT_NAME_TEMPLATE.to_string(),
+            Format::Toolpath(format) => format!("{{stem}}_dragknife.{}", format.extension()),
+        }
+    }
+}
+
+struct Options {
+    command: Subcommand,
+    /// The inputs as given, with globs, so they can be expanded again when watching.
+    patterns: Vec<String>,
+    inputs: Vec<PathBuf>,
+    watch: bool,
+    json: bool,
+    rapid_feedrate: f64,
+    settings: ConfigFile,
+}
+
+/// How the run for an input ended, from best to worst. With several inputs, the exit code is the
+/// worst of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    Success = 0,
+    /// Repathed, but with warnings.
+    Warnings = 1,
+    /// The input could not be read or parsed.
+    ParseFailure = 3,
+    /// The repath stopped on a problem with the input, or `validate` found warnings.
+    ValidationFailure = 4,
+    /// The output could not be written.
+    OutputFailure = 5,
+}
+
+/// The exit code for an invalid command line, between the codes of `Status`.
+const USAGE_ERROR: u8 = 2;
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Success => "success",
+            Status::Warnings => "warnings",
+            Status::ParseFailure => "parse_failure",
+            Status::ValidationFailure => "validation_failure",
+            Status::OutputFailure => "output_failure",
+        }
+    }
+
+    fn from_error(err: &JobError) -> Self {
+        match err {
+            JobError::Parameters(_)
+            | JobError::Subprograms(_)
+            | JobError::OperationMarker(_)
+            | JobError::Repath(RepathError::MissingArguments) => Status::ParseFailure,
+            JobError::Repath(_) => Status::ValidationFailure,
+        }
+    }
+}
+
+/// What happened to one input.
+struct Report<'a> {
+    input: &'a Path,
+    output: Option<PathBuf>,
+    status: Status,
+    error: Option<String>,
+    warnings: Vec<OutputWarning>,
+    stats: Option<JobStats>,
+}
+
+impl<'a> Report<'a> {
+    fn failed(mut self, status: Status, error: impl Display) -> Self {
+        self.status = status;
+        self.error = Some(error.to_string());
+        self
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1).collect()) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            return ExitCode::from(USAGE_ERROR);
+        }
+    };
+    match &options.command {
+        Subcommand::Marks { output } => return write_marks(&options.settings, output.as_deref()),
+        Subcommand::Resume { output, line } => {
+            return write_resume(&options, *line, output.as_deref())
+        }
+        _ => {}
+    }
+    if options.watch {
+        watch(&options);
+    }
+    let statuses: Vec<_> = options
+        .inputs
+        .iter()
+        .map(|input| (input, run(&options, input)))
+        .collect();
+    let failed: Vec<_> = statuses
+        .iter()
+        .filter(|(_, status)| *status > Status::Warnings)
+        .collect();
+    if options.inputs.len() > 1 {
+        eprintln!(
+            "{} of {} files succeeded",
+            options.inputs.len() - failed.len(),
+            options.inputs.len()
+        );
+        for (input, status) in &failed {
+            eprintln!("  {}: {}", status.name(), input.display());
+        }
+    }
+    let worst = statuses.iter().map(|(_, status)| *status).max();
+    ExitCode::from(worst.unwrap_or(Status::Success) as u8)
+}
+
+/// Writes the program which makes the registration marks.
+fn write_marks(settings: &ConfigFile, output: Option<&Path>) -> ExitCode {
+    let registration = &settings.knife.registration;
+    if let Some(stretch) = registration.stretch() {
+        eprintln!("The measured marks are {stretch:.3} mm further apart than in the design");
+    }
+    let program = registration.mark_program(&settings.output);
+    for warning in &program.warnings {
+        eprintln!("{warning}");
+    }
+    write_or_print(output, &program.text)
+}
+
+/// Writes the program from `line` of the input on, after restoring the state before it.
+fn write_resume(options: &Options, line: usize, output: Option<&Path>) -> ExitCode {
+    let input = &options.inputs[0];
+    let source = if input == Path::new(STDIO) {
+        std::io::read_to_string(std::io::stdin())
+    } else {
+        std::fs::read_to_string(input)
+    };
+    let source = match source {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("{}: {err}", input.display());
+            return ExitCode::from(Status::ParseFailure as u8);
+        }
+    };
+    let settings = &options.settings;
+    match resume::resume_program(&source, line, &settings.knife, &settings.output) {
+        Ok(text) => write_or_print(output, &text),
+        Err(err) => {
+            eprintln!("{}: {err}", input.display());
+            ExitCode::from(USAGE_ERROR)
+        }
+    }
+}
+
+/// Writes `text` to `output`, or to stdout without one.
+fn write_or_print(output: Option<&Path>, text: &str) -> ExitCode {
+    let written = match output {
+        Some(output) if output != Path::new(STDIO) => std::fs::write(output, text),
+        _ => std::io::stdout().write_all(text.as_bytes()),
+    };
+    match written {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}: {err}", output.unwrap_or(Path::new(STDIO)).display());
+            ExitCode::from(Status::OutputFailure as u8)
+        }
+    }
+}
+
+/// Runs the command again for every input which is changed or added, until stopped.
+///
+/// An input is only read once its modification time stayed the same for one poll, so files which
+/// are still being written by the CAM software are not picked up halfway.
+fn watch(options: &Options) -> ! {
+    let mut seen = HashMap::new();
+    let mut processed = HashMap::new();
+    eprintln!("Watching for changes, press Ctrl+C to stop");
+    loop {
+        let inputs = find_inputs(&options.patterns, &options.command).unwrap_or_default();
+        for input in inputs {
+            let Ok(modified) = std::fs::metadata(&input).and_then(|meta| meta.modified()) else {
+                continue;
+            };
+            if processed.get(&input) == Some(&modified) {
+                continue;
+            }
+            if seen.insert(input.clone(), modified) == Some(modified) {
+                if run(options, &input) <= Status::Warnings {
+                    eprintln!("{}: done", input.display());
+                }
+                processed.insert(input, modified);
+            }
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Runs the command for one input and prints what happened.
+fn run(options: &Options, input: &Path) -> Status {
+    let report = process(options, input);
+    if options.json {
+        print_json(options, &report);
+    } else {
+        print_report(options, &report);
+    }
+    report.status
+}
+
+fn process<'a>(options: &Options, input: &'a Path) -> Report<'a> {
+    let settings = &options.settings;
+    let report = Report {
+        input,
+        output: None,
+        status: Status::Success,
+        error: None,
+        warnings: vec![],
+        stats: None,
+    };
+    let source = if input == Path::new(STDIO) {
+        std::io::read_to_string(std::io::stdin())
+    } else {
+        std::fs::read_to_string(input)
+    };
+    let source = match source {
+        Ok(source) => source,
+        Err(err) => return report.failed(Status::ParseFailure, err),
+    };
+    let mut report = report;
+    let repaths = !matches!(
+        options.command,
+        Subcommand::Stats | Subcommand::Marks { .. } | Subcommand::Resume { .. }
+    );
+    let measures = options.json || matches!(options.command, Subcommand::Stats);
+    if !repaths && !measures {
+        return report;
+    }
+    let prepared = match job::prepare_path(&source, &settings.knife, settings.strict_parameters) {
+        Ok(prepared) => prepared,
+        Err(err) => return report.failed(Status::from_error(&err), err),
+    };
+    // The stats are taken from the written program, which is only checked if it is kept
+    let verify_output = repaths && settings.verify_output;
+    let mut output = match prepared.write(&settings.knife, &settings.output, verify_output) {
+        Ok(output) => output,
+        Err(err) => return report.failed(Status::from_error(&err), err),
+    };
+    if measures {
+        report.stats = Some(prepared.stats(&settings.knife, &output.text, options.rapid_feedrate));
+    }
+    let output_file = match &options.command {
+        Subcommand::Stats | Subcommand::Marks { .. } | Subcommand::Resume { .. } => return report,
+        Subcommand::Validate => None,
+        Subcommand::Repath {
+            output: Some(output),
+            ..
+        } => Some(output.clone()),
+        Subcommand::Repath { .. } if input == Path::new(STDIO) => Some(PathBuf::from(STDIO)),
+        Subcommand::Repath { name_template, .. } => Some(job::output_path(input, name_template)),
+    };
+    if let Subcommand::Repath {
+        format: Format::Toolpath(format),
+        ..
+    } = options.command
+    {
+        output.text = format.write(&prepared.toolpath());
+        output.sections.clear();
+    }
+    report.warnings = std::mem::take(&mut output.warnings);
+    if !report.warnings.is_empty() {
+        report.status = match options.command {
+            Subcommand::Validate => Status::ValidationFailure,
+            _ => Status::Warnings,
+        };
+    }
+    if let Some(output_file) = output_file {
+        let written = if output_file == Path::new(STDIO) {
+            std::io::stdout().write_all(output.text.as_bytes())
+        } else {
+            job::write_output(&output_file, &output).map(|files| {
+                for file in files.iter().skip(1) {
+                    eprintln!("{}: also wrote {}", input.display(), file.display());
+                }
+            })
+        };
+        if let Err(err) = written {
+            let error = format!("{}: {err}", output_file.display());
+            return report.failed(Status::OutputFailure, error);
+        }
+        report.output = match output.sections.first() {
+            Some(section) if output_file != Path::new(STDIO) => {
+                Some(job::section_path(&output_file, 0, &section.name))
+            }
+            _ => Some(output_file),
+        };
+    }
+    report
+}
+
+fn print_report(options: &Options, report: &Report<'_>) {
+    let name = report.input.display();
+    match options.command {
+        Subcommand::Repath { .. } => {
+            for warning in &report.warnings {
+                eprintln!("{name}: {warning}");
+            }
+            if let Some(err) = &report.error {
+                eprintln!("{name}: {err}");
+            }
+        }
+        Subcommand::Validate => {
+            for warning in &report.warnings {
+                println!("{name}: {warning}");
+            }
+            match &report.error {
+                Some(err) => println!("{name}: {err}"),
+                None if report.warnings.is_empty() => println!("{name}: valid"),
+                None => println!("{name}: {} warnings", report.warnings.len()),
+            }
+        }
+        Subcommand::Stats | Subcommand::Marks { .. } | Subcommand::Resume { .. } => {
+            if let Some(err) = &report.error {
+                eprintln!("{name}: {err}");
+            }
+            if let Some(stats) = &report.stats {
+                print_stats(report.input, stats);
+            }
+        }
+    }
+}
+
+fn print_stats(input: &Path, stats: &JobStats) {
+    let (before, output) = (stats.input, stats.output);
+    println!("{}", input.display());
+    println!(
+        "  Cut length:      {:.1} mm ({:.1} mm repathed)",
+        before.cut_length, output.cut_length
+    );
+    println!("  Rapid length:    {:.1} mm", output.rapid_length);
+    println!("  Contours:        {}", before.contours);
+    println!("  Corners:         {}", before.corners);
+    println!("  Swivels:         {}", before.swivels);
+    println!("  Reversals:       {}", before.reversals);
+    if let Some((min, max)) = output.bounds {
+        println!(
+            "  Bounds:          X {:.3} to {:.3}, Y {:.3} to {:.3}, Z {:.3} to {:.3} mm",
+            min.x, max.x, min.y, max.y, min.z, max.z
+        );
+    }
+    let seconds = (output.run_time * 60.).round() as u64;
+    println!(
+        "  Estimated time:  {}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+}
+
+/// Prints the report as one line of JSON, so several inputs give one line each.
+fn print_json(options: &Options, report: &Report<'_>) {
+    let json = serde_json::json!({
+        "file": report.input,
+        "output": report.output,
+        "status": report.status.name(),
+        "exit_code": report.status as u8,
+        "error": report.error,
+        "warnings": report
+            .warnings
+            .iter()
+            .map(|warning| serde_json::json!({
+                "line": warning.line().map(|line| line + 1),
+                "message": warning.to_string(),
+            }))
+            .collect::<Vec<_>>(),
+        "stats": report.stats,
+        "settings": options.settings,
+    });
+    println!("{json}");
+}
+
+/// Reads the command line, or returns `None` if only the help was asked for.
+fn parse_args(mut args: Vec<String>) -> Result<Option<Options>, String> {
+    let mut command = None;
+    let mut rapid_feedrate = 5000.;
+    let mut patterns = vec![];
+    let mut watch = false;
+    let mut output = None;
+    let mut name_template = None;
+    let mut format = Format::GCode;
+    let mut json = false;
+    let mut resume_line = None;
+    // The flags override the config file, wherever it is given
+    let config_path = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => {
+            let path = args.get(i + 1).ok_or("--config needs a value")?.clone();
+            args.drain(i..i + 2);
+            Some(PathBuf::from(path))
+        }
+        None => Some(PathBuf::from(CONFIG_FILE_NAME)).filter(|path| path.is_file()),
+    };
+    let mut settings = match config_path {
+        Some(path) => {
+            ConfigFile::load(&path).map_err(|err| format!("{}: {err}", path.display()))?
+        }
+        None => ConfigFile::default(),
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-o" | "--output" => output = Some(PathBuf::from(value()?)),
+            "--name" => name_template = Some(value()?),
+            "--format" => format = Format::parse(&value()?)?,
+            "--preset" => {
+                let name = value()?;
+                let library = PresetLibrary::builtin();
+                let preset = library
+                    .get(&name)
+                    .ok_or_else(|| format!("There is no built-in preset named {name}"))?;
+                let knife = std::mem::take(&mut settings.knife);
+                settings.knife = DragknifeConfig {
+                    max_cut_depth: knife.max_cut_depth,
+                    min_height: knife.min_height,
+                    unsupported_codes: knife.unsupported_codes,
+                    allowed_codes: knife.allowed_codes,
+                    blocked_codes: knife.blocked_codes,
+                    ..preset.config.clone()
+                };
+            }
+            "--offset" => settings.knife.knife_offset = parse_number(&arg, &value()?)?,
+            "--max-depth" => settings.knife.max_cut_depth = Some(parse_number(&arg, &value()?)?),
+            "--min-height" => settings.knife.min_height = Some(parse_number(&arg, &value()?)?),
+            "--compensation-radius" => {
+                settings.knife.compensation_radius = parse_number(&arg, &value()?)?
+            }
+            "--kerf-width" => settings.knife.kerf_width = parse_number(&arg, &value()?)?,
+            "--corner-radius" => settings.knife.corner_radius = parse_number(&arg, &value()?)?,
+            "--round-corners" => {
+                settings.knife.corner_rounding = match value()?.as_str() {
+                    "all" => CornerRounding::All,
+                    "unswiveled" => CornerRounding::Unswiveled,
+                    other => return Err(format!("{arg} needs all or unswiveled, got {other}")),
+                };
+            }
+            "--unsupported-codes" => {
+                settings.knife.unsupported_codes = match value()?.as_str() {
+                    "pass" => UnsupportedCodes::Pass,
+                    "warn" => UnsupportedCodes::Warn,
+                    "error" => UnsupportedCodes::Error,
+                    other => return Err(format!("{arg} needs pass, warn or error, got {other}")),
+                };
+            }
+            "--allow-codes" => {
+                settings.knife.allowed_codes =
+                    CodeRange::parse_list(&value()?).map_err(|err| err.to_string())?;
+            }
+            "--block-codes" => {
+                settings.knife.blocked_codes =
+                    CodeRange::parse_list(&value()?).map_err(|err| err.to_string())?;
+            }
+            "--soft-limits" => {
+                let text = value()?;
+                let limits = text
+                    .split(',')
+                    .map(|number| parse_number(&arg, number.trim()).map(|n| n as f32))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let [x0, y0, z0, x1, y1, z1] = limits[..] else {
+                    return Err(format!("{arg} needs six numbers, got {text}"));
+                };
+                settings.output.check_soft_limits = true;
+                settings.output.soft_limits.min = [x0, y0, z0];
+                settings.output.soft_limits.max = [x1, y1, z1];
+            }
+            "--max-feedrate" => {
+                settings.output.check_max_feedrate = true;
+                settings.output.max_feedrate = parse_number(&arg, &value()?)? as f32;
+            }
+            "--rapid-feedrate" => rapid_feedrate = parse_number(&arg, &value()?)?,
+            "--filter" => {
+                settings.output.filters =
+                    OutputFilter::parse_list(&value()?).map_err(|err| err.to_string())?;
+            }
+            "--dialect" => {
+                let name = value()?;
+                settings.output.dialect = match Dialect::by_name(&name) {
+                    Some(dialect) => dialect,
+                    None if Path::new(&name).is_file() => {
+                        Dialect::load(&name).map_err(|err| format!("{name}: {err}"))?
+                    }
+                    None => return Err(format!("Unknown dialect {name}")),
+                };
+            }
+            "--material" => {
+                settings.output.material = value()?;
+                if settings.output.material_depth().is_none() {
+                    return Err(format!(
+                        "Unknown material {}, add it to [[output.materials]] in the config file",
+                        settings.output.material
+                    ));
+                }
+            }
+            "--height-map" => {
+                let path = value()?;
+                settings.output.height_map =
+                    Some(HeightMap::load(&path).map_err(|err| format!("{path}: {err}"))?);
+            }
+            "--split" => settings.output.split_files = true,
+            "--probe" => settings.output.probe_start = true,
+            "--probe-contours" => settings.output.probe_contours = true,
+            "--probe-feedrate" => {
+                settings.output.probe_feedrate = parse_number(&arg, &value()?)? as f32;
+            }
+            "--probe-clearance" => {
+                settings.output.probe_clearance = parse_number(&arg, &value()?)? as f32;
+            }
+            "--marks" => settings.knife.registration.marks = parse_points(&arg, &value()?)?,
+            "--measured" => {
+                let text = value()?;
+                let points = parse_points(&arg, &text)?;
+                if points.len() != 2 {
+                    return Err(format!("{arg} needs two points, got {text}"));
+                }
+                settings.knife.registration.measured = points;
+            }
+            "--line" => {
+                let text = value()?;
+                let line = text
+                    .parse()
+                    .map_err(|_| format!("{arg} needs a line number, got {text}"))?;
+                resume_line = Some(line);
+            }
+            "--strict-parameters" => settings.strict_parameters = true,
+            "--no-verify" => settings.verify_output = false,
+            "--json" => json = true,
+            "--watch" => watch = true,
+            "repath" | "validate" | "stats" | "marks" | "resume"
+                if command.is_none() && patterns.is_empty() =>
+            {
+                command = Some(arg)
+            }
+            _ if arg.starts_with('-') && arg != STDIO => {
+                return Err(format!("Unknown option {arg}"))
+            }
+            _ => patterns.push(arg),
+        }
+    }
+    if command.as_deref() == Some("marks") {
+        if !patterns.is_empty() {
+            return Err("marks takes no inputs".to_string());
+        }
+        if settings.knife.registration.marks.is_empty() {
+            return Err("No registration marks given, use --marks".to_string());
+        }
+        return Ok(Some(Options {
+            command: Subcommand::Marks { output },
+            patterns,
+            inputs: vec![],
+            watch: false,
+            json: false,
+            rapid_feedrate,
+            settings,
+        }));
+    }
+    if command.as_deref() == Some("resume") {
+        let [pattern] = &patterns[..] else {
+            return Err("resume takes one program".to_string());
+        };
+        let line = resume_line.ok_or("resume needs --line")?;
+        return Ok(Some(Options {
+            command: Subcommand::Resume { output, line },
+            inputs: vec![PathBuf::from(pattern)],
+            patterns,
+            watch: false,
+            json: false,
+            rapid_feedrate,
+            settings,
+        }));
+    }
+    if patterns.is_empty() {
+        return Err("No input file given".to_string());
+    }
+    let custom_name = name_template.is_some();
+    let command = match command.as_deref() {
+        Some("validate") => Subcommand::Validate,
+        Some("stats") => Subcommand::Stats,
+        _ => Subcommand::Repath {
+            output,
+            name_template: name_template.unwrap_or_else(|| format.default_name_template()),
+            format,
+        },
+    };
+    if watch && patterns.iter().any(|pattern| pattern == STDIO) {
+        return Err("stdin can not be watched".to_string());
+    }
+    // The program and the report can not both go to stdout
+    if let (true, Subcommand::Repath { output, .. }) = (json, &command) {
+        let to_stdout = match output {
+            Some(output) => output == Path::new(STDIO),
+            None => patterns.iter().any(|pattern| pattern == STDIO),
+        };
+        if to_stdout {
+            return Err("--json can not be used when writing the program to stdout".to_string());
+        }
+    }
+    // New files may still show up while watching
+    let inputs = match find_inputs(&patterns, &command) {
+        Err(_) if watch => vec![],
+        inputs => inputs?,
+    };
+    if let Subcommand::Repath {
+        output: Some(_), ..
+    } = command
+    {
+        if inputs.len() > 1 || patterns.len() > 1 || custom_name {
+            return Err("-o can only be used with a single input and no --name".to_string());
+        }
+    }
+    Ok(Some(Options {
+        command,
+        patterns,
+        inputs,
+        watch,
+        json,
+        rapid_feedrate,
+        settings,
+    }))
+}
+
+fn parse_number(option: &str, text: &str) -> Result<f64, String> {
+    text.parse()
+        .map_err(|_| format!("{option} needs a number, got {text}"))
+}
+
+/// Reads points given as `x,y,x,y,...`.
+fn parse_points(option: &str, text: &str) -> Result<Vec<[f64; 2]>, String> {
+    let numbers = text
+        .split(',')
+        .map(|number| parse_number(option, number.trim()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if numbers.len() % 2 != 0 {
+        return Err(format!("{option} needs pairs of x,y, got {text}"));
+    }
+    Ok(numbers.chunks(2).map(|pair| [pair[0], pair[1]]).collect())
+}
+
+/// Expands the inputs given on the command line to files. For `repath`, the outputs of other
+/// inputs are left out, as an earlier run puts them next to the inputs.
+fn find_inputs(patterns: &[String], command: &Subcommand) -> Result<Vec<PathBuf>, String> {
+    let mut inputs = vec![];
+    for pattern in patterns {
+        inputs.extend(expand_glob(pattern)?);
+    }
+    if let Subcommand::Repath {
+        output: None,
+        name_template,
+        ..
+    } = command
+    {
+        let outputs: Vec<_> = inputs
+            .iter()
+            .map(|input| job::output_path(input, name_template))
+            .collect();
+        inputs.retain(|input| !outputs.contains(input));
+    }
+    Ok(inputs)
+}
+
+/// Expands `*` and `?` in the file name of `pattern`, for shells which do not do this themselves,
+/// like the Windows command prompt. A folder stands for all files in it.
+fn expand_glob(pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let path = Path::new(pattern);
+    if path.is_dir() {
+        return expand_glob(&path.join("*").to_string_lossy());
+    }
+    let Some(name) = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| name.contains(['*', '?']))
+    else {
+        return Ok(vec![path.to_path_buf()]);
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut paths: Vec<_> = std::fs::read_dir(dir)
+        .map_err(|err| format!("{}: {err}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_file()))
+        .filter(|entry| {
+            let file_name = entry.file_name();
+            file_name
+                .to_str()
+                .is_some_and(|file_name| matches_glob(name.as_bytes(), file_name.as_bytes()))
+        })
+        .map(|entry| path.with_file_name(entry.file_name()))
+        .collect();
+    if paths.is_empty() {
+        return Err(format!("No files match {pattern}"));
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn matches_glob(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some((b'*', rest)), _) => {
+            matches_glob(rest, name) || (!name.is_empty() && matches_glob(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name_rest))) => matches_glob(rest, name_rest),
+        (Some((p, rest)), Some((n, name_rest))) => p == n && matches_glob(rest, name_rest),
+        (Some(_), None) => false,
+    }
+}
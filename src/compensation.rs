@@ -0,0 +1,849 @@
+use std::{
+    borrow::Cow,
+    f64::consts::{FRAC_PI_2, TAU},
+    fmt::Display,
+    ops::Range,
+};
+
+use gcode::{GCode, Mnemonic, Span};
+
+use crate::{
+    contours::cut_runs,
+    output::source_line,
+    signed_angle,
+    types::{
+        word, ArcDirection, ArcMovement, Command, DragknifeConfig, GCodePlane, GCodeState,
+        LinearMovement, Movement, WordValues,
+    },
+    vec3::Vec3,
+    DragknifePath, ARC_TOLERANCE,
+};
+
+/// Moves shorter than this do not change the direction of the knife.
+const MIN_LENGTH: f64 = 0.00001;
+
+/// How far the corner of two offset moves may be from the corner they are offset from, in
+/// offset radii, before it is rounded instead, like the miter limit of SVG strokes.
+const MITER_LIMIT: f64 = 4.;
+
+/// The angle between the points of arcs in the outline of a contour, in radians.
+const OUTLINE_STEP: f64 = 0.1;
+
+/// How far apart in mm the bounds of contours may be to count as the same shape.
+const SHAPE_TOLERANCE: f64 = 0.001;
+
+/// The side of the programmed path the tool is moved to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// `G41`, left of the path in the direction it is cut in.
+    Left,
+    /// `G42`, right of the path.
+    Right,
+}
+
+impl Side {
+    /// The direction from the path to the tool, for a move in direction `angle`.
+    fn normal(self, angle: f64, plane: &GCodePlane) -> Vec3 {
+        match self {
+            Side::Left => Vec3::unit_angle(angle + FRAC_PI_2, plane),
+            Side::Right => Vec3::unit_angle(angle - FRAC_PI_2, plane),
+        }
+    }
+}
+
+/// A part of the path which cutter or kerf compensation could not offset as programmed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompensationWarning {
+    /// Zero-based input line of the code or move.
+    pub line: Option<usize>,
+    pub problem: CompensationProblem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompensationProblem {
+    /// `G41` or `G42` without `D` on `G41.1`/`G42.1`, while
+    /// `DragknifeConfig::compensation_radius` is 0. The path is not offset.
+    NoRadius,
+    /// An arc with a smaller radius than the offset, on the side it turns to. It is cut to its
+    /// center instead.
+    TightArc { radius: f64 },
+    /// Moves around an inside corner which are too short to be offset without the tool cutting
+    /// into one of them. They are joined by a line instead.
+    Gouge,
+}
+
+impl Display for CompensationWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let at = |f: &mut std::fmt::Formatter<'_>| match self.line {
+            Some(line) => write!(f, " at input line {}", line + 1),
+            None => Ok(()),
+        };
+        match self.problem {
+            CompensationProblem::NoRadius => {
+                write!(f, "Cutter compensation")?;
+                at(f)?;
+                write!(f, " has no radius, so the path is not offset")
+            }
+            CompensationProblem::TightArc { radius } => {
+                write!(f, "Arc")?;
+                at(f)?;
+                write!(
+                    f,
+                    " with a radius of {radius:.3} mm is not larger than the offset of the \
+                     path; cut to its center"
+                )
+            }
+            CompensationProblem::Gouge => {
+                write!(f, "Corner")?;
+                at(f)?;
+                write!(
+                    f,
+                    " can not be offset without cutting into a move next to it"
+                )
+            }
+        }
+    }
+}
+
+/// A move of an offset run, as a line or an arc.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Piece {
+    /// Index into `DragknifePath::commands`.
+    pub(crate) command: usize,
+    pub(crate) start: Vec3,
+    pub(crate) end: Vec3,
+    /// The center and direction of arcs.
+    pub(crate) arc: Option<(Vec3, ArcDirection)>,
+    pub(crate) rapid: bool,
+    /// An arc which is tighter than the offset, shrunk to its center.
+    pub(crate) collapsed: bool,
+}
+
+impl Piece {
+    /// The direction of the piece at `point`, which is on it.
+    pub(crate) fn angle_at(&self, point: Vec3, plane: &GCodePlane) -> f64 {
+        match self.arc {
+            None => self.start.angle_to(&self.end, plane),
+            Some((center, direction)) => arc_angle(center, point, direction, plane),
+        }
+    }
+
+    /// The piece moved by `radius` to `side`, or `None` for an arc which is not larger than
+    /// `radius` on the side it turns to.
+    pub(crate) fn offset(&self, side: Side, radius: f64, plane: &GCodePlane) -> Option<Piece> {
+        if let Some((center, direction)) = self.arc {
+            // The offset is inside the arc if it is to the side the arc turns to
+            let inside = matches!(
+                (side, direction),
+                (Side::Left, ArcDirection::CCW) | (Side::Right, ArcDirection::CW)
+            );
+            let arc_radius = (self.start - center).project_plane(plane).magnitude();
+            if inside && arc_radius - radius <= ARC_TOLERANCE {
+                return None;
+            }
+        }
+        Some(Piece {
+            start: self.start + side.normal(self.angle_at(self.start, plane), plane) * radius,
+            end: self.end + side.normal(self.angle_at(self.end, plane), plane) * radius,
+            ..*self
+        })
+    }
+}
+
+/// Moves which are offset together, to one side.
+struct OffsetRun {
+    /// Indices into `DragknifePath::commands`.
+    commands: Vec<usize>,
+    side: Side,
+    radius: f64,
+    plane: GCodePlane,
+    /// Whether the last move is joined to the first.
+    closed: bool,
+}
+
+/// A closed contour as a polygon, to tell which contours are inside which.
+struct Outline {
+    points: Vec<(f64, f64)>,
+    /// Positive if the contour runs counterclockwise.
+    area: f64,
+    /// The lowest and highest coordinates along both axes of the plane.
+    bounds: [(f64, f64); 2],
+}
+
+impl Outline {
+    fn new(points: Vec<(f64, f64)>) -> Self {
+        let area = points
+            .iter()
+            .zip(points.iter().cycle().skip(1))
+            .map(|(p, q)| p.0 * q.1 - q.0 * p.1)
+            .sum::<f64>()
+            / 2.;
+        let bounds = [|p: &(f64, f64)| p.0, |p: &(f64, f64)| p.1].map(|axis| {
+            points
+                .iter()
+                .map(axis)
+                .fold((f64::MAX, f64::MIN), |(low, high), value| {
+                    (low.min(value), high.max(value))
+                })
+        });
+        Outline {
+            points,
+            area,
+            bounds,
+        }
+    }
+
+    /// Whether `other` is the same contour, maybe starting elsewhere or cut the other way.
+    fn same_shape(&self, other: &Outline) -> bool {
+        let close = |a: f64, b: f64| (a - b).abs() <= SHAPE_TOLERANCE;
+        (self.area.abs() - other.area.abs()).abs() <= SHAPE_TOLERANCE * self.area.abs().max(1.)
+            && self
+                .bounds
+                .iter()
+                .zip(&other.bounds)
+                .all(|(a, b)| close(a.0, b.0) && close(a.1, b.1))
+    }
+
+    /// Whether `other` is inside this contour, going by its start.
+    fn encloses(&self, other: &Outline) -> bool {
+        if self.area.abs() <= other.area.abs() || self.same_shape(other) {
+            return false;
+        }
+        let (x, y) = other.points[0];
+        let mut inside = false;
+        for (p, q) in self.points.iter().zip(self.points.iter().cycle().skip(1)) {
+            if (p.1 > y) != (q.1 > y) && x < p.0 + (y - p.1) / (q.1 - p.1) * (q.0 - p.0) {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+}
+
+/// A moved end of two pieces, or an arc which joins them.
+struct Join {
+    /// Where the first piece ends and the second starts, if they meet.
+    meet: Option<Vec3>,
+    /// An arc around the corner from the end of the first piece to the start of the second.
+    round: Option<ArcDirection>,
+    gouge: bool,
+}
+
+impl<'a> DragknifePath<'a> {
+    /// Applies the cutter compensation of the program: the moves between `G41` or `G42` and
+    /// `G40` are offset to the left or right, by `D`/2 of `G41.1`/`G42.1` or by
+    /// `config.compensation_radius`, and the codes are left out of the path.
+    ///
+    /// The offset moves are extended or trimmed to meet at corners, or joined by an arc around
+    /// the corner if they would meet far from it. Like on a controller, the first move after
+    /// `G41` or `G42` starts where the path was, and the first one after `G40` ends where it was
+    /// programmed. Rapid moves go straight to the start of the next offset cut.
+    pub fn apply_cutter_compensation(
+        &mut self,
+        config: &DragknifeConfig,
+    ) -> Vec<CompensationWarning> {
+        let mut warnings = vec![];
+        let mut settings = self.initial_state;
+        let mut compensation: Option<OffsetRun> = None;
+        let mut codes = vec![];
+        let mut runs = vec![];
+        for (i, command) in self.commands.iter().enumerate() {
+            let original = command.original();
+            // Full circles end where they start
+            let planar = matches!(command, Command::Arc(_))
+                || (command.end_pos() - command.start_pos())
+                    .project_plane(&settings.plane)
+                    .magnitude()
+                    > MIN_LENGTH;
+            match command {
+                Command::Linear(_) | Command::Arc(_) | Command::Rapid(_) if planar => {
+                    if let Some(run) = &mut compensation {
+                        run.commands.push(i);
+                    }
+                }
+                Command::Other(_)
+                    if original.mnemonic() == Mnemonic::General
+                        && matches!(original.major_number(), 40..=42) =>
+                {
+                    runs.extend(compensation.take());
+                    codes.push(i);
+                    let side = match original.major_number() {
+                        41 => Some(Side::Left),
+                        42 => Some(Side::Right),
+                        _ => None,
+                    };
+                    if let Some(side) = side {
+                        let radius = match (original.minor_number(), original.value('D')) {
+                            (1, Some(diameter)) => diameter * settings.unit_factor() / 2.,
+                            _ => config.compensation_radius,
+                        };
+                        if radius <= 0. {
+                            warnings.push(CompensationWarning {
+                                line: source_line(original.span()),
+                                problem: CompensationProblem::NoRadius,
+                            });
+                        }
+                        compensation = Some(OffsetRun {
+                            commands: vec![],
+                            side,
+                            radius,
+                            plane: settings.plane,
+                            closed: false,
+                        });
+                    }
+                }
+                // Homing moves are not offset
+                Command::Home(_) => {
+                    if let Some(run) = &mut compensation {
+                        runs.push(OffsetRun {
+                            commands: std::mem::take(&mut run.commands),
+                            ..*run
+                        });
+                    }
+                }
+                _ => {}
+            }
+            command.update_settings(&mut settings);
+        }
+        runs.extend(compensation);
+        self.offset_runs(runs, &codes, &mut warnings);
+        warnings
+    }
+
+    /// Offsets every closed contour by half of `config.kerf_width`, so the blade cuts along the
+    /// outside of parts and along the inside of holes, and the parts come out at the size they
+    /// were drawn. Contours inside an odd number of others are holes; contours of the same
+    /// shape, like the passes of a multi-depth cut, do not count as inside each other.
+    ///
+    /// The corners are joined like those of [`DragknifePath::apply_cutter_compensation`], and
+    /// the rapid move to a contour goes to its new start.
+    pub fn apply_kerf_compensation(
+        &mut self,
+        config: &DragknifeConfig,
+    ) -> Vec<CompensationWarning> {
+        let mut warnings = vec![];
+        let radius = config.kerf_width / 2.;
+        if radius <= 0. {
+            return warnings;
+        }
+        let mut closed: Vec<_> = cut_runs(self)
+            .into_iter()
+            .filter(|(_, _, closed)| *closed)
+            .map(|(commands, settings, _)| (commands, settings.plane))
+            .collect();
+        // A full circle is a closed contour on its own, though it does not move the knife
+        let mut settings = self.initial_state;
+        for (i, command) in self.commands.iter().enumerate() {
+            if let Command::Arc(arc) = command {
+                if (arc.end - arc.start).magnitude() <= MIN_LENGTH {
+                    closed.push((i..i + 1, settings.plane));
+                }
+            }
+            command.update_settings(&mut settings);
+        }
+        closed.sort_by_key(|(commands, _)| commands.start);
+        let contours: Vec<_> = closed
+            .into_iter()
+            .map(|(commands, plane)| {
+                let outline = self.outline(commands.clone(), &plane);
+                (commands, plane, outline)
+            })
+            .collect();
+        let mut runs = vec![];
+        for (commands, plane, outline) in &contours {
+            let mut around: Vec<&Outline> = vec![];
+            for (_, other_plane, other) in &contours {
+                if other_plane == plane
+                    && other.encloses(outline)
+                    && !around.iter().any(|shape| shape.same_shape(other))
+                {
+                    around.push(other);
+                }
+            }
+            // The inside of a counterclockwise contour is on its left
+            let outside = if outline.area > 0. {
+                Side::Right
+            } else {
+                Side::Left
+            };
+            let hole = around.len() % 2 == 1;
+            let side = match (hole, outside) {
+                (false, side) => side,
+                (true, Side::Left) => Side::Right,
+                (true, Side::Right) => Side::Left,
+            };
+            runs.push(OffsetRun {
+                commands: commands.clone().collect(),
+                side,
+                radius,
+                plane: *plane,
+                closed: true,
+            });
+        }
+        self.offset_runs(runs, &[], &mut warnings);
+        warnings
+    }
+
+    /// Offsets the moves of `runs`, joins them, and leaves the commands `codes` out of the path.
+    fn offset_runs(
+        &mut self,
+        runs: Vec<OffsetRun>,
+        codes: &[usize],
+        warnings: &mut Vec<CompensationWarning>,
+    ) {
+        let mut placed = vec![false; self.commands.len()];
+        let mut rounds = vec![];
+        for run in runs {
+            if run.radius <= 0. || run.commands.is_empty() {
+                continue;
+            }
+            let (side, radius, plane) = (run.side, run.radius, run.plane);
+            let mut pieces: Vec<Piece> = run
+                .commands
+                .iter()
+                .map(|&i| self.offset_piece(i, side, radius, &plane, warnings))
+                .collect();
+            // Closed runs are also joined where the last move meets the first
+            let corners = if run.closed {
+                pieces.len()
+            } else {
+                pieces.len() - 1
+            };
+            for k in 0..corners {
+                let next = (k + 1) % pieces.len();
+                let corner = self.commands[pieces[k].command].end_pos();
+                let (a, b) = (pieces[k], pieces[next]);
+                let join = join(&a, &b, corner, side, radius, &plane);
+                if join.gouge {
+                    warnings.push(CompensationWarning {
+                        line: source_line(self.commands[b.command].original().span()),
+                        problem: CompensationProblem::Gouge,
+                    });
+                }
+                if let Some(point) = join.meet {
+                    pieces[k].end = with_height(point, a.end, &plane);
+                    pieces[next].start = with_height(point, b.start, &plane);
+                }
+                if let Some(direction) = join.round {
+                    rounds.push((
+                        a.command,
+                        self.round_corner(&a, &b, corner, direction, &plane),
+                    ));
+                }
+            }
+            if run.closed {
+                if let Some(i) = self.approach(pieces[0].command, &plane) {
+                    if let Command::Rapid(rapid) = &mut self.commands[i] {
+                        rapid.end = with_height(pieces[0].start, rapid.end, &plane);
+                        placed[i] = true;
+                    }
+                }
+            }
+            for piece in pieces {
+                placed[piece.command] = true;
+                self.place_piece(&piece, &plane);
+            }
+        }
+
+        self.reconnect(codes, &placed, rounds);
+    }
+
+    /// Leaves the commands `codes` out of the path, inserts the `arcs` after the commands they
+    /// are paired with, and starts every command where the one before it ends, see
+    /// [`connect`]. `placed` tells which commands were moved.
+    pub(crate) fn reconnect(
+        &mut self,
+        codes: &[usize],
+        placed: &[bool],
+        arcs: Vec<(usize, Command<'a>)>,
+    ) {
+        let mut commands = Vec::with_capacity(self.commands.len() + arcs.len());
+        let mut rounds = arcs.into_iter().peekable();
+        for (i, command) in std::mem::take(&mut self.commands).into_iter().enumerate() {
+            if codes.binary_search(&i).is_err() {
+                commands.push((command, placed[i]));
+            }
+            while let Some((_, round)) = rounds.next_if(|(after, _)| *after == i) {
+                commands.push((round, true));
+            }
+        }
+        self.commands = connect(commands, self.initial_state);
+    }
+
+    /// The rapid move in the plane to the move `first`, if only codes and moves out of the
+    /// plane, like lowering the knife, are in between.
+    fn approach(&self, first: usize, plane: &GCodePlane) -> Option<usize> {
+        for i in (0..first).rev() {
+            let command = &self.commands[i];
+            let planar = (command.end_pos() - command.start_pos())
+                .project_plane(plane)
+                .magnitude()
+                > MIN_LENGTH;
+            // Also a move to where the knife already is, like the first one of a program
+            let to_plane = [plane.axis_1(), plane.axis_2()]
+                .iter()
+                .any(|axis| command.original().value(axis.main_name()).is_some());
+            match command {
+                Command::Other(_) => {}
+                Command::Rapid(_) if planar || to_plane => return Some(i),
+                Command::Linear(_) | Command::Rapid(_) if !planar => {}
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    /// The moves `commands` as a polygon in `plane`, with arcs split into short lines.
+    fn outline(&self, commands: Range<usize>, plane: &GCodePlane) -> Outline {
+        let mut points = vec![];
+        for command in &self.commands[commands] {
+            points.push(command.start_pos().coords_for_plane(plane));
+            let Command::Arc(arc) = command else {
+                continue;
+            };
+            let radius = (arc.start - arc.center).project_plane(plane).magnitude();
+            let start = arc.center.angle_to(&arc.start, plane);
+            let end = arc.center.angle_to(&arc.end, plane);
+            let mut sweep = match arc.direction {
+                ArcDirection::CW => -(start - end).rem_euclid(TAU),
+                ArcDirection::CCW => (end - start).rem_euclid(TAU),
+            };
+            if sweep.abs() <= ARC_TOLERANCE {
+                sweep = match arc.direction {
+                    ArcDirection::CW => -TAU,
+                    ArcDirection::CCW => TAU,
+                };
+            }
+            let steps = (sweep.abs() / OUTLINE_STEP).ceil() as usize;
+            let (c1, c2) = arc.center.coords_for_plane(plane);
+            for step in 1..steps {
+                let angle = start + sweep * step as f64 / steps as f64;
+                points.push((c1 + radius * angle.cos(), c2 + radius * angle.sin()));
+            }
+        }
+        Outline::new(points)
+    }
+
+    /// The move `i` as a piece, as it is programmed.
+    pub(crate) fn piece(&self, i: usize) -> Piece {
+        let command = &self.commands[i];
+        Piece {
+            command: i,
+            start: command.start_pos(),
+            end: command.end_pos(),
+            arc: match command {
+                Command::Arc(arc) => Some((arc.center, arc.direction)),
+                _ => None,
+            },
+            rapid: matches!(command, Command::Rapid(_)),
+            collapsed: false,
+        }
+    }
+
+    /// The move `i` offset by `radius` to `side`.
+    fn offset_piece(
+        &self,
+        i: usize,
+        side: Side,
+        radius: f64,
+        plane: &GCodePlane,
+        warnings: &mut Vec<CompensationWarning>,
+    ) -> Piece {
+        let piece = self.piece(i);
+        if let Some(offset) = piece.offset(side, radius, plane) {
+            return offset;
+        }
+        let Command::Arc(arc) = &self.commands[i] else {
+            unreachable!("only arcs can be too tight to offset")
+        };
+        warnings.push(CompensationWarning {
+            line: source_line(arc.original.span()),
+            problem: CompensationProblem::TightArc {
+                radius: (arc.start - arc.center).project_plane(plane).magnitude(),
+            },
+        });
+        Piece {
+            start: with_height(arc.center, piece.start, plane),
+            end: with_height(arc.center, piece.end, plane),
+            arc: None,
+            collapsed: true,
+            ..piece
+        }
+    }
+
+    /// Moves the command of `piece` onto it.
+    pub(crate) fn place_piece(&mut self, piece: &Piece, plane: &GCodePlane) {
+        let command = &mut self.commands[piece.command];
+        match command {
+            Command::Arc(arc) if piece.collapsed => {
+                *command = Command::Linear(LinearMovement {
+                    original: arc.original.clone(),
+                    start: piece.start,
+                    end: piece.end,
+                    angle: None,
+                });
+            }
+            Command::Arc(arc) => {
+                arc.start = piece.start;
+                arc.end = piece.end;
+                arc.start_angle = arc_angle(arc.center, arc.start, arc.direction, plane);
+                arc.end_angle = arc_angle(arc.center, arc.end, arc.direction, plane);
+            }
+            Command::Linear(linear) => {
+                linear.start = piece.start;
+                linear.end = piece.end;
+            }
+            Command::Rapid(rapid) => {
+                rapid.start = piece.start;
+                rapid.end = piece.end;
+            }
+            _ => {}
+        }
+    }
+
+    /// An arc around `corner` from the end of `a` to the start of `b`.
+    pub(crate) fn round_corner(
+        &self,
+        a: &Piece,
+        b: &Piece,
+        corner: Vec3,
+        direction: ArcDirection,
+        plane: &GCodePlane,
+    ) -> Command<'a> {
+        let number = match direction {
+            ArcDirection::CW => 2.,
+            ArcDirection::CCW => 3.,
+        };
+        let span = self.commands[a.command].original().span();
+        let center = with_height(corner, a.end, plane);
+        Command::Arc(ArcMovement {
+            original: Cow::Owned(GCode::new(Mnemonic::General, number, span)),
+            direction,
+            start: a.end,
+            end: with_height(b.start, a.end, plane),
+            center,
+            start_angle: arc_angle(center, a.end, direction, plane),
+            end_angle: arc_angle(center, b.start, direction, plane),
+        })
+    }
+}
+
+/// How the offset pieces `a` and `b` are joined at `corner`, the end of the programmed move of
+/// `a`.
+fn join(a: &Piece, b: &Piece, corner: Vec3, side: Side, radius: f64, plane: &GCodePlane) -> Join {
+    let mut join = Join {
+        meet: None,
+        round: None,
+        gouge: false,
+    };
+    let distance = |p: Vec3, q: Vec3| (p - q).project_plane(plane).magnitude();
+    if b.rapid {
+        return join;
+    }
+    // Rapid moves go straight to the next cut
+    if a.rapid || distance(a.end, b.start) <= MIN_LENGTH {
+        join.meet = Some(b.start);
+        return join;
+    }
+    if a.collapsed || b.collapsed {
+        return join;
+    }
+    let meet = intersections(a, b, plane)
+        .into_iter()
+        .filter(|&point| distance(point, corner) <= radius * MITER_LIMIT)
+        .filter(|&point| keeps_direction(a, a.start, point, plane))
+        .filter(|&point| keeps_direction(b, point, b.end, plane))
+        .min_by(|&p, &q| distance(p, corner).total_cmp(&distance(q, corner)));
+    if meet.is_some() {
+        join.meet = meet;
+        return join;
+    }
+    // Right turns are positive, and their outside is on the left
+    let turn = signed_angle(a.angle_at(a.end, plane), b.angle_at(b.start, plane));
+    match (side, turn > 0.) {
+        (Side::Left, true) => join.round = Some(ArcDirection::CW),
+        (Side::Right, false) => join.round = Some(ArcDirection::CCW),
+        _ => join.gouge = true,
+    }
+    join
+}
+
+/// Whether `piece` from `start` to `end` still runs in its direction, and is not turned around
+/// by moving one of its ends.
+fn keeps_direction(piece: &Piece, start: Vec3, end: Vec3, plane: &GCodePlane) -> bool {
+    let (s1, s2) = start.coords_for_plane(plane);
+    let (e1, e2) = end.coords_for_plane(plane);
+    match piece.arc {
+        None => {
+            let (p1, p2) = piece.start.coords_for_plane(plane);
+            let (q1, q2) = piece.end.coords_for_plane(plane);
+            (e1 - s1) * (q1 - p1) + (e2 - s2) * (q2 - p2) > 0.
+        }
+        // Moving an end of an arc a quarter turn or more is not a trim at a corner
+        Some((center, direction)) => {
+            let sweep = |from: Vec3, to: Vec3| {
+                let (from, to) = (center.angle_to(&from, plane), center.angle_to(&to, plane));
+                match direction {
+                    ArcDirection::CW => (from - to).rem_euclid(TAU),
+                    ArcDirection::CCW => (to - from).rem_euclid(TAU),
+                }
+            };
+            (sweep(start, end) - sweep(piece.start, piece.end)).abs() < FRAC_PI_2
+        }
+    }
+}
+
+/// Where the lines and circles `a` and `b` lie on cross, in the plane.
+pub(crate) fn intersections(a: &Piece, b: &Piece, plane: &GCodePlane) -> Vec<Vec3> {
+    let point = |p: Vec3| p.coords_for_plane(plane);
+    let points = match (a.arc, b.arc) {
+        (None, None) => line_line(point(a.start), point(a.end), point(b.start), point(b.end))
+            .into_iter()
+            .collect(),
+        (None, Some((center, _))) => line_circle(
+            point(a.start),
+            point(a.end),
+            point(center),
+            (b.start - center).project_plane(plane).magnitude(),
+        ),
+        (Some((center, _)), None) => line_circle(
+            point(b.start),
+            point(b.end),
+            point(center),
+            (a.end - center).project_plane(plane).magnitude(),
+        ),
+        (Some((c1, _)), Some((c2, _))) => circle_circle(
+            point(c1),
+            (a.end - c1).project_plane(plane).magnitude(),
+            point(c2),
+            (b.start - c2).project_plane(plane).magnitude(),
+        ),
+    };
+    points
+        .into_iter()
+        .map(|(x, y)| Vec3::from_2d(x, y, plane))
+        .collect()
+}
+
+fn line_line(p: (f64, f64), p2: (f64, f64), q: (f64, f64), q2: (f64, f64)) -> Option<(f64, f64)> {
+    let (d, e) = ((p2.0 - p.0, p2.1 - p.1), (q2.0 - q.0, q2.1 - q.1));
+    let cross = d.0 * e.1 - d.1 * e.0;
+    if cross.abs() <= f64::EPSILON * d.0.hypot(d.1) * e.0.hypot(e.1) {
+        return None;
+    }
+    let t = ((q.0 - p.0) * e.1 - (q.1 - p.1) * e.0) / cross;
+    Some((p.0 + d.0 * t, p.1 + d.1 * t))
+}
+
+fn line_circle(p: (f64, f64), p2: (f64, f64), center: (f64, f64), radius: f64) -> Vec<(f64, f64)> {
+    let d = (p2.0 - p.0, p2.1 - p.1);
+    let f = (p.0 - center.0, p.1 - center.1);
+    let a = d.0 * d.0 + d.1 * d.1;
+    let b = 2. * (f.0 * d.0 + f.1 * d.1);
+    let c = f.0 * f.0 + f.1 * f.1 - radius * radius;
+    let discriminant = b * b - 4. * a * c;
+    if a == 0. || discriminant < 0. {
+        return vec![];
+    }
+    let root = discriminant.sqrt();
+    [(-b - root) / (2. * a), (-b + root) / (2. * a)]
+        .into_iter()
+        .map(|t| (p.0 + d.0 * t, p.1 + d.1 * t))
+        .collect()
+}
+
+fn circle_circle(c1: (f64, f64), r1: f64, c2: (f64, f64), r2: f64) -> Vec<(f64, f64)> {
+    let d = (c2.0 - c1.0).hypot(c2.1 - c1.1);
+    if d == 0. || d > r1 + r2 || d < (r1 - r2).abs() {
+        return vec![];
+    }
+    let along = (r1 * r1 - r2 * r2 + d * d) / (2. * d);
+    let across = (r1 * r1 - along * along).max(0.).sqrt();
+    let (u1, u2) = ((c2.0 - c1.0) / d, (c2.1 - c1.1) / d);
+    let base = (c1.0 + u1 * along, c1.1 + u2 * along);
+    vec![
+        (base.0 - u2 * across, base.1 + u1 * across),
+        (base.0 + u2 * across, base.1 - u1 * across),
+    ]
+}
+
+/// The direction of an arc around `center` at `point`.
+fn arc_angle(center: Vec3, point: Vec3, direction: ArcDirection, plane: &GCodePlane) -> f64 {
+    let angle = match direction {
+        ArcDirection::CW => center.angle_to(&point, plane) - FRAC_PI_2,
+        ArcDirection::CCW => center.angle_to(&point, plane) + FRAC_PI_2,
+    };
+    angle.rem_euclid(TAU)
+}
+
+/// `point` in the plane, at the height of `height`.
+pub(crate) fn with_height(point: Vec3, height: Vec3, plane: &GCodePlane) -> Vec3 {
+    point.project_plane(plane) + (height - height.project_plane(plane))
+}
+
+/// Starts every command where the one before it ends. Commands which were not `placed` keep
+/// their end, except moves out of the plane, which follow the ones before them. Arcs which do
+/// not start there are led into by a line, and rapid moves which end somewhere else than
+/// programmed are given both axes of the plane.
+fn connect<'a>(commands: Vec<(Command<'a>, bool)>, initial_state: GCodeState) -> Vec<Command<'a>> {
+    let mut settings = initial_state;
+    let mut out: Vec<Command<'a>> = Vec::with_capacity(commands.len());
+    for (mut command, placed) in commands {
+        let Some(prev) = out.last() else {
+            command.update_settings(&mut settings);
+            out.push(command);
+            continue;
+        };
+        let (pos, angle) = (prev.end_pos(), prev.end_angle());
+        let plane = settings.plane;
+        let planar = |start: Vec3, end: Vec3| (end - start).project_plane(&plane).magnitude();
+        match &mut command {
+            Command::Other(other) => {
+                other.pos = pos;
+                other.angle = angle;
+            }
+            Command::Linear(linear) => {
+                if !placed && planar(linear.start, linear.end) <= MIN_LENGTH {
+                    linear.end = pos + (linear.end - linear.start);
+                }
+                linear.start = pos;
+                linear.angle = Command::linear_angle(linear.start, linear.end, angle, &settings);
+            }
+            Command::Rapid(rapid) => {
+                if !placed && planar(rapid.start, rapid.end) <= MIN_LENGTH {
+                    rapid.end = pos + (rapid.end - rapid.start);
+                } else if placed || planar(rapid.start, pos) > MIN_LENGTH {
+                    rapid.original = Cow::Owned(with_plane_words(&rapid.original, &plane));
+                }
+                rapid.start = pos;
+            }
+            Command::Arc(arc) if planar(pos, arc.start) > MIN_LENGTH => {
+                let span = arc.original.span();
+                out.push(Command::Linear(LinearMovement {
+                    original: Cow::Owned(GCode::new(Mnemonic::General, 1., span)),
+                    start: pos,
+                    end: arc.start,
+                    angle: Command::linear_angle(pos, arc.start, angle, &settings),
+                }));
+            }
+            Command::Arc(arc) => arc.start = with_height(pos, arc.start, &plane),
+            Command::Home(home) => home.start = pos,
+        }
+        command.update_settings(&mut settings);
+        out.push(command);
+    }
+    out
+}
+
+/// `gcode` with words for both axes of `plane`, as rapid moves only write the axes they have.
+fn with_plane_words(gcode: &GCode, plane: &GCodePlane) -> GCode {
+    let mut gcode = gcode.clone();
+    for axis in [plane.axis_1(), plane.axis_2()] {
+        if gcode.value(axis.main_name()).is_none() {
+            gcode
+                .push_argument(word(axis.main_name(), 0., Span::PLACEHOLDER))
+                .unwrap();
+        }
+    }
+    gcode
+}
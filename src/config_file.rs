@@ -0,0 +1,186 @@
+use std::{error::Error, f64::consts::PI, fmt::Display, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    output::OutputConfig,
+    types::{DragknifeConfig, LiftConfig},
+};
+
+/// The file the command line tool loads from the working directory if no other is given.
+pub const CONFIG_FILE_NAME: &str = "dragknife.toml";
+
+/// A machine setup which can be kept in version control: the dragknife config, the output
+/// options like filters, dialect and machine limits, and how the input is read.
+///
+/// Settings missing from a file keep their default, so a file only needs what differs, e.g.
+///
+/// ```toml
+/// [knife]
+/// knife_offset = 0.35
+/// lift_config = { RelativeHeight = 0.5 }
+///
+/// [output]
+/// filters = [{ Code = { letter = "M", major_number = 3 } }]
+/// check_max_feedrate = true
+/// max_feedrate = 3000.0
+/// ```
+///
+/// A file ending in `.json` is read and written as JSON instead, with the same sections. Unlike
+/// TOML, JSON can turn off optional settings which are on by default, e.g. `"max_cut_depth": null`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigFile {
+    /// Stop on parameters which are used before they are set, instead of using 0.
+    pub strict_parameters: bool,
+    /// Parse the written program again and stop if it does not make the cuts of the input.
+    pub verify_output: bool,
+    pub knife: DragknifeConfig,
+    pub output: OutputConfig,
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        ConfigFile {
+            strict_parameters: false,
+            verify_output: true,
+            knife: DragknifeConfig {
+                reversal_lift: LiftConfig::RelativeHeight(5.0),
+                max_cut_depth: Some(3.),
+                ..DragknifeConfig::new(1., LiftConfig::RelativeHeight(1.0), 10. * PI / 180., 300.)
+            },
+            output: OutputConfig {
+                format_numbers: true,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl ConfigFile {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigFileError> {
+        let text = std::fs::read_to_string(&path)?;
+        if is_json(path.as_ref()) {
+            Self::from_json(&text)
+        } else {
+            Self::from_toml(&text)
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ConfigFileError> {
+        let text = if is_json(path.as_ref()) {
+            self.to_json()?
+        } else {
+            self.to_toml()?
+        };
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Reads a config from TOML text. Settings which are not given keep their default.
+    pub fn from_toml(text: &str) -> Result<Self, ConfigFileError> {
+        let file: toml::Table = toml::from_str(text)?;
+        let toml::Value::Table(mut config) = toml::Value::try_from(ConfigFile::default())? else {
+            unreachable!("a struct is written as a table")
+        };
+        for (key, value) in file {
+            match (config.get_mut(&key), value) {
+                // Settings within a section replace their default one by one
+                (Some(toml::Value::Table(section)), toml::Value::Table(values)) => {
+                    section.extend(values)
+                }
+                (_, value) => {
+                    config.insert(key, value);
+                }
+            }
+        }
+        Ok(toml::Value::Table(config).try_into()?)
+    }
+
+    pub fn to_toml(&self) -> Result<String, ConfigFileError> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    /// Reads a config from JSON text, filling in the defaults like [`ConfigFile::from_toml`].
+    pub fn from_json(text: &str) -> Result<Self, ConfigFileError> {
+        let file: serde_json::Map<String, serde_json::Value> = serde_json::from_str(text)?;
+        let serde_json::Value::Object(mut config) = serde_json::to_value(ConfigFile::default())?
+        else {
+            unreachable!("a struct is written as an object")
+        };
+        for (key, value) in file {
+            match (config.get_mut(&key), value) {
+                (Some(serde_json::Value::Object(section)), serde_json::Value::Object(values)) => {
+                    section.extend(values)
+                }
+                (_, value) => {
+                    config.insert(key, value);
+                }
+            }
+        }
+        Ok(serde_json::from_value(serde_json::Value::Object(config))?)
+    }
+
+    pub fn to_json(&self) -> Result<String, ConfigFileError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+#[derive(Debug)]
+pub enum ConfigFileError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Write(toml::ser::Error),
+    Json(serde_json::Error),
+}
+
+impl From<std::io::Error> for ConfigFileError {
+    fn from(err: std::io::Error) -> Self {
+        ConfigFileError::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for ConfigFileError {
+    fn from(err: toml::de::Error) -> Self {
+        ConfigFileError::Parse(err)
+    }
+}
+
+impl From<toml::ser::Error> for ConfigFileError {
+    fn from(err: toml::ser::Error) -> Self {
+        ConfigFileError::Write(err)
+    }
+}
+
+impl From<serde_json::Error> for ConfigFileError {
+    fn from(err: serde_json::Error) -> Self {
+        ConfigFileError::Json(err)
+    }
+}
+
+impl Display for ConfigFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "Could not access config file: {err}"),
+            Self::Parse(err) => write!(f, "Could not read config: {err}"),
+            Self::Write(err) => write!(f, "Could not write config: {err}"),
+            Self::Json(err) => write!(f, "Invalid config JSON: {err}"),
+        }
+    }
+}
+
+impl Error for ConfigFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Parse(err) => Some(err),
+            Self::Write(err) => Some(err),
+            Self::Json(err) => Some(err),
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    f64::consts::{PI, TAU},
+    fmt::Display,
+    ops::Range,
+};
+
+use crate::{
+    output::source_line,
+    signed_angle,
+    toolpath::Segment,
+    types::{
+        ArcDirection, ArcMovement, Command, DragknifeConfig, FeedMode, GCodeState, LinearMovement,
+        Movement,
+    },
+    DragknifePath,
+};
+
+/// Moves shorter than this do not change the direction of the knife.
+const MIN_LENGTH: f64 = 0.00001;
+
+/// A closed contour with features the knife is pushed through instead of pulled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContourReport {
+    /// The moves of the contour, as indices into `DragknifePath::commands`.
+    pub commands: Range<usize>,
+    /// Zero-based input line of the first move, if it is in the input.
+    pub line: Option<usize>,
+    /// Sharp corners cut without a swivel, as they are below the threshold for their direction.
+    pub pushed_corners: usize,
+    /// The same when the contour is cut in the other direction.
+    pub pushed_corners_reversed: usize,
+    /// Arcs with a smaller radius than the knife offset, which the blade can not follow in
+    /// either direction.
+    pub tight_arcs: usize,
+    /// Whether the contour was reversed.
+    pub reversed: bool,
+}
+
+impl ContourReport {
+    /// Whether cutting the contour in the other direction pushes the knife through fewer corners.
+    pub fn should_reverse(&self) -> bool {
+        self.pushed_corners_reversed < self.pushed_corners
+    }
+}
+
+impl Display for ContourReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Contour")?;
+        if let Some(line) = self.line {
+            write!(f, " at input line {}", line + 1)?;
+        }
+        if self.reversed {
+            write!(
+                f,
+                " was reversed: {} sharp corners were cut without a swivel, now {}",
+                self.pushed_corners, self.pushed_corners_reversed
+            )?;
+        } else {
+            write!(
+                f,
+                " cuts {} sharp corners without a swivel",
+                self.pushed_corners
+            )?;
+            if self.should_reverse() {
+                write!(f, ", {} when reversed", self.pushed_corners_reversed)?;
+            }
+        }
+        if self.tight_arcs > 0 {
+            write!(
+                f,
+                "; {} arcs are tighter than the knife offset",
+                self.tight_arcs
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A run of cuts at a constant height, from where the knife is lowered to where it is lifted
+/// or another command is in between.
+#[derive(Debug, Clone)]
+pub struct Contour {
+    /// The moves, as indices into `DragknifePath::commands`.
+    pub commands: Range<usize>,
+    /// Zero-based input line of the first move.
+    pub line: Option<usize>,
+    /// Whether it ends where it starts.
+    pub closed: bool,
+    /// The moves as lines and arcs, see [`DragknifePath::segments`].
+    pub segments: Vec<Segment>,
+}
+
+impl<'a> DragknifePath<'a> {
+    /// The runs of cuts of the path, open and closed, in order.
+    pub fn contours(&self) -> Vec<Contour> {
+        let mut segments = self.segments().peekable();
+        cut_runs(self)
+            .into_iter()
+            .map(|(commands, _, closed)| {
+                while segments.next_if(|s| s.command < commands.start).is_some() {}
+                let mut contour = Contour {
+                    line: source_line(self.commands[commands.start].original().span()),
+                    commands,
+                    closed,
+                    segments: vec![],
+                };
+                while let Some(segment) =
+                    segments.next_if(|s| contour.commands.contains(&s.command))
+                {
+                    contour.segments.push(segment.segment);
+                }
+                contour
+            })
+            .collect()
+    }
+
+    /// Finds the closed contours which push the knife through sharp corners or tight arcs.
+    pub fn analyze_contours(&self, config: &DragknifeConfig) -> Vec<ContourReport> {
+        closed_contours(self)
+            .into_iter()
+            .map(|(commands, settings)| self.analyze_contour(commands, &settings, config))
+            .filter(|report| report.pushed_corners > 0 || report.tight_arcs > 0)
+            .collect()
+    }
+
+    /// Reverses the contours which push the knife through fewer corners when cut the other
+    /// way, and reports all contours with pushed features.
+    ///
+    /// Contours in inverse time feed mode are only reported, as every move has its own feedrate.
+    pub fn reverse_pushed_contours(&mut self, config: &DragknifeConfig) -> Vec<ContourReport> {
+        let mut reports = vec![];
+        for (commands, settings) in closed_contours(self) {
+            let mut report = self.analyze_contour(commands, &settings, config);
+            if report.should_reverse() && settings.feed_mode == FeedMode::UnitsPerMinute {
+                self.reverse_contour(report.commands.clone(), &settings);
+                report.reversed = true;
+            }
+            if report.pushed_corners > 0 || report.tight_arcs > 0 {
+                reports.push(report);
+            }
+        }
+        reports
+    }
+
+    fn analyze_contour(
+        &self,
+        commands: Range<usize>,
+        settings: &GCodeState,
+        config: &DragknifeConfig,
+    ) -> ContourReport {
+        let moves = &self.commands[commands.clone()];
+        // Reversing a contour turns every left turn into a right turn
+        let pushed = |signed_angle: f64| {
+            let threshold = config.angle_threshold(signed_angle);
+            let lowest = threshold.min(config.angle_threshold(-signed_angle));
+            signed_angle.abs() > lowest && signed_angle.abs() <= threshold
+        };
+        let corners: Vec<f64> = moves
+            .windows(2)
+            .filter_map(|pair| Some(signed_angle(pair[0].end_angle()?, pair[1].start_angle()?)))
+            .collect();
+        let tight_arcs = moves
+            .iter()
+            .filter(|command| match command {
+                Command::Arc(arc) => {
+                    (arc.start - arc.center)
+                        .project_plane(&settings.plane)
+                        .magnitude()
+                        < config.knife_offset
+                }
+                _ => false,
+            })
+            .count();
+        ContourReport {
+            line: source_line(moves[0].original().span()),
+            commands,
+            pushed_corners: corners.iter().filter(|&&angle| pushed(angle)).count(),
+            pushed_corners_reversed: corners.iter().filter(|&&angle| pushed(-angle)).count(),
+            tight_arcs,
+            reversed: false,
+        }
+    }
+
+    /// Cuts the moves of a closed contour in the opposite direction.
+    ///
+    /// The input commands stay in their places, so feedrates are set at the same points of the
+    /// program as before.
+    fn reverse_contour(&mut self, commands: Range<usize>, settings: &GCodeState) {
+        let reversed: Vec<Command<'a>> = self.commands[commands.clone()]
+            .iter()
+            .rev()
+            .zip(&self.commands[commands.clone()])
+            .map(|(command, original)| match command {
+                Command::Linear(linear) => Command::Linear(LinearMovement {
+                    original: original.original_cow().clone(),
+                    start: linear.end,
+                    end: linear.start,
+                    angle: linear.angle.map(|angle| (angle + PI).rem_euclid(TAU)),
+                }),
+                Command::Arc(arc) => Command::Arc(ArcMovement {
+                    original: original.original_cow().clone(),
+                    direction: match arc.direction {
+                        ArcDirection::CW => ArcDirection::CCW,
+                        ArcDirection::CCW => ArcDirection::CW,
+                    },
+                    start: arc.end,
+                    end: arc.start,
+                    center: arc.center,
+                    start_angle: (arc.end_angle + PI).rem_euclid(TAU),
+                    end_angle: (arc.start_angle + PI).rem_euclid(TAU),
+                }),
+                _ => unreachable!("contours only contain linear moves and arcs"),
+            })
+            .collect();
+        let end_angle = reversed.last().and_then(|command| command.end_angle());
+        self.commands.splice(commands.clone(), reversed);
+        // Commands after the contour which keep the direction of the knife need the new one
+        for command in &mut self.commands[commands.end..] {
+            match command {
+                Command::Other(other) => other.angle = end_angle,
+                Command::Linear(linear)
+                    if (linear.end - linear.start)
+                        .project_plane(&settings.plane)
+                        .magnitude()
+                        <= MIN_LENGTH =>
+                {
+                    linear.angle = end_angle
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
+/// Runs of cutting moves at a constant height which end where they start, with the state at
+/// their start.
+fn closed_contours(path: &DragknifePath) -> Vec<(Range<usize>, GCodeState)> {
+    cut_runs(path)
+        .into_iter()
+        .filter(|(_, _, closed)| *closed)
+        .map(|(commands, settings, _)| (commands, settings))
+        .collect()
+}
+
+/// Runs of cutting moves at a constant height, with the state at their start and whether they
+/// end where they start.
+pub(crate) fn cut_runs(path: &DragknifePath) -> Vec<(Range<usize>, GCodeState, bool)> {
+    let mut runs = vec![];
+    let mut settings = path.initial_state;
+    let mut start: Option<(usize, GCodeState)> = None;
+    let is_closed = |range: &Range<usize>, settings: &GCodeState| {
+        range.len() >= 2
+            && (path.commands[range.end - 1].end_pos() - path.commands[range.start].start_pos())
+                .project_plane(&settings.plane)
+                .magnitude()
+                <= MIN_LENGTH
+    };
+    for (i, command) in path.commands.iter().enumerate() {
+        let cutting = match command {
+            Command::Linear(_) | Command::Arc(_) => {
+                let delta = command.end_pos() - command.start_pos();
+                delta.project_plane(&settings.plane).magnitude() > MIN_LENGTH
+                    && delta.third_coord(&settings.plane).abs() <= MIN_LENGTH
+            }
+            _ => false,
+        };
+        if !cutting {
+            if let Some((first, state)) = start.take() {
+                let closed = is_closed(&(first..i), &state);
+                runs.push((first..i, state, closed));
+            }
+        } else if start.is_none() {
+            start = Some((i, settings));
+        }
+        command.update_settings(&mut settings);
+    }
+    if let Some((first, state)) = start {
+        let range = first..path.commands.len();
+        let closed = is_closed(&range, &state);
+        runs.push((range, state, closed));
+    }
+    runs
+}
@@ -0,0 +1,165 @@
+use std::{
+    f64::consts::{PI, TAU},
+    fmt::Display,
+};
+
+use crate::{
+    output::source_line,
+    signed_angle,
+    types::{ArcDirection, Command, DragknifeConfig, GCodePlane, Movement},
+    vec3::Vec3,
+    DragknifePath,
+};
+
+/// Moves shorter than this do not change the direction of the knife.
+const MIN_LENGTH: f64 = 0.00001;
+
+/// Earlier arcs are checked as lines, one for every this many radians of the arc.
+const ARC_STEP: f64 = PI / 36.;
+
+/// A swivel which sweeps the knife across a cut made before it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwivelCrossing {
+    /// The move after the corner, as an index into `DragknifePath::commands`.
+    pub command: usize,
+    /// Zero-based input line of the move after the corner, if it is in the input.
+    pub line: Option<usize>,
+    /// Zero-based input line of the cut which is crossed.
+    pub crossed_line: Option<usize>,
+    /// The angle of the corner in radians.
+    pub angle: f64,
+    /// Whether the knife is lifted to `DragknifeConfig::crossing_lift` for this swivel.
+    pub lifted: bool,
+}
+
+impl Display for SwivelCrossing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The swivel at a {:.0}° corner",
+            self.angle.abs().to_degrees()
+        )?;
+        if let Some(line) = self.line {
+            write!(f, " before input line {}", line + 1)?;
+        }
+        write!(f, " crosses an earlier cut")?;
+        if let Some(line) = self.crossed_line {
+            write!(f, " from input line {}", line + 1)?;
+        }
+        if self.lifted {
+            write!(f, "; lifted higher")?;
+        }
+        Ok(())
+    }
+}
+
+/// A straight piece of a cut, in the plane of the cut.
+struct Segment {
+    start: (f64, f64),
+    end: (f64, f64),
+    line: Option<usize>,
+}
+
+impl<'a> DragknifePath<'a> {
+    /// Finds the swivels which sweep the knife across a cut made earlier in the program.
+    pub fn find_swivel_crossings(&self, config: &DragknifeConfig) -> Vec<SwivelCrossing> {
+        let mut crossings = vec![];
+        let mut segments: Vec<Segment> = vec![];
+        let mut settings = self.initial_state;
+        let mut prev_angle = None;
+        for (i, command) in self.commands.iter().enumerate() {
+            if let (Command::Linear(_) | Command::Arc(_), Some(from), Some(to)) =
+                (command, prev_angle, command.start_angle())
+            {
+                let signed_angle = signed_angle(from, to);
+                if signed_angle.abs() > config.angle_threshold(signed_angle)
+                    && !config.is_reversal(signed_angle)
+                {
+                    let corner = command.start_pos().coords_for_plane(&settings.plane);
+                    // The cut just made ends at the corner
+                    let earlier = &segments[..segments.len().saturating_sub(1)];
+                    if let Some(crossed) = earlier.iter().find(|segment| {
+                        sweeps_across(segment, corner, config.knife_offset, from, signed_angle)
+                    }) {
+                        crossings.push(SwivelCrossing {
+                            command: i,
+                            line: source_line(command.original().span()),
+                            crossed_line: crossed.line,
+                            angle: signed_angle,
+                            lifted: config.crossing_lift.is_some(),
+                        });
+                    }
+                }
+            }
+            segments.extend(cut_segments(command, &settings.plane));
+            prev_angle = command.end_angle();
+            command.update_settings(&mut settings);
+        }
+        crossings
+    }
+}
+
+/// The cut made by `command` as straight segments, with arcs split into short pieces.
+fn cut_segments(command: &Command, plane: &GCodePlane) -> Vec<Segment> {
+    let line = source_line(command.original().span());
+    let (start, end) = (command.start_pos(), command.end_pos());
+    if (end - start).project_plane(plane).magnitude() <= MIN_LENGTH {
+        return vec![];
+    }
+    let points = match command {
+        Command::Linear(_) => vec![start, end],
+        Command::Arc(arc) => {
+            let radius = (start - arc.center).project_plane(plane).magnitude();
+            let start_angle = arc.center.angle_to(&start, plane);
+            let end_angle = arc.center.angle_to(&end, plane);
+            let sweep = match arc.direction {
+                ArcDirection::CW => -(start_angle - end_angle).rem_euclid(TAU),
+                ArcDirection::CCW => (end_angle - start_angle).rem_euclid(TAU),
+            };
+            let steps = (sweep.abs() / ARC_STEP).ceil().max(1.) as usize;
+            (0..=steps)
+                .map(|step| {
+                    let angle = start_angle + sweep * step as f64 / steps as f64;
+                    arc.center + Vec3::unit_angle(angle, plane) * radius
+                })
+                .collect()
+        }
+        _ => return vec![],
+    };
+    points
+        .windows(2)
+        .map(|pair| Segment {
+            start: pair[0].coords_for_plane(plane),
+            end: pair[1].coords_for_plane(plane),
+            line,
+        })
+        .collect()
+}
+
+/// Whether the knife, swiveling around `corner` at distance `radius` from the direction `from`
+/// through the signed angle `turn`, crosses `segment`.
+fn sweeps_across(segment: &Segment, corner: (f64, f64), radius: f64, from: f64, turn: f64) -> bool {
+    let (d1, d2) = (
+        segment.end.0 - segment.start.0,
+        segment.end.1 - segment.start.1,
+    );
+    let (f1, f2) = (segment.start.0 - corner.0, segment.start.1 - corner.1);
+    // Where the segment meets the circle, from |f + s * d| = radius
+    let a = d1 * d1 + d2 * d2;
+    let b = 2. * (f1 * d1 + f2 * d2);
+    let c = f1 * f1 + f2 * f2 - radius * radius;
+    let discriminant = b * b - 4. * a * c;
+    if a <= 0. || discriminant < 0. {
+        return false;
+    }
+    [-1., 1.]
+        .map(|sign| (-b + sign * discriminant.sqrt()) / (2. * a))
+        .into_iter()
+        .filter(|s| (0. ..=1.).contains(s))
+        .any(|s| {
+            let angle = (f2 + s * d2).atan2(f1 + s * d1);
+            // Positive corners turn clockwise, towards smaller angles
+            let swept = signed_angle(from, angle) / turn;
+            swept > 0. && swept < 1.
+        })
+}
@@ -0,0 +1,426 @@
+use std::{error::Error, fmt::Display, ops::RangeInclusive, path::Path, str::FromStr};
+
+use gcode::{GCode, Mnemonic, Span};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{config_file::ConfigFileError, output::mnemonic_letter};
+
+/// The controller a program is written for: which commands and words it accepts, how words are
+/// written, how arcs are written and what every program has to start with. Applied by
+/// [`OutputConfig::apply_dialect`](crate::output::OutputConfig::apply_dialect) and when the
+/// program text is written.
+///
+/// LinuxCNC, Mach3/4, GRBL and Marlin are built in, see [`Dialect::builtin`]. Other controllers
+/// can be described in a config file, in the `[output.dialect]` section or a file of its own,
+/// with only what differs from LinuxCNC, e.g.
+///
+/// ```toml
+/// name = "Plotter"
+/// codes = ["G0-3", "G4", "G21", "G90-91", "M3-5"]
+/// words = "FIJPSXYZ"
+/// spaces = false
+/// max_decimals = 3
+/// preamble = "G21 G90"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Dialect {
+    pub name: String,
+    /// The commands the controller accepts, e.g. `G0-4`, `G38.2-38.5` or `T`. Empty for all.
+    pub codes: Vec<CodeRange>,
+    /// The letters of the words the controller accepts. Empty for all.
+    pub words: String,
+    /// The word `G4` takes its time in seconds with.
+    pub dwell_word: char,
+    pub arc_style: ArcStyle,
+    /// Write a space between the words of a command. Without them lines are shorter, which
+    /// controllers with a small line buffer like GRBL read faster.
+    pub spaces: bool,
+    /// The most decimals any number is written with, for controllers which reject longer
+    /// numbers.
+    pub max_decimals: Option<usize>,
+    /// Lines written at the start of every program, before the header.
+    pub preamble: String,
+    /// `$` system commands are only taken while the machine stands still, so a `G4 P0` has to
+    /// wait for the moves before them to finish.
+    pub sync_system_commands: bool,
+}
+
+/// How a dialect writes arcs, on top of the arc settings of the output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArcStyle {
+    /// With their center, relative or absolute as set in the output settings.
+    #[default]
+    Center,
+    /// With a radius (`R`), like `OutputConfig::radius_arcs`.
+    Radius,
+    /// As lines, like `OutputConfig::arcs_as_lines`. Also used if the dialect has no `G2`.
+    Lines,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect::linuxcnc()
+    }
+}
+
+impl Dialect {
+    /// Writes everything.
+    pub fn linuxcnc() -> Self {
+        Dialect {
+            name: "LinuxCNC".to_string(),
+            codes: vec![],
+            words: String::new(),
+            dwell_word: 'P',
+            arc_style: ArcStyle::Center,
+            spaces: true,
+            max_decimals: None,
+            preamble: String::new(),
+            sync_system_commands: false,
+        }
+    }
+
+    /// Mach3 and Mach4. Whether arc centers are relative is a setting of the controller, so
+    /// every program sets it with `G91.1`.
+    pub fn mach() -> Self {
+        Dialect {
+            name: "Mach3/4".to_string(),
+            codes: code_ranges(&[
+                "G0-4",
+                "G10",
+                "G12-13",
+                "G15-21",
+                "G28-28.1",
+                "G30-31",
+                "G40-44",
+                "G49-59",
+                "G61",
+                "G64",
+                "G68-69",
+                "G73",
+                "G80-89",
+                "G90-91",
+                "G90.1-91.1",
+                "G92-92.3",
+                "G93-95",
+                "G98-99",
+                "M0-11",
+                "M30",
+                "M47-49",
+                "M98-99",
+                "O",
+                "T",
+            ]),
+            preamble: "G91.1".to_string(),
+            ..Dialect::linuxcnc()
+        }
+    }
+
+    /// GRBL 1.1, e.g. on hobby CNC routers.
+    pub fn grbl() -> Self {
+        Dialect {
+            name: "GRBL".to_string(),
+            codes: code_ranges(&[
+                "G0-4",
+                "G10",
+                "G17-21",
+                "G28-28.1",
+                "G30-30.1",
+                "G38.2-38.5",
+                "G40",
+                "G43.1",
+                "G49",
+                "G53-59",
+                "G61",
+                "G80",
+                "G90-91",
+                "G91.1",
+                "G92-92.1",
+                "G93-94",
+                "M0-5",
+                "M7-9",
+                "M30",
+                "M56",
+                "T",
+            ]),
+            words: "FIJKLNPRSTXYZ".to_string(),
+            sync_system_commands: true,
+            ..Dialect::linuxcnc()
+        }
+    }
+
+    /// Marlin, on 3D printers converted to cut with a drag knife. It has no inches or inverse
+    /// time feedrates, so those are converted to mm and mm/min, and uses `P` of `G4` for
+    /// milliseconds. Arcs need `ARC_SUPPORT`, or can be written as lines with
+    /// `OutputConfig::arcs_as_lines`.
+    pub fn marlin() -> Self {
+        Dialect {
+            name: "Marlin".to_string(),
+            // M30 deletes a file from the SD card and G10 retracts the filament
+            codes: code_ranges(&[
+                "G0-4", "G17-19", "G28", "G53-59", "G90-92", "M0-1", "M3-5", "M7-9", "M17-18",
+                "M84", "M106-107", "M280", "M400",
+            ]),
+            words: "EFIJKNPRSTXYZ".to_string(),
+            dwell_word: 'S',
+            ..Dialect::linuxcnc()
+        }
+    }
+
+    pub fn builtin() -> [Dialect; 4] {
+        [
+            Dialect::linuxcnc(),
+            Dialect::mach(),
+            Dialect::grbl(),
+            Dialect::marlin(),
+        ]
+    }
+
+    /// The built-in dialect called `name`, ignoring case. `Generic` is LinuxCNC, as it was
+    /// called before, and `Mach3` and `Mach4` are both Mach3/4.
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "generic" => Some(Dialect::linuxcnc()),
+            "mach" | "mach3" | "mach4" => Some(Dialect::mach()),
+            _ => Self::builtin()
+                .into_iter()
+                .find(|dialect| dialect.name.eq_ignore_ascii_case(name)),
+        }
+    }
+
+    /// Reads a dialect from a TOML file, or JSON if its name ends in `.json`. Settings which are
+    /// not given are those of LinuxCNC.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigFileError> {
+        let text = std::fs::read_to_string(&path)?;
+        let is_json = path
+            .as_ref()
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        if is_json {
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            Ok(toml::from_str(&text)?)
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether this is one of the built-in dialects, unchanged.
+    pub fn is_builtin(&self) -> bool {
+        Self::builtin().contains(self)
+    }
+
+    /// Whether the controller accepts a command, e.g. `G64` or `M6`.
+    pub fn supports_code(&self, gcode: &GCode) -> bool {
+        self.codes.is_empty() || self.codes.iter().any(|range| range.matches(gcode))
+    }
+
+    /// Whether the controller accepts the `G` command `number`, e.g. `90.1`.
+    pub fn supports_general(&self, number: f32) -> bool {
+        self.supports_code(&GCode::new(Mnemonic::General, number, Span::PLACEHOLDER))
+    }
+
+    /// Whether the controller accepts a word, e.g. `A` or `Q`.
+    pub fn supports_word(&self, letter: char) -> bool {
+        self.words.is_empty() || self.words.contains(letter.to_ascii_uppercase())
+    }
+
+    /// Whether commands or words have to be left out or changed for the controller.
+    pub fn changes_commands(&self) -> bool {
+        !self.codes.is_empty() || !self.words.is_empty() || self.dwell_word != 'P'
+    }
+
+    /// Whether inches or inverse time feedrates have to be converted, as the controller has no
+    /// `G20` or `G93`.
+    pub fn converts_units(&self) -> bool {
+        !self.supports_general(20.) || !self.supports_general(93.)
+    }
+
+    /// Whether numbers or commands are written differently than by LinuxCNC, so text copied
+    /// from the input may not fit.
+    pub fn formats_words(&self) -> bool {
+        !self.spaces || self.max_decimals.is_some()
+    }
+}
+
+/// Commands with a letter and a range of numbers, e.g. `G0-4` for `G0` to `G4`. The minor
+/// numbers are a range of their own, so `G90-91` does not include `G90.1`, and `G38.2-38.5`
+/// is `G38.2` to `G38.5`. A letter alone, like `T`, is all its commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeRange {
+    pub letter: char,
+    pub major: RangeInclusive<u32>,
+    pub minor: RangeInclusive<u32>,
+}
+
+impl CodeRange {
+    pub fn matches(&self, gcode: &GCode) -> bool {
+        self.letter == mnemonic_letter(gcode.mnemonic())
+            && self.major.contains(&gcode.major_number())
+            && self.minor.contains(&gcode.minor_number())
+    }
+
+    /// Parses a comma or whitespace separated list such as `G64, M62-65`.
+    pub fn parse_list(text: &str) -> Result<Vec<CodeRange>, CodeRangeParseError> {
+        text.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|part| !part.is_empty())
+            .map(CodeRange::from_str)
+            .collect()
+    }
+
+    pub fn format_list(ranges: &[CodeRange]) -> String {
+        ranges
+            .iter()
+            .map(|range| range.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn code_ranges(ranges: &[&str]) -> Vec<CodeRange> {
+    ranges
+        .iter()
+        .map(|range| range.parse().expect("built-in code ranges are valid"))
+        .collect()
+}
+
+impl FromStr for CodeRange {
+    type Err = CodeRangeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || CodeRangeParseError(s.to_string());
+        let s = s.trim();
+        let mut chars = s.chars();
+        let letter = chars
+            .next()
+            .map(|c| c.to_ascii_uppercase())
+            .filter(|c| "GMOT".contains(*c))
+            .ok_or_else(err)?;
+        let numbers = chars.as_str();
+        if numbers.is_empty() {
+            return Ok(CodeRange {
+                letter,
+                major: 0..=u32::MAX,
+                minor: 0..=u32::MAX,
+            });
+        }
+        let number = |text: &str| -> Option<(u32, u32)> {
+            match text.split_once('.') {
+                Some((major, minor)) => Some((major.parse().ok()?, minor.parse().ok()?)),
+                None => Some((text.parse().ok()?, 0)),
+            }
+        };
+        let (start, end) = match numbers.split_once('-') {
+            Some((start, end)) => (number(start), number(end)),
+            None => (number(numbers), number(numbers)),
+        };
+        match (start, end) {
+            (Some(start), Some(end)) if start.0 <= end.0 && start.1 <= end.1 => Ok(CodeRange {
+                letter,
+                major: start.0..=end.0,
+                minor: start.1..=end.1,
+            }),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl Display for CodeRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.letter)?;
+        if *self.major.end() == u32::MAX {
+            return Ok(());
+        }
+        let number = |f: &mut std::fmt::Formatter<'_>, major, minor| {
+            write!(f, "{major}")?;
+            if minor != 0 {
+                write!(f, ".{minor}")?;
+            }
+            Ok(())
+        };
+        number(f, self.major.start(), *self.minor.start())?;
+        if self.major.start() != self.major.end() || self.minor.start() != self.minor.end() {
+            write!(f, "-")?;
+            number(f, self.major.end(), *self.minor.end())?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for CodeRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for CodeRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug)]
+pub struct CodeRangeParseError(pub String);
+
+impl Display for CodeRangeParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid code range \"{}\": expected a command like G28.1, a range like G0-4 or a \
+            letter like T.",
+            self.0
+        )
+    }
+}
+
+impl Error for CodeRangeParseError {}
+
+/// Writes a built-in dialect by its name and others with all their settings.
+pub(crate) fn serialize_dialect<S: Serializer>(
+    dialect: &Dialect,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    if dialect.is_builtin() {
+        serializer.serialize_str(dialect.name())
+    } else {
+        dialect.serialize(serializer)
+    }
+}
+
+/// Reads a dialect written by [`serialize_dialect`]: the name of a built-in one, or all the
+/// settings of another.
+pub(crate) fn deserialize_dialect<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Dialect, D::Error> {
+    struct DialectVisitor;
+
+    impl<'de> de::Visitor<'de> for DialectVisitor {
+        type Value = Dialect;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(
+                f,
+                "the name of a built-in dialect or the settings of a dialect"
+            )
+        }
+
+        fn visit_str<E: de::Error>(self, name: &str) -> Result<Dialect, E> {
+            Dialect::by_name(name).ok_or_else(|| E::custom(format!("unknown dialect {name}")))
+        }
+
+        /// RON reads the dialects saved by earlier versions, when they were an enum, without
+        /// their name, so they are read as the default instead of losing all the settings.
+        fn visit_unit<E: de::Error>(self) -> Result<Dialect, E> {
+            Ok(Dialect::default())
+        }
+
+        fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<Dialect, A::Error> {
+            Dialect::deserialize(de::value::MapAccessDeserializer::new(map))
+        }
+    }
+
+    deserializer.deserialize_any(DialectVisitor)
+}
@@ -0,0 +1,85 @@
+use crate::output::LineOrigin;
+
+/// How a row of [`diff_lines`] differs between the input and the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffKind {
+    Unchanged,
+    /// The output line was written for the input line, but reads differently.
+    Changed,
+    /// The output line is not in the input, e.g. a swivel or the header.
+    Inserted,
+    /// The input line is not in the output, e.g. a removed `M3`.
+    Removed,
+}
+
+/// A row of the input and output side by side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiffRow {
+    /// Zero-based line of the input.
+    pub input: Option<usize>,
+    /// Zero-based line of the output.
+    pub output: Option<usize>,
+    pub kind: DiffKind,
+}
+
+/// Lines up the input with the output written for it, using the `origins` of the output lines
+/// (see [`ProgramOutput::origins`](crate::output::ProgramOutput)). Every input line is shown
+/// once, in order. Output lines for an input line which was already passed, like the lines of a
+/// subprogram which is called again, are shown as changes on their own.
+pub fn diff_lines(input: &str, output: &str, origins: &[LineOrigin]) -> Vec<DiffRow> {
+    let input: Vec<&str> = input.lines().collect();
+    let mut rows = vec![];
+    let mut next_input = 0;
+    let removed_until = |rows: &mut Vec<DiffRow>, next_input: &mut usize, line: usize| {
+        for removed in *next_input..line.min(input.len()) {
+            rows.push(DiffRow {
+                input: Some(removed),
+                output: None,
+                kind: DiffKind::Removed,
+            });
+        }
+        *next_input = line.max(*next_input);
+    };
+    for (i, (text, origin)) in output.lines().zip(origins).enumerate() {
+        let Some(line) = origin.input_line else {
+            rows.push(DiffRow {
+                input: None,
+                output: Some(i),
+                kind: DiffKind::Inserted,
+            });
+            continue;
+        };
+        removed_until(&mut rows, &mut next_input, line);
+        let row = if origin.inserted {
+            DiffRow {
+                input: None,
+                output: Some(i),
+                kind: DiffKind::Inserted,
+            }
+        } else if line == next_input {
+            next_input += 1;
+            let unchanged = input
+                .get(line)
+                .is_some_and(|input| input.trim() == text.trim());
+            DiffRow {
+                input: Some(line),
+                output: Some(i),
+                kind: if unchanged {
+                    DiffKind::Unchanged
+                } else {
+                    DiffKind::Changed
+                },
+            }
+        } else {
+            // Another line for an input line which is already shown
+            DiffRow {
+                input: None,
+                output: Some(i),
+                kind: DiffKind::Changed,
+            }
+        };
+        rows.push(row);
+    }
+    removed_until(&mut rows, &mut next_input, input.len());
+    rows
+}
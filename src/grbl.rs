@@ -0,0 +1,410 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The size of GRBL's serial receive buffer. Lines are sent ahead as long as the lines which
+/// were not answered yet fit in it (the character counting protocol).
+const RX_BUFFER_SIZE: usize = 128;
+
+/// How often the machine is asked for its status.
+const STATUS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// GRBL's realtime commands, which are acted on right away instead of being buffered.
+const STATUS_REPORT: u8 = b'?';
+const FEED_HOLD: u8 = b'!';
+const CYCLE_START: u8 = b'~';
+const SOFT_RESET: u8 = 0x18;
+
+/// The state and position of the machine, from a GRBL status report like
+/// `<Idle|MPos:0.000,0.000,0.000|FS:0,0>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineStatus {
+    /// E.g. `Idle`, `Run`, `Hold:0` or `Alarm`.
+    pub state: String,
+    /// The position in mm, in machine coordinates if `work_position` is false.
+    pub position: Option<[f64; 3]>,
+    pub work_position: bool,
+}
+
+impl MachineStatus {
+    /// Reads a status report of GRBL 1.1, or of 0.9 (`<Idle,MPos:0.000,0.000,0.000,...>`).
+    pub fn parse(report: &str) -> Option<Self> {
+        let report = report.trim().strip_prefix('<')?.strip_suffix('>')?;
+        let state = report.split(['|', ',']).next()?.to_string();
+        let (position, work_position) = match (report.find("MPos:"), report.find("WPos:")) {
+            (Some(start), _) => (parse_position(&report[start + 5..]), false),
+            (None, Some(start)) => (parse_position(&report[start + 5..]), true),
+            (None, None) => (None, false),
+        };
+        Some(MachineStatus {
+            state,
+            position,
+            work_position,
+        })
+    }
+}
+
+/// The first three numbers of `x,y,z...`.
+fn parse_position(text: &str) -> Option<[f64; 3]> {
+    let mut numbers = text.split([',', '|']).map(|n| n.parse().ok());
+    Some([numbers.next()??, numbers.next()??, numbers.next()??])
+}
+
+/// What happened on the connection, see [`GrblConnection::events`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum GrblEvent {
+    Status(MachineStatus),
+    /// `acknowledged` of the `total` lines of the program were answered.
+    Progress {
+        acknowledged: usize,
+        total: usize,
+    },
+    /// GRBL answered a line with an error, e.g. `error:20`.
+    Error {
+        /// Zero-based line of the program, or `None` for a single command.
+        line: Option<usize>,
+        message: String,
+    },
+    /// GRBL stopped in an alarm state, e.g. `ALARM:1` for a hard limit. Streaming stops too.
+    Alarm(String),
+    /// Anything else GRBL wrote, like its welcome message or `[MSG:...]` feedback.
+    Message(String),
+    /// All lines of the program were answered.
+    Done,
+    /// The port could not be read or written. The connection is closed.
+    Disconnected(String),
+}
+
+enum Request {
+    Program(Vec<(usize, String)>),
+    Command(String),
+    Realtime(u8),
+}
+
+/// A serial connection to a GRBL controller, run on a thread of its own.
+pub struct GrblConnection {
+    requests: mpsc::Sender<Request>,
+    events: mpsc::Receiver<GrblEvent>,
+}
+
+impl GrblConnection {
+    /// Opens the serial port at `path` (e.g. `/dev/ttyUSB0` or `\\.\COM3`) at `baud_rate`.
+    /// `notify` is called whenever there are new events.
+    ///
+    /// The port is set up for GRBL on Unix. On other systems it has to be set up beforehand,
+    /// e.g. with `mode COM3 BAUD=115200 DATA=8 PARITY=N STOP=1`.
+    pub fn open(
+        path: impl AsRef<Path>,
+        baud_rate: u32,
+        notify: impl Fn() + Send + 'static,
+    ) -> io::Result<Self> {
+        let port = OpenOptions::new().read(true).write(true).open(path)?;
+        configure_port(&port, baud_rate)?;
+        Ok(Self::from_port(port.try_clone()?, port, notify))
+    }
+
+    /// Talks to GRBL through `reader` and `writer`, like [`GrblConnection::open`].
+    pub fn from_port(
+        reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+        notify: impl Fn() + Send + 'static,
+    ) -> Self {
+        let (requests, request_receiver) = mpsc::channel();
+        let (event_sender, events) = mpsc::channel();
+        let (response_sender, responses) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let reader_closed = closed.clone();
+        thread::spawn(move || read_responses(reader, response_sender, &reader_closed));
+        thread::spawn(move || {
+            let mut streamer = Streamer {
+                writer,
+                events: event_sender,
+                notify,
+                in_flight: VecDeque::new(),
+                program: VecDeque::new(),
+                acknowledged: 0,
+                total: 0,
+            };
+            if let Err(err) = streamer.run(&request_receiver, &responses) {
+                streamer.emit(GrblEvent::Disconnected(err.to_string()));
+            }
+            closed.store(true, Ordering::Relaxed);
+        });
+        GrblConnection { requests, events }
+    }
+
+    /// Streams the lines of `program`, leaving out comments and empty lines. Lines of a
+    /// program which is still being sent are dropped.
+    pub fn send_program(&self, program: &str) {
+        let lines = program
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i, strip_comments(line)))
+            .filter(|(_, line)| !line.is_empty() && line != "%")
+            .collect();
+        let _ = self.requests.send(Request::Program(lines));
+    }
+
+    /// Sends a single line, e.g. `$X` to unlock or `$H` to home.
+    pub fn send_command(&self, command: &str) {
+        let _ = self
+            .requests
+            .send(Request::Command(command.trim().to_string()));
+    }
+
+    /// Pauses the motion, which [`GrblConnection::resume`] continues.
+    pub fn feed_hold(&self) {
+        let _ = self.requests.send(Request::Realtime(FEED_HOLD));
+    }
+
+    pub fn resume(&self) {
+        let _ = self.requests.send(Request::Realtime(CYCLE_START));
+    }
+
+    /// Stops the machine right away and drops the rest of the program. GRBL may need to be
+    /// unlocked (`$X`) afterwards.
+    pub fn reset(&self) {
+        let _ = self.requests.send(Request::Realtime(SOFT_RESET));
+    }
+
+    /// The events since the last call.
+    pub fn events(&self) -> impl Iterator<Item = GrblEvent> + '_ {
+        self.events.try_iter()
+    }
+}
+
+/// Removes `(comments)` and `; comments` and the whitespace around the line, so more of it fits
+/// in GRBL's buffer.
+fn strip_comments(line: &str) -> String {
+    let mut stripped = String::new();
+    let mut in_comment = false;
+    for c in line.chars() {
+        match c {
+            ';' if !in_comment => break,
+            '(' => in_comment = true,
+            ')' if in_comment => in_comment = false,
+            c if !in_comment => stripped.push(c),
+            _ => {}
+        }
+    }
+    stripped.trim().to_string()
+}
+
+/// Sends the lines GRBL writes to `responses`, until `closed` is set or the port fails.
+fn read_responses(
+    reader: impl Read,
+    responses: mpsc::Sender<io::Result<String>>,
+    closed: &AtomicBool,
+) {
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    while !closed.load(Ordering::Relaxed) {
+        match reader.read_line(&mut line) {
+            // Nothing arrived before the port timed out
+            Ok(0) => thread::sleep(Duration::from_millis(10)),
+            Ok(_) if !line.ends_with('\n') => {}
+            Ok(_) => {
+                let _ = responses.send(Ok(line.trim().to_string()));
+                line.clear();
+            }
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::TimedOut
+                        | io::ErrorKind::WouldBlock
+                        | io::ErrorKind::Interrupted
+                ) => {}
+            Err(err) => {
+                let _ = responses.send(Err(err));
+                return;
+            }
+        }
+    }
+}
+
+struct Streamer<W, N> {
+    writer: W,
+    events: mpsc::Sender<GrblEvent>,
+    notify: N,
+    /// The lines GRBL has not answered yet, with their program line and length in its buffer.
+    in_flight: VecDeque<(Option<usize>, usize)>,
+    /// The lines of the program still to send.
+    program: VecDeque<(usize, String)>,
+    acknowledged: usize,
+    total: usize,
+}
+
+impl<W: Write, N: Fn()> Streamer<W, N> {
+    fn run(
+        &mut self,
+        requests: &mpsc::Receiver<Request>,
+        responses: &mpsc::Receiver<io::Result<String>>,
+    ) -> io::Result<()> {
+        let mut last_status = Instant::now() - STATUS_INTERVAL;
+        loop {
+            loop {
+                match requests.try_recv() {
+                    Ok(request) => self.handle(request)?,
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    // The connection was dropped
+                    Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+            if last_status.elapsed() >= STATUS_INTERVAL {
+                self.writer.write_all(&[STATUS_REPORT])?;
+                self.writer.flush()?;
+                last_status = Instant::now();
+            }
+            self.fill_buffer()?;
+            match responses.recv_timeout(Duration::from_millis(20)) {
+                Ok(response) => self.answer(&response?),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Port closed"))
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, request: Request) -> io::Result<()> {
+        match request {
+            Request::Program(lines) => {
+                self.total = lines.len();
+                self.acknowledged = 0;
+                self.program = lines.into();
+                self.emit(self.progress());
+            }
+            Request::Command(command) => self.send_line(None, &command)?,
+            Request::Realtime(byte) => {
+                self.writer.write_all(&[byte])?;
+                self.writer.flush()?;
+                if byte == SOFT_RESET {
+                    // GRBL forgets its buffer and does not answer the lines in it
+                    self.in_flight.clear();
+                    self.program.clear();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the next lines of the program as long as they fit in GRBL's buffer.
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        while let Some((_, line)) = self.program.front() {
+            let buffered: usize = self.in_flight.iter().map(|(_, len)| len).sum();
+            // A line longer than the buffer is sent once the buffer is empty
+            if !self.in_flight.is_empty() && buffered + line.len() + 1 > RX_BUFFER_SIZE {
+                break;
+            }
+            let (i, line) = self.program.pop_front().unwrap();
+            self.send_line(Some(i), &line)?;
+        }
+        Ok(())
+    }
+
+    fn send_line(&mut self, program_line: Option<usize>, line: &str) -> io::Result<()> {
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        self.in_flight.push_back((program_line, line.len() + 1));
+        Ok(())
+    }
+
+    fn answer(&mut self, response: &str) {
+        if response.is_empty() {
+            return;
+        }
+        let answered = || self.in_flight.front().map(|(line, _)| *line);
+        if response == "ok" || response.starts_with("error") {
+            let line = answered().flatten();
+            self.in_flight.pop_front();
+            if response != "ok" {
+                self.emit(GrblEvent::Error {
+                    line,
+                    message: response.to_string(),
+                });
+            }
+            if line.is_some() {
+                self.acknowledged += 1;
+                self.emit(self.progress());
+                if self.acknowledged == self.total {
+                    self.emit(GrblEvent::Done);
+                }
+            }
+        } else if let Some(status) = MachineStatus::parse(response) {
+            self.emit(GrblEvent::Status(status));
+        } else if response.starts_with("ALARM") {
+            self.in_flight.clear();
+            self.program.clear();
+            self.emit(GrblEvent::Alarm(response.to_string()));
+        } else {
+            self.emit(GrblEvent::Message(response.to_string()));
+        }
+    }
+
+    fn progress(&self) -> GrblEvent {
+        GrblEvent::Progress {
+            acknowledged: self.acknowledged,
+            total: self.total,
+        }
+    }
+
+    fn emit(&self, event: GrblEvent) {
+        let _ = self.events.send(event);
+        (self.notify)();
+    }
+}
+
+/// Sets the port to `baud_rate`, 8 data bits, no parity and one stop bit, without any
+/// translation of the bytes. Reads time out after a tenth of a second.
+#[cfg(unix)]
+fn configure_port(port: &File, baud_rate: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let speed = match baud_rate {
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported baud rate {baud_rate}"),
+            ))
+        }
+    };
+    let fd = port.as_raw_fd();
+    // Safety: the termios struct is filled in by tcgetattr before it is used
+    unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        termios.c_cflag &= !(libc::CSTOPB | libc::CRTSCTS);
+        termios.c_cc[libc::VMIN] = 0;
+        termios.c_cc[libc::VTIME] = 1;
+        if libc::cfsetspeed(&mut termios, speed) != 0
+            || libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn configure_port(_port: &File, _baud_rate: u32) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,233 @@
+use std::{error::Error, fmt::Display, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+/// Z offsets measured over the bed, e.g. by probing a grid, for a table which is not flat. The
+/// output follows the surface they describe, see [`crate::output::OutputConfig::apply_height_map`].
+///
+/// In a TOML config file:
+///
+/// ```toml
+/// [output.height_map]
+/// x = [0, 100, 200]
+/// y = [0, 150]
+/// z = [[0, 0.1, 0.15], [-0.05, 0.02, 0.1]]
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeightMap {
+    /// The X of every column, ascending, in mm.
+    pub x: Vec<f32>,
+    /// The Y of every row, ascending, in mm.
+    pub y: Vec<f32>,
+    /// The offsets in mm, row by row: `z[row][column]` is measured at `x[column]`, `y[row]`.
+    pub z: Vec<Vec<f32>>,
+}
+
+impl HeightMap {
+    /// Reads a height map from a CSV file with a point `x,y,z` on every line, or from JSON if
+    /// its name ends in `.json`, either as a list of `[x, y, z]` points or with `x`, `y` and `z`
+    /// like the config file. The points have to form a grid.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, HeightMapError> {
+        let text = std::fs::read_to_string(&path)?;
+        let is_json = path
+            .as_ref()
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        if is_json {
+            Self::parse_json(&text)
+        } else {
+            Self::parse_csv(&text)
+        }
+    }
+
+    pub fn parse_json(text: &str) -> Result<Self, HeightMapError> {
+        let value: serde_json::Value = serde_json::from_str(text)?;
+        let map = if value.is_array() {
+            let points: Vec<[f32; 3]> = serde_json::from_value(value)?;
+            Self::from_points(&points)?
+        } else {
+            serde_json::from_value(value)?
+        };
+        map.check()?;
+        Ok(map)
+    }
+
+    /// Reads `x,y,z` lines. Blank lines, `#` comments and a header line are skipped, and the
+    /// values may also be separated by semicolons, tabs or spaces.
+    pub fn parse_csv(text: &str) -> Result<Self, HeightMapError> {
+        let mut points = vec![];
+        for (line, text) in text.lines().enumerate() {
+            let text = text.split('#').next().unwrap().trim();
+            if text.is_empty() {
+                continue;
+            }
+            let values: Vec<_> = text
+                .split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+                .filter(|value| !value.is_empty())
+                .map(str::parse::<f32>)
+                .collect();
+            match values[..] {
+                [Ok(x), Ok(y), Ok(z)] => points.push([x, y, z]),
+                _ if points.is_empty() && values.iter().all(Result::is_err) => {}
+                _ => {
+                    return Err(HeightMapError::Csv {
+                        line,
+                        text: text.to_string(),
+                    })
+                }
+            }
+        }
+        Self::from_points(&points)
+    }
+
+    /// Sorts points into a grid. Every X has to be measured at every Y.
+    pub fn from_points(points: &[[f32; 3]]) -> Result<Self, HeightMapError> {
+        let coordinates = |axis: usize| {
+            let mut values: Vec<f32> = points.iter().map(|point| point[axis]).collect();
+            values.sort_by(f32::total_cmp);
+            values.dedup_by(|a, b| (*a - *b).abs() < GRID_TOLERANCE);
+            values
+        };
+        let (x, y) = (coordinates(0), coordinates(1));
+        let mut z = vec![vec![None; x.len()]; y.len()];
+        let index = |values: &[f32], value: f32| {
+            values
+                .iter()
+                .position(|v| (v - value).abs() < GRID_TOLERANCE)
+                .unwrap()
+        };
+        for &[px, py, pz] in points {
+            z[index(&y, py)][index(&x, px)] = Some(pz);
+        }
+        let z = z
+            .into_iter()
+            .enumerate()
+            .map(|(row, values)| {
+                values
+                    .into_iter()
+                    .enumerate()
+                    .map(|(column, value)| {
+                        value.ok_or(HeightMapError::MissingPoint {
+                            x: x[column],
+                            y: y[row],
+                        })
+                    })
+                    .collect()
+            })
+            .collect::<Result<_, _>>()?;
+        let map = HeightMap { x, y, z };
+        map.check()?;
+        Ok(map)
+    }
+
+    /// Checks that there is a point, and an offset for every column of every row.
+    pub fn check(&self) -> Result<(), HeightMapError> {
+        let ascending = |values: &[f32]| values.windows(2).all(|pair| pair[0] < pair[1]);
+        if self.x.is_empty() || self.y.is_empty() {
+            return Err(HeightMapError::Empty);
+        }
+        if !ascending(&self.x)
+            || !ascending(&self.y)
+            || self.z.len() != self.y.len()
+            || self.z.iter().any(|row| row.len() != self.x.len())
+        {
+            return Err(HeightMapError::Shape);
+        }
+        Ok(())
+    }
+
+    /// The offset at `x`, `y` in mm, interpolated between the four points around it. Outside
+    /// the map, the offset at its nearest edge is used.
+    pub fn offset(&self, x: f32, y: f32) -> f32 {
+        let (column, tx) = cell(&self.x, x);
+        let (row, ty) = cell(&self.y, y);
+        let at = |row: usize, column: usize| {
+            let row = &self.z[row.min(self.y.len() - 1)];
+            row[column.min(self.x.len() - 1)]
+        };
+        let bottom = at(row, column) * (1. - tx) + at(row, column + 1) * tx;
+        let top = at(row + 1, column) * (1. - tx) + at(row + 1, column + 1) * tx;
+        bottom * (1. - ty) + top * ty
+    }
+
+    /// The smallest and largest offset.
+    pub fn range(&self) -> (f32, f32) {
+        self.z
+            .iter()
+            .flatten()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &z| {
+                (min.min(z), max.max(z))
+            })
+    }
+}
+
+/// How close coordinates of points have to be to be in the same row or column, in mm.
+const GRID_TOLERANCE: f32 = 1e-3;
+
+/// The index of the cell of `values` which `value` is in, and how far along it, from 0 to 1.
+fn cell(values: &[f32], value: f32) -> (usize, f32) {
+    let next = values.partition_point(|v| *v <= value);
+    if next == 0 {
+        return (0, 0.);
+    }
+    if next == values.len() {
+        return (values.len() - 1, 0.);
+    }
+    let (start, end) = (values[next - 1], values[next]);
+    (next - 1, (value - start) / (end - start))
+}
+
+#[derive(Debug)]
+pub enum HeightMapError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// A line which is not a point. Zero-based.
+    Csv {
+        line: usize,
+        text: String,
+    },
+    /// A grid point which was not measured.
+    MissingPoint {
+        x: f32,
+        y: f32,
+    },
+    Empty,
+    /// Coordinates which are not ascending, or rows of another length.
+    Shape,
+}
+
+impl From<std::io::Error> for HeightMapError {
+    fn from(err: std::io::Error) -> Self {
+        HeightMapError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for HeightMapError {
+    fn from(err: serde_json::Error) -> Self {
+        HeightMapError::Json(err)
+    }
+}
+
+impl Display for HeightMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "Could not read height map: {err}"),
+            Self::Json(err) => write!(f, "Invalid height map JSON: {err}"),
+            Self::Csv { line, text } => write!(
+                f,
+                "Invalid height map at line {}: expected x,y,z, found \"{text}\"",
+                line + 1
+            ),
+            Self::MissingPoint { x, y } => {
+                write!(f, "The height map is not a grid: no point at X{x} Y{y}")
+            }
+            Self::Empty => write!(f, "The height map has no points"),
+            Self::Shape => write!(
+                f,
+                "The height map needs ascending x and y, and a value in z for each of them"
+            ),
+        }
+    }
+}
+
+impl Error for HeightMapError {}
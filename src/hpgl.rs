@@ -0,0 +1,78 @@
+use std::fmt::Write;
+
+use crate::{
+    toolpath::{SegmentShape, Toolpath},
+    types::GCodePlane,
+    vec3::Vec3,
+};
+
+/// HPGL plotter units per mm, 0.025 mm each.
+pub const UNITS_PER_MM: f64 = 40.;
+
+/// The file extension of HPGL files written for cutters.
+pub const HPGL_EXTENSION: &str = "plt";
+
+/// Writes the toolpath as HPGL, for vinyl cutters and plotters which do not read G-code. Rapid
+/// moves are made with the pen (the knife) up, all other moves with it down, so the swivels
+/// inserted at corners are cut like in the G-code. Arcs in the XY plane are written as `AA`, and
+/// arcs in other planes as a line between their ends, as HPGL has no height.
+pub fn write_hpgl(toolpath: &Toolpath) -> String {
+    let mut hpgl = String::from("IN;\nSP1;\nPA;\n");
+    let mut position = (0, 0);
+    let mut pen_down = false;
+    for segment in &toolpath.segments {
+        let (start, end) = (to_units(segment.start), to_units(segment.end));
+        let full_circle = matches!(
+            segment.shape,
+            SegmentShape::Arc {
+                plane: GCodePlane::XY,
+                ..
+            }
+        );
+        if start == end && !full_circle {
+            // Lifts and plunges
+            continue;
+        }
+        if start != position {
+            let _ = writeln!(hpgl, "PU{},{};", start.0, start.1);
+            pen_down = false;
+        }
+        match segment.shape {
+            _ if segment.rapid => {
+                let _ = writeln!(hpgl, "PU{},{};", end.0, end.1);
+                pen_down = false;
+            }
+            SegmentShape::Arc {
+                center,
+                sweep,
+                plane: GCodePlane::XY,
+            } => {
+                if !pen_down {
+                    hpgl.push_str("PD;\n");
+                    pen_down = true;
+                }
+                let center = to_units(center);
+                let _ = writeln!(
+                    hpgl,
+                    "AA{},{},{:.2};",
+                    center.0,
+                    center.1,
+                    sweep.to_degrees()
+                );
+            }
+            _ => {
+                let _ = writeln!(hpgl, "PD{},{};", end.0, end.1);
+                pen_down = true;
+            }
+        }
+        position = end;
+    }
+    hpgl.push_str("PU;\nSP0;\n");
+    hpgl
+}
+
+/// The X and Y of `point`, in mm, in plotter units.
+fn to_units(point: Vec3) -> (i64, i64) {
+    let units = |mm: f64| (mm * UNITS_PER_MM).round() as i64;
+    (units(point.x), units(point.y))
+}
@@ -0,0 +1,79 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        OnceLock,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The languages the app can be shown in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Language {
+    #[default]
+    English,
+    German,
+    Spanish,
+}
+
+impl Language {
+    pub const ALL: [Language; 3] = [Language::English, Language::German, Language::Spanish];
+
+    /// The name of the language in itself, to pick it by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::English => "English",
+            Language::German => "Deutsch",
+            Language::Spanish => "Español",
+        }
+    }
+
+    /// The translations of the English texts, read from `locales/`.
+    fn catalog(self) -> &'static HashMap<&'static str, &'static str> {
+        static CATALOGS: [OnceLock<HashMap<&str, &str>>; 3] =
+            [OnceLock::new(), OnceLock::new(), OnceLock::new()];
+        let source = match self {
+            Language::English => "",
+            Language::German => include_str!("../locales/de.txt"),
+            Language::Spanish => include_str!("../locales/es.txt"),
+        };
+        CATALOGS[self as usize].get_or_init(|| parse_catalog(source))
+    }
+}
+
+/// The language texts are translated to, see [`set_language`].
+static LANGUAGE: AtomicU8 = AtomicU8::new(Language::English as u8);
+
+/// Sets the language [`tr`] translates to.
+pub fn set_language(language: Language) {
+    LANGUAGE.store(language as u8, Ordering::Relaxed);
+}
+
+/// The language [`tr`] translates to.
+pub fn language() -> Language {
+    Language::ALL[LANGUAGE.load(Ordering::Relaxed) as usize]
+}
+
+/// `text` in the current language, or `text` itself if it has no translation.
+pub fn tr(text: &'static str) -> &'static str {
+    language().catalog().get(text).copied().unwrap_or(text)
+}
+
+/// Translates `text` like [`tr`] and replaces its placeholders, e.g. `{count}`, by the `args`.
+pub fn tr_args(text: &'static str, args: &[(&str, &dyn Display)]) -> String {
+    args.iter()
+        .fold(tr(text).to_string(), |text, (name, value)| {
+            text.replace(&format!("{{{name}}}"), &value.to_string())
+        })
+}
+
+/// Reads the lines `English text = translation` of a catalog, skipping `#` comments.
+fn parse_catalog(source: &'static str) -> HashMap<&'static str, &'static str> {
+    source
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once(" = "))
+        .collect()
+}
@@ -0,0 +1,604 @@
+use std::{borrow::Cow, collections::HashMap, error::Error, fmt::Display, io::BufRead, ops::Range};
+
+use gcode::{Callbacks, GCode, Mnemonic, Span, Word};
+use log::debug;
+
+use crate::output::{self, command_number};
+
+/// Words which belong to the motion mode rather than to a modal setting command on the same line.
+const MOTION_WORDS: [char; 11] = ['X', 'Y', 'Z', 'I', 'J', 'K', 'R', 'A', 'B', 'C', 'F'];
+
+/// Text from the input which is not gcode, kept so it can be written to the output.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceNote {
+    /// A comment including its delimiters. `inline` if it shares its line with gcode.
+    Comment {
+        line: usize,
+        text: String,
+        inline: bool,
+    },
+    Blank {
+        line: usize,
+    },
+    /// A `%` line, which starts or ends the program on Fanuc style controllers.
+    Delimiter {
+        line: usize,
+    },
+}
+
+impl SourceNote {
+    pub fn line(&self) -> usize {
+        match self {
+            SourceNote::Comment { line, .. } => *line,
+            SourceNote::Blank { line } => *line,
+            SourceNote::Delimiter { line } => *line,
+        }
+    }
+
+    pub fn is_inline(&self) -> bool {
+        matches!(self, SourceNote::Comment { inline: true, .. })
+    }
+}
+
+/// Text the parser skipped or could not make sense of, so the program may not do what its CAM
+/// export meant.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseIssue {
+    /// Zero-based input line.
+    pub line: usize,
+    /// The line as written, without its line ending.
+    pub text: String,
+    pub kind: ParseIssueKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseIssueKind {
+    /// Text which is not gcode, e.g. `$H` or `?`, which is dropped.
+    UnknownContent(String),
+    /// A number without a letter in front of it, which is dropped.
+    NumberWithoutLetter(String),
+    /// A letter without a number after it, which is dropped.
+    LetterWithoutNumber(String),
+    /// A line number (`N10`) which is not at the start of the line.
+    UnexpectedLineNumber(f32),
+    /// Coordinates or a feedrate without a command while no motion mode is active, e.g. after
+    /// `G80`, which are dropped.
+    NoMotionMode(String),
+}
+
+impl Display for ParseIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            ParseIssueKind::UnknownContent(text) => write!(f, "Skipped `{text}`")?,
+            ParseIssueKind::NumberWithoutLetter(number) => {
+                write!(f, "Skipped the number {number} without a letter")?
+            }
+            ParseIssueKind::LetterWithoutNumber(letter) => {
+                write!(f, "Skipped the letter {letter} without a number")?
+            }
+            ParseIssueKind::UnexpectedLineNumber(number) => {
+                write!(f, "Line number N{number} is not at the start of the line")?
+            }
+            ParseIssueKind::NoMotionMode(words) => {
+                write!(f, "Dropped {words} as no motion mode (G0-G3) is active")?
+            }
+        }
+        write!(f, " on input line {}: {}", self.line + 1, self.text.trim())
+    }
+}
+
+/// A parsed input program.
+#[derive(Debug, Clone, Default)]
+pub struct InputProgram {
+    pub gcodes: Vec<GCode>,
+    /// Comments and blank lines, in order.
+    pub notes: Vec<SourceNote>,
+    /// What the parser skipped, in order.
+    pub issues: Vec<ParseIssue>,
+    /// The code of every line, without comments and surrounding whitespace.
+    pub lines: Vec<String>,
+    pub source: String,
+}
+
+impl InputProgram {
+    /// The text of a parsed command, or `None` if it was not in the input.
+    pub fn source_text(&self, span: Span) -> Option<&str> {
+        if span.is_placeholder() {
+            return None;
+        }
+        self.source.get(span.start..span.end).map(str::trim)
+    }
+}
+
+impl InputProgram {
+    /// Replaces every `M98 P<number> L<repeats>` call with the commands of subprogram
+    /// `O<number>`, which ends with `M99`, and removes the subprograms. The copied commands are
+    /// treated as inserted, as they do not belong to a single input line.
+    ///
+    /// `K` is accepted instead of `L`, as is the Fanuc style `P<repeats><4 digit number>`.
+    pub fn expand_subprograms(&mut self) -> Result<(), SubprogramError> {
+        let (main, subprograms) = split_subprograms(&self.gcodes);
+        let mut out = Vec::with_capacity(main.len());
+        expand(&main, &subprograms, &mut vec![], &mut out)?;
+        self.gcodes = out;
+        Ok(())
+    }
+}
+
+/// A subprogram call which could not be expanded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubprogramError {
+    /// `M98` without a `P` word, on the given input line.
+    MissingNumber(Option<usize>),
+    /// There is no subprogram with this number in the input.
+    Missing(u32),
+    /// The subprogram calls itself, directly or through other subprograms.
+    Recursive(u32),
+}
+
+impl Display for SubprogramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubprogramError::MissingNumber(Some(line)) => {
+                write!(f, "M98 on line {} has no program number (P)", line + 1)
+            }
+            SubprogramError::MissingNumber(None) => write!(f, "M98 has no program number (P)"),
+            SubprogramError::Missing(number) => {
+                write!(f, "Subprogram O{number} is called but not in the file")
+            }
+            SubprogramError::Recursive(number) => write!(f, "Subprogram O{number} calls itself"),
+        }
+    }
+}
+
+impl Error for SubprogramError {}
+
+/// Separates the main program from the subprograms, which are `O` blocks ending with `M99`.
+fn split_subprograms(gcodes: &[GCode]) -> (Vec<GCode>, HashMap<u32, Vec<GCode>>) {
+    let mut main = vec![];
+    let mut subprograms = HashMap::new();
+    let mut i = 0;
+    while i < gcodes.len() {
+        let gcode = &gcodes[i];
+        i += 1;
+        if gcode.mnemonic() != Mnemonic::ProgramNumber {
+            main.push(gcode.clone());
+            continue;
+        }
+        let block = &gcodes[i..];
+        let end = block
+            .iter()
+            .position(|g| g.mnemonic() == Mnemonic::ProgramNumber || is_m(g, 99));
+        match end {
+            Some(end) if is_m(&block[end], 99) => {
+                subprograms.insert(gcode.major_number(), block[..end].to_vec());
+                i += end + 1;
+            }
+            _ => main.push(gcode.clone()),
+        }
+    }
+    (main, subprograms)
+}
+
+fn expand(
+    gcodes: &[GCode],
+    subprograms: &HashMap<u32, Vec<GCode>>,
+    stack: &mut Vec<u32>,
+    out: &mut Vec<GCode>,
+) -> Result<(), SubprogramError> {
+    for gcode in gcodes {
+        if is_m(gcode, 99) {
+            // Returning from the main program restarts it, which is left to the operator
+            continue;
+        }
+        if !is_m(gcode, 98) {
+            out.push(if stack.is_empty() {
+                gcode.clone()
+            } else {
+                without_spans(gcode)
+            });
+            continue;
+        }
+        let number =
+            gcode
+                .value_for('P')
+                .ok_or(SubprogramError::MissingNumber(output::source_line(
+                    gcode.span(),
+                )))? as u32;
+        let (number, repeats) = match gcode.value_for('L').or(gcode.value_for('K')) {
+            Some(repeats) => (number, repeats as u32),
+            None if number > 9999 => (number % 10000, number / 10000),
+            None => (number, 1),
+        };
+        if stack.contains(&number) {
+            return Err(SubprogramError::Recursive(number));
+        }
+        let subprogram = subprograms
+            .get(&number)
+            .ok_or(SubprogramError::Missing(number))?;
+        stack.push(number);
+        for _ in 0..repeats {
+            expand(subprogram, subprograms, stack, out)?;
+        }
+        stack.pop();
+    }
+    Ok(())
+}
+
+fn is_m(gcode: &GCode, number: u32) -> bool {
+    gcode.mnemonic() == Mnemonic::Miscellaneous && gcode.major_number() == number
+}
+
+/// A copy of the command which does not point at the input.
+fn without_spans(gcode: &GCode) -> GCode {
+    let mut new = GCode::new(gcode.mnemonic(), command_number(gcode), Span::PLACEHOLDER);
+    for arg in gcode.arguments() {
+        new.push_argument(Word::new(arg.letter, arg.value, Span::PLACEHOLDER))
+            .unwrap();
+    }
+    new
+}
+
+/// Parses a program line by line.
+///
+/// Unlike `gcode::parse`, lines which only contain coordinates (`X20 Y5`) are turned into a move
+/// in the active motion mode (`G0`-`G3`), lowercase words are read like uppercase ones
+/// (`g1 x10.y5` is `G1 X10 Y5`), and spans always point at the line the code is on.
+pub fn parse_gcode(src: &str) -> Vec<GCode> {
+    parse_program(src).gcodes
+}
+
+/// Parses a program like [`parse_gcode`], also keeping comments and blank lines.
+pub fn parse_program(src: &str) -> InputProgram {
+    let src = src.strip_prefix('\u{feff}').unwrap_or(src);
+    let mut parser = LineParser::default();
+    let mut program = InputProgram {
+        source: src.to_string(),
+        ..Default::default()
+    };
+    for line in src.split_inclusive('\n') {
+        let parsed = parser.parse_line(line);
+        program.gcodes.extend(parsed.gcodes);
+        program.notes.extend(parsed.notes);
+        program.issues.extend(parsed.issues);
+        program.lines.push(parsed.code);
+    }
+    program
+}
+
+/// The commands, comments and code of one line.
+#[derive(Debug, Clone, Default)]
+pub struct ParsedLine {
+    pub gcodes: Vec<GCode>,
+    pub notes: Vec<SourceNote>,
+    pub issues: Vec<ParseIssue>,
+    /// The code without comments and surrounding whitespace.
+    pub code: String,
+}
+
+/// Parses a program one line at a time, keeping the motion mode and the position in the
+/// program between lines.
+#[derive(Debug, Clone, Default)]
+pub struct LineParser {
+    motion_mode: Option<f32>,
+    /// Byte offset of the next line in the program.
+    offset: usize,
+    line_index: usize,
+}
+
+impl LineParser {
+    /// Parses the next line, including its line ending.
+    pub fn parse_line(&mut self, line: &str) -> ParsedLine {
+        let (line_index, offset) = (self.line_index, self.offset);
+        let mut out = vec![];
+        let mut notes = vec![];
+        let mut callbacks = LineCallbacks::default();
+        let mut comments = vec![];
+        let (parsable, unparsable) = blank_unparsable(line);
+        let gcodes: Vec<_> = gcode::full_parse_with_callbacks(&parsable, &mut callbacks)
+            .flat_map(|line| {
+                comments.extend(
+                    line.comments()
+                        .iter()
+                        .map(|c| c.value.trim_end().to_string()),
+                );
+                line.gcodes().to_vec()
+            })
+            .collect();
+        let LineCallbacks {
+            orphans,
+            mut issues,
+        } = callbacks;
+        issues.extend(
+            unparsable
+                .into_iter()
+                .map(|range| ParseIssueKind::UnknownContent(line[range].to_string())),
+        );
+        let has_code = !gcodes.is_empty() || !orphans.is_empty();
+        let code = comments.iter().fold(line.to_string(), |code, comment| {
+            code.replacen(comment, "", 1)
+        });
+        if line.trim().is_empty() {
+            notes.push(SourceNote::Blank { line: line_index });
+        } else if line.trim_start().starts_with('%') {
+            notes.push(SourceNote::Delimiter { line: line_index });
+        }
+        notes.extend(comments.into_iter().map(|text| SourceNote::Comment {
+            line: line_index,
+            text,
+            inline: has_code,
+        }));
+        let move_span = |span: Span| {
+            if span.is_placeholder() {
+                span
+            } else {
+                Span::new(span.start + offset, span.end + offset, line_index)
+            }
+        };
+        // The parser keeps the case of the words, but not of the commands
+        let move_word = |word: &Word| {
+            Word::new(
+                word.letter.to_ascii_uppercase(),
+                word.value,
+                move_span(word.span),
+            )
+        };
+        for gcode in gcodes.iter() {
+            let mut new = GCode::new(
+                gcode.mnemonic(),
+                command_number(gcode),
+                move_span(gcode.span()),
+            );
+            let mut motion_args = vec![];
+            for arg in gcode.arguments().iter().map(move_word) {
+                if is_modal_setting(gcode) && MOTION_WORDS.contains(&arg.letter) {
+                    motion_args.push(arg);
+                } else {
+                    new.push_argument(arg).unwrap();
+                }
+            }
+            if is_motion(gcode) {
+                self.motion_mode = Some(command_number(gcode));
+            } else if gcode.mnemonic() == Mnemonic::General && gcode.major_number() == 80 {
+                self.motion_mode = None;
+            }
+            if self.motion_mode.is_none() {
+                // The feedrate is modal, so it stays on the setting until a move uses it
+                let (feedrates, others) =
+                    motion_args.into_iter().partition(|arg| arg.letter == 'F');
+                for feedrate in feedrates {
+                    new.push_argument(feedrate).unwrap();
+                }
+                motion_args = others;
+            }
+            out.push(new);
+            if !motion_args.is_empty() {
+                match modal_motion(self.motion_mode, motion_args.clone()) {
+                    Some(gcode) => out.push(gcode),
+                    None => issues.extend(dropped_motion(&motion_args)),
+                }
+            }
+        }
+        if !orphans.is_empty() {
+            let words: Vec<_> = orphans.iter().map(move_word).collect();
+            match modal_motion(self.motion_mode, words.clone()) {
+                Some(gcode) => out.push(gcode),
+                None => issues.extend(dropped_motion(&words)),
+            }
+        }
+        self.offset += line.len();
+        self.line_index += 1;
+        if notes.contains(&SourceNote::Delimiter { line: line_index }) {
+            // `%` is not gcode, but it is kept
+            issues.retain(|issue| !matches!(issue, ParseIssueKind::UnknownContent(_)));
+        }
+        let text = line.trim_end_matches(['\r', '\n']);
+        ParsedLine {
+            gcodes: out,
+            notes,
+            issues: issues
+                .into_iter()
+                .map(|kind| ParseIssue {
+                    line: line_index,
+                    text: text.to_string(),
+                    kind,
+                })
+                .collect(),
+            code: code.split_whitespace().collect::<Vec<_>>().join(" "),
+        }
+    }
+}
+
+/// Reads and parses a program line by line, so it does not have to be held in memory. Comments
+/// are dropped, and what the parser skipped is kept in `issues`.
+///
+/// Stops at the first read error, which is kept in `error`.
+pub struct GCodeReader<R> {
+    input: R,
+    parser: LineParser,
+    line: String,
+    pending: std::vec::IntoIter<GCode>,
+    pub issues: Vec<ParseIssue>,
+    pub error: Option<std::io::Error>,
+}
+
+impl<R: BufRead> GCodeReader<R> {
+    pub fn new(input: R) -> Self {
+        GCodeReader {
+            input,
+            parser: LineParser::default(),
+            line: String::new(),
+            pending: vec![].into_iter(),
+            issues: vec![],
+            error: None,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for GCodeReader<R> {
+    type Item = GCode;
+
+    fn next(&mut self) -> Option<GCode> {
+        loop {
+            if let Some(gcode) = self.pending.next() {
+                return Some(gcode);
+            }
+            if self.error.is_some() {
+                return None;
+            }
+            self.line.clear();
+            match self.input.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => {
+                    let mut line = self.line.as_str();
+                    if self.parser.line_index == 0 {
+                        line = line.strip_prefix('\u{feff}').unwrap_or(line);
+                    }
+                    let parsed = self.parser.parse_line(line);
+                    self.pending = parsed.gcodes.into_iter();
+                    self.issues.extend(parsed.issues);
+                }
+                Err(err) => self.error = Some(err),
+            }
+        }
+    }
+}
+
+/// Blanks out what the `gcode` parser panics on: numbers without a digit, like `X-` or `x.`
+/// with their letter, which would otherwise take the number of the next word, and a `)` outside
+/// of a comment. They are replaced with spaces, so the spans of the rest of the line stay the
+/// same, and their ranges are returned.
+fn blank_unparsable(line: &str) -> (Cow<'_, str>, Vec<Range<usize>>) {
+    let bytes = line.as_bytes();
+    let mut unparsable = vec![];
+    let mut letter = None;
+    let mut i = 0;
+    // Follows the lexer of the parser, which reads a sign only at the start of a number
+    while i < bytes.len() {
+        match bytes[i] {
+            b';' => break,
+            b'(' => {
+                while i < bytes.len() && !matches!(bytes[i], b')' | b'\n') {
+                    i += 1;
+                }
+                i += 1;
+            }
+            b')' => {
+                unparsable.push(i..i + 1);
+                i += 1;
+            }
+            b'0'..=b'9' | b'.' | b'-' | b'+' => {
+                let start = i;
+                let mut decimal_seen = false;
+                while i < bytes.len() {
+                    match bytes[i] {
+                        b'-' | b'+' if i == start => {}
+                        b'0'..=b'9' => {}
+                        b'.' if !decimal_seen => decimal_seen = true,
+                        _ => break,
+                    }
+                    i += 1;
+                }
+                if !bytes[start..i].iter().any(u8::is_ascii_digit) {
+                    unparsable.push(letter.unwrap_or(start)..i);
+                }
+            }
+            c => {
+                if c.is_ascii_alphabetic() {
+                    letter = Some(i);
+                } else if !c.is_ascii_whitespace() {
+                    letter = None;
+                }
+                i += 1;
+                continue;
+            }
+        }
+        letter = None;
+    }
+    if unparsable.is_empty() {
+        return (Cow::Borrowed(line), unparsable);
+    }
+    let mut parsable = line.to_string();
+    for range in &unparsable {
+        parsable.replace_range(range.clone(), &" ".repeat(range.len()));
+    }
+    (Cow::Owned(parsable), unparsable)
+}
+
+/// Creates a move in the active motion mode from words without a command.
+fn modal_motion(motion_mode: Option<f32>, words: Vec<Word>) -> Option<GCode> {
+    let Some(motion_mode) = motion_mode else {
+        debug!("Dropping {:?}: no active motion mode", words);
+        return None;
+    };
+    let span = words
+        .iter()
+        .map(|w| w.span)
+        .reduce(Span::merge)
+        .unwrap_or(Span::PLACEHOLDER);
+    let mut gcode = GCode::new(Mnemonic::General, motion_mode, span);
+    for word in words {
+        gcode.push_argument(word).unwrap();
+    }
+    Some(gcode)
+}
+
+/// The issue of dropping `words` for lack of a motion mode, unless none of them belong to a move,
+/// like a spindle speed (`S`) on its own.
+fn dropped_motion(words: &[Word]) -> Option<ParseIssueKind> {
+    words
+        .iter()
+        .any(|word| MOTION_WORDS.contains(&word.letter))
+        .then(|| {
+            let words: Vec<_> = words.iter().map(Word::to_string).collect();
+            ParseIssueKind::NoMotionMode(words.join(" "))
+        })
+}
+
+fn is_motion(gcode: &GCode) -> bool {
+    gcode.mnemonic() == Mnemonic::General
+        && gcode.minor_number() == 0
+        && (0..=3).contains(&gcode.major_number())
+}
+
+/// Commands which only change a setting, so any coordinates on their line are a modal move.
+fn is_modal_setting(gcode: &GCode) -> bool {
+    gcode.mnemonic() == Mnemonic::General
+        && matches!(
+            gcode.major_number(),
+            17..=21 | 40..=42 | 49 | 54..=59 | 61 | 64 | 80 | 90 | 91 | 93 | 94
+        )
+}
+
+/// Collects words the parser could not attach to a command, and what it skipped.
+#[derive(Default)]
+struct LineCallbacks {
+    orphans: Vec<Word>,
+    issues: Vec<ParseIssueKind>,
+}
+
+impl Callbacks for LineCallbacks {
+    fn argument_without_a_command(&mut self, letter: char, value: f32, span: Span) {
+        self.orphans.push(Word::new(letter, value, span));
+    }
+
+    fn unknown_content(&mut self, text: &str, _span: Span) {
+        self.issues
+            .push(ParseIssueKind::UnknownContent(text.trim().to_string()));
+    }
+
+    fn number_without_a_letter(&mut self, value: &str, _span: Span) {
+        self.issues
+            .push(ParseIssueKind::NumberWithoutLetter(value.to_string()));
+    }
+
+    fn letter_without_a_number(&mut self, value: &str, _span: Span) {
+        self.issues
+            .push(ParseIssueKind::LetterWithoutNumber(value.to_string()));
+    }
+
+    fn unexpected_line_number(&mut self, line_number: f32, _span: Span) {
+        self.issues
+            .push(ParseIssueKind::UnexpectedLineNumber(line_number));
+    }
+}
@@ -0,0 +1,307 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::{
+    input::{self, InputProgram, SubprogramError},
+    operations,
+    output::{self, OutputConfig, OutputWarning, ProgramOutput},
+    parameters::{self, ParameterError},
+    preview::Preview,
+    stats::PathStats,
+    toolpath::Toolpath,
+    types::{DragknifeConfig, FixedGCode, GCodeState, RepathError},
+    verify::VERIFY_TOLERANCE,
+    DragknifePath,
+};
+
+/// Why a program could not be repathed.
+#[derive(Debug)]
+pub enum JobError {
+    Parameters(ParameterError),
+    Subprograms(SubprogramError),
+    Repath(RepathError),
+    /// The marker of the operations is not a valid regex.
+    OperationMarker(regex::Error),
+}
+
+impl Display for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobError::Parameters(err) => err.fmt(f),
+            JobError::Subprograms(err) => err.fmt(f),
+            JobError::Repath(err) => err.fmt(f),
+            JobError::OperationMarker(err) => write!(f, "Invalid operation marker: {err}"),
+        }
+    }
+}
+
+impl Error for JobError {}
+
+impl From<ParameterError> for JobError {
+    fn from(err: ParameterError) -> Self {
+        JobError::Parameters(err)
+    }
+}
+
+impl From<SubprogramError> for JobError {
+    fn from(err: SubprogramError) -> Self {
+        JobError::Subprograms(err)
+    }
+}
+
+impl From<RepathError> for JobError {
+    fn from(err: RepathError) -> Self {
+        JobError::Repath(err)
+    }
+}
+
+impl From<regex::Error> for JobError {
+    fn from(err: regex::Error) -> Self {
+        JobError::OperationMarker(err)
+    }
+}
+
+/// A program repathed up to the output stage, which its output, preview, toolpath and stats are
+/// built from, see [`prepare_path`].
+#[derive(Debug, Clone)]
+pub struct PreparedPath {
+    pub program: InputProgram,
+    /// After the compensation, the corner rounding and reversing contours.
+    pub path: DragknifePath<'static>,
+    pub fixed: Vec<FixedGCode>,
+    /// The warnings of the repath, in the order they are written after those of the output. The
+    /// parse warnings are in `program`.
+    pub warnings: Vec<OutputWarning>,
+}
+
+/// Runs every step from the input text to the repathed commands: parameters, subprograms, the
+/// operations, the checks of the input, the cutter and kerf compensation, the corner rounding and
+/// the repath itself.
+pub fn prepare_path(
+    source: &str,
+    config: &DragknifeConfig,
+    strict_parameters: bool,
+) -> Result<PreparedPath, JobError> {
+    let program = parse_source(source, strict_parameters)?;
+    let operations = operations::find_operations(&program, config)?;
+    let gcodes = config.registration.apply(&program.gcodes);
+    let gcodes = operations::apply_depths(&gcodes, &operations, config);
+    let mut path =
+        DragknifePath::try_from_gcode_with_state(gcodes.iter(), GCodeState::from_config(config))?;
+    path.operations = operations;
+    path.check_codes(config)?;
+    // The checks of the input compare the moves with the words they were read from
+    let validation = path.validate(config);
+    let mut compensation = path.apply_cutter_compensation(config);
+    compensation.extend(path.apply_kerf_compensation(config));
+    path.round_corners(config);
+    let degenerate_arcs = std::mem::take(&mut path.degenerate_arcs);
+    let contours = if config.reverse_pushed_contours {
+        path.reverse_pushed_contours(config)
+    } else {
+        path.analyze_contours(config)
+    };
+    let crossings = path.find_swivel_crossings(config);
+    let fixed = path.try_to_fixed_gcode(config)?;
+    let warnings = degenerate_arcs
+        .into_iter()
+        .map(OutputWarning::DegenerateArc)
+        .chain(compensation.into_iter().map(OutputWarning::Compensation))
+        .chain(validation.into_iter().map(OutputWarning::Validation))
+        .chain(contours.into_iter().map(OutputWarning::Contour))
+        .chain(crossings.into_iter().map(OutputWarning::SwivelCrossing))
+        .collect();
+    Ok(PreparedPath {
+        path: path.into_owned(),
+        program,
+        fixed,
+        warnings,
+    })
+}
+
+impl PreparedPath {
+    /// Applies the output stage. All warnings are collected in the returned output.
+    ///
+    /// With `verify_output`, the written program is parsed again and checked against the input,
+    /// see [`DragknifePath::verify_output`].
+    pub fn write(
+        &self,
+        config: &DragknifeConfig,
+        output_config: &OutputConfig,
+        verify_output: bool,
+    ) -> Result<ProgramOutput, JobError> {
+        let operation_of = |line| {
+            operations::operation_at(&self.path.operations, line)
+                .map(|operation| config.operations[operation].name.clone())
+        };
+        let mut output = output::write_program_with_operations(
+            &self.program,
+            self.fixed.clone(),
+            output_config,
+            operation_of,
+        );
+        if verify_output {
+            self.path
+                .verify_output(&output.text, config, VERIFY_TOLERANCE)?;
+        }
+        // First, as they may explain the others
+        output.warnings.splice(
+            0..0,
+            self.program
+                .issues
+                .iter()
+                .cloned()
+                .map(OutputWarning::Parse),
+        );
+        output.warnings.extend(self.warnings.iter().cloned());
+        Ok(output)
+    }
+
+    /// Measures the program before and after repathing it, where `written` is its output, see
+    /// [`DragknifePath::stats`].
+    pub fn stats(&self, config: &DragknifeConfig, written: &str, rapid_feedrate: f64) -> JobStats {
+        let written = input::parse_program(written);
+        let output = DragknifePath::from_gcode_with_state(
+            written.gcodes.iter(),
+            GCodeState::from_config(config),
+        )
+        .stats(config, rapid_feedrate);
+        JobStats {
+            input: self.path.stats(config, rapid_feedrate),
+            output,
+        }
+    }
+
+    /// Traces the repathed program for display, see [`Preview`]. Rapid moves are timed at
+    /// `rapid_feedrate` mm/min.
+    pub fn preview(&self, config: &DragknifeConfig, rapid_feedrate: f64) -> Preview {
+        Preview::new(&self.path, &self.fixed, config, rapid_feedrate)
+    }
+
+    /// The moves with the knife correction applied, see [`Toolpath`].
+    pub fn toolpath(&self) -> Toolpath {
+        Toolpath::new(&self.path, &self.fixed)
+    }
+}
+
+/// Runs every step from the input text to the program text, see [`prepare_path`] and
+/// [`PreparedPath::write`].
+pub fn repath_source(
+    source: &str,
+    config: &DragknifeConfig,
+    output_config: &OutputConfig,
+    strict_parameters: bool,
+    verify_output: bool,
+) -> Result<ProgramOutput, JobError> {
+    prepare_path(source, config, strict_parameters)?.write(config, output_config, verify_output)
+}
+
+/// Stats of a program as written and after repathing it.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct JobStats {
+    /// The cuts of the input, after reversing contours if that is set. Its swivels are the ones
+    /// which are inserted.
+    pub input: PathStats,
+    /// Includes the swivels and lifts, so its run time is the one to expect.
+    pub output: PathStats,
+}
+
+/// Measures the program in `source` before and after repathing it, see [`PreparedPath::stats`].
+/// The output is not checked.
+pub fn stats_source(
+    source: &str,
+    config: &DragknifeConfig,
+    output_config: &OutputConfig,
+    strict_parameters: bool,
+    rapid_feedrate: f64,
+) -> Result<JobStats, JobError> {
+    let prepared = prepare_path(source, config, strict_parameters)?;
+    let output = prepared.write(config, output_config, false)?;
+    Ok(prepared.stats(config, &output.text, rapid_feedrate))
+}
+
+/// Repaths the program in `source` and traces the result for display, see [`Preview`]. The
+/// output stage is not applied. Rapid moves are timed at `rapid_feedrate` mm/min.
+pub fn preview_source(
+    source: &str,
+    config: &DragknifeConfig,
+    strict_parameters: bool,
+    rapid_feedrate: f64,
+) -> Result<Preview, JobError> {
+    Ok(prepare_path(source, config, strict_parameters)?.preview(config, rapid_feedrate))
+}
+
+/// Repaths the program in `source` and collects its moves with the knife correction applied,
+/// see [`Toolpath`]. The output stage is not applied.
+pub fn toolpath_source(
+    source: &str,
+    config: &DragknifeConfig,
+    strict_parameters: bool,
+) -> Result<Toolpath, JobError> {
+    Ok(prepare_path(source, config, strict_parameters)?.toolpath())
+}
+
+/// Resolves parameters and expands subprograms, so the program can be repathed.
+fn parse_source(source: &str, strict_parameters: bool) -> Result<InputProgram, JobError> {
+    let source = parameters::resolve_parameters(source, strict_parameters)?;
+    let mut program = input::parse_program(&source);
+    program.expand_subprograms()?;
+    Ok(program)
+}
+
+/// The output name template used if no other is given, see [`output_path`].
+pub const DEFAULT_NAME_TEMPLATE: &str = "{stem}_dragknife.{ext}";
+
+/// The file next to `input` to write its output to. In `template`, `{stem}` is replaced by the
+/// file name of the input without its extension, `{ext}` by its extension and `{name}` by the whole
+/// file name, e.g. `{stem}_dragknife.nc`.
+pub fn output_path(input: &Path, template: &str) -> PathBuf {
+    let part =
+        |part: Option<&std::ffi::OsStr>| part.unwrap_or_default().to_string_lossy().into_owned();
+    let name = template
+        .replace("{stem}", &part(input.file_stem()))
+        .replace("{ext}", &part(input.extension()))
+        .replace("{name}", &part(input.file_name()));
+    input.with_file_name(name)
+}
+
+/// The file to write section `index` of a split program to, next to `path` with the number and
+/// name of the section added, e.g. `part_dragknife_2_crease.nc`.
+pub fn section_path(path: &Path, index: usize, name: &str) -> PathBuf {
+    let mut stem = path.file_stem().unwrap_or_default().to_os_string();
+    stem.push(format!("_{}", index + 1));
+    // Only characters which are safe in file names on every system
+    let name: String = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        .collect();
+    if !name.is_empty() {
+        stem.push(format!("_{name}"));
+    }
+    if let Some(ext) = path.extension() {
+        stem.push(".");
+        stem.push(ext);
+    }
+    path.with_file_name(stem)
+}
+
+/// Writes the program to `path`, or its sections next to it if it was split, see
+/// [`OutputConfig::split_files`]. Returns the files written.
+pub fn write_output(path: &Path, output: &ProgramOutput) -> std::io::Result<Vec<PathBuf>> {
+    if output.sections.is_empty() {
+        std::fs::write(path, &output.text)?;
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut written = vec![];
+    for (i, section) in output.sections.iter().enumerate() {
+        let path = section_path(path, i, &section.name);
+        std::fs::write(&path, &section.text)?;
+        written.push(path);
+    }
+    Ok(written)
+}
@@ -1,57 +1,372 @@
+#[cfg(feature = "gui")]
 pub mod app;
+pub mod compensation;
+pub mod config_file;
+pub mod contours;
+pub mod crossings;
+pub mod dialect;
+pub mod diff;
+pub mod grbl;
+pub mod height_map;
+pub mod hpgl;
+pub mod i18n;
+pub mod input;
+pub mod job;
+pub mod operations;
+pub mod output;
+pub mod parameters;
+pub mod preset;
+pub mod preview;
+pub mod registration;
+pub mod resume;
+pub mod rounding;
+pub mod stats;
+pub mod stream;
+pub mod svg;
+pub mod toolpath;
 pub mod types;
+pub mod validate;
 pub mod vec3;
+pub mod verify;
+#[cfg(all(feature = "gui", target_arch = "wasm32"))]
+pub mod web;
 
-use std::f32::consts::FRAC_PI_2;
-use std::f32::consts::PI;
-use std::f32::consts::TAU;
+use std::borrow::{Borrow, Cow};
+use std::f64::consts::FRAC_PI_2;
+use std::f64::consts::PI;
+use std::f64::consts::TAU;
 
 use gcode::{GCode, Mnemonic, Span, Word};
 use log::debug;
+use operations::{OperationConfigs, OperationStart};
+use output::{next_source_lines, source_line};
+use serde::{Deserialize, Serialize};
 use types::DragknifeState;
 use types::RepathError;
+use types::{word, GCodePositioning, ROTARY_AXES};
 use vec3::Vec3;
 
 use types::{
-    ArcDirection, ArcMovement, Command, DragknifeConfig, GCodeState, HomeMovement, LinearMovement,
-    Movement, OtherCommand, RapidMovement,
+    ArcDirection, ArcMovement, ArcProblem, Command, DegenerateArc, DegenerateArcs, DragknifeConfig,
+    FeedMode, FixedGCode, GCodeAxis, GCodeState, HomeMovement, LinearMovement, Movement,
+    OtherCommand, RapidMovement, WordValues,
 };
 
+/// Arc radii which differ by less than this (in mm, or 0.1% of the radius if that is larger)
+/// are the same.
+const ARC_TOLERANCE: f64 = 0.002;
+
+/// How far beside the corner the blade is turned around at direction reversals, in knife
+/// offsets.
+pub(crate) const REVERSAL_PIVOT: f64 = 2.;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DragknifePath<'a> {
     pub commands: Vec<Command<'a>>,
+    /// The state before the first command, e.g. the configured work offsets.
+    pub initial_state: GCodeState,
+    /// Arcs which were cut as lines or dropped.
+    pub degenerate_arcs: Vec<DegenerateArc>,
+    /// Where the operations of the config start, see [`operations::find_operations`].
+    #[serde(default)]
+    pub operations: Vec<OperationStart>,
 }
 
 impl<'a> DragknifePath<'a> {
     pub fn from_gcode(gcodes: impl Iterator<Item = &'a GCode>) -> DragknifePath<'a> {
+        DragknifePath::from_gcode_with_state(gcodes, GCodeState::default())
+    }
+
+    /// Builds the path, dropping commands which can not be repathed.
+    pub fn from_gcode_with_state(
+        gcodes: impl Iterator<Item = &'a GCode>,
+        initial_state: GCodeState,
+    ) -> DragknifePath<'a> {
         let mut output = Vec::with_capacity(gcodes.size_hint().0);
-        let mut settings = GCodeState::default();
+        let mut degenerate_arcs = vec![];
+        let mut settings = initial_state;
         for gcode in gcodes {
-            let command = Command::from_gcode(gcode, output.last(), &mut settings);
-            if let Ok(command) = command {
-                output.push(command);
-            } else {
-                debug!("Dropping due to {:?}, {:?}", command, gcode)
+            let command =
+                Command::from_gcode(gcode, output.last(), &mut settings, &mut degenerate_arcs);
+            match command {
+                Ok(command) => output.push(command),
+                Err(RepathError::DegenerateArc(arc)) => {
+                    degenerate_arcs.push(DegenerateArc {
+                        handling: DegenerateArcs::Drop,
+                        ..arc
+                    });
+                }
+                Err(_) => debug!("Dropping due to {:?}, {:?}", command, gcode),
             }
         }
-        DragknifePath { commands: output }
+        DragknifePath {
+            commands: output,
+            initial_state,
+            degenerate_arcs,
+            operations: vec![],
+        }
+    }
+
+    /// Like `from_gcode_with_state`, but stops at degenerate arcs if the state says so.
+    pub fn try_from_gcode_with_state(
+        gcodes: impl Iterator<Item = &'a GCode>,
+        initial_state: GCodeState,
+    ) -> Result<DragknifePath<'a>, RepathError> {
+        let path = DragknifePath::from_gcode_with_state(gcodes, initial_state);
+        match path.degenerate_arcs.first() {
+            Some(arc) if initial_state.degenerate_arcs == DegenerateArcs::Error => {
+                Err(RepathError::DegenerateArc(DegenerateArc {
+                    handling: DegenerateArcs::Error,
+                    ..*arc
+                }))
+            }
+            _ => Ok(path),
+        }
+    }
+
+    /// Copies the input commands, so the path no longer borrows the parsed program and can be
+    /// kept or sent to another thread.
+    pub fn into_owned(self) -> DragknifePath<'static> {
+        DragknifePath {
+            commands: self.commands.into_iter().map(Command::into_owned).collect(),
+            initial_state: self.initial_state,
+            degenerate_arcs: self.degenerate_arcs,
+            operations: self.operations,
+        }
+    }
+
+    /// Repaths the commands, each with the config of its operation. The tool change of an
+    /// operation is written before its first cut, after the moves which lift the knife and take
+    /// it there, and the new tool is assumed to point nowhere in particular. Swivels which cross
+    /// an earlier cut are lifted to `DragknifeConfig::crossing_lift`, if it is set.
+    pub fn to_fixed_gcode(&self, config: &DragknifeConfig) -> Vec<FixedGCode> {
+        self.to_fixed_gcode_with_hooks(config, &mut CommandHooks::default())
     }
 
-    pub fn to_fixed_gcode(&self, config: &DragknifeConfig) -> Vec<GCode> {
+    /// Like `to_fixed_gcode`, with the codes of `hooks` written around those of every command.
+    pub fn to_fixed_gcode_with_hooks(
+        &self,
+        config: &DragknifeConfig,
+        hooks: &mut CommandHooks<'_>,
+    ) -> Vec<FixedGCode> {
         let mut fixed = vec![];
         let mut prev_angle = None;
-        let mut settings = GCodeState::default();
+        let mut settings = self.initial_state;
         let mut dragknife_state = DragknifeState::default();
-        for command in self.commands.iter() {
+        let crossings: Vec<usize> = match config.crossing_lift {
+            Some(_) => self
+                .find_swivel_crossings(config)
+                .iter()
+                .map(|crossing| crossing.command)
+                .collect(),
+            None => vec![],
+        };
+        let operations = OperationConfigs::new(config);
+        let mut prev_operation = None;
+        for (i, command) in self.commands.iter().enumerate() {
+            let operation = self.operation_of(i);
+            let cuts = matches!(command, Command::Linear(_) | Command::Arc(_));
+            if operation != prev_operation && cuts {
+                if let Some(operation) = operation.map(|i| &config.operations[i]) {
+                    let reason = format!("tool change for {}", operation.name);
+                    fixed.extend(
+                        operation
+                            .tool_change_gcode()
+                            .into_iter()
+                            .map(|gcode| FixedGCode::inserted(gcode, &reason)),
+                    );
+                    prev_angle = None;
+                }
+                prev_operation = operation;
+            }
+            let config = operations.get(operation);
+            let crossing_config;
+            let config = match config.crossing_lift {
+                Some(lift) if crossings.binary_search(&i).is_ok() => {
+                    crossing_config = DragknifeConfig {
+                        lift_config: lift,
+                        ..config.clone()
+                    };
+                    &crossing_config
+                }
+                _ => config,
+            };
+            hooks.run_before(command, &mut fixed);
             fixed.append(&mut command.to_fixed_gcode(
                 prev_angle,
                 &mut settings,
                 &mut dragknife_state,
-                &config,
+                config,
             ));
+            hooks.run_after(command, &mut fixed);
             prev_angle = command.end_angle();
         }
         fixed
     }
+
+    /// Like `to_fixed_gcode`, but returns an error instead of writing NaN or infinite values.
+    pub fn try_to_fixed_gcode(
+        &self,
+        config: &DragknifeConfig,
+    ) -> Result<Vec<FixedGCode>, RepathError> {
+        let fixed = self.to_fixed_gcode(config);
+        check_finite(&fixed)?;
+        Ok(fixed)
+    }
+}
+
+/// A command and the codes a hook adds for it.
+type Hook<'h> = Box<dyn FnMut(&Command<'_>, &mut Vec<GCode>) + 'h>;
+
+/// Callbacks which add codes before or after those written for each command, see
+/// [`DragknifePath::to_fixed_gcode_with_hooks`], e.g. to trigger a camera at the end of every
+/// cut:
+///
+/// ```
+/// use dragknife_repath::{input::parse_gcode, types::Command, CommandHooks};
+///
+/// let mut hooks = CommandHooks::default().after(|command, gcodes| {
+///     if let Command::Linear(_) = command {
+///         gcodes.extend(parse_gcode("M64 P0"));
+///     }
+/// });
+/// ```
+///
+/// The codes of a hook are written as they are, after the tool change of an operation and
+/// before the swivel and lift of the command.
+#[derive(Default)]
+pub struct CommandHooks<'h> {
+    before: Vec<Hook<'h>>,
+    after: Vec<Hook<'h>>,
+}
+
+impl<'h> CommandHooks<'h> {
+    /// Adds a hook called before the codes of each command are written.
+    pub fn before(mut self, hook: impl FnMut(&Command<'_>, &mut Vec<GCode>) + 'h) -> Self {
+        self.before.push(Box::new(hook));
+        self
+    }
+
+    /// Adds a hook called after the codes of each command are written.
+    pub fn after(mut self, hook: impl FnMut(&Command<'_>, &mut Vec<GCode>) + 'h) -> Self {
+        self.after.push(Box::new(hook));
+        self
+    }
+
+    fn run_before(&mut self, command: &Command<'_>, fixed: &mut Vec<FixedGCode>) {
+        Self::run(&mut self.before, command, fixed);
+    }
+
+    fn run_after(&mut self, command: &Command<'_>, fixed: &mut Vec<FixedGCode>) {
+        Self::run(&mut self.after, command, fixed);
+    }
+
+    fn run(hooks: &mut [Hook<'h>], command: &Command<'_>, fixed: &mut Vec<FixedGCode>) {
+        let mut gcodes = vec![];
+        for hook in hooks {
+            hook(command, &mut gcodes);
+        }
+        fixed.extend(
+            gcodes
+                .into_iter()
+                .map(|gcode| FixedGCode::inserted(gcode, "command hook")),
+        );
+    }
+}
+
+/// Repaths `gcodes` one command at a time, so programs too large to hold as a `DragknifePath`
+/// can be streamed through. Contours are not analyzed and swivels are not checked against
+/// earlier cuts, as that needs the whole path.
+pub fn fix_gcode_iter<G: Borrow<GCode>, I: IntoIterator<Item = G>>(
+    gcodes: I,
+    initial_state: GCodeState,
+    config: &DragknifeConfig,
+) -> FixGCodeIter<'_, I::IntoIter> {
+    FixGCodeIter {
+        gcodes: gcodes.into_iter(),
+        config,
+        parse_settings: initial_state,
+        settings: initial_state,
+        state: DragknifeState::default(),
+        end_pos: Vec3::zero(),
+        end_angle: None,
+        pending: vec![].into_iter(),
+        degenerate_arcs: vec![],
+    }
+}
+
+/// The iterator returned by `fix_gcode_iter`.
+pub struct FixGCodeIter<'c, I> {
+    gcodes: I,
+    config: &'c DragknifeConfig,
+    /// The state while reading the input, which runs ahead of the output.
+    parse_settings: GCodeState,
+    settings: GCodeState,
+    state: DragknifeState,
+    end_pos: Vec3,
+    end_angle: Option<f64>,
+    pending: std::vec::IntoIter<FixedGCode>,
+    /// Arcs which were cut as lines or dropped so far.
+    pub degenerate_arcs: Vec<DegenerateArc>,
+}
+
+impl<'c, G: Borrow<GCode>, I: Iterator<Item = G>> Iterator for FixGCodeIter<'c, I> {
+    type Item = FixedGCode;
+
+    fn next(&mut self) -> Option<FixedGCode> {
+        loop {
+            if let Some(fixed) = self.pending.next() {
+                return Some(fixed);
+            }
+            let gcode = self.gcodes.next()?;
+            let command = Command::from_gcode_at(
+                gcode.borrow(),
+                self.end_pos,
+                self.end_angle,
+                &mut self.parse_settings,
+                &mut self.degenerate_arcs,
+            );
+            match command {
+                Ok(command) => {
+                    self.pending = command
+                        .to_fixed_gcode(
+                            self.end_angle,
+                            &mut self.settings,
+                            &mut self.state,
+                            self.config,
+                        )
+                        .into_iter();
+                    self.end_pos = command.end_pos();
+                    self.end_angle = command.end_angle();
+                }
+                Err(RepathError::DegenerateArc(arc)) => {
+                    self.degenerate_arcs.push(DegenerateArc {
+                        handling: DegenerateArcs::Drop,
+                        ..arc
+                    });
+                }
+                Err(err) => debug!("Dropping due to {:?}, {:?}", err, gcode.borrow()),
+            }
+        }
+    }
+}
+
+/// Finds the first word which is not a finite number.
+pub(crate) fn check_finite(gcodes: &[FixedGCode]) -> Result<(), RepathError> {
+    for (fixed, line) in gcodes.iter().zip(next_source_lines(gcodes)) {
+        let gcode = &fixed.gcode;
+        if let Some(word) = gcode.arguments().iter().find(|w| !w.value.is_finite()) {
+            return Err(RepathError::NonFinite {
+                line,
+                word: word.letter,
+                computation: match &fixed.reason {
+                    Some(reason) => reason.clone(),
+                    None => format!("repathed {}{}", gcode.mnemonic(), gcode.major_number()),
+                },
+            });
+        }
+    }
+    Ok(())
 }
 
 impl<'a> Command<'a> {
@@ -59,8 +374,25 @@ impl<'a> Command<'a> {
         gcode: &'a GCode,
         prev_command: Option<&Command>,
         settings: &mut GCodeState,
+        degenerate_arcs: &mut Vec<DegenerateArc>,
+    ) -> Result<Command<'a>, RepathError> {
+        Command::from_gcode_at(
+            gcode,
+            prev_command.end_pos(),
+            prev_command.end_angle(),
+            settings,
+            degenerate_arcs,
+        )
+    }
+
+    /// Like `from_gcode`, with the position and direction the previous command ended at.
+    pub fn from_gcode_at(
+        gcode: &'a GCode,
+        start: Vec3,
+        prev_angle: Option<f64>,
+        settings: &mut GCodeState,
+        degenerate_arcs: &mut Vec<DegenerateArc>,
     ) -> Result<Command<'a>, RepathError> {
-        let start = prev_command.end_pos();
         debug!(
             "{:?} {:?} ({:?}) {:?}",
             gcode.mnemonic(),
@@ -70,63 +402,63 @@ impl<'a> Command<'a> {
         );
         match gcode.mnemonic() {
             Mnemonic::Miscellaneous => Ok(Command::Other(OtherCommand {
-                original: gcode,
+                original: Cow::Borrowed(gcode),
                 pos: start,
-                angle: prev_command.end_angle(),
+                angle: prev_angle,
             })),
             Mnemonic::ProgramNumber => Ok(Command::Other(OtherCommand {
-                original: gcode,
+                original: Cow::Borrowed(gcode),
                 pos: start,
-                angle: prev_command.end_angle(),
+                angle: prev_angle,
             })),
             Mnemonic::ToolChange => Ok(Command::Other(OtherCommand {
-                original: gcode,
+                original: Cow::Borrowed(gcode),
                 pos: start,
-                angle: prev_command.end_angle(),
+                angle: prev_angle,
             })),
             Mnemonic::General => match gcode.major_number() {
                 0 /* Rapid movement */ => {
                     let end = settings.get_target(start, gcode);
                     Ok(Command::Rapid(RapidMovement {
-                        original: gcode,
+                        original: Cow::Borrowed(gcode),
                         start,
                         end,
                     }))
                 },
                 1 /* Linear interpolation */ => {
-                    if gcode.arguments().len() == 0 {
+                    if gcode.arguments().is_empty() {
                         return Err(RepathError::MissingArguments)
                     }
                     let end = settings.get_target(start, gcode);
-                    let angle = if (start-end).project_plane(&settings.plane).magnitude() <= 0.00001 {
-                        debug!("Insufficient length; using previous end angle");
-                        prev_command.end_angle()
-                    } else {
-                        Some(start.angle_to(&end, &settings.plane))
-                    };
+                    let angle = Command::linear_angle(start, end, prev_angle, settings);
                     debug!("G1: {angle:.2?} {start} {end}");
                     Ok(Command::Linear(LinearMovement {
-                        original: gcode,
+                        original: Cow::Borrowed(gcode),
                         start,
                         end,
                         angle,
                     }))
                 },
                 2 /* Circular interpolation, clockwise */ => {
-                    if gcode.arguments().len() == 0 {
+                    if gcode.arguments().is_empty() {
                         return Err(RepathError::MissingArguments)
                     }
                     let target = settings.get_target(start, gcode);
-                    let center_off = settings.get_center_offset(gcode);
+                    let center_off = settings.get_center_offset(start, gcode);
                     let center = start + center_off;
+                    if let Some(problem) = Command::check_arc(start, center, target, settings) {
+                        return Command::degenerate_arc(gcode, problem, start, target, prev_angle, settings, degenerate_arcs);
+                    }
                     let start_angle = center.angle_to(&start, &settings.plane) - FRAC_PI_2;
                     let end_angle = center.angle_to(&target, &settings.plane) - FRAC_PI_2;
                     let (start_angle, end_angle) = (start_angle.rem_euclid(TAU), end_angle.rem_euclid(TAU));
                     let radius = (start - center).project_plane(&settings.plane).magnitude();
-                    let end = (target-center).normalized()*radius + center;
+                    // Keep the target's height so helical moves end where they were programmed
+                    let planar = (target-center).project_plane(&settings.plane);
+                    let end = target - planar + planar.normalized()*radius;
                     debug!("G2: {radius} {start_angle:.2} {end_angle:.2} {target} {center_off} {center} {end}");
                     Ok(Command::Arc(ArcMovement {
-                        original: gcode,
+                        original: Cow::Borrowed(gcode),
                         direction: ArcDirection::CW,
                         start,
                         end,
@@ -136,20 +468,25 @@ impl<'a> Command<'a> {
                     }))
                 },
                 3 /* Circular interpolation, counterclockwise */ => {
-                    if gcode.arguments().len() == 0 {
+                    if gcode.arguments().is_empty() {
                         return Err(RepathError::MissingArguments)
                     }
                     let target = settings.get_target(start, gcode);
-                    let center_off = settings.get_center_offset(gcode);
+                    let center_off = settings.get_center_offset(start, gcode);
                     let center = start + center_off;
+                    if let Some(problem) = Command::check_arc(start, center, target, settings) {
+                        return Command::degenerate_arc(gcode, problem, start, target, prev_angle, settings, degenerate_arcs);
+                    }
                     let start_angle = center.angle_to(&start, &settings.plane) + FRAC_PI_2;
                     let end_angle = center.angle_to(&target, &settings.plane) + FRAC_PI_2;
                     let (start_angle, end_angle) = (start_angle.rem_euclid(TAU),end_angle.rem_euclid(TAU));
                     let radius = (start - center).project_plane(&settings.plane).magnitude();
-                    let end = (target-center).normalized()*radius + center;
+                    // Keep the target's height so helical moves end where they were programmed
+                    let planar = (target-center).project_plane(&settings.plane);
+                    let end = target - planar + planar.normalized()*radius;
                     debug!("G3: {radius} {start_angle:.2} {end_angle:.2} {start} {target} {center_off} {center} {end}");
                     Ok(Command::Arc(ArcMovement {
-                        original: gcode,
+                        original: Cow::Borrowed(gcode),
                         direction: ArcDirection::CCW,
                         start,
                         end,
@@ -159,25 +496,44 @@ impl<'a> Command<'a> {
                     }))
                 },
                 28 /* Go to machine zero */=> {
+                    let axes = [GCodeAxis::X, GCodeAxis::Y, GCodeAxis::Z]
+                        .map(|axis| gcode.value(axis.main_name()).is_some());
+                    let rotary = ROTARY_AXES.iter().any(|&letter| gcode.value(letter).is_some());
+                    if !axes.contains(&true) && !rotary {
+                        return Ok(Command::Home(HomeMovement {
+                            original: Cow::Borrowed(gcode),
+                            start,
+                            intermediate: None,
+                            end: Vec3::zero(),
+                        }));
+                    }
+                    // Only the given axes are homed, through the given point.
+                    let intermediate = settings.get_target(start, gcode);
+                    let mut end = intermediate;
+                    if axes[0] {
+                        end.x = 0.;
+                    }
+                    if axes[1] {
+                        end.y = 0.;
+                    }
+                    if axes[2] {
+                        end.z = 0.;
+                    }
                     Ok(Command::Home(HomeMovement {
-                        original: gcode,
+                        original: Cow::Borrowed(gcode),
                         start,
+                        intermediate: Some(intermediate),
+                        end,
                     }))
                 },
-                17 /* Select XY plane */|
-                18 /* Select ZX plane */|
-                19 /* Select YZ plane */|
-                20 /* Select inches */|
-                21 /* Select mm */|
-                90 /* Select absolute positioning */|
-                91 /* Select relative positioning */|
-                40..=44 /* Tool compensation: NOOP */ |
-                54..=59 /* Set coord systems: NOOP */|
+                // 17-19: Select plane, 20-21: Select units, 90-91: Select positioning,
+                // 40-42: Cutter compensation, see `DragknifePath::apply_cutter_compensation`,
+                // 43-44: Tool length (NOOP), 54-59: Set coord systems (NOOP)
                 _ => {
                     let other_command = OtherCommand {
-                        original: gcode,
+                        original: Cow::Borrowed(gcode),
                         pos: start,
-                        angle: prev_command.end_angle(),
+                        angle: prev_angle,
                     };
                     other_command.update_settings(settings);
                     Ok(Command::Other(other_command))
@@ -186,21 +542,120 @@ impl<'a> Command<'a> {
         }
     }
 
+    /// The direction of a line, or the previous direction if it is too short to have one.
+    fn linear_angle(
+        start: Vec3,
+        end: Vec3,
+        prev_angle: Option<f64>,
+        settings: &GCodeState,
+    ) -> Option<f64> {
+        if (start - end).project_plane(&settings.plane).magnitude() <= 0.00001 {
+            debug!("Insufficient length; using previous end angle");
+            prev_angle
+        } else {
+            Some(start.angle_to(&end, &settings.plane))
+        }
+    }
+
+    /// Checks that an arc from `start` around `center` can end at `target`.
+    fn check_arc(
+        start: Vec3,
+        center: Vec3,
+        target: Vec3,
+        settings: &GCodeState,
+    ) -> Option<ArcProblem> {
+        let start_radius = (start - center).project_plane(&settings.plane).magnitude();
+        let end_radius = (target - center).project_plane(&settings.plane).magnitude();
+        if start_radius < ARC_TOLERANCE {
+            Some(ArcProblem::ZeroRadius)
+        } else if (end_radius - start_radius).abs() > ARC_TOLERANCE.max(start_radius * 0.001) {
+            Some(ArcProblem::RadiusMismatch {
+                start: start_radius,
+                end: end_radius,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Cuts an arc which can not be cut as programmed as a line, or drops it.
+    fn degenerate_arc(
+        gcode: &'a GCode,
+        problem: ArcProblem,
+        start: Vec3,
+        target: Vec3,
+        prev_angle: Option<f64>,
+        settings: &GCodeState,
+        degenerate_arcs: &mut Vec<DegenerateArc>,
+    ) -> Result<Command<'a>, RepathError> {
+        let arc = DegenerateArc {
+            line: source_line(gcode.span()),
+            problem,
+            handling: settings.degenerate_arcs,
+        };
+        if settings.degenerate_arcs != DegenerateArcs::Linear {
+            return Err(RepathError::DegenerateArc(arc));
+        }
+        degenerate_arcs.push(arc);
+        Ok(Command::Linear(LinearMovement {
+            original: Cow::Borrowed(gcode),
+            start,
+            end: target,
+            angle: Command::linear_angle(start, target, prev_angle, settings),
+        }))
+    }
+
     pub fn to_fixed_gcode(
         &self,
-        previous_angle: Option<f32>,
+        previous_angle: Option<f64>,
         settings: &mut GCodeState,
         state: &mut DragknifeState,
         config: &DragknifeConfig,
-    ) -> Vec<GCode> {
+    ) -> Vec<FixedGCode> {
         match self {
             Command::Other(command) => {
-                if command.original.major_number() == 91 {
-                    // Discard relative positioning command
-                    vec![]
+                if command.original.mnemonic() == Mnemonic::General
+                    && command.original.major_number() == 91
+                    && command.original.minor_number() == 0
+                {
+                    // The output is always absolute, the rotary axes are followed in the
+                    // positioning of the input
+                    command.update_settings(settings);
+                    vec![GCode::new(Mnemonic::General, 90.0, command.original.span()).into()]
+                } else if command.original.mnemonic() == Mnemonic::General
+                    && command.original.major_number() == 90
+                    && command.original.minor_number() == 1
+                {
+                    // Arc centers are always written relative to the start of the arc
+                    command.update_settings(settings);
+                    vec![GCode::new(Mnemonic::General, 91.1, command.original.span()).into()]
+                } else if let (Mnemonic::General, 10, Some(20), Some(index)) = (
+                    command.original.mnemonic(),
+                    command.original.major_number(),
+                    command.original.value('L').map(|l| l as u32),
+                    command.work_offset_index(settings),
+                ) {
+                    // The knife offset moves the tool away from the programmed position, so the
+                    // resulting offset is set directly
+                    command.update_settings(settings);
+                    let offset = settings.work_offsets[index] / settings.unit_factor();
+                    let mut new = GCode::new(Mnemonic::General, 10.0, command.original.span())
+                        .with_argument(word('L', 2.0, Span::PLACEHOLDER))
+                        .with_argument(word('P', index as f64 + 1.0, Span::PLACEHOLDER));
+                    for (axis, value) in [
+                        (GCodeAxis::X, offset.x),
+                        (GCodeAxis::Y, offset.y),
+                        (GCodeAxis::Z, offset.z),
+                    ] {
+                        if command.original.value(axis.main_name()).is_some() {
+                            new.push_argument(word(axis.main_name(), value, Span::PLACEHOLDER))
+                                .unwrap();
+                        }
+                    }
+                    vec![new.into()]
                 } else {
                     command.update_settings(settings);
-                    vec![command.original.clone()]
+                    vec![command.original.clone().into_owned().into()]
                 }
             }
             Command::Linear(command) => {
@@ -211,20 +666,12 @@ impl<'a> Command<'a> {
                 } else {
                     command.end
                 };
-                let target = target.coords_for_plane(&settings.plane);
-                let mut new = GCode::new(Mnemonic::General, 1.0, Span::PLACEHOLDER)
-                    .with_argument(Word::new(
-                        settings.plane.axis_1().main_name(),
-                        target.0,
-                        Span::PLACEHOLDER,
-                    ))
-                    .with_argument(Word::new(
-                        settings.plane.axis_2().main_name(),
-                        target.1,
-                        Span::PLACEHOLDER,
-                    ));
+                let target = settings
+                    .to_program_coords(target)
+                    .coords_for_plane(&settings.plane);
+                let mut new = Command::plane_move(1.0, target, command.original.span(), settings);
                 Command::add_misc_args_and_update_settings(&mut new, self, state, settings);
-                out.push(new);
+                out.push(new.into());
                 out
             }
             Command::Arc(command) => {
@@ -239,150 +686,432 @@ impl<'a> Command<'a> {
                     Vec3::unit_angle(command.end_angle, &settings.plane),
                     command.end
                 );
-                let center_offset = command.center - new_start;
-                let new_end = new_end.coords_for_plane(&settings.plane);
+                let center_offset = (command.center - new_start) / settings.unit_factor();
+                let new_end = settings
+                    .to_program_coords(new_end)
+                    .coords_for_plane(&settings.plane);
                 let center_offset = center_offset.coords_for_plane(&settings.plane);
-                let mut new = GCode::new(
-                    Mnemonic::General,
+                let mut new = Command::plane_arc(
                     if let ArcDirection::CW = command.direction {
                         2.0
                     } else {
                         3.0
                     },
-                    Span::PLACEHOLDER,
-                )
-                .with_argument(Word::new(
-                    settings.plane.axis_1().main_name(),
-                    new_end.0,
-                    Span::PLACEHOLDER,
-                ))
-                .with_argument(Word::new(
-                    settings.plane.axis_2().main_name(),
-                    new_end.1,
-                    Span::PLACEHOLDER,
-                ))
-                .with_argument(Word::new(
-                    settings.plane.axis_1().center_name(),
-                    center_offset.0,
-                    Span::PLACEHOLDER,
-                ))
-                .with_argument(Word::new(
-                    settings.plane.axis_2().center_name(),
-                    center_offset.1,
-                    Span::PLACEHOLDER,
-                ));
+                    new_end,
+                    center_offset,
+                    command.original.span(),
+                    settings,
+                );
                 Command::add_misc_args_and_update_settings(&mut new, self, state, settings);
-                out.push(new);
+                out.push(new.into());
                 out
             }
-            Command::Home(command) => vec![command.original.clone()],
-            Command::Rapid(command) => vec![command.original.clone()],
+            Command::Home(command) => {
+                let Some(intermediate) = command.intermediate else {
+                    settings.update_rotary(&command.original);
+                    return vec![command.original.clone().into_owned().into()];
+                };
+                // Re-emit the intermediate point in absolute coordinates
+                let intermediate = settings.to_program_coords(intermediate);
+                let mut new = GCode::new(Mnemonic::General, 28.0, command.original.span());
+                for (axis, value) in [
+                    (GCodeAxis::X, intermediate.x),
+                    (GCodeAxis::Y, intermediate.y),
+                    (GCodeAxis::Z, intermediate.z),
+                ] {
+                    if command.original.value(axis.main_name()).is_some() {
+                        new.push_argument(word(axis.main_name(), value, Span::PLACEHOLDER))
+                            .unwrap();
+                    }
+                }
+                for (axis, letter) in ROTARY_AXES.into_iter().enumerate() {
+                    if let Some(value) = command.original.value(letter) {
+                        let value = match settings.positioning {
+                            GCodePositioning::Absolute => value,
+                            GCodePositioning::Relative => settings.rotary[axis] + value,
+                        };
+                        new.push_argument(word(letter, value, Span::PLACEHOLDER))
+                            .unwrap();
+                    }
+                }
+                settings.update_rotary(&command.original);
+                vec![new.into()]
+            }
+            Command::Rapid(command) => {
+                // Re-emit the target in absolute coordinates
+                let end = settings.to_program_coords(command.end);
+                settings.update_rotary(&command.original);
+                let mut new = GCode::new(Mnemonic::General, 0.0, command.original.span());
+                for arg in command.original.arguments() {
+                    let value = match arg.letter {
+                        'X' => end.x,
+                        'Y' => end.y,
+                        'Z' => end.z,
+                        'A' => settings.rotary[0],
+                        'B' => settings.rotary[1],
+                        'C' => settings.rotary[2],
+                        _ => f64::from(arg.value),
+                    };
+                    new.push_argument(word(arg.letter, value, arg.span))
+                        .unwrap();
+                }
+                vec![new.into()]
+            }
         }
     }
 
     fn handle_previous_angle(
-        previous_angle: Option<f32>,
+        previous_angle: Option<f64>,
         next: &Command<'a>,
         settings: &GCodeState,
         state: &mut DragknifeState,
         config: &DragknifeConfig,
-    ) -> Vec<GCode> {
+    ) -> Vec<FixedGCode> {
         if let (Some(from_angle), Some(to_angle)) = (previous_angle, next.start_angle()) {
-            // Add swivel
             let signed_angle = signed_angle(from_angle, to_angle);
-            if signed_angle.abs() > config.sharp_angle_threshold {
-                let mut out = vec![];
-                let start_height = next.start_pos().third_coord(&settings.plane);
-                out.push(
-                    GCode::new(Mnemonic::General, 1.0, Span::PLACEHOLDER)
-                        .with_argument(Word::new(
-                            settings.plane.axis_3().main_name(),
-                            config.lift_config.calcute_height(start_height),
-                            Span::PLACEHOLDER,
-                        ))
-                        .with_argument(Word::new(
-                            'F',
-                            config.swivel_feedrate / settings.unit_factor(),
-                            Span::PLACEHOLDER,
-                        )),
-                );
-                let center_offset = (Vec3::unit_angle(from_angle + PI, &settings.plane)
-                    * config.knife_offset)
-                    .coords_for_plane(&settings.plane);
-                let target = (Vec3::unit_angle(to_angle, &settings.plane) * config.knife_offset
-                    + next.start_pos())
-                .coords_for_plane(&settings.plane);
-                out.push(
-                    GCode::new(
-                        Mnemonic::General,
-                        if signed_angle > 0. { 2.0 } else { 3.0 },
-                        Span::PLACEHOLDER,
-                    )
-                    .with_argument(Word::new(
-                        settings.plane.axis_1().main_name(),
-                        target.0,
-                        Span::PLACEHOLDER,
-                    ))
-                    .with_argument(Word::new(
-                        settings.plane.axis_2().main_name(),
-                        target.1,
-                        Span::PLACEHOLDER,
-                    ))
-                    .with_argument(Word::new(
-                        settings.plane.axis_1().center_name(),
-                        center_offset.0,
-                        Span::PLACEHOLDER,
-                    ))
-                    .with_argument(Word::new(
-                        settings.plane.axis_2().center_name(),
-                        center_offset.1,
-                        Span::PLACEHOLDER,
-                    )),
-                );
-                out.push(
-                    GCode::new(Mnemonic::General, 1.0, Span::PLACEHOLDER).with_argument(Word::new(
-                        settings.plane.axis_3().main_name(),
-                        start_height,
-                        Span::PLACEHOLDER,
-                    )),
+            if signed_angle.abs() > config.angle_threshold(signed_angle) {
+                return Command::create_swivel_path(
+                    from_angle,
+                    to_angle,
+                    signed_angle,
+                    next,
+                    settings,
+                    state,
+                    config,
                 );
-                state.next_feedrate = Some(settings.feedrate / settings.unit_factor());
-                return out;
             }
         } else if let (None, Some(to_angle)) = (previous_angle, next.start_angle()) {
             // We need to add an initial offset to compensate for the length offset.
             let mut out = vec![];
             let offset_pos = Vec3::unit_angle(to_angle, &settings.plane) * config.knife_offset
                 + next.start_pos();
-            let offset_pos = offset_pos.coords_for_plane(&settings.plane);
-            out.push(
-                GCode::new(Mnemonic::General, 1.0, Span::PLACEHOLDER)
-                    .with_argument(Word::new(
-                        settings.plane.axis_1().main_name(),
-                        offset_pos.0,
-                        Span::PLACEHOLDER,
+            let offset_pos = settings
+                .to_program_coords(offset_pos)
+                .coords_for_plane(&settings.plane);
+            let mut offset_move = Command::plane_move(1.0, offset_pos, Span::PLACEHOLDER, settings);
+            if settings.feed_mode == FeedMode::InverseTime {
+                offset_move
+                    .push_argument(settings.feedrate_word(
+                        config.effective_swivel_feedrate(settings),
+                        config.knife_offset,
                     ))
-                    .with_argument(Word::new(
-                        settings.plane.axis_2().main_name(),
-                        offset_pos.1,
-                        Span::PLACEHOLDER,
-                    )),
-            );
+                    .unwrap();
+            }
+            out.push(FixedGCode::inserted(offset_move, "knife offset"));
             return out;
         }
         vec![]
     }
 
+    /// Lifts the knife, swivels it around the corner and lowers it again.
+    fn create_swivel_path(
+        from_angle: f64,
+        to_angle: f64,
+        signed_angle: f64,
+        next: &Command<'a>,
+        settings: &GCodeState,
+        state: &mut DragknifeState,
+        config: &DragknifeConfig,
+    ) -> Vec<FixedGCode> {
+        if config.is_reversal(signed_angle) {
+            return Command::create_reversal_path(
+                from_angle,
+                to_angle,
+                signed_angle,
+                next,
+                settings,
+                state,
+                config,
+            );
+        }
+        let unit = settings.unit_factor();
+        let start_height = settings
+            .to_work_coords(next.start_pos())
+            .third_coord(&settings.plane);
+        let inverse_time = settings.feed_mode == FeedMode::InverseTime;
+        let lift_height = config.lift_config.calcute_height(start_height);
+        let lift_feedrate = settings.feedrate_word(
+            config.effective_swivel_feedrate(settings),
+            (lift_height - start_height).abs(),
+        );
+        let mut out = Command::lift_knife(
+            lift_height,
+            lift_feedrate,
+            "lift for swivel",
+            settings,
+            state,
+            config,
+        );
+        let center_offset = (Vec3::unit_angle(from_angle + PI, &settings.plane)
+            * (config.knife_offset / unit))
+            .coords_for_plane(&settings.plane);
+        let target = settings
+            .to_program_coords(
+                Vec3::unit_angle(to_angle, &settings.plane) * config.knife_offset
+                    + next.start_pos(),
+            )
+            .coords_for_plane(&settings.plane);
+        let mut swivel = Command::plane_arc(
+            if signed_angle > 0. { 2.0 } else { 3.0 },
+            target,
+            center_offset,
+            Span::PLACEHOLDER,
+            settings,
+        );
+        if inverse_time || out.iter().all(|g| g.gcode.value('F').is_none()) {
+            // The custom lift did not set the swivel feedrate, or every move needs its own
+            swivel
+                .push_argument(settings.feedrate_word(
+                    config.effective_swivel_feedrate(settings),
+                    config.knife_offset * signed_angle.abs(),
+                ))
+                .unwrap();
+        }
+        out.push(FixedGCode::inserted(
+            swivel,
+            format!("swivel: {:.0}° corner", signed_angle.abs().to_degrees()),
+        ));
+        out.extend(Command::lower_knife(
+            start_height,
+            inverse_time.then_some(lift_feedrate),
+            "lower after swivel",
+            settings,
+            state,
+            config,
+        ));
+        if config.swivel_dwell > 0. {
+            out.push(FixedGCode::inserted(
+                GCode::new(Mnemonic::General, 4.0, Span::PLACEHOLDER).with_argument(word(
+                    'P',
+                    config.swivel_dwell,
+                    Span::PLACEHOLDER,
+                )),
+                "dwell after swivel",
+            ));
+        }
+        state.next_feedrate = Some(settings.feedrate);
+        out
+    }
+
+    /// Turns the blade around beside the cut instead of over it: the knife is lifted to
+    /// `reversal_lift`, moved to the side away from the corner, lowered to the swivel height and
+    /// swiveled by half a turn there, then lifted again, moved over the start of the next cut and
+    /// lowered. A swivel at the corner would sweep the blade across the cut it just made.
+    fn create_reversal_path(
+        from_angle: f64,
+        to_angle: f64,
+        signed_angle: f64,
+        next: &Command<'a>,
+        settings: &GCodeState,
+        state: &mut DragknifeState,
+        config: &DragknifeConfig,
+    ) -> Vec<FixedGCode> {
+        let start_height = settings
+            .to_work_coords(next.start_pos())
+            .third_coord(&settings.plane);
+        let lift_height = config.reversal_lift.calcute_height(start_height);
+        let swivel_height = config.lift_config.calcute_height(start_height);
+        let swivel_feedrate = config.effective_swivel_feedrate(settings);
+        let lift_feedrate =
+            settings.feedrate_word(swivel_feedrate, (lift_height - start_height).abs());
+        let turn_feedrate =
+            settings.feedrate_word(swivel_feedrate, (lift_height - swivel_height).abs());
+        let mut out = Command::lift_knife(
+            lift_height,
+            lift_feedrate,
+            "lift for reversal",
+            settings,
+            state,
+            config,
+        );
+        let lift_sets_feedrate = out.iter().any(|g| g.gcode.value('F').is_some());
+        let inverse_time = settings.feed_mode == FeedMode::InverseTime;
+        // Right turns are positive, so the side away from the next cut is on the left
+        let side = if signed_angle > 0. { PI / 2. } else { -PI / 2. };
+        let direction = Vec3::unit_angle(from_angle, &settings.plane) * config.knife_offset;
+        let pivot = Vec3::unit_angle(from_angle + side, &settings.plane)
+            * (REVERSAL_PIVOT * config.knife_offset)
+            + next.start_pos();
+        let program_coords = |pos: Vec3| {
+            settings
+                .to_program_coords(pos)
+                .coords_for_plane(&settings.plane)
+        };
+        out.push(FixedGCode::inserted(
+            Command::plane_move(
+                0.0,
+                program_coords(pivot + direction),
+                Span::PLACEHOLDER,
+                settings,
+            ),
+            format!("reversal: {:.0}° corner", signed_angle.abs().to_degrees()),
+        ));
+        out.push(FixedGCode::inserted(
+            Command::height_move(
+                swivel_height,
+                (inverse_time || !lift_sets_feedrate).then_some(turn_feedrate),
+                settings,
+            ),
+            "lower to turn the blade",
+        ));
+        // Around the far side of the pivot, so the blade does not sweep toward the cut
+        let center_offset =
+            (direction * -(1. / settings.unit_factor())).coords_for_plane(&settings.plane);
+        let mut turn = Command::plane_arc(
+            if signed_angle > 0. { 3.0 } else { 2.0 },
+            program_coords(pivot - direction),
+            center_offset,
+            Span::PLACEHOLDER,
+            settings,
+        );
+        if inverse_time {
+            turn.push_argument(settings.feedrate_word(swivel_feedrate, config.knife_offset * PI))
+                .unwrap();
+        }
+        out.push(FixedGCode::inserted(turn, "turn the blade around"));
+        out.push(FixedGCode::inserted(
+            Command::height_move(lift_height, inverse_time.then_some(turn_feedrate), settings),
+            "lift after turning the blade",
+        ));
+        let target =
+            Vec3::unit_angle(to_angle, &settings.plane) * config.knife_offset + next.start_pos();
+        out.push(FixedGCode::inserted(
+            Command::plane_move(0.0, program_coords(target), Span::PLACEHOLDER, settings),
+            "move to the next cut",
+        ));
+        out.extend(Command::lower_knife(
+            start_height,
+            inverse_time.then_some(lift_feedrate),
+            "lower after reversal",
+            settings,
+            state,
+            config,
+        ));
+        state.next_feedrate = Some(settings.feedrate);
+        out
+    }
+
+    /// Moves the knife up to `height` (in work coordinates), or runs the custom lift code.
+    fn lift_knife(
+        height: f64,
+        feedrate: Word,
+        reason: &str,
+        settings: &GCodeState,
+        state: &mut DragknifeState,
+        config: &DragknifeConfig,
+    ) -> Vec<FixedGCode> {
+        let lift_template = state.template_gcode(&config.lift_template);
+        if !lift_template.is_empty() {
+            return lift_template
+                .iter()
+                .map(|g| FixedGCode::inserted(g.clone(), reason))
+                .collect();
+        }
+        vec![FixedGCode::inserted(
+            Command::height_move(height, Some(feedrate), settings),
+            reason,
+        )]
+    }
+
+    /// Moves the knife back down to `height` (in work coordinates), or runs the custom lower
+    /// code.
+    fn lower_knife(
+        height: f64,
+        feedrate: Option<Word>,
+        reason: &str,
+        settings: &GCodeState,
+        state: &mut DragknifeState,
+        config: &DragknifeConfig,
+    ) -> Vec<FixedGCode> {
+        let lower_template = state.template_gcode(&config.lower_template);
+        if !lower_template.is_empty() {
+            return lower_template
+                .iter()
+                .map(|g| FixedGCode::inserted(g.clone(), reason))
+                .collect();
+        }
+        vec![FixedGCode::inserted(
+            Command::height_move(height, feedrate, settings),
+            reason,
+        )]
+    }
+
+    /// A feed move to `height` in work coordinates.
+    fn height_move(height: f64, feedrate: Option<Word>, settings: &GCodeState) -> GCode {
+        let mut gcode = GCode::new(Mnemonic::General, 1.0, Span::PLACEHOLDER).with_argument(word(
+            settings.plane.axis_3().main_name(),
+            height / settings.unit_factor(),
+            Span::PLACEHOLDER,
+        ));
+        if let Some(feedrate) = feedrate {
+            gcode.push_argument(feedrate).unwrap();
+        }
+        gcode
+    }
+
+    /// A move to `target` in the active plane, in program coordinates. There is room for the
+    /// arc center, height and feedrate, so adding them does not reallocate.
+    fn plane_move(number: f32, target: (f64, f64), span: Span, settings: &GCodeState) -> GCode {
+        let mut gcode =
+            GCode::new_with_argument_buffer(Mnemonic::General, number, span, Vec::with_capacity(6));
+        gcode.extend([
+            word(
+                settings.plane.axis_1().main_name(),
+                target.0,
+                Span::PLACEHOLDER,
+            ),
+            word(
+                settings.plane.axis_2().main_name(),
+                target.1,
+                Span::PLACEHOLDER,
+            ),
+        ]);
+        gcode
+    }
+
+    /// An arc to `target` around `center_offset`, which is relative to the start of the arc.
+    fn plane_arc(
+        number: f32,
+        target: (f64, f64),
+        center_offset: (f64, f64),
+        span: Span,
+        settings: &GCodeState,
+    ) -> GCode {
+        let mut gcode = Command::plane_move(number, target, span, settings);
+        gcode.extend([
+            word(
+                settings.plane.axis_1().center_name(),
+                center_offset.0,
+                Span::PLACEHOLDER,
+            ),
+            word(
+                settings.plane.axis_2().center_name(),
+                center_offset.1,
+                Span::PLACEHOLDER,
+            ),
+        ]);
+        gcode
+    }
+
     fn add_misc_args_and_update_settings(
         new_gcode: &mut GCode,
         command: &Command,
         state: &mut DragknifeState,
         settings: &mut GCodeState,
     ) {
-        if command.update_settings(settings) {
+        let feedrate_changed = command.update_settings(settings);
+        if settings.feed_mode == FeedMode::InverseTime {
+            // Every move has its own feedrate, which is kept as it is
+            state.next_feedrate = None;
+            if let Some(feedrate) = command.original().value('F') {
+                new_gcode
+                    .push_argument(word('F', feedrate, Span::PLACEHOLDER))
+                    .unwrap();
+            }
+        } else if feedrate_changed {
             state.next_feedrate = None;
             new_gcode
-                .push_argument(Word::new(
+                .push_argument(word(
                     'F',
                     settings.feedrate / settings.unit_factor(),
                     Span::PLACEHOLDER,
@@ -391,7 +1120,7 @@ impl<'a> Command<'a> {
         } else if let Some(feedrate) = state.next_feedrate {
             state.next_feedrate = None;
             new_gcode
-                .push_argument(Word::new(
+                .push_argument(word(
                     'F',
                     feedrate / settings.unit_factor(),
                     Span::PLACEHOLDER,
@@ -399,7 +1128,12 @@ impl<'a> Command<'a> {
                 .unwrap();
         }
         let plane = settings.plane;
+        // Arcs are written with their center, even if the input gave a radius
+        let is_arc = matches!(command.original().major_number(), 2 | 3);
         for arg in command.original().arguments() {
+            if is_arc && arg.letter == 'R' {
+                continue;
+            }
             if ![
                 plane.axis_1().main_name(),
                 plane.axis_2().main_name(),
@@ -409,12 +1143,26 @@ impl<'a> Command<'a> {
             ]
             .contains(&arg.letter)
             {
-                new_gcode.push_argument(*arg).unwrap();
+                if arg.letter == plane.axis_3().main_name() {
+                    // Written in absolute coordinates, like the other axes
+                    let value = settings
+                        .to_program_coords(command.end_pos())
+                        .third_coord(&plane);
+                    new_gcode
+                        .push_argument(word(arg.letter, value, arg.span))
+                        .unwrap();
+                } else if let Some(axis) = ROTARY_AXES.iter().position(|&a| a == arg.letter) {
+                    new_gcode
+                        .push_argument(word(arg.letter, settings.rotary[axis], arg.span))
+                        .unwrap();
+                } else {
+                    new_gcode.push_argument(*arg).unwrap();
+                }
             }
         }
     }
 }
 
-fn signed_angle(a: f32, b: f32) -> f32 {
-    (a - b + std::f32::consts::PI).rem_euclid(std::f32::consts::TAU) - std::f32::consts::PI
+fn signed_angle(a: f64, b: f64) -> f64 {
+    (a - b + std::f64::consts::PI).rem_euclid(std::f64::consts::TAU) - std::f64::consts::PI
 }
@@ -5,7 +5,6 @@ use dragknife_repath::app::DragknifeApp;
 
 #[cfg(not(target_arch = "wasm32"))]
 fn main() -> eframe::Result<()> {
-
     tracing_subscriber::fmt::init();
 
     let native_options = eframe::NativeOptions::default();
@@ -28,7 +27,7 @@ fn main() {
         eframe::start_web(
             "the_canvas_id", // hardcode it
             web_options,
-            Box::new(|cc| Box::new(eframe_template::TemplateApp::new(cc))),
+            Box::new(|cc| Box::new(DragknifeApp::new(cc))),
         )
         .await
         .expect("failed to start eframe");
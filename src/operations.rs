@@ -0,0 +1,216 @@
+use std::borrow::Cow;
+
+use gcode::{GCode, Mnemonic, Word};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    input::{parse_gcode, InputProgram, SourceNote},
+    output::{command_number, source_line, update_plane},
+    types::{DragknifeConfig, GCodePlane, LiftConfig},
+    DragknifePath,
+};
+
+/// The marker used if `DragknifeConfig::operation_marker` is empty. It matches comments like
+/// `(Layer: crease)` or `(operation = cut)`, as written by most CAD programs for packaging.
+pub const DEFAULT_OPERATION_MARKER: &str = r"(?i)\b(?:layer|operation)\s*[:=]?\s*([\w.-]+)";
+
+/// A part of the program which is cut with other settings than the rest, e.g. crease lines made
+/// with a creasing wheel at part depth in a program which also cuts with the knife.
+///
+/// An operation starts at a comment matching `DragknifeConfig::operation_marker` with its name,
+/// and lasts until the next marker. Settings which are not given are those of the knife.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Operation {
+    /// The name in the marker comments, e.g. `crease`. Compared ignoring case.
+    pub name: String,
+    /// Feed moves below this height are raised to it, e.g. to press a creasing wheel in
+    /// partway. In mm in work coordinates. Only moves in absolute coordinates are changed.
+    pub depth: Option<f64>,
+    /// Lines written before the first cut of the operation, e.g. `M0` to swap in the creasing
+    /// wheel by hand or `T2 M6`. Not written if the cuts before it were of the same operation.
+    pub tool_change: String,
+    pub knife_offset: Option<f64>,
+    pub sharp_angle_threshold: Option<f64>,
+    pub swivel_feedrate: Option<f64>,
+    pub lift_config: Option<LiftConfig>,
+    pub lift_template: Option<String>,
+    pub lower_template: Option<String>,
+}
+
+impl Operation {
+    /// The knife config for this operation: `config` with the settings this operation gives.
+    pub fn apply(&self, config: &DragknifeConfig) -> DragknifeConfig {
+        let mut config = DragknifeConfig {
+            operations: vec![],
+            ..config.clone()
+        };
+        if let Some(knife_offset) = self.knife_offset {
+            config.knife_offset = knife_offset;
+        }
+        if let Some(threshold) = self.sharp_angle_threshold {
+            config.sharp_angle_threshold = threshold;
+            config.right_turn_threshold = None;
+        }
+        if let Some(swivel_feedrate) = self.swivel_feedrate {
+            config.swivel_feedrate = swivel_feedrate;
+            config.swivel_feedrate_percentage = None;
+        }
+        if let Some(lift_config) = self.lift_config {
+            config.lift_config = lift_config;
+        }
+        if let Some(template) = &self.lift_template {
+            config.lift_template = template.clone();
+        }
+        if let Some(template) = &self.lower_template {
+            config.lower_template = template.clone();
+        }
+        config
+    }
+
+    pub fn tool_change_gcode(&self) -> Vec<GCode> {
+        parse_gcode(&self.tool_change)
+    }
+}
+
+/// A marker comment, where an operation starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationStart {
+    /// Zero-based input line of the comment.
+    pub line: usize,
+    /// Index into `DragknifeConfig::operations`. `None` for a name which has no settings, which
+    /// is cut with those of the knife.
+    pub operation: Option<usize>,
+}
+
+/// Finds the marker comments in `program`. None are looked for if `config` has no operations.
+pub fn find_operations(
+    program: &InputProgram,
+    config: &DragknifeConfig,
+) -> Result<Vec<OperationStart>, regex::Error> {
+    if config.operations.is_empty() {
+        return Ok(vec![]);
+    }
+    let marker = match config.operation_marker.as_str() {
+        "" => DEFAULT_OPERATION_MARKER,
+        marker => marker,
+    };
+    let marker = Regex::new(marker)?;
+    let mut starts: Vec<_> = program
+        .notes
+        .iter()
+        .filter_map(|note| {
+            let SourceNote::Comment { line, text, .. } = note else {
+                return None;
+            };
+            let captures = marker.captures(text)?;
+            // The first group is the name, or the whole match without one
+            let name = captures.get(1).or(captures.get(0))?.as_str().trim();
+            Some(OperationStart {
+                line: *line,
+                operation: config
+                    .operations
+                    .iter()
+                    .position(|operation| operation.name.eq_ignore_ascii_case(name)),
+            })
+        })
+        .collect();
+    starts.sort_by_key(|start| start.line);
+    Ok(starts)
+}
+
+/// The operation of input line `line`: that of the last marker on or before it.
+pub fn operation_at(starts: &[OperationStart], line: usize) -> Option<usize> {
+    let before = starts.partition_point(|start| start.line <= line);
+    starts[..before].last().and_then(|start| start.operation)
+}
+
+/// Raises the feed moves of operations with a `depth` to it. `gcodes` are returned as they are
+/// if no operation has a depth.
+pub fn apply_depths<'a>(
+    gcodes: &'a [GCode],
+    starts: &[OperationStart],
+    config: &DragknifeConfig,
+) -> Cow<'a, [GCode]> {
+    if !starts
+        .iter()
+        .filter_map(|start| start.operation)
+        .any(|operation| config.operations[operation].depth.is_some())
+    {
+        return Cow::Borrowed(gcodes);
+    }
+    let mut plane = GCodePlane::default();
+    let mut absolute = true;
+    let mut unit = 1.;
+    let mut out = Vec::with_capacity(gcodes.len());
+    for gcode in gcodes {
+        update_plane(&mut plane, gcode);
+        if gcode.mnemonic() == Mnemonic::General && gcode.minor_number() == 0 {
+            match gcode.major_number() {
+                20 => unit = 25.4,
+                21 => unit = 1.,
+                90 => absolute = true,
+                91 => absolute = false,
+                _ => {}
+            }
+        }
+        let depth = source_line(gcode.span())
+            .and_then(|line| operation_at(starts, line))
+            .and_then(|operation| config.operations[operation].depth);
+        let height_name = plane.axis_3().main_name();
+        let is_feed = gcode.mnemonic() == Mnemonic::General
+            && matches!(gcode.major_number(), 1..=3)
+            && gcode.minor_number() == 0;
+        let (Some(depth), true, true) = (depth, is_feed, absolute) else {
+            out.push(gcode.clone());
+            continue;
+        };
+        let depth = (depth / unit) as f32;
+        let mut new = GCode::new(gcode.mnemonic(), command_number(gcode), gcode.span());
+        for arg in gcode.arguments() {
+            let mut arg: Word = *arg;
+            if arg.letter == height_name && arg.value < depth {
+                arg.value = depth;
+            }
+            new.push_argument(arg).unwrap();
+        }
+        out.push(new);
+    }
+    Cow::Owned(out)
+}
+
+/// The knife config of every operation, made once for a whole path.
+pub struct OperationConfigs<'c> {
+    knife: &'c DragknifeConfig,
+    operations: Vec<DragknifeConfig>,
+}
+
+impl<'c> OperationConfigs<'c> {
+    pub fn new(config: &'c DragknifeConfig) -> Self {
+        OperationConfigs {
+            knife: config,
+            operations: config
+                .operations
+                .iter()
+                .map(|operation| operation.apply(config))
+                .collect(),
+        }
+    }
+
+    /// The config of an operation, or of the knife for `None`.
+    pub fn get(&self, operation: Option<usize>) -> &DragknifeConfig {
+        match operation {
+            Some(operation) => &self.operations[operation],
+            None => self.knife,
+        }
+    }
+}
+
+impl<'a> DragknifePath<'a> {
+    /// The operation of the command at `index` in `commands`, see [`find_operations`].
+    pub fn operation_of(&self, index: usize) -> Option<usize> {
+        source_line(self.commands[index].original().span())
+            .and_then(|line| operation_at(&self.operations, line))
+    }
+}
@@ -0,0 +1,2492 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    collections::HashMap,
+    error::Error,
+    f64::consts::{FRAC_PI_2, PI, TAU},
+    fmt::{Display, Write},
+    str::FromStr,
+};
+
+use gcode::{GCode, Mnemonic, Span, Word};
+use serde::{Deserialize, Serialize};
+
+use crate::compensation::CompensationWarning;
+use crate::contours::ContourReport;
+use crate::crossings::SwivelCrossing;
+use crate::dialect::{deserialize_dialect, serialize_dialect, ArcStyle, Dialect};
+use crate::height_map::HeightMap;
+use crate::input::{InputProgram, ParseIssue, SourceNote};
+use crate::types::{DegenerateArc, FixedGCode, GCodePlane, ROTARY_AXES};
+use crate::validate::ValidationWarning;
+
+/// Removes commands or words from the output.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum OutputFilter {
+    /// Drops whole commands, e.g. `M3`.
+    Code { letter: char, major_number: u32 },
+    /// Drops single words from all commands, e.g. `S`.
+    Word(char),
+}
+
+impl OutputFilter {
+    pub fn matches_code(&self, gcode: &GCode) -> bool {
+        match self {
+            OutputFilter::Code {
+                letter,
+                major_number,
+            } => {
+                *letter == mnemonic_letter(gcode.mnemonic())
+                    && *major_number == gcode.major_number()
+            }
+            OutputFilter::Word(_) => false,
+        }
+    }
+
+    pub fn matches_word(&self, letter: char) -> bool {
+        match self {
+            OutputFilter::Code { .. } => false,
+            OutputFilter::Word(word) => *word == letter,
+        }
+    }
+
+    /// Parses a comma or whitespace separated list such as `M3, M7, M8, S`.
+    pub fn parse_list(text: &str) -> Result<Vec<OutputFilter>, FilterParseError> {
+        text.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|part| !part.is_empty())
+            .map(OutputFilter::from_str)
+            .collect()
+    }
+
+    pub fn format_list(filters: &[OutputFilter]) -> String {
+        filters
+            .iter()
+            .map(|f| f.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl FromStr for OutputFilter {
+    type Err = FilterParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        let letter = chars
+            .next()
+            .filter(|c| c.is_ascii_alphabetic())
+            .ok_or_else(|| FilterParseError(s.to_string()))?
+            .to_ascii_uppercase();
+        let number = chars.as_str();
+        if number.is_empty() {
+            return Ok(OutputFilter::Word(letter));
+        }
+        if Mnemonic::for_letter(letter).is_none() {
+            return Err(FilterParseError(s.to_string()));
+        }
+        let major_number = number
+            .parse()
+            .map_err(|_| FilterParseError(s.to_string()))?;
+        Ok(OutputFilter::Code {
+            letter,
+            major_number,
+        })
+    }
+}
+
+impl Display for OutputFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFilter::Code {
+                letter,
+                major_number,
+            } => write!(f, "{letter}{major_number}"),
+            OutputFilter::Word(letter) => write!(f, "{letter}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FilterParseError(pub String);
+
+impl Display for FilterParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid filter \"{}\": expected a command like M3 or a word letter like S.",
+            self.0
+        )
+    }
+}
+
+impl Error for FilterParseError {}
+
+/// Settings for turning the fixed gcode into the final program text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputConfig {
+    pub filters: Vec<OutputFilter>,
+    /// Insert `M3` after lowering the knife and `M5` before lifting it.
+    pub gate_knife: bool,
+    /// Keep the knife above `dry_run_height` and remove spindle/knife activation codes.
+    pub dry_run: bool,
+    /// In mm.
+    pub dry_run_height: f32,
+    /// Warn about rapid moves below `clearance_height`.
+    pub check_clearance: bool,
+    /// In mm.
+    pub clearance_height: f32,
+    /// Lift the knife to `clearance_height` for rapid moves below it.
+    pub enforce_clearance: bool,
+    /// Warn about moves which leave `soft_limits`, including the bulge of arcs.
+    pub check_soft_limits: bool,
+    pub soft_limits: SoftLimits,
+    /// Warn about feedrates above `max_feedrate`, in the input or computed for swivels.
+    pub check_max_feedrate: bool,
+    /// The fastest feedrate the machine accepts, in mm/min.
+    pub max_feedrate: f32,
+    /// Lower feedrates above `max_feedrate` to it.
+    pub clamp_feedrate: bool,
+    /// Leave out comments and blank lines from the input.
+    pub strip_comments: bool,
+    /// Number every line with an N word, starting at `line_number_start`.
+    pub line_numbers: bool,
+    pub line_number_start: u32,
+    pub line_number_step: u32,
+    /// Append a `*nn` XOR checksum to every numbered line, for Marlin/RepRap style firmware.
+    /// Implies `line_numbers`.
+    pub checksums: bool,
+    /// Write numbers with a fixed number of decimals instead of the shortest exact representation.
+    pub format_numbers: bool,
+    /// Decimals for feedrates (`F`).
+    pub feedrate_decimals: usize,
+    /// Decimals for all other words, e.g. coordinates.
+    pub coordinate_decimals: usize,
+    /// Remove trailing zeros after the decimal point.
+    pub trim_zeros: bool,
+    /// Warn about lines longer than `max_line_length`, after removing their comments.
+    pub limit_line_length: bool,
+    pub max_line_length: usize,
+    /// Write moves relative to the previous position (`G91`) instead of in absolute coordinates.
+    pub incremental: bool,
+    /// Write arc centers as absolute coordinates (`G90.1`) instead of relative to the start of
+    /// the arc.
+    pub absolute_arc_centers: bool,
+    /// Write arcs with a radius (`R`) instead of their center, for controllers which only
+    /// support that. Overrides `absolute_arc_centers`.
+    pub radius_arcs: bool,
+    /// Lines written before the program. See [`OutputConfig::expand_template`] for placeholders.
+    pub header: String,
+    /// Lines written after the program, like `header`.
+    pub footer: String,
+    /// Add a comment with the reason to every inserted line, e.g. `(lift for swivel)`.
+    pub annotate: bool,
+    /// Write the input line as a comment above every move which was changed.
+    pub original_comments: bool,
+    /// Copy commands which were not changed from the input exactly as they were written. Ignored
+    /// with `format_numbers`.
+    pub keep_original_text: bool,
+    /// Write the commands copied by `keep_original_text` in uppercase and spaced like the rest of
+    /// the output, keeping the digits of their numbers.
+    pub normalize_original_text: bool,
+    pub line_ending: LineEnding,
+    /// Replace all characters which are not ASCII, e.g. in comments.
+    pub ascii_only: bool,
+    /// Start and end the program with a `%` line, even if the input does not. `%` lines at the
+    /// start and end of the input are always kept.
+    pub percent_delimiters: bool,
+    /// The controller the program is written for. Settings it does not support, like absolute
+    /// arc centers for GRBL, are ignored. Built-in dialects are saved by their name.
+    #[serde(
+        serialize_with = "serialize_dialect",
+        deserialize_with = "deserialize_dialect"
+    )]
+    pub dialect: Dialect,
+    /// Write arcs as lines, for controllers without arcs, like Marlin without `ARC_SUPPORT`.
+    pub arcs_as_lines: bool,
+    /// How far the lines may stray from the arc, in mm.
+    pub arc_tolerance: f32,
+    /// The materials programs can be cut in, with the depth to cut each at.
+    pub materials: Vec<MaterialDepth>,
+    /// The name of the material in `materials` to cut, empty to cut at the depths of the
+    /// program.
+    pub material: String,
+    /// Probe the surface before the first cut and make it Z zero, see
+    /// [`OutputConfig::apply_probing`].
+    pub probe_start: bool,
+    /// Probe again before every cut which follows a rapid move, for material which varies in
+    /// thickness.
+    pub probe_contours: bool,
+    /// The feedrate to probe at, in mm/min.
+    pub probe_feedrate: f32,
+    /// How far below the work zero to probe before the controller gives up, in mm.
+    pub probe_depth: f32,
+    /// The height to retract to after probing, in mm above the surface.
+    pub probe_clearance: f32,
+    /// Offsets over the bed added to every height, for a table which is not flat. Arcs are
+    /// written as lines to follow it.
+    pub height_map: Option<HeightMap>,
+    /// The longest move written with a height map, in mm. Longer moves are split.
+    pub height_map_segment: f32,
+    /// Also write the program as separate files, split at every tool change, pause and change
+    /// of operation, for controllers which can not pause for a tool swap. See
+    /// [`ProgramOutput::sections`].
+    pub split_files: bool,
+}
+
+/// The depth to cut a material at, for programs written for a nominal depth. Heights below the
+/// work zero are scaled by `depth / nominal_depth`, so with a nominal depth of 1 mm and a depth
+/// of 0.6 mm for 0.6 mm vinyl, `Z-1` becomes `Z-0.6` and `Z-0.5` becomes `Z-0.3`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaterialDepth {
+    pub name: String,
+    /// The depth of the cuts in the programs, in mm below the work zero.
+    pub nominal_depth: f32,
+    /// The depth to cut this material at instead, in mm below the work zero.
+    pub depth: f32,
+}
+
+impl Default for MaterialDepth {
+    fn default() -> Self {
+        MaterialDepth {
+            name: String::new(),
+            nominal_depth: 1.,
+            depth: 1.,
+        }
+    }
+}
+
+/// The envelope the machine can move in, in mm from the zero of the program for X, Y and Z.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SoftLimits {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+impl Default for SoftLimits {
+    fn default() -> Self {
+        SoftLimits {
+            min: [0., 0., -50.],
+            max: [300., 300., 50.],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum LineEnding {
+    /// `\n`, used on Linux and Mac
+    #[default]
+    Lf,
+    /// `\r\n`, used on Windows and by some older controllers
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            filters: vec![],
+            gate_knife: false,
+            dry_run: false,
+            dry_run_height: 5.0,
+            check_clearance: false,
+            clearance_height: 5.0,
+            enforce_clearance: false,
+            check_soft_limits: false,
+            soft_limits: Default::default(),
+            check_max_feedrate: false,
+            max_feedrate: 5000.,
+            clamp_feedrate: false,
+            strip_comments: false,
+            line_numbers: false,
+            line_number_start: 10,
+            line_number_step: 10,
+            checksums: false,
+            format_numbers: false,
+            feedrate_decimals: 1,
+            coordinate_decimals: 4,
+            trim_zeros: true,
+            limit_line_length: false,
+            max_line_length: 80,
+            incremental: false,
+            absolute_arc_centers: false,
+            radius_arcs: false,
+            header: String::new(),
+            footer: String::new(),
+            annotate: false,
+            original_comments: false,
+            keep_original_text: true,
+            normalize_original_text: false,
+            line_ending: LineEnding::Lf,
+            ascii_only: false,
+            percent_delimiters: false,
+            dialect: Dialect::default(),
+            arcs_as_lines: false,
+            arc_tolerance: 0.01,
+            materials: vec![],
+            material: String::new(),
+            probe_start: false,
+            probe_contours: false,
+            probe_feedrate: 50.,
+            probe_depth: 5.,
+            probe_clearance: 2.,
+            height_map: None,
+            height_map_segment: 5.,
+            split_files: false,
+        }
+    }
+}
+
+impl OutputConfig {
+    /// Removes filtered commands and words.
+    pub fn apply_filters(&self, gcodes: Vec<FixedGCode>) -> Vec<FixedGCode> {
+        if self.filters.is_empty() {
+            return gcodes;
+        }
+        gcodes
+            .into_iter()
+            .filter(|g| !self.filters.iter().any(|f| f.matches_code(&g.gcode)))
+            .map(|g| {
+                g.map(|g| {
+                    if !g
+                        .arguments()
+                        .iter()
+                        .any(|arg| self.filters.iter().any(|f| f.matches_word(arg.letter)))
+                    {
+                        return g;
+                    }
+                    let mut new = GCode::new(g.mnemonic(), command_number(&g), g.span());
+                    for arg in g.arguments() {
+                        if !self.filters.iter().any(|f| f.matches_word(arg.letter)) {
+                            new.push_argument(*arg).unwrap();
+                        }
+                    }
+                    new
+                })
+            })
+            .collect()
+    }
+
+    /// The entry of `material` in `materials`, ignoring case, if one is chosen.
+    pub fn material_depth(&self) -> Option<&MaterialDepth> {
+        if self.material.is_empty() {
+            return None;
+        }
+        self.materials
+            .iter()
+            .find(|material| material.name.eq_ignore_ascii_case(&self.material))
+    }
+
+    /// Scales the heights below the work zero to the depth of the chosen material, see
+    /// [`MaterialDepth`]. Nothing is changed if no material is chosen, nor in incremental mode.
+    pub fn apply_material_depth(&self, gcodes: Vec<FixedGCode>) -> Vec<FixedGCode> {
+        let Some(material) = self.material_depth() else {
+            return gcodes;
+        };
+        let scale = material.depth / material.nominal_depth;
+        if !scale.is_finite() || scale == 1. {
+            return gcodes;
+        }
+        let mut plane = GCodePlane::default();
+        let mut absolute = true;
+        gcodes
+            .into_iter()
+            .map(|g| {
+                update_plane(&mut plane, &g.gcode);
+                if g.gcode.mnemonic() == Mnemonic::General && g.gcode.minor_number() == 0 {
+                    match g.gcode.major_number() {
+                        90 => absolute = true,
+                        91 => absolute = false,
+                        _ => {}
+                    }
+                }
+                let height_name = plane.axis_3().main_name();
+                if !absolute
+                    || g.gcode.mnemonic() != Mnemonic::General
+                    || g.gcode.major_number() > 3
+                    || g.gcode.value_for(height_name).is_none_or(|h| h >= 0.)
+                {
+                    return g;
+                }
+                g.map(|g| {
+                    let mut new = GCode::new(g.mnemonic(), command_number(&g), g.span());
+                    for arg in g.arguments() {
+                        let mut arg = *arg;
+                        if arg.letter == height_name && arg.value < 0. {
+                            arg.value *= scale;
+                        }
+                        new.push_argument(arg).unwrap();
+                    }
+                    new
+                })
+            })
+            .collect()
+    }
+
+    /// Removes the commands and words the controller of `dialect` does not accept, with a warning
+    /// for each. Dwell times in seconds are written with the word of the dialect.
+    pub fn apply_dialect(
+        &self,
+        gcodes: Vec<FixedGCode>,
+        warnings: &mut Vec<OutputWarning>,
+    ) -> Vec<FixedGCode> {
+        let dialect = &self.dialect;
+        if !dialect.changes_commands() {
+            return gcodes;
+        }
+        let mut unsupported = |gcode: &GCode, code: String| {
+            warnings.push(OutputWarning::Unsupported {
+                line: source_line(gcode.span()),
+                code,
+                dialect: dialect.name.clone(),
+            })
+        };
+        let mut out = Vec::with_capacity(gcodes.len());
+        let dwell_word = dialect.dwell_word;
+        for fixed in gcodes {
+            let is_dwell =
+                (fixed.gcode.mnemonic(), fixed.gcode.major_number()) == (Mnemonic::General, 4);
+            let fixed = if is_dwell && dwell_word != 'P' {
+                fixed.map(|g| {
+                    let mut new = GCode::new(g.mnemonic(), command_number(&g), g.span());
+                    for arg in g.arguments() {
+                        let mut arg = *arg;
+                        if arg.letter == 'P' {
+                            arg.letter = dwell_word;
+                        }
+                        new.push_argument(arg).unwrap();
+                    }
+                    new
+                })
+            } else {
+                fixed
+            };
+            let gcode = &fixed.gcode;
+            if !dialect.supports_code(gcode) {
+                let mut code = format!(
+                    "{}{}",
+                    mnemonic_letter(gcode.mnemonic()),
+                    gcode.major_number()
+                );
+                if gcode.minor_number() != 0 {
+                    code += &format!(".{}", gcode.minor_number());
+                }
+                unsupported(gcode, code);
+                continue;
+            }
+            let dropped: Vec<_> = gcode
+                .arguments()
+                .iter()
+                .filter(|arg| !dialect.supports_word(arg.letter))
+                .collect();
+            if dropped.is_empty() {
+                out.push(fixed);
+                continue;
+            }
+            for arg in dropped {
+                unsupported(gcode, arg.letter.to_string());
+            }
+            out.push(fixed.map(|g| {
+                let mut new = GCode::new(g.mnemonic(), command_number(&g), g.span());
+                for arg in g.arguments() {
+                    if dialect.supports_word(arg.letter) {
+                        new.push_argument(*arg).unwrap();
+                    }
+                }
+                new
+            }));
+        }
+        out
+    }
+
+    /// Converts inches to mm if the dialect has no `G20`, and inverse time feedrates to mm/min
+    /// if it has no `G93`. The unit and feed mode commands it does not support are removed.
+    pub fn apply_metric_feedrates(&self, gcodes: Vec<FixedGCode>) -> Vec<FixedGCode> {
+        let convert_inches = !self.dialect.supports_general(20.);
+        let convert_inverse_time = !self.dialect.supports_general(93.);
+        if !convert_inches && !convert_inverse_time {
+            return gcodes;
+        }
+        let mut position = ProgramPosition::default();
+        let mut plane = GCodePlane::default();
+        let mut inverse_time = false;
+        let mut out = Vec::with_capacity(gcodes.len());
+        for fixed in gcodes {
+            let gcode = &fixed.gcode;
+            update_plane(&mut plane, gcode);
+            let start = position.pos;
+            let inches = position.inches;
+            position.update(gcode);
+            if gcode.mnemonic() == Mnemonic::General && gcode.minor_number() == 0 {
+                match gcode.major_number() {
+                    20 | 21 if convert_inches => continue,
+                    93 | 94 if convert_inverse_time => {
+                        inverse_time = gcode.major_number() == 93;
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+            let unit = if inches && convert_inches { 25.4 } else { 1. };
+            let length = match gcode.major_number() {
+                _ if gcode.mnemonic() != Mnemonic::General => 0.,
+                0 | 1 => (0..3)
+                    .map(|i| position.pos[i] - start[i])
+                    .fold(0., f64::hypot),
+                2 | 3 => {
+                    let arc = PlaneArc::new(gcode, &plane, &start, &position.pos);
+                    let third = axis_index(plane.axis_3().main_name()).unwrap();
+                    arc.length(position.pos[third] - start[third])
+                }
+                _ => 0.,
+            };
+            if unit == 1. && !(inverse_time && gcode.value_for('F').is_some()) {
+                out.push(fixed);
+                continue;
+            }
+            out.push(fixed.map(|g| {
+                let mut new = GCode::new(g.mnemonic(), command_number(&g), g.span());
+                for arg in g.arguments() {
+                    let mut arg = *arg;
+                    match arg.letter {
+                        // The move takes 1/F minutes
+                        'F' if inverse_time => arg.value *= length as f32 * unit,
+                        'F' | 'R' => arg.value *= unit,
+                        letter if axis_index(letter).or(center_index(letter)).is_some() => {
+                            arg.value *= unit
+                        }
+                        _ => {}
+                    }
+                    new.push_argument(arg).unwrap();
+                }
+                new
+            }));
+        }
+        out
+    }
+
+    /// Whether arcs are written as lines, as set or because the dialect has no arcs.
+    pub fn writes_arcs_as_lines(&self) -> bool {
+        self.arcs_as_lines
+            || self.height_map.is_some()
+            || self.dialect.arc_style == ArcStyle::Lines
+            || !self.dialect.supports_general(2.)
+    }
+
+    /// Whether arcs are written with a radius, as set or because the dialect only has those.
+    pub fn writes_radius_arcs(&self) -> bool {
+        self.radius_arcs || self.dialect.arc_style == ArcStyle::Radius
+    }
+
+    /// Writes arcs as lines which stray at most `arc_tolerance` from them. Inverse time
+    /// feedrates are divided among the lines.
+    pub fn apply_arcs_as_lines(&self, gcodes: Vec<FixedGCode>) -> Vec<FixedGCode> {
+        if !self.writes_arcs_as_lines() {
+            return gcodes;
+        }
+        let mut position = ProgramPosition::default();
+        let mut plane = GCodePlane::default();
+        let mut inverse_time = false;
+        let mut out = Vec::with_capacity(gcodes.len());
+        for fixed in gcodes {
+            let gcode = &fixed.gcode;
+            update_plane(&mut plane, gcode);
+            let (start, start_rotary) = (position.pos, position.rotary);
+            position.update(gcode);
+            let end = position.pos;
+            if gcode.mnemonic() != Mnemonic::General || gcode.minor_number() != 0 {
+                out.push(fixed);
+                continue;
+            }
+            match gcode.major_number() {
+                2 | 3 => {}
+                number => {
+                    inverse_time = match number {
+                        93 => true,
+                        94 => false,
+                        _ => inverse_time,
+                    };
+                    out.push(fixed);
+                    continue;
+                }
+            }
+            let arc = PlaneArc::new(gcode, &plane, &start, &end);
+            let unit = if position.inches { 25.4 } else { 1. };
+            let tolerance = (f64::from(self.arc_tolerance) / unit).min(arc.radius);
+            // The sagitta of each line is at most the tolerance
+            let max_step = 2. * (1. - tolerance / arc.radius).acos();
+            let steps = if max_step > 0. {
+                (arc.sweep / max_step).ceil().clamp(1., 10000.) as usize
+            } else {
+                1
+            };
+            let third = axis_index(plane.axis_3().main_name()).unwrap();
+            for step in 1..=steps {
+                let fraction = step as f64 / steps as f64;
+                let mut point = end;
+                if step < steps {
+                    let [a, b] = arc.point_along(fraction);
+                    point[arc.index[0]] = a;
+                    point[arc.index[1]] = b;
+                    point[third] = start[third] + (end[third] - start[third]) * fraction;
+                }
+                // Every line keeps the span of the arc, so it is known to come from its line
+                let mut line = GCode::new(Mnemonic::General, 1.0, gcode.span());
+                for (axis, name) in ['X', 'Y', 'Z'].into_iter().enumerate() {
+                    let given = gcode.value_for(name).is_some();
+                    if given || (step < steps && arc.index.contains(&axis)) {
+                        line.push_argument(Word::new(name, point[axis] as f32, Span::PLACEHOLDER))
+                            .unwrap();
+                    }
+                }
+                for arg in gcode.arguments() {
+                    if axis_index(arg.letter).is_some() || center_index(arg.letter).is_some() {
+                        continue;
+                    }
+                    let mut arg = *arg;
+                    if let Some(axis) = rotary_index(arg.letter) {
+                        // The rotary axes turn along with the lines
+                        let [start, end] = [start_rotary[axis], position.rotary[axis]];
+                        arg.value = (start + (end - start) * fraction) as f32;
+                    } else if arg.letter == 'F' && inverse_time {
+                        arg.value *= steps as f32;
+                    } else if step > 1 {
+                        continue;
+                    }
+                    line.push_argument(arg).unwrap();
+                }
+                out.push(FixedGCode {
+                    gcode: line,
+                    reason: fixed.reason.clone(),
+                });
+            }
+        }
+        out
+    }
+
+    /// Finds rapid moves which travel below `clearance_height` and optionally lifts the knife to
+    /// the clearance height for the travel, returning to the original height afterwards.
+    pub fn apply_clearance(
+        &self,
+        gcodes: Vec<FixedGCode>,
+        warnings: &mut Vec<OutputWarning>,
+    ) -> Vec<FixedGCode> {
+        if !self.check_clearance {
+            return gcodes;
+        }
+        let mut out = Vec::with_capacity(gcodes.len());
+        let mut plane = GCodePlane::default();
+        let mut height: Option<f32> = None;
+        let mut unit = 1.;
+        for fixed in gcodes {
+            let gcode = &fixed.gcode;
+            update_plane(&mut plane, gcode);
+            unit = selected_unit(gcode).unwrap_or(unit);
+            if gcode.mnemonic() != Mnemonic::General || gcode.major_number() > 3 {
+                out.push(fixed);
+                continue;
+            }
+            let clearance_height = self.clearance_height / unit;
+            let height_name = plane.axis_3().main_name();
+            let target_height = gcode.value_for(height_name).or(height);
+            let travels = gcode.value_for(plane.axis_1().main_name()).is_some()
+                || gcode.value_for(plane.axis_2().main_name()).is_some();
+            let start_low = height.is_some_and(|h| h < clearance_height);
+            let end_low = target_height.is_some_and(|h| h < clearance_height);
+            let start_height = height;
+            height = target_height;
+            if gcode.major_number() != 0 || !travels || !(start_low || end_low) {
+                out.push(fixed);
+                continue;
+            }
+            warnings.push(OutputWarning::LowRapid {
+                line: source_line(gcode.span()),
+                height: start_height
+                    .unwrap_or(clearance_height)
+                    .min(target_height.unwrap_or(clearance_height)),
+                lifted: self.enforce_clearance,
+            });
+            if !self.enforce_clearance {
+                out.push(fixed);
+                continue;
+            }
+            if start_low {
+                out.push(FixedGCode::inserted(
+                    height_move(height_name, clearance_height),
+                    "lift for clearance",
+                ));
+            }
+            out.push(fixed.map(|gcode| {
+                let mut travel = GCode::new(Mnemonic::General, 0.0, gcode.span());
+                for arg in gcode.arguments() {
+                    if arg.letter != height_name || !end_low {
+                        travel.push_argument(*arg).unwrap();
+                    }
+                }
+                travel
+            }));
+            if let (true, Some(target_height)) = (end_low, target_height) {
+                out.push(FixedGCode::inserted(
+                    height_move(height_name, target_height),
+                    "lower after clearance",
+                ));
+            }
+        }
+        out
+    }
+
+    /// Inserts `M3` after every feed move which lowers the knife and `M5` before every move which
+    /// lifts it, so a solenoid held knife follows the height changes.
+    pub fn apply_knife_gating(&self, gcodes: Vec<FixedGCode>) -> Vec<FixedGCode> {
+        if !self.gate_knife {
+            return gcodes;
+        }
+        let mut out = Vec::with_capacity(gcodes.len());
+        let mut plane = GCodePlane::default();
+        let mut height: Option<f32> = None;
+        let mut knife_down = false;
+        for fixed in gcodes {
+            let gcode = &fixed.gcode;
+            if gcode.mnemonic() != Mnemonic::General {
+                out.push(fixed);
+                continue;
+            }
+            update_plane(&mut plane, gcode);
+            let new_height = match gcode.major_number() {
+                0..=3 => gcode.value_for(plane.axis_3().main_name()),
+                _ => None,
+            };
+            let Some(new_height) = new_height else {
+                out.push(fixed);
+                continue;
+            };
+            let lifts = height.is_some_and(|h| new_height > h);
+            let lowers = gcode.major_number() == 1 && new_height < height.unwrap_or(f32::INFINITY);
+            height = Some(new_height);
+            if lifts && knife_down {
+                knife_down = false;
+                out.push(FixedGCode::inserted(
+                    GCode::new(Mnemonic::Miscellaneous, 5.0, Span::PLACEHOLDER),
+                    "knife off",
+                ));
+                out.push(fixed);
+            } else if lowers && !knife_down {
+                knife_down = true;
+                out.push(fixed);
+                out.push(FixedGCode::inserted(
+                    GCode::new(Mnemonic::Miscellaneous, 3.0, Span::PLACEHOLDER),
+                    "knife on",
+                ));
+            } else {
+                out.push(fixed);
+            }
+        }
+        out
+    }
+
+    /// Adds the offset of the height map under every move to its height, once the height is
+    /// known. Feed moves are split into pieces of at most `height_map_segment`, so the knife
+    /// follows the surface between the points of the map. Incremental moves in the input, and
+    /// moves with inverse time feedrates, are not split.
+    pub fn apply_height_map(&self, gcodes: Vec<FixedGCode>) -> Vec<FixedGCode> {
+        let Some(map) = &self.height_map else {
+            return gcodes;
+        };
+        let mut out = Vec::with_capacity(gcodes.len());
+        let mut position = ProgramPosition::default();
+        let mut absolute = true;
+        let mut inverse_time = false;
+        let mut height_known = false;
+        for fixed in gcodes {
+            let gcode = &fixed.gcode;
+            let general = gcode.mnemonic() == Mnemonic::General && gcode.minor_number() == 0;
+            if general {
+                match gcode.major_number() {
+                    90 => absolute = true,
+                    91 => absolute = false,
+                    93 => inverse_time = true,
+                    94 | 95 => inverse_time = false,
+                    _ => {}
+                }
+            }
+            let is_move = general && gcode.major_number() <= 1;
+            height_known |= is_move && gcode.value_for('Z').is_some();
+            if !is_move || !absolute || !height_known {
+                position.update(gcode);
+                out.push(fixed);
+                continue;
+            }
+            let (start, start_rotary) = (position.pos, position.rotary);
+            position.update(gcode);
+            let end = position.pos;
+            let unit = if position.inches { 25.4 } else { 1. };
+            let length = (end[0] - start[0]).hypot(end[1] - start[1]) * unit;
+            let pieces = if gcode.major_number() == 1 && !inverse_time {
+                (length / f64::from(self.height_map_segment.max(0.1)))
+                    .ceil()
+                    .max(1.) as usize
+            } else {
+                1
+            };
+            let at = |t: f64| {
+                let [x, y, z] = [0, 1, 2].map(|i| start[i] + (end[i] - start[i]) * t);
+                let offset = map.offset((x * unit) as f32, (y * unit) as f32);
+                [x, y, z + f64::from(offset) / unit].map(|value| value as f32)
+            };
+            for i in 1..pieces {
+                let mut piece = GCode::new(Mnemonic::General, 1., Span::PLACEHOLDER);
+                for (letter, value) in ['X', 'Y', 'Z']
+                    .into_iter()
+                    .zip(at(i as f64 / pieces as f64))
+                {
+                    piece
+                        .push_argument(Word::new(letter, value, Span::PLACEHOLDER))
+                        .unwrap();
+                }
+                for (axis, letter) in ROTARY_AXES.into_iter().enumerate() {
+                    if gcode.value_for(letter).is_some() {
+                        let [start, end] = [start_rotary[axis], position.rotary[axis]];
+                        let value = (start + (end - start) * i as f64 / pieces as f64) as f32;
+                        piece
+                            .push_argument(Word::new(letter, value, Span::PLACEHOLDER))
+                            .unwrap();
+                    }
+                }
+                // The feedrate applies from the first piece
+                if let (1, Some(feedrate)) = (i, gcode.value_for('F')) {
+                    piece
+                        .push_argument(Word::new('F', feedrate, Span::PLACEHOLDER))
+                        .unwrap();
+                }
+                out.push(FixedGCode::inserted(piece, "follow the height map"));
+            }
+            let height = at(1.)[2];
+            out.push(fixed.map(|g| {
+                let mut new = GCode::new(g.mnemonic(), command_number(&g), g.span());
+                for arg in g.arguments().iter().filter(|arg| arg.letter != 'Z') {
+                    new.push_argument(*arg).unwrap();
+                }
+                new.with_argument(Word::new('Z', height, Span::PLACEHOLDER))
+            }));
+        }
+        out
+    }
+
+    pub fn probes(&self) -> bool {
+        self.probe_start || self.probe_contours
+    }
+
+    /// Inserts a probing routine where the knife is lowered into the material, at the start of
+    /// the cut: `G38.2` down to `probe_depth` below zero, `G10 L20 P0` to make the surface Z zero
+    /// and a retract to `probe_clearance`. Controllers without `G38.2` probe with `G31`, and
+    /// those without `G10` set the zero with `G92`. Dry runs are not probed, nor programs for
+    /// controllers which can not probe, with a warning.
+    pub fn apply_probing(
+        &self,
+        gcodes: Vec<FixedGCode>,
+        warnings: &mut Vec<OutputWarning>,
+    ) -> Vec<FixedGCode> {
+        if !self.probes() || self.dry_run {
+            return gcodes;
+        }
+        let probe_code = if self.dialect.supports_general(38.2) {
+            38.2
+        } else if self.dialect.supports_general(31.) {
+            31.
+        } else {
+            warnings.push(OutputWarning::Unsupported {
+                line: None,
+                code: "G38.2".to_string(),
+                dialect: self.dialect.name().to_string(),
+            });
+            return gcodes;
+        };
+        let mut out = Vec::with_capacity(gcodes.len());
+        let mut plane = GCodePlane::default();
+        let mut height: Option<f32> = None;
+        let mut feedrate = None;
+        let mut unit = 1.;
+        let mut probed = false;
+        let mut traveled = false;
+        for fixed in gcodes {
+            let gcode = &fixed.gcode;
+            if gcode.mnemonic() != Mnemonic::General {
+                out.push(fixed);
+                continue;
+            }
+            update_plane(&mut plane, gcode);
+            unit = selected_unit(gcode).unwrap_or(unit);
+            if gcode.major_number() > 3 {
+                out.push(fixed);
+                continue;
+            }
+            let height_name = plane.axis_3().main_name();
+            let new_height = gcode.value_for(height_name);
+            let lowers = gcode.major_number() == 1
+                && new_height.is_some_and(|h| h < 0. && h < height.unwrap_or(f32::INFINITY));
+            if gcode.major_number() == 0
+                && (gcode.value_for(plane.axis_1().main_name()).is_some()
+                    || gcode.value_for(plane.axis_2().main_name()).is_some())
+            {
+                traveled = true;
+            }
+            height = new_height.or(height);
+            if !lowers || !(self.probe_start && !probed || self.probe_contours && traveled) {
+                feedrate = gcode.value_for('F').or(feedrate);
+                out.push(fixed);
+                continue;
+            }
+            probed = true;
+            traveled = false;
+            let probe = GCode::new(Mnemonic::General, probe_code, Span::PLACEHOLDER)
+                .with_argument(Word::new(
+                    height_name,
+                    -self.probe_depth / unit,
+                    Span::PLACEHOLDER,
+                ))
+                .with_argument(Word::new(
+                    'F',
+                    self.probe_feedrate / unit,
+                    Span::PLACEHOLDER,
+                ));
+            out.push(FixedGCode::inserted(probe, "probe the surface"));
+            let zero = if self.dialect.supports_general(10.) {
+                GCode::new(Mnemonic::General, 10., Span::PLACEHOLDER)
+                    .with_argument(Word::new('L', 20., Span::PLACEHOLDER))
+                    .with_argument(Word::new('P', 0., Span::PLACEHOLDER))
+            } else {
+                GCode::new(Mnemonic::General, 92., Span::PLACEHOLDER)
+            };
+            let zero = zero.with_argument(Word::new(height_name, 0., Span::PLACEHOLDER));
+            out.push(FixedGCode::inserted(zero, "make the surface zero"));
+            out.push(FixedGCode::inserted(
+                height_move(height_name, self.probe_clearance / unit),
+                "retract after probing",
+            ));
+            // The probe changed the feedrate
+            let restore = feedrate.filter(|_| gcode.value_for('F').is_none());
+            feedrate = gcode.value_for('F').or(feedrate);
+            out.push(match restore {
+                Some(feedrate) => {
+                    fixed.map(|g| g.with_argument(Word::new('F', feedrate, Span::PLACEHOLDER)))
+                }
+                None => fixed,
+            });
+        }
+        out
+    }
+
+    /// Formats a command and its arguments.
+    pub fn format_gcode(&self, gcode: &GCode) -> String {
+        let mut text = String::new();
+        self.write_gcode(&mut text, gcode);
+        text
+    }
+
+    /// Appends a command and its arguments to `text`, like `format_gcode`.
+    pub fn write_gcode(&self, text: &mut String, gcode: &GCode) {
+        if !self.format_numbers && !self.dialect.formats_words() {
+            write!(text, "{gcode}").unwrap();
+            return;
+        }
+        write!(
+            text,
+            "{}{}",
+            mnemonic_letter(gcode.mnemonic()),
+            gcode.major_number()
+        )
+        .unwrap();
+        if gcode.minor_number() != 0 {
+            write!(text, ".{}", gcode.minor_number()).unwrap();
+        }
+        for arg in gcode.arguments() {
+            if self.dialect.spaces {
+                text.push(' ');
+            }
+            text.push(arg.letter);
+            if self.format_numbers {
+                self.write_number(text, arg.letter, arg.value);
+                continue;
+            }
+            let start = text.len();
+            write!(text, "{}", arg.value).unwrap();
+            let max = self.dialect.max_decimals.unwrap_or(usize::MAX);
+            if text[start..]
+                .split_once('.')
+                .is_some_and(|(_, decimals)| decimals.len() > max)
+            {
+                text.truncate(start);
+                write!(text, "{:.max$}", arg.value).unwrap();
+                trim_number(text, start, true);
+            }
+        }
+    }
+
+    /// The decimals numbers for `letter` are written with, at most as many as the dialect allows.
+    fn decimals(&self, letter: char) -> usize {
+        let decimals = if letter == 'F' {
+            self.feedrate_decimals
+        } else {
+            self.coordinate_decimals
+        };
+        self.dialect
+            .max_decimals
+            .map_or(decimals, |max| decimals.min(max))
+    }
+
+    pub fn format_number(&self, letter: char, value: f32) -> String {
+        let mut text = String::new();
+        self.write_number(&mut text, letter, value);
+        text
+    }
+
+    /// Appends a number to `text`, like `format_number`.
+    pub fn write_number(&self, text: &mut String, letter: char, value: f32) {
+        let decimals = self.decimals(letter);
+        let start = text.len();
+        write!(text, "{value:.decimals$}").unwrap();
+        trim_number(text, start, self.trim_zeros);
+    }
+
+    /// Removes comments from lines which are too long, and warns if they are still too long.
+    pub fn apply_line_length(
+        &self,
+        lines: Vec<OutputLine>,
+        warnings: &mut Vec<OutputWarning>,
+    ) -> Vec<OutputLine> {
+        if !self.limit_line_length {
+            return lines;
+        }
+        lines
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut line)| {
+                if line.text_len() > self.max_line_length && !line.code.is_empty() {
+                    line.comments.clear();
+                }
+                let length = line.text_len();
+                if length > self.max_line_length {
+                    warnings.push(OutputWarning::LineTooLong {
+                        output_line: i,
+                        length,
+                    });
+                }
+                line
+            })
+            .collect()
+    }
+
+    /// Prefixes all lines containing code with increasing N words, followed by a checksum if
+    /// enabled.
+    pub fn apply_line_numbers(&self, lines: Vec<OutputLine>) -> Vec<OutputLine> {
+        if !self.line_numbers && !self.checksums {
+            return lines;
+        }
+        let mut number = self.line_number_start;
+        lines
+            .into_iter()
+            .map(|mut line| {
+                if line.code.is_empty() {
+                    return line;
+                }
+                line.code = format!("N{number} {}", line.code);
+                if self.checksums {
+                    line.code = format!("{}*{}", line.code, checksum(&line.code));
+                }
+                number += self.line_number_step;
+                line
+            })
+            .collect()
+    }
+
+    /// Replaces characters which are not ASCII: `°` with `deg` and others with `?`.
+    pub fn apply_ascii(&self, lines: Vec<OutputLine>) -> Vec<OutputLine> {
+        if !self.ascii_only {
+            return lines;
+        }
+        let to_ascii = |text: &String| {
+            text.chars()
+                .map(|c| match c {
+                    c if c.is_ascii() => c.to_string(),
+                    '°' => "deg".to_string(),
+                    _ => "?".to_string(),
+                })
+                .collect::<String>()
+        };
+        lines
+            .into_iter()
+            .map(|line| OutputLine {
+                code: to_ascii(&line.code),
+                comments: line.comments.iter().map(to_ascii).collect(),
+                origin: line.origin,
+            })
+            .collect()
+    }
+
+    /// Clamps all heights to at least `dry_run_height` and removes `M3`, `M4` and `M5`, so the
+    /// program can be run in the air.
+    pub fn apply_dry_run(&self, gcodes: Vec<FixedGCode>) -> Vec<FixedGCode> {
+        if !self.dry_run {
+            return gcodes;
+        }
+        let mut plane = GCodePlane::default();
+        let mut unit = 1.;
+        gcodes
+            .into_iter()
+            .filter(|g| {
+                !(g.gcode.mnemonic() == Mnemonic::Miscellaneous
+                    && (3..=5).contains(&g.gcode.major_number()))
+            })
+            .map(|g| {
+                g.map(|g| {
+                    update_plane(&mut plane, &g);
+                    unit = selected_unit(&g).unwrap_or(unit);
+                    let dry_run_height = self.dry_run_height / unit;
+                    let height_name = plane.axis_3().main_name();
+                    if g.mnemonic() != Mnemonic::General
+                        || g.major_number() > 3
+                        || g.value_for(height_name).is_none_or(|h| h >= dry_run_height)
+                    {
+                        return g;
+                    }
+                    let mut new = GCode::new(g.mnemonic(), command_number(&g), g.span());
+                    for arg in g.arguments() {
+                        let mut arg = *arg;
+                        if arg.letter == height_name {
+                            arg.value = dry_run_height;
+                        }
+                        new.push_argument(arg).unwrap();
+                    }
+                    new
+                })
+            })
+            .collect()
+    }
+
+    /// Replaces the placeholders in a header or footer:
+    /// `{date}` (`2024-01-31`), `{time}` (`13:45`, UTC) and `{safe_height}` (`clearance_height`,
+    /// in units of `unit` mm).
+    pub fn expand_template(&self, template: &str, unit: f32) -> String {
+        #[cfg(not(target_arch = "wasm32"))]
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        // The system clock of the standard library panics in the browser
+        #[cfg(target_arch = "wasm32")]
+        let seconds = (js_sys::Date::now() / 1000.) as u64;
+        let (year, month, day) = civil_date(seconds / 86400);
+        template
+            .replace("{date}", &format!("{year:04}-{month:02}-{day:02}"))
+            .replace(
+                "{time}",
+                &format!("{:02}:{:02}", seconds / 3600 % 24, seconds / 60 % 60),
+            )
+            .replace(
+                "{safe_height}",
+                &self.format_number('Z', self.clearance_height / unit),
+            )
+    }
+
+    pub(crate) fn template_lines(&self, template: &str, unit: f32) -> Vec<OutputLine> {
+        if template.trim().is_empty() {
+            return vec![];
+        }
+        self.expand_template(template, unit)
+            .lines()
+            .map(OutputLine::from_template_line)
+            .collect()
+    }
+
+    /// The preamble of the dialect and the header lines, with heights in units of `unit` mm.
+    pub(crate) fn header_lines(&self, unit: f32) -> Vec<OutputLine> {
+        let mut lines = self.template_lines(&self.dialect.preamble, unit);
+        lines.extend(self.template_lines(&self.header, unit));
+        lines
+    }
+
+    /// The footer lines, with heights in units of `unit` mm. If the dialect only takes `$` system
+    /// commands while the machine stands still, a `G4 P0` before the first of them waits for the
+    /// moves to finish.
+    pub(crate) fn footer_lines(&self, unit: f32) -> Vec<OutputLine> {
+        let mut lines = self.template_lines(&self.footer, unit);
+        if self.dialect.sync_system_commands {
+            if let Some(i) = lines.iter().position(|line| line.code.starts_with('$')) {
+                let mut wait = OutputLine::code("G4 P0".to_string());
+                wait.origin.inserted = true;
+                lines.insert(i, wait);
+            }
+        }
+        lines
+    }
+
+    /// Finds moves which leave the soft limits. Inserted commands are reported with the input
+    /// line they were inserted for.
+    pub fn check_soft_limits(&self, gcodes: &[FixedGCode], warnings: &mut Vec<OutputWarning>) {
+        if !self.check_soft_limits {
+            return;
+        }
+        let mut position = ProgramPosition::default();
+        let mut plane = GCodePlane::default();
+        let next_lines = next_source_lines(gcodes);
+        for (fixed, line) in gcodes.iter().zip(next_lines) {
+            let gcode = &fixed.gcode;
+            update_plane(&mut plane, gcode);
+            let start = position.pos;
+            position.update(gcode);
+            if !is_move(gcode) {
+                continue;
+            }
+            let (min, max) = move_extent(gcode, &plane, &start, &position.pos);
+            let unit = if position.inches { 25.4 } else { 1. };
+            let [min, max] = [min, max].map(|extent| extent.map(|value| (value * unit) as f32));
+            for (axis, name) in ['X', 'Y', 'Z'].into_iter().enumerate() {
+                let value = if min[axis] < self.soft_limits.min[axis] {
+                    min[axis]
+                } else if max[axis] > self.soft_limits.max[axis] {
+                    max[axis]
+                } else {
+                    continue;
+                };
+                warnings.push(OutputWarning::SoftLimit {
+                    line,
+                    reason: fixed.reason.clone(),
+                    axis: name,
+                    value,
+                    min: self.soft_limits.min[axis],
+                    max: self.soft_limits.max[axis],
+                });
+            }
+        }
+    }
+
+    /// Finds feedrates above `max_feedrate`, after converting inches to mm, and optionally
+    /// lowers them to it. Inverse time feedrates are left alone. Inserted commands are reported
+    /// with the input line they were inserted for.
+    pub fn apply_max_feedrate(
+        &self,
+        gcodes: Vec<FixedGCode>,
+        warnings: &mut Vec<OutputWarning>,
+    ) -> Vec<FixedGCode> {
+        if !self.check_max_feedrate {
+            return gcodes;
+        }
+        let next_lines = next_source_lines(&gcodes);
+        let mut inches = false;
+        let mut inverse_time = false;
+        let mut out = Vec::with_capacity(gcodes.len());
+        for (fixed, line) in gcodes.into_iter().zip(next_lines) {
+            let gcode = &fixed.gcode;
+            if gcode.mnemonic() == Mnemonic::General && gcode.minor_number() == 0 {
+                match gcode.major_number() {
+                    20 => inches = true,
+                    21 => inches = false,
+                    93 => inverse_time = true,
+                    94 => inverse_time = false,
+                    _ => {}
+                }
+            }
+            let unit = if inches { 25.4 } else { 1. };
+            match gcode.value_for('F') {
+                Some(feedrate) if !inverse_time && feedrate * unit > self.max_feedrate => {
+                    warnings.push(OutputWarning::Feedrate {
+                        line,
+                        reason: fixed.reason.clone(),
+                        feedrate: feedrate * unit,
+                        max: self.max_feedrate,
+                        clamped: self.clamp_feedrate,
+                    });
+                    if !self.clamp_feedrate {
+                        out.push(fixed);
+                        continue;
+                    }
+                    out.push(fixed.map(|gcode| {
+                        let mut new =
+                            GCode::new(gcode.mnemonic(), command_number(&gcode), gcode.span());
+                        for arg in gcode.arguments() {
+                            let mut arg = *arg;
+                            if arg.letter == 'F' {
+                                arg.value = self.max_feedrate / unit;
+                            }
+                            new.push_argument(arg).unwrap();
+                        }
+                        new
+                    }));
+                }
+                _ => out.push(fixed),
+            }
+        }
+        out
+    }
+
+    /// Writes arcs with a radius (`R`) instead of their center. `R` is negative for arcs of more
+    /// than 180°. Full circles can not be written this way and keep their center.
+    pub fn apply_radius_arcs(&self, gcodes: Vec<FixedGCode>) -> Vec<FixedGCode> {
+        if !self.writes_radius_arcs() {
+            return gcodes;
+        }
+        let mut position = ProgramPosition::default();
+        let mut plane = GCodePlane::default();
+        gcodes
+            .into_iter()
+            .map(|g| {
+                update_plane(&mut plane, &g.gcode);
+                let new = g.map(|g| {
+                    if g.mnemonic() == Mnemonic::General
+                        && matches!(g.major_number(), 2 | 3)
+                        && g.minor_number() == 0
+                    {
+                        radius_arc(&g, &plane, &position.pos).unwrap_or(g)
+                    } else {
+                        g
+                    }
+                });
+                position.update(&new.gcode);
+                new
+            })
+            .collect()
+    }
+
+    /// Writes arc centers (`I`, `J`, `K`) as absolute coordinates instead of relative to the
+    /// start of the arc. The program starts with `G90.1`, and `G91.1` is turned into `G90.1`.
+    pub fn apply_absolute_arc_centers(&self, gcodes: Vec<FixedGCode>) -> Vec<FixedGCode> {
+        if !self.absolute_arc_centers || !self.dialect.supports_general(90.1) {
+            return gcodes;
+        }
+        let mut position = ProgramPosition::default();
+        let start = GCode::new(Mnemonic::General, 90.1, Span::PLACEHOLDER).into();
+        std::iter::once(start)
+            .chain(gcodes.into_iter().map(|g| {
+                let new = g.map(
+                    |g| match (g.mnemonic(), g.major_number(), g.minor_number()) {
+                        (Mnemonic::General, 91, 1) => GCode::new(Mnemonic::General, 90.1, g.span()),
+                        (Mnemonic::General, 2 | 3, 0) => {
+                            let mut new = GCode::new(g.mnemonic(), command_number(&g), g.span());
+                            for arg in g.arguments() {
+                                let mut arg = *arg;
+                                if let Some(axis) = center_index(arg.letter) {
+                                    arg.value = (f64::from(arg.value) + position.pos[axis]) as f32;
+                                }
+                                new.push_argument(arg).unwrap();
+                            }
+                            new
+                        }
+                        _ => g,
+                    },
+                );
+                position.update(&new.gcode);
+                new
+            }))
+            .collect()
+    }
+
+    /// Turns the absolute moves into relative ones and starts the program with `G91`.
+    ///
+    /// The program is assumed to start at the origin, which is also where `G28` ends up.
+    pub fn apply_incremental(&self, gcodes: Vec<FixedGCode>) -> Vec<FixedGCode> {
+        if !self.incremental {
+            return gcodes;
+        }
+        let mut position = ProgramPosition::default();
+        let mut out = vec![GCode::new(Mnemonic::General, 91.0, Span::PLACEHOLDER).into()];
+        for g in gcodes {
+            match (
+                g.gcode.mnemonic(),
+                g.gcode.major_number(),
+                g.gcode.minor_number(),
+            ) {
+                // Positioning is set once at the start
+                (Mnemonic::General, 90 | 91, 0) => {}
+                // Probing moves too, in the same positioning mode
+                (Mnemonic::General, 0..=3 | 28 | 31, 0) | (Mnemonic::General, 38, 2..=5) => {
+                    out.push(g.map(|g| {
+                        let mut new = GCode::new(g.mnemonic(), command_number(&g), g.span());
+                        for arg in g.arguments() {
+                            let mut arg = *arg;
+                            if let Some(axis) = axis_index(arg.letter) {
+                                // Follow the written values, so rounding errors do not add up
+                                let delta = self
+                                    .round_coordinate(f64::from(arg.value) - position.pos[axis]);
+                                position.pos[axis] += delta;
+                                arg.value = delta as f32;
+                            } else if let Some(axis) = rotary_index(arg.letter) {
+                                let delta = self
+                                    .round_coordinate(f64::from(arg.value) - position.rotary[axis]);
+                                position.rotary[axis] += delta;
+                                arg.value = delta as f32;
+                            }
+                            new.push_argument(arg).unwrap();
+                        }
+                        position.update_settings(&g);
+                        new
+                    }))
+                }
+                _ => {
+                    position.update_settings(&g.gcode);
+                    out.push(g);
+                }
+            }
+        }
+        out
+    }
+
+    /// Rounds a coordinate to the decimals it is written with, so relative moves add up exactly.
+    fn round_coordinate(&self, value: f64) -> f64 {
+        let scale = 10f64.powi(self.decimals('X') as i32);
+        (value * scale).round() / scale
+    }
+}
+
+/// Something in the output which may need attention, but did not stop the program from being
+/// written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputWarning {
+    LowRapid {
+        line: Option<usize>,
+        height: f32,
+        lifted: bool,
+    },
+    LineTooLong {
+        /// Zero-based line in the output.
+        output_line: usize,
+        length: usize,
+    },
+    /// A move which leaves the soft limits.
+    SoftLimit {
+        /// Zero-based input line of the move, or of the command it was inserted for.
+        line: Option<usize>,
+        /// Why the move was inserted, if it was.
+        reason: Option<String>,
+        axis: char,
+        /// The furthest the move goes past the limit, in mm.
+        value: f32,
+        min: f32,
+        max: f32,
+    },
+    /// A feedrate above the machine's maximum.
+    Feedrate {
+        /// Zero-based input line of the command, or of the command it was inserted for.
+        line: Option<usize>,
+        /// Why the command was inserted, if it was.
+        reason: Option<String>,
+        /// The feedrate in mm/min.
+        feedrate: f32,
+        max: f32,
+        clamped: bool,
+    },
+    /// A closed contour which pushes the knife through sharp corners or tight arcs.
+    Contour(ContourReport),
+    /// An arc which was cut as a line or dropped.
+    DegenerateArc(DegenerateArc),
+    /// Something in the input which may not be repathed as intended.
+    Validation(ValidationWarning),
+    /// A swivel which sweeps the knife across an earlier cut.
+    SwivelCrossing(SwivelCrossing),
+    /// A part of the path which the cutter compensation could not offset as programmed.
+    Compensation(CompensationWarning),
+    /// Input the parser skipped.
+    Parse(ParseIssue),
+    /// A command or word the controller does not accept, which was left out.
+    Unsupported {
+        /// Zero-based input line of the command, if it came from the input.
+        line: Option<usize>,
+        /// The command, e.g. `G64`, or the letter of the word.
+        code: String,
+        /// The name of the dialect.
+        dialect: String,
+    },
+}
+
+impl OutputWarning {
+    /// Zero-based input line the warning is about, if it is about one.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            OutputWarning::LowRapid { line, .. }
+            | OutputWarning::SoftLimit { line, .. }
+            | OutputWarning::Feedrate { line, .. }
+            | OutputWarning::Unsupported { line, .. } => *line,
+            OutputWarning::LineTooLong { .. } => None,
+            OutputWarning::Contour(report) => report.line,
+            OutputWarning::DegenerateArc(arc) => arc.line,
+            OutputWarning::Validation(warning) => source_line(warning.span()),
+            OutputWarning::SwivelCrossing(crossing) => crossing.line,
+            OutputWarning::Compensation(warning) => warning.line,
+            OutputWarning::Parse(issue) => Some(issue.line),
+        }
+    }
+}
+
+impl Display for OutputWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputWarning::LowRapid {
+                line,
+                height,
+                lifted,
+            } => {
+                write!(
+                    f,
+                    "Rapid move at height {height:.3} is below the clearance height"
+                )?;
+                if let Some(line) = line {
+                    write!(f, " (input line {})", line + 1)?;
+                }
+                if *lifted {
+                    write!(f, "; lifted to clearance height")?;
+                }
+                Ok(())
+            }
+            OutputWarning::LineTooLong {
+                output_line,
+                length,
+            } => write!(
+                f,
+                "Output line {} is {length} characters long",
+                output_line + 1
+            ),
+            OutputWarning::SoftLimit {
+                line,
+                reason,
+                axis,
+                value,
+                min,
+                max,
+            } => {
+                write!(
+                    f,
+                    "{axis}{value:.3} is outside the soft limits ({min} to {max} mm)"
+                )?;
+                if let Some(reason) = reason {
+                    write!(f, " in the {reason}")?;
+                }
+                if let Some(line) = line {
+                    write!(f, " at input line {}", line + 1)?;
+                }
+                Ok(())
+            }
+            OutputWarning::Feedrate {
+                line,
+                reason,
+                feedrate,
+                max,
+                clamped,
+            } => {
+                write!(
+                    f,
+                    "Feedrate {feedrate:.0} mm/min is above the maximum of {max}"
+                )?;
+                if let Some(reason) = reason {
+                    write!(f, " in the {reason}")?;
+                }
+                if let Some(line) = line {
+                    write!(f, " at input line {}", line + 1)?;
+                }
+                if *clamped {
+                    write!(f, "; lowered to the maximum")?;
+                }
+                Ok(())
+            }
+            OutputWarning::Contour(report) => report.fmt(f),
+            OutputWarning::DegenerateArc(arc) => arc.fmt(f),
+            OutputWarning::Validation(warning) => warning.fmt(f),
+            OutputWarning::SwivelCrossing(crossing) => crossing.fmt(f),
+            OutputWarning::Compensation(warning) => warning.fmt(f),
+            OutputWarning::Parse(issue) => issue.fmt(f),
+            OutputWarning::Unsupported {
+                line,
+                code,
+                dialect,
+            } => {
+                write!(f, "{code} is not supported by {dialect}")?;
+                if let Some(line) = line {
+                    write!(f, " at input line {}", line + 1)?;
+                }
+                write!(f, "; left out")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProgramOutput {
+    pub text: String,
+    pub warnings: Vec<OutputWarning>,
+    /// Where each line of `text` came from.
+    pub origins: Vec<LineOrigin>,
+    /// The program split into files, with `OutputConfig::split_files` and more than one
+    /// section. Empty otherwise.
+    pub sections: Vec<ProgramSection>,
+}
+
+/// A part of the program which is written to its own file, with the header and footer and the
+/// modal state the part before it left.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramSection {
+    /// The operation, or the tool for a tool change like `T2`. Empty if neither is known.
+    pub name: String,
+    pub text: String,
+}
+
+/// Applies the output stage to the fixed gcode and returns the program text.
+///
+/// Comments and blank lines from `input` are written before the first command which came from a
+/// later input line.
+pub fn write_program(
+    input: &InputProgram,
+    gcodes: Vec<FixedGCode>,
+    config: &OutputConfig,
+) -> ProgramOutput {
+    write_program_with_operations(input, gcodes, config, |_| None)
+}
+
+/// Like [`write_program`], where `operation_of` gives the name of the operation of an input
+/// line, so `OutputConfig::split_files` also splits the program where the operation changes.
+pub fn write_program_with_operations(
+    input: &InputProgram,
+    gcodes: Vec<FixedGCode>,
+    config: &OutputConfig,
+    operation_of: impl Fn(usize) -> Option<String>,
+) -> ProgramOutput {
+    let mut warnings = vec![];
+    let gcodes = config.apply_filters(gcodes);
+    let gcodes = config.apply_material_depth(gcodes);
+    let gcodes = config.apply_clearance(gcodes, &mut warnings);
+    let gcodes = config.apply_knife_gating(gcodes);
+    let gcodes = config.apply_probing(gcodes, &mut warnings);
+    let gcodes = config.apply_dry_run(gcodes);
+    let gcodes = config.apply_metric_feedrates(gcodes);
+    let gcodes = config.apply_max_feedrate(gcodes, &mut warnings);
+    config.check_soft_limits(&gcodes, &mut warnings);
+    let gcodes = config.apply_arcs_as_lines(gcodes);
+    let gcodes = config.apply_height_map(gcodes);
+    let gcodes = config.apply_radius_arcs(gcodes);
+    let gcodes = config.apply_absolute_arc_centers(gcodes);
+    let gcodes = config.apply_incremental(gcodes);
+    let mut gcodes = config.apply_dialect(gcodes, &mut warnings);
+    // The program number comes first, before the header and any inserted commands
+    if let Some(i) = gcodes
+        .iter()
+        .position(|g| source_line(g.gcode.span()).is_some())
+    {
+        if gcodes[i].gcode.mnemonic() == Mnemonic::ProgramNumber {
+            let number = gcodes.remove(i);
+            gcodes.insert(0, number);
+        }
+    }
+    let sections = match config.split_files {
+        true => split_sections(&gcodes, &operation_of),
+        false => vec![],
+    };
+    let mut output = format_program(input, &gcodes, config, &input.notes, warnings);
+    if sections.len() > 1 {
+        // Comments between two sections are written with the later one
+        let ends: Vec<_> = sections.iter().map(|section| section.last_line).collect();
+        let count = sections.len();
+        output.sections = sections
+            .into_iter()
+            .enumerate()
+            .map(|(i, section)| {
+                let after = ends[..i].iter().rev().find_map(|&line| line);
+                let end = ends[i].filter(|_| i + 1 < count);
+                let notes: Vec<_> = input
+                    .notes
+                    .iter()
+                    .filter(|note| {
+                        after.is_none_or(|after| note.line() > after)
+                            && end.is_none_or(|end| note.line() <= end)
+                    })
+                    .cloned()
+                    .collect();
+                let text = format_program(input, &section.gcodes, config, &notes, vec![]).text;
+                ProgramSection {
+                    name: section.name,
+                    text,
+                }
+            })
+            .collect();
+    }
+    output
+}
+
+/// Turns the commands after the output stage into the program text, with the `notes` from the
+/// input between them.
+fn format_program(
+    input: &InputProgram,
+    gcodes: &[FixedGCode],
+    config: &OutputConfig,
+    notes: &[SourceNote],
+    mut warnings: Vec<OutputWarning>,
+) -> ProgramOutput {
+    let has_program_number = gcodes
+        .first()
+        .is_some_and(|g| g.gcode.mnemonic() == Mnemonic::ProgramNumber);
+
+    let code_lines = || input.gcodes.iter().filter_map(|g| source_line(g.span()));
+    let (first_code, last_code) = (code_lines().min(), code_lines().max());
+    let delimiters = || {
+        input.notes.iter().filter_map(|note| match note {
+            SourceNote::Delimiter { line } => Some(*line),
+            _ => None,
+        })
+    };
+    // The program envelope is written around the header and footer
+    let start_delimiter = config.percent_delimiters
+        || delimiters().any(|line| first_code.is_none_or(|first| line < first));
+    let end_delimiter = config.percent_delimiters
+        || delimiters().any(|line| last_code.is_some_and(|last| line > last));
+    let notes: &[SourceNote] = if config.strip_comments { &[] } else { notes };
+    let next_lines = next_source_lines(gcodes);
+    // The header and footer are in the units the program starts and ends in
+    let unit = |fixed: &FixedGCode| selected_unit(&fixed.gcode);
+    let first_unit = gcodes.iter().find_map(unit).unwrap_or(1.);
+    let last_unit = gcodes.iter().rev().find_map(unit).unwrap_or(first_unit);
+    let originals: HashMap<_, _> = if config.original_comments || config.keep_original_text {
+        input
+            .gcodes
+            .iter()
+            .map(|g| ((g.span().start, g.span().end), g))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let mut last_original = None;
+    let mut notes = notes
+        .iter()
+        .filter(|note| !matches!(note, SourceNote::Delimiter { .. }))
+        .peekable();
+    let mut header = Some(config.header_lines(first_unit));
+    let mut lines = if has_program_number {
+        vec![]
+    } else {
+        header.take().unwrap()
+    };
+    for (fixed, next_line) in gcodes.iter().zip(next_lines) {
+        let gcode = &fixed.gcode;
+        if let Some(next_line) = next_line {
+            while let Some(note) = notes.next_if(|n| n.line() < next_line) {
+                lines.push(OutputLine::from_note(note));
+            }
+        }
+        let original = originals.get(&(gcode.span().start, gcode.span().end));
+        let unchanged = original.is_some_and(|original| same_command(original, gcode));
+        if let (Some(source), Some(_), false) = (source_line(gcode.span()), original, unchanged) {
+            if config.original_comments && last_original != Some(source) && is_move(gcode) {
+                last_original = Some(source);
+                lines.push(OutputLine {
+                    comments: vec![format!("(original: {})", input.lines[source])],
+                    origin: LineOrigin {
+                        input_line: Some(source),
+                        inserted: false,
+                    },
+                    ..Default::default()
+                });
+            }
+        }
+        let original_text = input
+            .source_text(gcode.span())
+            // Formatting the numbers rewrites them, so they can not be kept
+            .filter(|_| {
+                unchanged
+                    && config.keep_original_text
+                    && !config.format_numbers
+                    && !config.dialect.formats_words()
+            })
+            // Words continuing a modal move can not be copied, as inserted moves change the mode
+            .filter(|text| {
+                text.chars()
+                    .next()
+                    .is_some_and(|c| c.eq_ignore_ascii_case(&mnemonic_letter(gcode.mnemonic())))
+            })
+            .and_then(|text| {
+                if config.normalize_original_text {
+                    canonical_text(text)
+                } else {
+                    Some(text.to_string())
+                }
+            });
+        let mut line =
+            OutputLine::code(original_text.unwrap_or_else(|| config.format_gcode(gcode)));
+        if let Some(source) = source_line(gcode.span()) {
+            while let Some(SourceNote::Comment { text: comment, .. }) =
+                notes.next_if(|n| n.line() == source && n.is_inline())
+            {
+                line.comments.push(comment.clone());
+            }
+        }
+        if let (true, Some(reason)) = (config.annotate, &fixed.reason) {
+            line.comments.push(format!("({reason})"));
+        }
+        line.origin = LineOrigin {
+            input_line: source_line(gcode.span()).or(next_line),
+            inserted: fixed.reason.is_some(),
+        };
+        lines.push(line);
+        lines.extend(header.take().unwrap_or_default());
+    }
+    lines.extend(notes.map(OutputLine::from_note));
+    lines.extend(config.footer_lines(last_unit));
+    if start_delimiter {
+        lines.insert(0, OutputLine::delimiter());
+    }
+    if end_delimiter {
+        lines.push(OutputLine::delimiter());
+    }
+    let lines = config.apply_ascii(lines);
+    let lines = config.apply_line_numbers(lines);
+    let lines = config.apply_line_length(lines, &mut warnings);
+    let mut text = String::with_capacity(lines.iter().map(|line| line.text_len() + 2).sum());
+    for line in &lines {
+        line.write_text(&mut text);
+        text += config.line_ending.as_str();
+    }
+    let origins = lines.iter().map(|line| line.origin).collect();
+    ProgramOutput {
+        text,
+        warnings,
+        origins,
+        sections: vec![],
+    }
+}
+
+/// A part of the program for [`ProgramSection`], before it is written.
+struct Section {
+    name: String,
+    gcodes: Vec<FixedGCode>,
+    /// The last input line of its commands.
+    last_line: Option<usize>,
+}
+
+/// Splits the program before tool changes (`T` and `M6`), at pauses (`M0`, `M1`), which are
+/// left out as the end of the file takes their place, and where the operation changes. A new
+/// part is only started once the current one cut.
+///
+/// Every part after the first starts with the modal state the one before it left, and the
+/// parts before the last end with the end of the program, `M2` or `M30`, if it has one.
+fn split_sections(
+    gcodes: &[FixedGCode],
+    operation_of: &impl Fn(usize) -> Option<String>,
+) -> Vec<Section> {
+    let end = gcodes
+        .iter()
+        .rev()
+        .find(|g| {
+            g.gcode.mnemonic() == Mnemonic::Miscellaneous
+                && matches!(g.gcode.major_number(), 2 | 30)
+        })
+        .map(|g| {
+            GCode::new(
+                Mnemonic::Miscellaneous,
+                command_number(&g.gcode),
+                Span::PLACEHOLDER,
+            )
+        });
+    let next_lines = next_source_lines(gcodes);
+    let mut sections = vec![];
+    let mut section = Section {
+        name: String::new(),
+        gcodes: vec![],
+        last_line: None,
+    };
+    let mut operation = None;
+    let mut cut = false;
+    let mut feedrate_set = true;
+    let mut modal = ModalState::default();
+    let mut feedrate = None;
+    for (fixed, next_line) in gcodes.iter().zip(next_lines) {
+        let gcode = &fixed.gcode;
+        let new_operation = next_line.and_then(operation_of);
+        let misc =
+            |number| gcode.mnemonic() == Mnemonic::Miscellaneous && gcode.major_number() == number;
+        let tool_change = gcode.mnemonic() == Mnemonic::ToolChange || misc(6);
+        let pause = misc(0) || misc(1);
+        if cut && (new_operation != operation || tool_change || pause) {
+            section.gcodes.extend(
+                end.clone()
+                    .map(|end| FixedGCode::inserted(end, "end of file")),
+            );
+            let gcodes = modal.restore("state of the previous file");
+            sections.push(std::mem::replace(
+                &mut section,
+                Section {
+                    name: String::new(),
+                    gcodes,
+                    last_line: None,
+                },
+            ));
+            cut = false;
+            feedrate_set = false;
+        }
+        if !cut {
+            section.name = match (&new_operation, gcode.mnemonic()) {
+                (Some(name), _) => name.clone(),
+                (None, Mnemonic::ToolChange) => format!("T{}", command_number(gcode)),
+                (None, _) => section.name.clone(),
+            };
+        }
+        operation = new_operation;
+        modal.update(gcode);
+        if pause && !cut {
+            continue;
+        }
+        section.last_line = source_line(gcode.span()).or(section.last_line);
+        let is_feed = gcode.mnemonic() == Mnemonic::General
+            && (1..=3).contains(&gcode.major_number())
+            && gcode.minor_number() == 0;
+        cut |= is_feed;
+        let restore_feedrate = is_feed && !feedrate_set && gcode.value_for('F').is_none();
+        feedrate = gcode.value_for('F').or(feedrate);
+        feedrate_set |= is_feed;
+        match (restore_feedrate, feedrate) {
+            (true, Some(feedrate)) => section.gcodes.push(
+                fixed
+                    .clone()
+                    .map(|g| g.with_argument(Word::new('F', feedrate, Span::PLACEHOLDER))),
+            ),
+            _ => section.gcodes.push(fixed.clone()),
+        }
+    }
+    sections.push(section);
+    sections
+}
+
+/// `text` of a command written the way commands are written to the output: uppercase letters,
+/// a space between words, and a digit in front of the point of numbers but none after it, e.g.
+/// `G1 X10 Y0.5` for `g1x10.y.5`. The digits are kept as they were written. `None` if the text
+/// holds more than words, like a comment.
+fn canonical_text(text: &str) -> Option<String> {
+    let mut words: Vec<String> = vec![];
+    for c in text.chars() {
+        match c {
+            c if c.is_ascii_alphabetic() => words.push(c.to_ascii_uppercase().to_string()),
+            '0'..='9' | '.' | '-' | '+' => words.last_mut()?.push(c),
+            c if c.is_whitespace() => {}
+            _ => return None,
+        }
+    }
+    let words = words
+        .iter()
+        .map(|word| {
+            let (letter, number) = word.split_at(1);
+            let (sign, digits) = match number.strip_prefix('-') {
+                Some(digits) => ("-", digits),
+                None => ("", number),
+            };
+            let digits = digits.strip_suffix('.').unwrap_or(digits);
+            let zero = if digits.starts_with('.') { "0" } else { "" };
+            (!digits.is_empty()).then(|| format!("{letter}{sign}{zero}{digits}"))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(words.join(" "))
+}
+
+/// The modal `G` codes in effect, which a file split from a program, or a resumed program, has
+/// to start with. Each is the number of the last command of its group, `None` if there was none.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModalState {
+    /// `G20` or `G21`.
+    pub units: Option<f32>,
+    /// `G17`, `G18` or `G19`.
+    pub plane: Option<f32>,
+    /// `G90` or `G91`.
+    pub distance: Option<f32>,
+    /// `G90.1` or `G91.1`.
+    pub arc_distance: Option<f32>,
+    /// `G93`, `G94` or `G95`.
+    pub feedrate_mode: Option<f32>,
+    /// `G54` to `G59.3`.
+    pub coordinate_system: Option<f32>,
+}
+
+impl ModalState {
+    pub fn update(&mut self, gcode: &GCode) {
+        if gcode.mnemonic() != Mnemonic::General {
+            return;
+        }
+        let number = Some(command_number(gcode));
+        match (gcode.major_number(), gcode.minor_number()) {
+            (20 | 21, 0) => self.units = number,
+            (17..=19, 0) => self.plane = number,
+            (90 | 91, 0) => self.distance = number,
+            (90 | 91, 1) => self.arc_distance = number,
+            (93..=95, 0) => self.feedrate_mode = number,
+            (54..=58, 0) | (59, 0..=3) => self.coordinate_system = number,
+            _ => {}
+        }
+    }
+
+    /// The commands which set this state, with `reason`.
+    pub fn restore(&self, reason: &str) -> Vec<FixedGCode> {
+        [
+            self.units,
+            self.plane,
+            self.distance,
+            self.arc_distance,
+            self.feedrate_mode,
+            self.coordinate_system,
+        ]
+        .into_iter()
+        .flatten()
+        .map(|number| {
+            FixedGCode::inserted(
+                GCode::new(Mnemonic::General, number, Span::PLACEHOLDER),
+                reason,
+            )
+        })
+        .collect()
+    }
+}
+
+/// Where a line of the output came from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineOrigin {
+    /// Zero-based input line of the command or comment. Inserted commands belong to the input
+    /// command they were inserted before. `None` for the header, footer and `%` lines.
+    pub input_line: Option<usize>,
+    /// The line holds a command which is not in the input, e.g. a swivel.
+    pub inserted: bool,
+}
+
+/// A line of the output program, before it is turned into text.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OutputLine {
+    pub code: String,
+    /// Comments including their delimiters, written after the code. Also holds the `%` of a
+    /// delimiter line, which must not be numbered.
+    pub comments: Vec<String>,
+    /// Where the line came from. It is not written.
+    pub origin: LineOrigin,
+}
+
+impl OutputLine {
+    pub fn code(code: String) -> Self {
+        OutputLine {
+            code,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_note(note: &SourceNote) -> Self {
+        let line = match note {
+            SourceNote::Comment { text, .. } => OutputLine {
+                comments: vec![text.clone()],
+                ..Default::default()
+            },
+            SourceNote::Blank { .. } => OutputLine::default(),
+            SourceNote::Delimiter { .. } => OutputLine::delimiter(),
+        };
+        OutputLine {
+            origin: LineOrigin {
+                input_line: Some(note.line()),
+                inserted: false,
+            },
+            ..line
+        }
+    }
+
+    /// A line from a header or footer, which is either code or a comment.
+    pub fn from_template_line(text: &str) -> Self {
+        let text = text.trim();
+        if text.starts_with('(') || text.starts_with(';') {
+            OutputLine {
+                comments: vec![text.to_string()],
+                ..Default::default()
+            }
+        } else {
+            OutputLine::code(text.to_string())
+        }
+    }
+
+    /// A `%` line.
+    pub fn delimiter() -> Self {
+        OutputLine {
+            comments: vec!["%".to_string()],
+            ..Default::default()
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::with_capacity(self.text_len());
+        self.write_text(&mut text);
+        text
+    }
+
+    /// Appends the line to `text`, like `to_text`.
+    pub fn write_text(&self, text: &mut String) {
+        let start = text.len();
+        *text += &self.code;
+        for comment in self.comments.iter() {
+            if text.len() > start {
+                *text += " ";
+            }
+            *text += comment;
+        }
+    }
+
+    /// The length of `to_text` in bytes.
+    pub fn text_len(&self) -> usize {
+        self.comments
+            .iter()
+            .fold(self.code.len(), |length, comment| {
+                length + usize::from(length > 0) + comment.len()
+            })
+    }
+}
+
+/// The (year, month, day) of a number of days since 1970-01-01.
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// The XOR of all bytes in the line, as used by RepRap style firmware.
+pub fn checksum(line: &str) -> u8 {
+    line.bytes().fold(0, |acc, b| acc ^ b)
+}
+
+/// The input line of each command, or for inserted commands that of the next command from the
+/// input, which they were inserted for.
+pub fn next_source_lines(gcodes: &[FixedGCode]) -> Vec<Option<usize>> {
+    let mut next_lines = vec![None; gcodes.len()];
+    let mut next_line = None;
+    for (i, fixed) in gcodes.iter().enumerate().rev() {
+        next_line = source_line(fixed.gcode.span()).or(next_line);
+        next_lines[i] = next_line;
+    }
+    next_lines
+}
+
+/// The zero-based input line of a span, if it came from the input.
+pub fn source_line(span: Span) -> Option<usize> {
+    if span.is_placeholder() {
+        None
+    } else {
+        Some(span.line)
+    }
+}
+
+fn is_move(gcode: &GCode) -> bool {
+    gcode.mnemonic() == Mnemonic::General && matches!(gcode.major_number(), 0..=3 | 28)
+}
+
+/// Whether two commands are the same, ignoring where they are in the program.
+fn same_command(a: &GCode, b: &GCode) -> bool {
+    a.mnemonic() == b.mnemonic()
+        && command_number(a) == command_number(b)
+        && a.arguments().len() == b.arguments().len()
+        && a.arguments()
+            .iter()
+            .zip(b.arguments())
+            .all(|(a, b)| a.letter == b.letter && a.value == b.value)
+}
+
+fn height_move(height_name: char, height: f32) -> GCode {
+    GCode::new(Mnemonic::General, 0.0, Span::PLACEHOLDER).with_argument(Word::new(
+        height_name,
+        height,
+        Span::PLACEHOLDER,
+    ))
+}
+
+/// The smallest and largest coordinates a move from `start` to `end` passes through, including
+/// the points where arcs bulge out furthest.
+fn move_extent(
+    gcode: &GCode,
+    plane: &GCodePlane,
+    start: &[f64; 3],
+    end: &[f64; 3],
+) -> ([f64; 3], [f64; 3]) {
+    let mut min = [0, 1, 2].map(|i| start[i].min(end[i]));
+    let mut max = [0, 1, 2].map(|i| start[i].max(end[i]));
+    if !matches!(gcode.major_number(), 2 | 3) {
+        return (min, max);
+    }
+    let arc = PlaneArc::new(gcode, plane, start, end);
+    for quadrant in 0..4 {
+        let angle = quadrant as f64 * FRAC_PI_2;
+        let along = if arc.clockwise {
+            arc.from - angle
+        } else {
+            angle - arc.from
+        }
+        .rem_euclid(TAU);
+        if along > arc.sweep {
+            continue;
+        }
+        let point = arc.point(angle);
+        for i in 0..2 {
+            min[arc.index[i]] = min[arc.index[i]].min(point[i]);
+            max[arc.index[i]] = max[arc.index[i]].max(point[i]);
+        }
+    }
+    (min, max)
+}
+
+/// An arc command with a relative center, in its plane.
+struct PlaneArc {
+    /// The axes of the plane, as indices into positions.
+    index: [usize; 2],
+    center: [f64; 2],
+    radius: f64,
+    /// The angle of the start around the center.
+    from: f64,
+    /// How far the arc turns, in radians. Arcs ending where they start are full circles.
+    sweep: f64,
+    clockwise: bool,
+}
+
+impl PlaneArc {
+    fn new(gcode: &GCode, plane: &GCodePlane, start: &[f64; 3], end: &[f64; 3]) -> Self {
+        let index =
+            [plane.axis_1(), plane.axis_2()].map(|axis| axis_index(axis.main_name()).unwrap());
+        let center = [plane.axis_1(), plane.axis_2()]
+            .map(|axis| f64::from(gcode.value_for(axis.center_name()).unwrap_or(0.)));
+        let center = [0, 1].map(|i| start[index[i]] + center[i]);
+        let angle_of =
+            |pos: &[f64; 3]| (pos[index[1]] - center[1]).atan2(pos[index[0]] - center[0]);
+        let (from, to) = (angle_of(start), angle_of(end));
+        let clockwise = gcode.major_number() == 2;
+        let sweep = if clockwise { from - to } else { to - from }.rem_euclid(TAU);
+        // A full circle ends where it starts
+        let sweep = if sweep < 1e-6 { TAU } else { sweep };
+        PlaneArc {
+            index,
+            center,
+            radius: (start[index[0]] - center[0]).hypot(start[index[1]] - center[1]),
+            from,
+            sweep,
+            clockwise,
+        }
+    }
+
+    /// The point of the circle at `angle` around the center.
+    fn point(&self, angle: f64) -> [f64; 2] {
+        [
+            self.center[0] + self.radius * angle.cos(),
+            self.center[1] + self.radius * angle.sin(),
+        ]
+    }
+
+    /// The point `fraction` of the way along the arc.
+    fn point_along(&self, fraction: f64) -> [f64; 2] {
+        let turned = self.sweep * fraction;
+        self.point(if self.clockwise {
+            self.from - turned
+        } else {
+            self.from + turned
+        })
+    }
+
+    /// The length of the arc, including the rise along the third axis of helixes.
+    fn length(&self, rise: f64) -> f64 {
+        (self.radius * self.sweep).hypot(rise)
+    }
+}
+
+/// Turns an arc with a relative center, starting at `start`, into one with a radius.
+///
+/// Returns `None` for full circles.
+fn radius_arc(arc: &GCode, plane: &GCodePlane, start: &[f64; 3]) -> Option<GCode> {
+    let names = [plane.axis_1().main_name(), plane.axis_2().main_name()];
+    let center_names = [plane.axis_1().center_name(), plane.axis_2().center_name()];
+    let index = names.map(|name| axis_index(name).unwrap());
+    let center = center_names.map(|name| f64::from(arc.value_for(name).unwrap_or(0.)));
+    let end = [0, 1].map(|i| arc.value_for(names[i]).map_or(start[index[i]], f64::from));
+    // From the center to the start and end of the arc
+    let from = [-center[0], -center[1]];
+    let to = [
+        end[0] - start[index[0]] - center[0],
+        end[1] - start[index[1]] - center[1],
+    ];
+    if (to[0] - from[0]).hypot(to[1] - from[1]) < 1e-6 {
+        return None;
+    }
+    let ccw_angle = (from[0] * to[1] - from[1] * to[0]).atan2(from[0] * to[0] + from[1] * to[1]);
+    let angle = if arc.major_number() == 2 {
+        -ccw_angle
+    } else {
+        ccw_angle
+    }
+    .rem_euclid(TAU);
+    let radius = from[0].hypot(from[1]);
+
+    let mut new = GCode::new(arc.mnemonic(), command_number(arc), arc.span());
+    for arg in arc.arguments() {
+        if !center_names.contains(&arg.letter) {
+            new.push_argument(*arg).unwrap();
+        }
+    }
+    new.push_argument(Word::new(
+        'R',
+        if angle > PI { -radius } else { radius } as f32,
+        Span::PLACEHOLDER,
+    ))
+    .unwrap();
+    Some(new)
+}
+
+/// The absolute position in program coordinates, followed through the output.
+///
+/// The program is assumed to start at the origin, which is also where `G28` ends up.
+#[derive(Debug, Default)]
+struct ProgramPosition {
+    pos: [f64; 3],
+    /// The rotary axes `A`, `B` and `C`, in degrees, which do not change with the units.
+    rotary: [f64; 3],
+    inches: bool,
+}
+
+impl ProgramPosition {
+    /// Follows a command with absolute coordinates.
+    fn update(&mut self, gcode: &GCode) {
+        if gcode.mnemonic() == Mnemonic::General && (0..=3).contains(&gcode.major_number()) {
+            for arg in gcode.arguments() {
+                if let Some(axis) = axis_index(arg.letter) {
+                    self.pos[axis] = arg.value.into();
+                } else if let Some(axis) = rotary_index(arg.letter) {
+                    self.rotary[axis] = arg.value.into();
+                }
+            }
+        }
+        self.update_settings(gcode);
+    }
+
+    /// Follows unit changes, homing and coordinates set with `G10 L20` or `G92`.
+    fn update_settings(&mut self, gcode: &GCode) {
+        if gcode.mnemonic() != Mnemonic::General || gcode.minor_number() != 0 {
+            return;
+        }
+        match gcode.major_number() {
+            20 if !self.inches => {
+                self.inches = true;
+                self.pos = self.pos.map(|p| p / 25.4);
+            }
+            21 if self.inches => {
+                self.inches = false;
+                self.pos = self.pos.map(|p| p * 25.4);
+            }
+            28 => {
+                let all = gcode.arguments().is_empty();
+                for (axis, pos) in self.pos.iter_mut().enumerate() {
+                    if all
+                        || gcode
+                            .arguments()
+                            .iter()
+                            .any(|a| axis_index(a.letter) == Some(axis))
+                    {
+                        *pos = 0.;
+                    }
+                }
+                for (axis, rotary) in self.rotary.iter_mut().enumerate() {
+                    if all
+                        || gcode
+                            .arguments()
+                            .iter()
+                            .any(|a| rotary_index(a.letter) == Some(axis))
+                    {
+                        *rotary = 0.;
+                    }
+                }
+            }
+            10 | 92 if gcode.major_number() == 92 || gcode.value_for('L') == Some(20.) => {
+                for arg in gcode.arguments() {
+                    if let Some(axis) = axis_index(arg.letter) {
+                        self.pos[axis] = arg.value.into();
+                    } else if let Some(axis) = rotary_index(arg.letter) {
+                        self.rotary[axis] = arg.value.into();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn rotary_index(letter: char) -> Option<usize> {
+    ROTARY_AXES.iter().position(|&axis| axis == letter)
+}
+
+fn axis_index(letter: char) -> Option<usize> {
+    match letter {
+        'X' => Some(0),
+        'Y' => Some(1),
+        'Z' => Some(2),
+        _ => None,
+    }
+}
+
+fn center_index(letter: char) -> Option<usize> {
+    match letter {
+        'I' => Some(0),
+        'J' => Some(1),
+        'K' => Some(2),
+        _ => None,
+    }
+}
+
+/// Millimeters per unit of the units `gcode` selects, if it is `G20` or `G21`.
+pub fn selected_unit(gcode: &GCode) -> Option<f32> {
+    match (gcode.mnemonic(), gcode.major_number(), gcode.minor_number()) {
+        (Mnemonic::General, 20, 0) => Some(25.4),
+        (Mnemonic::General, 21, 0) => Some(1.),
+        _ => None,
+    }
+}
+
+/// Tracks plane selection commands.
+pub fn update_plane(plane: &mut GCodePlane, gcode: &GCode) {
+    if gcode.mnemonic() != Mnemonic::General {
+        return;
+    }
+    match gcode.major_number() {
+        17 => *plane = GCodePlane::XY,
+        18 => *plane = GCodePlane::ZX,
+        19 => *plane = GCodePlane::YZ,
+        _ => {}
+    }
+}
+
+pub fn mnemonic_letter(mnemonic: Mnemonic) -> char {
+    match mnemonic {
+        Mnemonic::General => 'G',
+        Mnemonic::Miscellaneous => 'M',
+        Mnemonic::ProgramNumber => 'O',
+        Mnemonic::ToolChange => 'T',
+    }
+}
+
+/// Removes trailing zeros after the decimal point from the number at `start` of `text` if
+/// `trim_zeros` is set, and the sign of negative zero.
+fn trim_number(text: &mut String, start: usize, trim_zeros: bool) {
+    if trim_zeros && text[start..].contains('.') {
+        let trimmed = text.trim_end_matches('0').trim_end_matches('.').len();
+        text.truncate(trimmed);
+    }
+    if text[start..].starts_with('-') && text[start + 1..].chars().all(|c| c == '0' || c == '.') {
+        // Avoid writing negative zero
+        text.remove(start);
+    }
+}
+
+/// The full command number, e.g. `90.1`.
+pub fn command_number(gcode: &GCode) -> f32 {
+    gcode.major_number() as f32 + gcode.minor_number() as f32 / 10.
+}
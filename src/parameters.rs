@@ -0,0 +1,349 @@
+use std::{collections::HashMap, error::Error, fmt::Display};
+
+/// A parameter or expression which could not be evaluated.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParameterError {
+    /// The parameter is used before it is set. Only returned in strict mode.
+    Unresolved {
+        line: usize,
+        parameter: String,
+    },
+    Syntax {
+        line: usize,
+        message: String,
+    },
+    /// A value which is infinite or not a number, e.g. from a division by zero.
+    NotFinite {
+        line: usize,
+    },
+}
+
+impl Display for ParameterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParameterError::Unresolved { line, parameter } => {
+                write!(f, "Parameter #{parameter} on line {} is not set", line + 1)
+            }
+            ParameterError::Syntax { line, message } => {
+                write!(f, "Invalid expression on line {}: {message}", line + 1)
+            }
+            ParameterError::NotFinite { line } => {
+                write!(f, "Expression on line {} is not a finite number", line + 1)
+            }
+        }
+    }
+}
+
+impl Error for ParameterError {}
+
+/// Replaces parameters (`#100`, `#<depth>`) and expressions (`[#100*2]`) in word values with
+/// their numbers, so the program can be parsed as plain gcode.
+///
+/// Assignments (`#100 = 5.0`) are kept as comments. Unset parameters are 0, like on most
+/// controllers, unless `strict` is set.
+pub fn resolve_parameters(src: &str, strict: bool) -> Result<String, ParameterError> {
+    if !src.contains('#') && !src.contains('[') {
+        return Ok(src.to_string());
+    }
+    let mut evaluator = Evaluator {
+        parameters: HashMap::new(),
+        strict,
+    };
+    let mut out = String::with_capacity(src.len());
+    for (line, text) in src.split_inclusive('\n').enumerate() {
+        let mut parser = Parser {
+            chars: text.chars().collect(),
+            pos: 0,
+            line,
+        };
+        evaluator.resolve_line(&mut parser, &mut out)?;
+    }
+    Ok(out)
+}
+
+type Function = fn(f64) -> f64;
+
+struct Evaluator {
+    parameters: HashMap<String, f64>,
+    strict: bool,
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c == ' ' || c == '\t') {
+            self.pos += 1;
+        }
+    }
+
+    /// Skips whitespace and consumes `c` if it is next.
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek().is_some_and(|n| n.eq_ignore_ascii_case(&c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Skips whitespace and consumes `word` (case insensitive) if it is next.
+    fn eat_word(&mut self, word: &str) -> bool {
+        self.skip_whitespace();
+        let matches = word.chars().enumerate().all(|(i, c)| {
+            self.chars
+                .get(self.pos + i)
+                .is_some_and(|n| n.eq_ignore_ascii_case(&c))
+        });
+        if matches {
+            self.pos += word.len();
+        }
+        matches
+    }
+
+    fn error(&self, message: impl Into<String>) -> ParameterError {
+        ParameterError::Syntax {
+            line: self.line,
+            message: message.into(),
+        }
+    }
+}
+
+impl Evaluator {
+    fn resolve_line(&mut self, p: &mut Parser, out: &mut String) -> Result<(), ParameterError> {
+        while let Some(c) = p.peek() {
+            match c {
+                '(' => {
+                    let end = p.chars[p.pos..]
+                        .iter()
+                        .position(|&c| c == ')')
+                        .map_or(p.chars.len(), |end| p.pos + end + 1);
+                    out.extend(&p.chars[p.pos..end]);
+                    p.pos = end;
+                }
+                ';' => {
+                    out.extend(&p.chars[p.pos..]);
+                    p.pos = p.chars.len();
+                }
+                '#' => {
+                    let start = p.pos;
+                    p.pos += 1;
+                    let name = self.parameter_name(p)?;
+                    if !p.eat('=') {
+                        return Err(p.error(format!("expected `=` after #{name}")));
+                    }
+                    let value = self.expression(p)?;
+                    self.parameters.insert(name, value);
+                    let assignment: String = p.chars[start..p.pos].iter().collect();
+                    out.push_str(&format!("({})", assignment.trim()));
+                }
+                c if c.is_ascii_alphabetic() => {
+                    out.push(c);
+                    p.pos += 1;
+                    // Only values starting with a parameter or expression need evaluating
+                    let start = p.pos;
+                    let negative = p.eat('-');
+                    if p.eat('#') || p.eat('[') {
+                        p.pos -= 1;
+                        let value = self.factor(p)?;
+                        let value = if negative { -value } else { value } as f32;
+                        if !value.is_finite() {
+                            return Err(ParameterError::NotFinite { line: p.line });
+                        }
+                        out.push_str(&format!("{value}"));
+                    } else {
+                        p.pos = start;
+                    }
+                }
+                c => {
+                    out.push(c);
+                    p.pos += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The name after a `#`: a number, `<name>` or `[expression]`.
+    fn parameter_name(&mut self, p: &mut Parser) -> Result<String, ParameterError> {
+        if p.eat('<') {
+            let end = p.chars[p.pos..]
+                .iter()
+                .position(|&c| c == '>')
+                .ok_or_else(|| p.error("missing `>`"))?;
+            let name: String = p.chars[p.pos..p.pos + end].iter().collect();
+            p.pos += end + 1;
+            return Ok(format!("<{}>", name.trim().to_lowercase()));
+        }
+        if p.peek() == Some('[') {
+            return Ok(format!("{}", self.factor(p)? as i64));
+        }
+        let number = self.number(p)?;
+        Ok(format!("{}", number as i64))
+    }
+
+    fn expression(&mut self, p: &mut Parser) -> Result<f64, ParameterError> {
+        let mut value = self.term(p)?;
+        loop {
+            if p.eat('+') {
+                value += self.term(p)?;
+            } else if p.eat('-') {
+                value -= self.term(p)?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self, p: &mut Parser) -> Result<f64, ParameterError> {
+        let mut value = self.factor(p)?;
+        loop {
+            if p.eat('*') {
+                value *= self.factor(p)?;
+            } else if p.eat('/') {
+                value /= self.factor(p)?;
+            } else if p.eat_word("MOD") {
+                value = value.rem_euclid(self.factor(p)?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn factor(&mut self, p: &mut Parser) -> Result<f64, ParameterError> {
+        if p.eat('-') {
+            return Ok(-self.factor(p)?);
+        }
+        if p.eat('+') {
+            return self.factor(p);
+        }
+        if p.eat('[') {
+            let value = self.expression(p)?;
+            if !p.eat(']') {
+                return Err(p.error("missing `]`"));
+            }
+            return Ok(value);
+        }
+        if p.eat('#') {
+            let name = self.parameter_name(p)?;
+            return match self.parameters.get(&name) {
+                Some(value) => Ok(*value),
+                None if self.strict => Err(ParameterError::Unresolved {
+                    line: p.line,
+                    parameter: name,
+                }),
+                None => Ok(0.),
+            };
+        }
+        // Angles are in degrees
+        if p.eat_word("ATAN") {
+            let y = self.bracketed(p, "ATAN")?;
+            // `ATAN[y]/[x]` is the angle of the point (x, y)
+            if !p.eat('/') {
+                return Ok(y.atan().to_degrees());
+            }
+            let x = self.bracketed(p, "ATAN[...]/")?;
+            return Ok(y.atan2(x).to_degrees());
+        }
+        let functions: [(&str, Function); 7] = [
+            ("ABS", f64::abs),
+            ("SQRT", f64::sqrt),
+            ("SIN", |a| a.to_radians().sin()),
+            ("COS", |a| a.to_radians().cos()),
+            ("TAN", |a| a.to_radians().tan()),
+            ("ROUND", f64::round),
+            ("FIX", f64::floor),
+        ];
+        for (name, function) in functions {
+            if p.eat_word(name) {
+                return Ok(function(self.bracketed(p, name)?));
+            }
+        }
+        self.number(p)
+    }
+
+    /// The `[expression]` after `after`.
+    fn bracketed(&mut self, p: &mut Parser, after: &str) -> Result<f64, ParameterError> {
+        if !p.eat('[') {
+            return Err(p.error(format!("expected `[` after {after}")));
+        }
+        p.pos -= 1;
+        self.factor(p)
+    }
+
+    fn number(&mut self, p: &mut Parser) -> Result<f64, ParameterError> {
+        p.skip_whitespace();
+        let start = p.pos;
+        while p.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+            p.pos += 1;
+        }
+        let text: String = p.chars[start..p.pos].iter().collect();
+        text.parse()
+            .map_err(|_| p.error(format!("expected a number at column {}", start + 1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The value written for `X` on the last line of `program`.
+    fn x_value(program: &str) -> f64 {
+        let resolved = resolve_parameters(program, false).unwrap();
+        let line = resolved.lines().last().unwrap();
+        line[line.find('X').unwrap() + 1..].parse().unwrap()
+    }
+
+    #[test]
+    fn operators_follow_precedence() {
+        assert_eq!(x_value("G1 X[1+2*3]"), 7.);
+        assert_eq!(x_value("G1 X[[1+2]*3]"), 9.);
+        assert_eq!(x_value("G1 X[10-4-3]"), 3.);
+        assert_eq!(x_value("G1 X[12/2/3]"), 2.);
+        assert_eq!(x_value("G1 X[2+7 MOD 4]"), 5.);
+        assert_eq!(x_value("G1 X[-2*-3]"), 6.);
+        assert_eq!(x_value("#1=4\nG1 X-[#1+1]"), -5.);
+    }
+
+    #[test]
+    fn atan_takes_one_or_two_arguments() {
+        assert!((x_value("#1=1\n#2=2\nG1 X[ATAN[#1]/[#2]]") - 26.565_051).abs() < 1e-4);
+        assert_eq!(x_value("G1 X[ATAN[1]/[-1]]"), 135.);
+        assert_eq!(x_value("G1 X[ATAN[1]]"), 45.);
+        assert_eq!(x_value("G1 X[ATAN[1]/[1]*2]"), 90.);
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        assert_eq!(
+            resolve_parameters("#1=0\nG1 X[1/#1]", false),
+            Err(ParameterError::NotFinite { line: 1 })
+        );
+        assert_eq!(
+            resolve_parameters("G1 X[SQRT[-1]]", false),
+            Err(ParameterError::NotFinite { line: 0 })
+        );
+    }
+
+    #[test]
+    fn undefined_parameters_are_zero_unless_strict() {
+        assert_eq!(x_value("G1 X[#5+1]"), 1.);
+        assert_eq!(x_value("#<depth>=2\nG1 X#<Depth>"), 2.);
+        assert_eq!(
+            resolve_parameters("G1 X#5", true),
+            Err(ParameterError::Unresolved {
+                line: 0,
+                parameter: "5".to_string()
+            })
+        );
+    }
+}
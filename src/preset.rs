@@ -0,0 +1,196 @@
+use std::{error::Error, f64::consts::PI, fmt::Display, fs::File, io::BufReader, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    operations::Operation,
+    types::{DragknifeConfig, LiftConfig},
+};
+
+/// A named dragknife configuration, usually describing a blade and material combination.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preset {
+    pub name: String,
+    pub config: DragknifeConfig,
+}
+
+impl Preset {
+    pub fn new(name: impl Into<String>, config: DragknifeConfig) -> Self {
+        Preset {
+            name: name.into(),
+            config,
+        }
+    }
+}
+
+/// A collection of presets which can be stored in and loaded from a JSON file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PresetLibrary {
+    pub presets: Vec<Preset>,
+}
+
+impl PresetLibrary {
+    /// A few common blade and material combinations to start from.
+    pub fn builtin() -> Self {
+        PresetLibrary {
+            presets: vec![
+                Preset::new(
+                    "30° blade on 3mm cardboard",
+                    DragknifeConfig::new(
+                        0.25,
+                        LiftConfig::RelativeHeight(1.0),
+                        10. * PI / 180.,
+                        300.,
+                    ),
+                ),
+                Preset::new(
+                    "45° blade on paper",
+                    DragknifeConfig::new(
+                        0.35,
+                        LiftConfig::RelativeHeight(0.5),
+                        10. * PI / 180.,
+                        300.,
+                    ),
+                ),
+                Preset::new(
+                    "60° blade on vinyl",
+                    DragknifeConfig::new(
+                        0.5,
+                        LiftConfig::RelativeHeight(0.5),
+                        15. * PI / 180.,
+                        500.,
+                    ),
+                ),
+                // For 3D printers converted to cutters, with the knife on a servo or on a
+                // solenoid driven by the part cooling fan output
+                Preset::new(
+                    "60° blade on vinyl, servo lift (Marlin)",
+                    DragknifeConfig {
+                        lift_template: "M280 P0 S90\nG4 S0.2".to_string(),
+                        lower_template: "M280 P0 S0\nG4 S0.2".to_string(),
+                        ..DragknifeConfig::new(
+                            0.5,
+                            LiftConfig::RelativeHeight(0.5),
+                            15. * PI / 180.,
+                            500.,
+                        )
+                    },
+                ),
+                Preset::new(
+                    "60° blade on vinyl, fan output solenoid (Marlin)",
+                    DragknifeConfig {
+                        lift_template: "M107\nG4 S0.1".to_string(),
+                        lower_template: "M106 S255\nG4 S0.1".to_string(),
+                        ..DragknifeConfig::new(
+                            0.5,
+                            LiftConfig::RelativeHeight(0.5),
+                            15. * PI / 180.,
+                            500.,
+                        )
+                    },
+                ),
+                // Crease lines pressed in partway with a creasing wheel, which needs no swivels,
+                // and the rest cut through with the knife, swapped by hand at the M0
+                Preset::new(
+                    "Packaging: crease and cut 3mm cardboard",
+                    DragknifeConfig {
+                        operations: vec![
+                            Operation {
+                                name: "crease".to_string(),
+                                depth: Some(-1.),
+                                tool_change: "M0".to_string(),
+                                knife_offset: Some(0.),
+                                sharp_angle_threshold: Some(PI),
+                                ..Default::default()
+                            },
+                            Operation {
+                                name: "cut".to_string(),
+                                tool_change: "M0".to_string(),
+                                ..Default::default()
+                            },
+                        ],
+                        ..DragknifeConfig::new(
+                            0.25,
+                            LiftConfig::RelativeHeight(1.0),
+                            10. * PI / 180.,
+                            300.,
+                        )
+                    },
+                ),
+            ],
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Preset> {
+        self.presets.iter().find(|p| p.name == name)
+    }
+
+    /// Adds a preset, replacing any existing preset with the same name.
+    pub fn insert(&mut self, preset: Preset) {
+        if let Some(existing) = self.presets.iter_mut().find(|p| p.name == preset.name) {
+            *existing = preset;
+        } else {
+            self.presets.push(preset);
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Preset> {
+        let index = self.presets.iter().position(|p| p.name == name)?;
+        Some(self.presets.remove(index))
+    }
+
+    /// Adds all presets from `other`, replacing presets with the same name.
+    pub fn merge(&mut self, other: PresetLibrary) {
+        for preset in other.presets {
+            self.insert(preset);
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PresetError> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), PresetError> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    Io(std::io::Error),
+    Format(serde_json::Error),
+}
+
+impl From<std::io::Error> for PresetError {
+    fn from(err: std::io::Error) -> Self {
+        PresetError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for PresetError {
+    fn from(err: serde_json::Error) -> Self {
+        PresetError::Format(err)
+    }
+}
+
+impl Display for PresetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "Could not access preset file: {err}"),
+            Self::Format(err) => write!(f, "Could not read presets: {err}"),
+        }
+    }
+}
+
+impl Error for PresetError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Format(err) => Some(err),
+        }
+    }
+}
@@ -0,0 +1,473 @@
+use std::{
+    collections::HashMap, error::Error, f64::consts::PI, fmt::Display, fmt::Write, ops::Range,
+    path::Path,
+};
+
+use serde::Serialize;
+
+use crate::{
+    output::source_line,
+    signed_angle,
+    stats::{arc_sweep, run_time, MIN_CORNER},
+    types::{Command, DragknifeConfig, FixedGCode, GCodeState, Movement},
+    vec3::Vec3,
+    DragknifePath,
+};
+
+/// Arcs are drawn as lines turning at most this much.
+const ARC_STEP: f64 = PI / 36.;
+
+/// Space around the path in exported images, in mm.
+const IMAGE_MARGIN: f64 = 2.;
+
+/// Exported images are at most this many pixels wide and high.
+const MAX_IMAGE_SIZE: f64 = 16384.;
+
+/// What a move of the preview does to the material.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MoveKind {
+    Cut,
+    Rapid,
+    /// Inserted to turn the knife at a corner: the lift, swivel or reversal move and lower.
+    Swivel,
+    /// A pause (`G4`) at the only point of the move.
+    Dwell,
+}
+
+/// A move of the repathed program as a line through `points`, in mm in work coordinates.
+#[derive(Debug, Clone)]
+pub struct PreviewMove {
+    pub kind: MoveKind,
+    pub points: Vec<Vec3>,
+    /// The time the move takes at the programmed feedrate, in seconds.
+    pub duration: f64,
+}
+
+/// A change of direction between two cuts of the input, of more than a degree.
+#[derive(Debug, Clone)]
+pub struct PreviewCorner {
+    /// The corner of the input cut, in mm in work coordinates.
+    pub position: Vec3,
+    /// The change of direction in radians, positive for left turns.
+    pub angle: f64,
+    /// The angle above which the corner gets a swivel, see `DragknifeConfig::angle_threshold`.
+    pub threshold: f64,
+    /// Zero-based input line of the cut after the corner.
+    pub line: Option<usize>,
+    /// The moves inserted to turn the knife, if the corner is sharp enough.
+    pub swivel: Option<PreviewSwivel>,
+}
+
+/// The moves inserted at a corner to turn the knife.
+#[derive(Debug, Clone)]
+pub struct PreviewSwivel {
+    /// The knife is lifted and turned beside the corner instead of swiveled at it, see
+    /// `DragknifeConfig::reversal_angle`.
+    pub reversal: bool,
+    /// The inserted moves, as indices into `Preview::moves`.
+    pub moves: Range<usize>,
+}
+
+/// The repathed program traced for display, with its corners and the swivels they got.
+#[derive(Debug, Clone, Default)]
+pub struct Preview {
+    pub moves: Vec<PreviewMove>,
+    pub corners: Vec<PreviewCorner>,
+    /// How far the blade trails behind the knife holder, in mm.
+    pub knife_offset: f64,
+}
+
+/// Where the knife is at a moment of the program, see [`Preview::knife_at`].
+#[derive(Debug, Clone, Copy)]
+pub struct KnifePosition {
+    /// The position of the knife holder, which follows the program, in mm in work coordinates.
+    pub holder: Vec3,
+    /// Where the blade tip is dragged to, `Preview::knife_offset` behind the holder once it
+    /// has moved that far.
+    pub tip: Vec3,
+    /// Index into `Preview::moves` of the move being made.
+    pub move_index: usize,
+}
+
+impl Preview {
+    /// Traces `fixed`, the output of [`DragknifePath::to_fixed_gcode`] for `path`, and finds the
+    /// corners of `path`. Rapid moves are timed at `rapid_feedrate` mm/min.
+    pub fn new(
+        path: &DragknifePath,
+        fixed: &[FixedGCode],
+        config: &DragknifeConfig,
+        rapid_feedrate: f64,
+    ) -> Self {
+        let mut preview = Preview {
+            knife_offset: config.knife_offset,
+            ..Default::default()
+        };
+        // The moves inserted before each input line
+        let mut inserted_moves = HashMap::new();
+        let mut inserted_from = None;
+        let mut settings = path.initial_state;
+        let mut degenerate_arcs = vec![];
+        let mut prev: Option<Command> = None;
+        for fixed in fixed {
+            let Ok(command) = Command::from_gcode(
+                &fixed.gcode,
+                prev.as_ref(),
+                &mut settings,
+                &mut degenerate_arcs,
+            ) else {
+                continue;
+            };
+            command.update_settings(&mut settings);
+            let inserted = fixed
+                .reason
+                .as_deref()
+                .is_some_and(|reason| reason != "knife offset");
+            if inserted {
+                inserted_from.get_or_insert(preview.moves.len());
+            } else if let Some(line) = source_line(fixed.gcode.span()) {
+                if let Some(from) = inserted_from.take() {
+                    inserted_moves.insert(line, from..preview.moves.len());
+                }
+            }
+            let duration = run_time(&command, &settings, rapid_feedrate) * 60.;
+            // Cuts before a feedrate is set would take forever
+            let duration = if duration.is_finite() { duration } else { 0. };
+            let kind = match &command {
+                Command::Other(_) if duration > 0. => Some(MoveKind::Dwell),
+                Command::Other(_) => None,
+                _ if inserted => Some(MoveKind::Swivel),
+                Command::Linear(_) | Command::Arc(_) => Some(MoveKind::Cut),
+                Command::Rapid(_) | Command::Home(_) => Some(MoveKind::Rapid),
+            };
+            if let Some(kind) = kind {
+                let points = trace(&command, &settings)
+                    .into_iter()
+                    .map(|point| settings.to_work_coords(point))
+                    .collect();
+                preview.moves.push(PreviewMove {
+                    kind,
+                    points,
+                    duration,
+                });
+            }
+            prev = Some(command);
+        }
+        let mut settings = path.initial_state;
+        let mut prev_angle = None;
+        for command in &path.commands {
+            command.update_settings(&mut settings);
+            if let (Command::Linear(_) | Command::Arc(_), Some(from), Some(to)) =
+                (command, prev_angle, command.start_angle())
+            {
+                let angle = signed_angle(from, to);
+                let line = source_line(command.original().span());
+                let swivel = line
+                    .and_then(|line| inserted_moves.remove(&line))
+                    .map(|moves| PreviewSwivel {
+                        reversal: config.is_reversal(angle),
+                        moves,
+                    });
+                if swivel.is_some() || angle.abs() > MIN_CORNER {
+                    preview.corners.push(PreviewCorner {
+                        position: settings.to_work_coords(command.start_pos()),
+                        angle,
+                        threshold: config.angle_threshold(angle),
+                        line,
+                        swivel,
+                    });
+                }
+            }
+            prev_angle = command.end_angle();
+        }
+        preview
+    }
+
+    /// The time the whole program takes, in seconds.
+    pub fn duration(&self) -> f64 {
+        self.moves.iter().map(|m| m.duration).sum()
+    }
+
+    /// Where the knife is `time` seconds into the program, or `None` if nothing moves. The blade
+    /// tip is dragged behind the holder in the XY plane, like the back wheel of a bicycle.
+    pub fn knife_at(&self, time: f64) -> Option<KnifePosition> {
+        let mut holder = *self.moves.first()?.points.first()?;
+        let mut tip = holder;
+        let drag_to = |tip: &mut Vec3, to: Vec3| {
+            let behind = Vec3::new(tip.x - to.x, tip.y - to.y, 0.);
+            if behind.magnitude() > self.knife_offset {
+                *tip = to + behind.normalized() * self.knife_offset;
+            }
+            tip.z = to.z;
+        };
+        let mut elapsed = 0.;
+        for (move_index, preview_move) in self.moves.iter().enumerate() {
+            let fraction = if elapsed + preview_move.duration > time {
+                ((time - elapsed) / preview_move.duration).max(0.)
+            } else {
+                1.
+            };
+            elapsed += preview_move.duration;
+            let segments = preview_move.points.windows(2);
+            let length: f64 = segments.clone().map(|s| (s[1] - s[0]).magnitude()).sum();
+            let mut left = length * fraction;
+            for segment in segments {
+                if left <= 0. && fraction < 1. {
+                    break;
+                }
+                let (from, to) = (segment[0], segment[1]);
+                let along = (to - from).magnitude().min(left);
+                left -= along;
+                // Small steps, so the tip follows curves instead of cutting across them
+                let steps = (along / (self.knife_offset / 4.).max(0.01)).ceil().max(1.) as usize;
+                for step in 1..=steps {
+                    holder = from + (to - from).normalized() * (along * step as f64 / steps as f64);
+                    drag_to(&mut tip, holder);
+                }
+            }
+            if fraction < 1. || move_index + 1 == self.moves.len() {
+                return Some(KnifePosition {
+                    holder,
+                    tip,
+                    move_index,
+                });
+            }
+        }
+        None
+    }
+
+    /// The lowest and highest X, Y and Z of all moves, or `None` if nothing moves.
+    pub fn bounds(&self) -> Option<(Vec3, Vec3)> {
+        let mut points = self.moves.iter().flat_map(|m| m.points.iter());
+        let first = *points.next()?;
+        Some(points.fold((first, first), |(min, max), point| {
+            (
+                Vec3::new(min.x.min(point.x), min.y.min(point.y), min.z.min(point.z)),
+                Vec3::new(max.x.max(point.x), max.y.max(point.y), max.z.max(point.z)),
+            )
+        }))
+    }
+}
+
+impl Preview {
+    /// Draws the moves seen from above as an SVG image of `pixels_per_mm` pixels per mm:
+    /// cuts solid, swivels and rapid moves dashed. Coordinates in the image are in mm.
+    pub fn to_svg(&self, pixels_per_mm: f64) -> Result<String, PreviewImageError> {
+        let (min, max, width, height) = self.image_size(pixels_per_mm)?;
+        let (left, top) = (min.x - IMAGE_MARGIN, -max.y - IMAGE_MARGIN);
+        let (view_width, view_height) =
+            (width as f64 / pixels_per_mm, height as f64 / pixels_per_mm);
+        let mut svg = String::new();
+        let _ = writeln!(
+            svg,
+            concat!(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}""#,
+                r#" viewBox="{:.3} {:.3} {:.3} {:.3}">"#
+            ),
+            width, height, left, top, view_width, view_height
+        );
+        let _ = writeln!(
+            svg,
+            concat!(
+                r#"<g fill="none" stroke-linecap="round" stroke-linejoin="round""#,
+                r#" stroke-width="{:.4}">"#
+            ),
+            1.5 / pixels_per_mm
+        );
+        for preview_move in &self.moves {
+            let Some((color, dashed)) = image_style(preview_move.kind) else {
+                continue;
+            };
+            let mut points = preview_move.points.iter();
+            let Some(first) = points.next() else {
+                continue;
+            };
+            // Y points up in G-code and down in SVG, and `0. - y` avoids writing `-0`
+            let mut path = format!("M{:.3} {:.3}", first.x, 0. - first.y);
+            for point in points {
+                let _ = write!(path, " L{:.3} {:.3}", point.x, 0. - point.y);
+            }
+            let [r, g, b] = color;
+            let dash = if dashed {
+                format!(
+                    r#" stroke-dasharray="{:.4} {:.4}""#,
+                    6. / pixels_per_mm,
+                    4. / pixels_per_mm
+                )
+            } else {
+                String::new()
+            };
+            let _ = writeln!(svg, r#"<path d="{path}" stroke="rgb({r},{g},{b})"{dash}/>"#);
+        }
+        svg.push_str("</g>\n</svg>\n");
+        Ok(svg)
+    }
+
+    /// Draws the moves like [`Preview::to_svg`], as a PNG image on a white background.
+    pub fn to_png(&self, pixels_per_mm: f64) -> Result<Vec<u8>, PreviewImageError> {
+        let (min, max, width, height) = self.image_size(pixels_per_mm)?;
+        let mut pixmap = tiny_skia::Pixmap::new(width, height)
+            .ok_or(PreviewImageError::TooLarge { width, height })?;
+        pixmap.fill(tiny_skia::Color::WHITE);
+        let to_pixels = |point: &Vec3| {
+            (
+                ((point.x - min.x + IMAGE_MARGIN) * pixels_per_mm) as f32,
+                ((max.y - point.y + IMAGE_MARGIN) * pixels_per_mm) as f32,
+            )
+        };
+        for preview_move in &self.moves {
+            let Some(([r, g, b], dashed)) = image_style(preview_move.kind) else {
+                continue;
+            };
+            let mut points = preview_move.points.iter().map(to_pixels);
+            let Some((x, y)) = points.next() else {
+                continue;
+            };
+            let mut builder = tiny_skia::PathBuilder::new();
+            builder.move_to(x, y);
+            for (x, y) in points {
+                builder.line_to(x, y);
+            }
+            let Some(path) = builder.finish() else {
+                continue;
+            };
+            let mut paint = tiny_skia::Paint::default();
+            paint.set_color_rgba8(r, g, b, 255);
+            paint.anti_alias = true;
+            let stroke = tiny_skia::Stroke {
+                width: 1.5,
+                line_cap: tiny_skia::LineCap::Round,
+                line_join: tiny_skia::LineJoin::Round,
+                dash: dashed
+                    .then(|| tiny_skia::StrokeDash::new(vec![6., 4.], 0.))
+                    .flatten(),
+                ..Default::default()
+            };
+            pixmap.stroke_path(
+                &path,
+                &paint,
+                &stroke,
+                tiny_skia::Transform::identity(),
+                None,
+            );
+        }
+        pixmap
+            .encode_png()
+            .map_err(|err| PreviewImageError::Png(Box::new(err)))
+    }
+
+    /// Writes the image to `path`, as SVG if it ends in `.svg` and as PNG otherwise.
+    pub fn save_image(
+        &self,
+        path: impl AsRef<Path>,
+        pixels_per_mm: f64,
+    ) -> Result<(), PreviewImageError> {
+        let path = path.as_ref();
+        let is_svg = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"));
+        let contents = if is_svg {
+            self.to_svg(pixels_per_mm)?.into_bytes()
+        } else {
+            self.to_png(pixels_per_mm)?
+        };
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// The XY bounds of the moves and the size of an image of them with a margin, in pixels.
+    fn image_size(&self, pixels_per_mm: f64) -> Result<(Vec3, Vec3, u32, u32), PreviewImageError> {
+        let (min, max) = self.bounds().ok_or(PreviewImageError::Empty)?;
+        let size = |from: f64, to: f64| ((to - from + 2. * IMAGE_MARGIN) * pixels_per_mm).ceil();
+        let (width, height) = (size(min.x, max.x), size(min.y, max.y));
+        if !(width.is_finite() && height.is_finite()) || width.max(height) > MAX_IMAGE_SIZE {
+            return Err(PreviewImageError::TooLarge {
+                width: width.min(u32::MAX as f64) as u32,
+                height: height.min(u32::MAX as f64) as u32,
+            });
+        }
+        Ok((min, max, (width as u32).max(1), (height as u32).max(1)))
+    }
+}
+
+/// The color of a move in exported images and whether it is dashed, or `None` if it is not
+/// drawn.
+pub(crate) fn image_style(kind: MoveKind) -> Option<([u8; 3], bool)> {
+    match kind {
+        MoveKind::Cut => Some(([0, 0, 0], false)),
+        MoveKind::Swivel => Some(([230, 120, 0], true)),
+        MoveKind::Rapid => Some(([150, 150, 150], true)),
+        MoveKind::Dwell => None,
+    }
+}
+
+#[derive(Debug)]
+pub enum PreviewImageError {
+    /// Nothing moves, so there is nothing to draw.
+    Empty,
+    TooLarge {
+        width: u32,
+        height: u32,
+    },
+    Png(Box<dyn Error + Send + Sync>),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for PreviewImageError {
+    fn from(err: std::io::Error) -> Self {
+        PreviewImageError::Io(err)
+    }
+}
+
+impl Display for PreviewImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "The preview is empty"),
+            Self::TooLarge { width, height } => write!(
+                f,
+                "The image would be {width}×{height} pixels, use a smaller scale"
+            ),
+            Self::Png(err) => write!(f, "Could not encode the image: {err}"),
+            Self::Io(err) => write!(f, "Could not write the image: {err}"),
+        }
+    }
+}
+
+impl Error for PreviewImageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Empty | Self::TooLarge { .. } => None,
+            Self::Png(err) => Some(err.as_ref()),
+            Self::Io(err) => Some(err),
+        }
+    }
+}
+
+/// The points a move passes through, in machine coordinates. Arcs are split into short lines.
+fn trace(command: &Command, settings: &GCodeState) -> Vec<Vec3> {
+    let start = command.start_pos();
+    let end = command.end_pos();
+    match command {
+        Command::Home(home) => [Some(start), home.intermediate, Some(end)]
+            .into_iter()
+            .flatten()
+            .collect(),
+        Command::Arc(arc) => {
+            let plane = &settings.plane;
+            let sweep = arc_sweep(command, settings);
+            let radius = (start - arc.center).project_plane(plane).magnitude();
+            let start_angle = arc.center.angle_to(&start, plane);
+            let rise = end - start;
+            let rise = rise - rise.project_plane(plane);
+            let steps = (sweep.abs() / ARC_STEP).ceil().max(1.) as usize;
+            let center = start + (arc.center - start).project_plane(plane);
+            (0..=steps)
+                .map(|step| {
+                    let t = step as f64 / steps as f64;
+                    center + Vec3::unit_angle(start_angle + sweep * t, plane) * radius + rise * t
+                })
+                .collect()
+        }
+        _ => vec![start, end],
+    }
+}
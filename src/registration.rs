@@ -0,0 +1,242 @@
+use std::borrow::Cow;
+
+use gcode::{GCode, Mnemonic, Span, Word};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    input::InputProgram,
+    output::{self, command_number, OutputConfig, ProgramOutput},
+    types::FixedGCode,
+};
+
+/// Registration marks, for cutting out printed designs: the marks are printed with the design,
+/// measured on the machine, e.g. by jogging the knife onto them, and the whole path is moved and
+/// rotated so the marks of the design land where they were measured.
+///
+/// The marks can also be made by the machine with [`Registration::mark_program`], to print onto
+/// the marked material or to check the alignment.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Registration {
+    /// Where the marks are in the design, in mm in work coordinates.
+    pub marks: Vec<[f64; 2]>,
+    /// Where the first two marks were measured, in mm in work coordinates. The path is only
+    /// moved once both are given.
+    pub measured: Vec<[f64; 2]>,
+    pub mark_style: MarkStyle,
+    /// The length of the lines of a cross, in mm.
+    pub mark_size: f64,
+    /// How deep to mark, in mm below the work zero.
+    pub mark_depth: f64,
+    /// The feedrate to mark at, in mm/min.
+    pub mark_feedrate: f64,
+    /// The height to move between the marks at, in mm.
+    pub safe_height: f64,
+}
+
+impl Default for Registration {
+    fn default() -> Self {
+        Registration {
+            marks: vec![],
+            measured: vec![],
+            mark_style: MarkStyle::default(),
+            mark_size: 5.,
+            mark_depth: 0.5,
+            mark_feedrate: 300.,
+            safe_height: 5.,
+        }
+    }
+}
+
+/// How [`Registration::mark_program`] makes a mark.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MarkStyle {
+    /// Plunge once at the center of the mark, e.g. with a drill or a pen.
+    #[default]
+    Drill,
+    /// Cut a cross with lines of `mark_size` through the center.
+    Cross,
+}
+
+/// A rotation around the work zero followed by a move, in mm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    /// Counterclockwise, in radians.
+    pub angle: f64,
+    pub offset: [f64; 2],
+}
+
+impl Transform {
+    pub fn apply(&self, [x, y]: [f64; 2]) -> [f64; 2] {
+        let [x, y] = self.rotate([x, y]);
+        [x + self.offset[0], y + self.offset[1]]
+    }
+
+    /// Rotates a distance, like that of a relative move, which is not moved.
+    pub fn rotate(&self, [x, y]: [f64; 2]) -> [f64; 2] {
+        let (sin, cos) = self.angle.sin_cos();
+        [x * cos - y * sin, x * sin + y * cos]
+    }
+}
+
+impl Registration {
+    /// The transform which moves the first two marks onto where they were measured, if both
+    /// were. The marks are only used for their direction, not scaled, see
+    /// [`Registration::stretch`].
+    pub fn transform(&self) -> Option<Transform> {
+        let ([a1, a2, ..], [b1, b2, ..]) = (&self.marks[..], &self.measured[..]) else {
+            return None;
+        };
+        let design = [a2[0] - a1[0], a2[1] - a1[1]];
+        let measured = [b2[0] - b1[0], b2[1] - b1[1]];
+        if design[0].hypot(design[1]) < 1e-9 || measured[0].hypot(measured[1]) < 1e-9 {
+            return None;
+        }
+        let angle = measured[1].atan2(measured[0]) - design[1].atan2(design[0]);
+        let mut transform = Transform {
+            angle,
+            offset: [0., 0.],
+        };
+        let [x, y] = transform.rotate(*a1);
+        transform.offset = [b1[0] - x, b1[1] - y];
+        Some(transform)
+    }
+
+    /// How much further apart the measured marks are than those of the design, in mm. More
+    /// than a fraction of a mm means a mark was measured wrong, or the print is scaled.
+    pub fn stretch(&self) -> Option<f64> {
+        let ([a1, a2, ..], [b1, b2, ..]) = (&self.marks[..], &self.measured[..]) else {
+            return None;
+        };
+        Some((b2[0] - b1[0]).hypot(b2[1] - b1[1]) - (a2[0] - a1[0]).hypot(a2[1] - a1[1]))
+    }
+
+    /// Moves and rotates the X and Y of every move and arc center of `gcodes` by the
+    /// [`Registration::transform`]. Relative moves and arc centers are only rotated, and arcs
+    /// in other planes than XY are left as they are. The `gcodes` are returned as they are if no
+    /// marks were measured.
+    pub fn apply<'a>(&self, gcodes: &'a [GCode]) -> Cow<'a, [GCode]> {
+        let Some(transform) = self.transform() else {
+            return Cow::Borrowed(gcodes);
+        };
+        let mut unit = 1.;
+        let mut absolute = true;
+        let mut absolute_centers = false;
+        let mut xy_plane = true;
+        // Where the last move ended, in the design, in program units
+        let mut position = [0., 0.];
+        let mut out = Vec::with_capacity(gcodes.len());
+        for gcode in gcodes {
+            let number = (gcode.major_number(), gcode.minor_number());
+            if gcode.mnemonic() == Mnemonic::General {
+                match number {
+                    (20, 0) => unit = 25.4,
+                    (21, 0) => unit = 1.,
+                    (90, 0) => absolute = true,
+                    (91, 0) => absolute = false,
+                    (90, 1) => absolute_centers = true,
+                    (91, 1) => absolute_centers = false,
+                    (17, 0) => xy_plane = true,
+                    (18 | 19, 0) => xy_plane = false,
+                    _ => {}
+                }
+            }
+            let moves = gcode.mnemonic() == Mnemonic::General
+                && match number {
+                    (0 | 1, 0) | (38, 2..=5) => true,
+                    (2 | 3, 0) => xy_plane,
+                    _ => false,
+                };
+            let value = |letter| gcode.value_for(letter).map(f64::from);
+            if !moves
+                || ['X', 'Y', 'I', 'J']
+                    .iter()
+                    .all(|&letter| value(letter).is_none())
+            {
+                out.push(gcode.clone());
+                continue;
+            }
+            let in_mm = |[x, y]: [f64; 2]| [x * unit, y * unit];
+            let in_units = |[x, y]: [f64; 2]| [x / unit, y / unit];
+            let end = if absolute {
+                position = [
+                    value('X').unwrap_or(position[0]),
+                    value('Y').unwrap_or(position[1]),
+                ];
+                in_units(transform.apply(in_mm(position)))
+            } else {
+                let delta = [value('X').unwrap_or(0.), value('Y').unwrap_or(0.)];
+                position = [position[0] + delta[0], position[1] + delta[1]];
+                transform.rotate(delta)
+            };
+            let center = [value('I'), value('J')];
+            let center = (center != [None, None]).then(|| {
+                let center = center.map(|c| c.unwrap_or(0.));
+                if absolute_centers {
+                    in_units(transform.apply(in_mm(center)))
+                } else {
+                    transform.rotate(center)
+                }
+            });
+            let mut new = GCode::new(gcode.mnemonic(), command_number(gcode), gcode.span());
+            for arg in gcode.arguments() {
+                if !matches!(arg.letter, 'X' | 'Y' | 'I' | 'J') {
+                    new.push_argument(*arg).unwrap();
+                }
+            }
+            let words = [('X', end[0]), ('Y', end[1])]
+                .into_iter()
+                .chain(center.into_iter().flat_map(|[i, j]| [('I', i), ('J', j)]));
+            for (letter, value) in words {
+                new.push_argument(Word::new(letter, value as f32, Span::PLACEHOLDER))
+                    .unwrap();
+            }
+            out.push(new);
+        }
+        Cow::Owned(out)
+    }
+
+    /// A program which makes every mark, written by the output stage of `output_config`. With
+    /// measured marks, they are made where the design puts them after the transform, to check
+    /// the alignment.
+    pub fn mark_program(&self, output_config: &OutputConfig) -> ProgramOutput {
+        let transform = self.transform();
+        let general = |number: f32, words: &[(char, f64)]| {
+            let mut gcode = GCode::new(Mnemonic::General, number, Span::PLACEHOLDER);
+            for &(letter, value) in words {
+                gcode
+                    .push_argument(Word::new(letter, value as f32, Span::PLACEHOLDER))
+                    .unwrap();
+            }
+            FixedGCode::inserted(gcode, "registration mark")
+        };
+        let depth = -self.mark_depth;
+        let mut gcodes = vec![
+            general(21., &[]),
+            general(90., &[]),
+            general(0., &[('Z', self.safe_height)]),
+        ];
+        for &mark in &self.marks {
+            let at = |dx: f64, dy: f64| {
+                let point = [mark[0] + dx, mark[1] + dy];
+                transform.map_or(point, |transform| transform.apply(point))
+            };
+            let lines = match self.mark_style {
+                MarkStyle::Drill => vec![(at(0., 0.), at(0., 0.))],
+                MarkStyle::Cross => {
+                    let half = self.mark_size / 2.;
+                    vec![(at(-half, 0.), at(half, 0.)), (at(0., -half), at(0., half))]
+                }
+            };
+            for ([x0, y0], [x1, y1]) in lines {
+                gcodes.push(general(0., &[('X', x0), ('Y', y0)]));
+                gcodes.push(general(1., &[('Z', depth), ('F', self.mark_feedrate)]));
+                if (x0, y0) != (x1, y1) {
+                    gcodes.push(general(1., &[('X', x1), ('Y', y1)]));
+                }
+                gcodes.push(general(0., &[('Z', self.safe_height)]));
+            }
+        }
+        output::write_program(&InputProgram::default(), gcodes, output_config)
+    }
+}
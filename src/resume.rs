@@ -0,0 +1,325 @@
+use std::{error::Error, f64::consts::PI, fmt::Display};
+
+use gcode::{GCode, Mnemonic, Span, Word};
+
+use crate::{
+    input::parse_gcode,
+    output::{ModalState, OutputConfig},
+    types::DragknifeConfig,
+};
+
+/// The state of the machine before a line of a written program, as far as the program tells it,
+/// e.g. to restart it from that line after the job stopped.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResumeState {
+    pub modal: ModalState,
+    /// The last `F` word.
+    pub feedrate: Option<f32>,
+    /// Where the last move ended, in program units in work coordinates. `None` for an axis which
+    /// was not moved yet, or which ended where the program can not tell, e.g. after probing.
+    pub position: [Option<f64>; 3],
+    /// The direction the blade points in after the last cut in the XY plane, from its tip to the
+    /// knife, counterclockwise from X in radians.
+    pub blade_angle: Option<f64>,
+    /// The motion mode lines with only coordinates continue, `G0` to `G3`. `None` before the
+    /// first move and after `G80`.
+    pub motion: Option<f32>,
+}
+
+impl ResumeState {
+    /// Replays the commands of `program` before `line`, which is one-based like in an editor,
+    /// for a knife with `knife_offset` in mm.
+    pub fn before_line(program: &str, line: usize, knife_offset: f64) -> Self {
+        let lines: String = program
+            .split_inclusive('\n')
+            .take(line.saturating_sub(1))
+            .collect();
+        let mut state = ResumeState::default();
+        for gcode in parse_gcode(&lines) {
+            state.update(&gcode, knife_offset);
+        }
+        state
+    }
+
+    pub fn update(&mut self, gcode: &GCode, knife_offset: f64) {
+        self.modal.update(gcode);
+        if let Some(feedrate) = gcode.value_for('F') {
+            self.feedrate = Some(feedrate);
+        }
+        if gcode.mnemonic() != Mnemonic::General {
+            return;
+        }
+        let value = |letter| gcode.value_for(letter).map(f64::from);
+        let axes = ['X', 'Y', 'Z'];
+        match (gcode.major_number(), gcode.minor_number()) {
+            (0..=3, 0) => {
+                self.motion = Some(gcode.major_number() as f32);
+                let start = self.position;
+                let incremental = self.modal.distance == Some(91.);
+                for (i, letter) in axes.into_iter().enumerate() {
+                    self.position[i] = match (value(letter), incremental) {
+                        (Some(value), false) => Some(value),
+                        (Some(value), true) => start[i].map(|start| start + value),
+                        (None, _) => start[i],
+                    };
+                }
+                if gcode.major_number() != 0 {
+                    self.drag_blade(gcode, start, knife_offset);
+                }
+            }
+            // Probing ends wherever the surface is
+            (38, 2..=5) | (31, 0) => {
+                for (i, letter) in axes.into_iter().enumerate() {
+                    if value(letter).is_some() {
+                        self.position[i] = None;
+                    }
+                }
+            }
+            // Setting the work zero at the current position
+            (92, 0) => {
+                for (i, letter) in axes.into_iter().enumerate() {
+                    if let Some(value) = value(letter) {
+                        self.position[i] = Some(value);
+                    }
+                }
+            }
+            (10, 0) if value('L') == Some(20.) => {
+                for (i, letter) in axes.into_iter().enumerate() {
+                    if let Some(value) = value(letter) {
+                        self.position[i] = Some(value);
+                    }
+                }
+            }
+            // Moves to the home or in machine coordinates end outside of the work coordinates
+            (28 | 30, 0) => self.position = [None; 3],
+            (53, 0) => {
+                for (i, letter) in axes.into_iter().enumerate() {
+                    if value(letter).is_some() {
+                        self.position[i] = None;
+                    }
+                }
+            }
+            (80, 0) => self.motion = None,
+            _ => {}
+        }
+    }
+
+    /// Drags the blade along a feed move from `start`: its tip trails `knife_offset` behind the
+    /// knife, so it turns into the direction of a cut, and stays where it is during a swivel
+    /// around it. Arcs in other planes than XY, or with `R`, are followed along their chord.
+    fn drag_blade(&mut self, gcode: &GCode, start: [Option<f64>; 3], knife_offset: f64) {
+        let ([Some(x0), Some(y0), _], [Some(x1), Some(y1), _]) = (start, self.position) else {
+            return;
+        };
+        if (x1 - x0).hypot(y1 - y0) < 1e-9 {
+            return;
+        }
+        let value = |letter| gcode.value_for(letter).map(f64::from);
+        let xy_plane = matches!(self.modal.plane, None | Some(17.));
+        let (i, j) = (value('I'), value('J'));
+        let points = if !xy_plane || gcode.major_number() == 1 || (i, j) == (None, None) {
+            line_points([x0, y0], [x1, y1])
+        } else {
+            let (i, j) = (i.unwrap_or(0.), j.unwrap_or(0.));
+            let center = if self.modal.arc_distance == Some(90.1) {
+                [i, j]
+            } else {
+                [x0 + i, y0 + j]
+            };
+            arc_points([x0, y0], [x1, y1], center, gcode.major_number() == 3)
+        };
+        let unit = if self.modal.units == Some(20.) {
+            25.4
+        } else {
+            1.
+        };
+        let offset = knife_offset / unit;
+        // Without a blade angle yet, the blade starts out along the cut
+        let first = points[0];
+        let angle = self
+            .blade_angle
+            .unwrap_or_else(|| (first[1] - y0).atan2(first[0] - x0));
+        let mut tip = [x0 - offset * angle.cos(), y0 - offset * angle.sin()];
+        let mut previous = [x0, y0];
+        for point in points {
+            let (dx, dy) = (point[0] - tip[0], point[1] - tip[1]);
+            let length = dx.hypot(dy);
+            if offset > 0. && length > 1e-9 {
+                tip = [
+                    point[0] - dx / length * offset,
+                    point[1] - dy / length * offset,
+                ];
+                self.blade_angle = Some(dy.atan2(dx));
+            } else if offset <= 0. {
+                self.blade_angle = Some((point[1] - previous[1]).atan2(point[0] - previous[0]));
+            }
+            previous = point;
+        }
+    }
+}
+
+/// How far the knife is moved at a time while following the blade, in program units.
+const BLADE_STEP: f64 = 0.05;
+
+/// Points along a line from `start`, without `start`.
+fn line_points(start: [f64; 2], end: [f64; 2]) -> Vec<[f64; 2]> {
+    let length = (end[0] - start[0]).hypot(end[1] - start[1]);
+    let steps = ((length / BLADE_STEP).ceil() as usize).clamp(1, 10_000);
+    (1..=steps)
+        .map(|step| {
+            let t = step as f64 / steps as f64;
+            [
+                start[0] + (end[0] - start[0]) * t,
+                start[1] + (end[1] - start[1]) * t,
+            ]
+        })
+        .collect()
+}
+
+/// Points along an arc around `center` from `start`, without `start`.
+fn arc_points(start: [f64; 2], end: [f64; 2], center: [f64; 2], ccw: bool) -> Vec<[f64; 2]> {
+    let radius = (start[0] - center[0]).hypot(start[1] - center[1]);
+    let start_angle = (start[1] - center[1]).atan2(start[0] - center[0]);
+    let end_angle = (end[1] - center[1]).atan2(end[0] - center[0]);
+    let mut sweep = end_angle - start_angle;
+    if ccw && sweep <= 0. {
+        sweep += 2. * PI;
+    } else if !ccw && sweep >= 0. {
+        sweep -= 2. * PI;
+    }
+    let steps = ((sweep.abs() * radius / BLADE_STEP).ceil() as usize).clamp(1, 10_000);
+    (1..=steps)
+        .map(|step| {
+            let angle = start_angle + sweep * step as f64 / steps as f64;
+            [
+                center[0] + radius * angle.cos(),
+                center[1] + radius * angle.sin(),
+            ]
+        })
+        .collect()
+}
+
+/// The program from `line` on, one-based, after commands which bring the machine back to the
+/// state it was in before that line: the modal codes, the feedrate and the position.
+///
+/// If the knife was cutting, it is lowered `config.knife_offset` behind the position and cuts
+/// along the last cut to it, which turns the blade back into the direction it was cutting. The
+/// knife is moved at `output_config.clearance_height` before that. Last, the motion mode of the
+/// program is set again, for lines which only give coordinates.
+pub fn resume_program(
+    program: &str,
+    line: usize,
+    config: &DragknifeConfig,
+    output_config: &OutputConfig,
+) -> Result<String, ResumeError> {
+    let lines: Vec<_> = program.split_inclusive('\n').collect();
+    if line == 0 || line > lines.len() {
+        return Err(ResumeError::NoSuchLine {
+            line,
+            lines: lines.len(),
+        });
+    }
+    let state = ResumeState::before_line(program, line, config.knife_offset);
+    let unit = if state.modal.units == Some(20.) {
+        25.4
+    } else {
+        1.
+    };
+    let general = |number: f32, words: &[(char, f64)]| {
+        let mut gcode = GCode::new(Mnemonic::General, number, Span::PLACEHOLDER);
+        for &(letter, value) in words {
+            gcode
+                .push_argument(Word::new(letter, value as f32, Span::PLACEHOLDER))
+                .unwrap();
+        }
+        gcode
+    };
+    let modal = ModalState {
+        distance: Some(90.),
+        ..state.modal.clone()
+    };
+    let mut gcodes: Vec<_> = modal.restore("").into_iter().map(|g| g.gcode).collect();
+    let clearance = f64::from(output_config.clearance_height) / unit;
+    gcodes.push(general(0., &[('Z', clearance)]));
+    let [x, y, z] = state.position;
+    // The knife is lowered at the feedrate of the program if it was cutting
+    let plunge = |z: f64| {
+        let words: Vec<_> = [('Z', z)]
+            .into_iter()
+            .chain(state.feedrate.map(|feedrate| ('F', f64::from(feedrate))))
+            .collect();
+        general(1., &words)
+    };
+    let cut_depth = z.filter(|&z| z < 0.);
+    match (x, y, state.blade_angle, cut_depth) {
+        (Some(x), Some(y), Some(angle), Some(z)) if config.knife_offset > 0. => {
+            let offset = config.knife_offset / unit;
+            let start = [x - offset * angle.cos(), y - offset * angle.sin()];
+            gcodes.push(general(0., &[('X', start[0]), ('Y', start[1])]));
+            gcodes.push(plunge(z));
+            gcodes.push(general(1., &[('X', x), ('Y', y)]));
+        }
+        (x, y, _, _) => {
+            if let (Some(x), Some(y)) = (x, y) {
+                gcodes.push(general(0., &[('X', x), ('Y', y)]));
+            }
+            match z {
+                Some(z) if z < 0. => gcodes.push(plunge(z)),
+                Some(z) if z != clearance => gcodes.push(general(0., &[('Z', z)])),
+                _ => {}
+            }
+        }
+    }
+    // The moves above leave `G0` or `G1` in effect
+    if let Some(motion) = state.motion {
+        if gcodes.last().map(|gcode| gcode.major_number() as f32) != Some(motion) {
+            gcodes.push(general(motion, &[]));
+        }
+    }
+    if state.modal.distance == Some(91.) {
+        gcodes.push(general(91., &[]));
+    }
+
+    let ending = output_config.line_ending.as_str();
+    let mut text = String::new();
+    // The program envelope is kept around the resumed part
+    if lines[..line - 1].iter().any(|l| l.trim() == "%") {
+        text.push('%');
+        text.push_str(ending);
+    }
+    if !output_config.strip_comments {
+        text.push_str(&format!("(resumed at line {line}){ending}"));
+    }
+    for gcode in &gcodes {
+        text.push_str(&output_config.format_gcode(gcode));
+        text.push_str(ending);
+    }
+    if let (Some(feedrate), None) = (state.feedrate, cut_depth) {
+        text.push('F');
+        text.push_str(&output_config.format_number('F', feedrate));
+        text.push_str(ending);
+    }
+    text.extend(lines[line - 1..].iter().copied());
+    Ok(text)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResumeError {
+    /// The line to resume at is not in the program. One-based.
+    NoSuchLine { line: usize, lines: usize },
+}
+
+impl Display for ResumeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoSuchLine { line, lines } => {
+                write!(
+                    f,
+                    "Can not resume at line {line}, the program has {lines} lines"
+                )
+            }
+        }
+    }
+}
+
+impl Error for ResumeError {}
@@ -0,0 +1,155 @@
+use std::f64::consts::TAU;
+
+use crate::{
+    compensation::{intersections, with_height, Piece, Side},
+    contours::cut_runs,
+    signed_angle,
+    stats::MIN_CORNER,
+    types::{ArcDirection, CornerRounding, DragknifeConfig, FeedMode, GCodePlane},
+    vec3::Vec3,
+    DragknifePath,
+};
+
+/// Moves shorter than this do not change the direction of the knife.
+const MIN_LENGTH: f64 = 0.00001;
+
+impl<'a> DragknifePath<'a> {
+    /// Replaces the corners between cuts by arcs of `config.corner_radius`, which the blade
+    /// follows without stopping, so it stays tangent to the cut. With
+    /// [`CornerRounding::Unswiveled`], the sharp corners are left to be swiveled at. Direction
+    /// reversals are not rounded.
+    ///
+    /// A corner is left as it is if a move next to it is too short for the arc, or is an arc
+    /// which turns more tightly. Cuts in inverse time feed mode are left as they are, as every
+    /// move has its own feedrate.
+    pub fn round_corners(&mut self, config: &DragknifeConfig) {
+        let radius = config.corner_radius;
+        if radius <= 0. {
+            return;
+        }
+        let mut placed = vec![false; self.commands.len()];
+        let mut arcs = vec![];
+        for (commands, settings, _) in cut_runs(self) {
+            if settings.feed_mode != FeedMode::UnitsPerMinute {
+                continue;
+            }
+            let plane = settings.plane;
+            let mut pieces: Vec<Piece> = commands.map(|i| self.piece(i)).collect();
+            for k in 1..pieces.len() {
+                let (a, b) = (pieces[k - 1], pieces[k]);
+                let turn = signed_angle(a.angle_at(a.end, &plane), b.angle_at(b.start, &plane));
+                let swiveled = turn.abs() > config.angle_threshold(turn);
+                if turn.abs() <= MIN_CORNER
+                    || config.is_reversal(turn)
+                    || swiveled && config.corner_rounding == CornerRounding::Unswiveled
+                {
+                    continue;
+                }
+                // Right turns are positive, and their inside is on the right
+                let (side, direction) = if turn > 0. {
+                    (Side::Right, ArcDirection::CW)
+                } else {
+                    (Side::Left, ArcDirection::CCW)
+                };
+                let Some((center, end, start)) = fillet(&a, &b, side, radius, &plane) else {
+                    continue;
+                };
+                pieces[k - 1].end = with_height(end, a.end, &plane);
+                pieces[k].start = with_height(start, b.start, &plane);
+                placed[a.command] = true;
+                placed[b.command] = true;
+                arcs.push((
+                    a.command,
+                    self.round_corner(&pieces[k - 1], &pieces[k], center, direction, &plane),
+                ));
+            }
+            for piece in pieces {
+                if placed[piece.command] {
+                    self.place_piece(&piece, &plane);
+                }
+            }
+        }
+        if !arcs.is_empty() {
+            self.reconnect(&[], &placed, arcs);
+        }
+    }
+}
+
+/// The center of an arc of `radius` on `side` of the corner from `a` to `b` which touches both,
+/// and where it touches them. The arc closest to the corner is taken.
+fn fillet(
+    a: &Piece,
+    b: &Piece,
+    side: Side,
+    radius: f64,
+    plane: &GCodePlane,
+) -> Option<(Vec3, Vec3, Vec3)> {
+    let (offset_a, offset_b) = (
+        a.offset(side, radius, plane)?,
+        b.offset(side, radius, plane)?,
+    );
+    let distance = |p: Vec3| (p - a.end).project_plane(plane).magnitude();
+    intersections(&offset_a, &offset_b, plane)
+        .into_iter()
+        .map(|center| (center, closest(a, center, plane), closest(b, center, plane)))
+        // What is left of the moves has to run the way they did
+        .filter(|&(_, end, start)| {
+            let (end, start) = (along(a, end, plane), along(b, start, plane));
+            end > MIN_LENGTH
+                && end <= length(a, plane) + MIN_LENGTH
+                && start >= -MIN_LENGTH
+                && start < length(b, plane) - MIN_LENGTH
+        })
+        .min_by(|p, q| distance(p.0).total_cmp(&distance(q.0)))
+}
+
+/// The point on the line or circle of `piece` closest to `point`, in the plane.
+fn closest(piece: &Piece, point: Vec3, plane: &GCodePlane) -> Vec3 {
+    match piece.arc {
+        None => {
+            let direction = (piece.end - piece.start).project_plane(plane).normalized();
+            piece.start + direction * along(piece, point, plane)
+        }
+        Some((center, _)) => {
+            let radius = (piece.start - center).project_plane(plane).magnitude();
+            center + (point - center).project_plane(plane).normalized() * radius
+        }
+    }
+}
+
+/// How far `point` is along `piece` from its start. Points before the start of an arc are near
+/// its end, and those before a line are negative.
+fn along(piece: &Piece, point: Vec3, plane: &GCodePlane) -> f64 {
+    match piece.arc {
+        None => {
+            let direction = (piece.end - piece.start).project_plane(plane);
+            let delta = (point - piece.start).project_plane(plane);
+            (delta.x * direction.x + delta.y * direction.y + delta.z * direction.z)
+                / direction.magnitude()
+        }
+        Some((center, direction)) => {
+            let radius = (piece.start - center).project_plane(plane).magnitude();
+            sweep(center, piece.start, point, direction, plane) * radius
+        }
+    }
+}
+
+fn length(piece: &Piece, plane: &GCodePlane) -> f64 {
+    let length = along(piece, piece.end, plane);
+    match piece.arc {
+        // Full circles end where they start
+        Some((center, _)) if length <= MIN_LENGTH => {
+            TAU * (piece.start - center).project_plane(plane).magnitude()
+        }
+        _ => length,
+    }
+}
+
+/// The angle an arc around `center` turns through from `from` to `to`.
+fn sweep(center: Vec3, from: Vec3, to: Vec3, direction: ArcDirection, plane: &GCodePlane) -> f64 {
+    let (from, to) = (center.angle_to(&from, plane), center.angle_to(&to, plane));
+    match direction {
+        ArcDirection::CW => (from - to).rem_euclid(TAU),
+        ArcDirection::CCW => (to - from).rem_euclid(TAU),
+    }
+}
@@ -0,0 +1,194 @@
+use std::f64::consts::{FRAC_PI_2, TAU};
+
+use serde::Serialize;
+
+use crate::{
+    signed_angle,
+    types::{
+        ArcDirection, Command, DragknifeConfig, FeedMode, GCodeState, HomeMovement, Movement,
+        WordValues,
+    },
+    vec3::Vec3,
+    DragknifePath,
+};
+
+/// Changes of direction smaller than this, e.g. between an arc and the line it blends into, are
+/// not counted as corners.
+pub(crate) const MIN_CORNER: f64 = FRAC_PI_2 / 90.;
+
+/// Numbers to sanity-check a job with before sending it to the machine.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct PathStats {
+    /// Length of the cuts (`G1-3`) in mm.
+    pub cut_length: f64,
+    /// Length of the rapid and home moves (`G0`, `G28`) in mm.
+    pub rapid_length: f64,
+    /// Runs of cuts between rapid moves, each cut without lifting the knife out of the material.
+    pub contours: usize,
+    /// Changes of direction between two cuts, of more than a degree.
+    pub corners: usize,
+    /// Corners which get a swivel with the config.
+    pub swivels: usize,
+    /// Corners which are direction reversals, where the knife is turned beside the cut instead.
+    pub reversals: usize,
+    /// The lowest and highest X, Y and Z reached, in mm in work coordinates. `None` if nothing
+    /// moves.
+    pub bounds: Option<(Vec3, Vec3)>,
+    /// The time the moves and dwells take at the programmed feedrates, in minutes. Acceleration
+    /// is not taken into account.
+    pub run_time: f64,
+}
+
+impl<'a> DragknifePath<'a> {
+    /// Measures the path. Rapid moves are assumed to run at `rapid_feedrate` mm/min.
+    pub fn stats(&self, config: &DragknifeConfig, rapid_feedrate: f64) -> PathStats {
+        let mut stats = PathStats::default();
+        let mut settings = self.initial_state;
+        let mut prev_angle = None;
+        let mut cutting = false;
+        for command in &self.commands {
+            command.update_settings(&mut settings);
+            if let (Command::Linear(_) | Command::Arc(_), Some(from), Some(to)) =
+                (command, prev_angle, command.start_angle())
+            {
+                let signed_angle = signed_angle(from, to);
+                if signed_angle.abs() > MIN_CORNER {
+                    stats.corners += 1;
+                }
+                if signed_angle.abs() > config.angle_threshold(signed_angle) {
+                    if config.is_reversal(signed_angle) {
+                        stats.reversals += 1;
+                    } else {
+                        stats.swivels += 1;
+                    }
+                }
+            }
+            prev_angle = command.end_angle();
+            match command {
+                Command::Linear(_) | Command::Arc(_) => {
+                    if !cutting {
+                        stats.contours += 1;
+                        cutting = true;
+                    }
+                    stats.cut_length += cut_length(command, &settings)
+                }
+                Command::Rapid(_) | Command::Home(_) => {
+                    cutting = false;
+                    stats.rapid_length += rapid_length(command)
+                }
+                Command::Other(_) => {}
+            }
+            stats.run_time += run_time(command, &settings, rapid_feedrate);
+            for point in extreme_points(command, &settings) {
+                let point = settings.to_work_coords(point);
+                stats.bounds = Some(match stats.bounds {
+                    None => (point, point),
+                    Some((min, max)) => (
+                        Vec3::new(min.x.min(point.x), min.y.min(point.y), min.z.min(point.z)),
+                        Vec3::new(max.x.max(point.x), max.y.max(point.y), max.z.max(point.z)),
+                    ),
+                });
+            }
+        }
+        stats
+    }
+}
+
+/// The time a command takes at the programmed feedrate, in minutes. Rapid moves are assumed to
+/// run at `rapid_feedrate` mm/min.
+pub(crate) fn run_time(command: &Command, settings: &GCodeState, rapid_feedrate: f64) -> f64 {
+    match command {
+        Command::Linear(_) | Command::Arc(_) => match settings.feed_mode {
+            FeedMode::UnitsPerMinute => cut_length(command, settings) / settings.feedrate,
+            FeedMode::InverseTime => command.original().value('F').map_or(0., |f| 1. / f),
+        },
+        Command::Rapid(_) | Command::Home(_) => rapid_length(command) / rapid_feedrate,
+        // G4 P is in seconds
+        Command::Other(other) if other.original.major_number() == 4 => {
+            other.original.value('P').unwrap_or(0.) / 60.
+        }
+        Command::Other(_) => 0.,
+    }
+}
+
+/// The length of a rapid or home move in mm, through the intermediate point of `G28`.
+fn rapid_length(command: &Command) -> f64 {
+    let (start, end) = (command.start_pos(), command.end_pos());
+    match command {
+        Command::Home(HomeMovement {
+            intermediate: Some(via),
+            ..
+        }) => (*via - start).magnitude() + (end - *via).magnitude(),
+        _ => (end - start).magnitude(),
+    }
+}
+
+/// The length of a cut in mm, including the helical part of arcs.
+fn cut_length(command: &Command, settings: &GCodeState) -> f64 {
+    let (start, end) = (command.start_pos(), command.end_pos());
+    let Command::Arc(arc) = command else {
+        return (end - start).magnitude();
+    };
+    let plane = &settings.plane;
+    let radius = (start - arc.center).project_plane(plane).magnitude();
+    let planar = radius * arc_sweep(command, settings).abs();
+    let height = end.third_coord(plane) - start.third_coord(plane);
+    (planar * planar + height * height).sqrt()
+}
+
+/// The signed angle an arc turns through in its plane. Arcs ending where they start are full
+/// circles.
+pub(crate) fn arc_sweep(command: &Command, settings: &GCodeState) -> f64 {
+    let Command::Arc(arc) = command else {
+        return 0.;
+    };
+    let plane = &settings.plane;
+    let start_angle = arc.center.angle_to(&arc.start, plane);
+    let end_angle = arc.center.angle_to(&arc.end, plane);
+    let sweep = match arc.direction {
+        ArcDirection::CW => (start_angle - end_angle).rem_euclid(TAU),
+        ArcDirection::CCW => (end_angle - start_angle).rem_euclid(TAU),
+    };
+    let sweep = if sweep == 0. { TAU } else { sweep };
+    match arc.direction {
+        ArcDirection::CW => -sweep,
+        ArcDirection::CCW => sweep,
+    }
+}
+
+/// The points of a move which can be furthest out along an axis: its ends, and for arcs the
+/// points where they cross an axis of their plane.
+fn extreme_points(command: &Command, settings: &GCodeState) -> Vec<Vec3> {
+    let mut points = match command {
+        Command::Other(_) => return vec![],
+        Command::Home(home) => home.intermediate.into_iter().collect(),
+        _ => vec![],
+    };
+    points.push(command.end_pos());
+    if let Command::Arc(arc) = command {
+        let plane = &settings.plane;
+        let start = command.start_pos();
+        let radius = (start - arc.center).project_plane(plane).magnitude();
+        let start_angle = arc.center.angle_to(&start, plane);
+        let sweep = arc_sweep(command, settings);
+        for quarter in 0..4 {
+            let angle = quarter as f64 * FRAC_PI_2;
+            let turned = match arc.direction {
+                ArcDirection::CW => (start_angle - angle).rem_euclid(TAU),
+                ArcDirection::CCW => (angle - start_angle).rem_euclid(TAU),
+            };
+            if turned < sweep.abs() {
+                // The height changes evenly along helical arcs
+                let rise = command.end_pos() - start;
+                let rise = rise - rise.project_plane(plane);
+                points.push(
+                    start
+                        + (arc.center - start).project_plane(plane)
+                        + Vec3::unit_angle(angle, plane) * radius
+                        + rise * (turned / sweep.abs()),
+                );
+            }
+        }
+    }
+    points
+}
@@ -0,0 +1,182 @@
+use std::io::{self, BufRead, BufWriter, Write};
+
+use gcode::Mnemonic;
+
+use crate::{
+    check_finite, fix_gcode_iter,
+    input::GCodeReader,
+    output::{selected_unit, source_line, OutputConfig, OutputLine, OutputWarning},
+    types::{DragknifeConfig, FixedGCode, GCodeState},
+    validate::check_code,
+};
+
+/// Repathed commands are filtered, checked and written in chunks of about this many.
+const CHUNK_SIZE: usize = 4096;
+
+/// Repaths a program while reading it and writes the output as it goes, so programs too large to
+/// hold in memory can be processed. The state of the program is kept from one chunk to the next.
+///
+/// Only the output options which work on one command at a time are supported: filters, the
+/// dialect, number formatting, annotations, the header and footer, `%` delimiters, ASCII only
+/// output and line endings. Other options and operations return an `InvalidInput` error.
+/// Comments are not copied, and parameters and subprograms are not resolved. Cutter
+/// compensation needs the moves after it, so `G41` and `G42` return an `InvalidData` error.
+/// Kerf compensation, corner rounding and reversing contours need whole contours, so they return
+/// an `InvalidInput` error.
+pub fn stream_program<R: BufRead, W: Write>(
+    input: R,
+    output: W,
+    config: &DragknifeConfig,
+    output_config: &OutputConfig,
+) -> io::Result<Vec<OutputWarning>> {
+    if !config.operations.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Operations can not be used when streaming a program, as comments are not read",
+        ));
+    }
+    if let Some(option) = unsupported_repathing(config) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{option} can not be used when streaming a program, as it needs whole contours"
+            ),
+        ));
+    }
+    if let Some(option) = unsupported_option(output_config) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{option} can not be used when streaming a program"),
+        ));
+    }
+    let mut output = BufWriter::new(output);
+    let mut reader = GCodeReader::new(input);
+    let mut fixed = fix_gcode_iter(&mut reader, GCodeState::from_config(config), config);
+    if output_config.percent_delimiters {
+        write_lines(&mut output, vec![OutputLine::delimiter()], output_config)?;
+    }
+    // The units of the program are not known yet
+    write_lines(&mut output, output_config.header_lines(1.), output_config)?;
+    let mut unit = 1.;
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    let mut dialect_warnings = vec![];
+    let mut code_warnings = vec![];
+    loop {
+        let next = fixed.next();
+        let end = next.is_none();
+        chunk.extend(next);
+        // Inserted commands are kept in the chunk of the command they were inserted for
+        let from_input = chunk
+            .last()
+            .is_some_and(|g: &FixedGCode| source_line(g.gcode.span()).is_some());
+        if end || (chunk.len() >= CHUNK_SIZE && from_input) {
+            check_finite(&chunk).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            for fixed in chunk.iter().filter(|fixed| fixed.reason.is_none()) {
+                let gcode = &fixed.gcode;
+                if gcode.mnemonic() == Mnemonic::General && matches!(gcode.major_number(), 41 | 42)
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Cutter compensation can not be used when streaming a program",
+                    ));
+                }
+                let warning = check_code(&fixed.gcode, config)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                code_warnings.extend(warning.map(OutputWarning::Validation));
+            }
+            let gcodes = output_config.apply_filters(std::mem::take(&mut chunk));
+            let gcodes = output_config.apply_dialect(gcodes, &mut dialect_warnings);
+            unit = (gcodes.iter().rev())
+                .find_map(|fixed| selected_unit(&fixed.gcode))
+                .unwrap_or(unit);
+            let lines = gcodes
+                .iter()
+                .map(|fixed| {
+                    let mut line = OutputLine::code(output_config.format_gcode(&fixed.gcode));
+                    if let (true, Some(reason)) = (output_config.annotate, &fixed.reason) {
+                        line.comments.push(format!("({reason})"));
+                    }
+                    line
+                })
+                .collect();
+            write_lines(&mut output, lines, output_config)?;
+        }
+        if end {
+            break;
+        }
+    }
+    let degenerate_arcs = std::mem::take(&mut fixed.degenerate_arcs);
+    drop(fixed);
+    let warnings = std::mem::take(&mut reader.issues)
+        .into_iter()
+        .map(OutputWarning::Parse)
+        .chain(
+            degenerate_arcs
+                .into_iter()
+                .map(OutputWarning::DegenerateArc),
+        )
+        .chain(code_warnings)
+        .chain(dialect_warnings)
+        .collect();
+    if let Some(err) = reader.error {
+        return Err(err);
+    }
+    write_lines(&mut output, output_config.footer_lines(unit), output_config)?;
+    if output_config.percent_delimiters {
+        write_lines(&mut output, vec![OutputLine::delimiter()], output_config)?;
+    }
+    output.flush()?;
+    Ok(warnings)
+}
+
+fn write_lines(
+    output: &mut impl Write,
+    lines: Vec<OutputLine>,
+    config: &OutputConfig,
+) -> io::Result<()> {
+    let mut text = String::new();
+    for line in config.apply_ascii(lines) {
+        line.write_text(&mut text);
+        text += config.line_ending.as_str();
+    }
+    output.write_all(text.as_bytes())
+}
+
+/// The first enabled repathing option which needs the whole program.
+fn unsupported_repathing(config: &DragknifeConfig) -> Option<&'static str> {
+    [
+        (config.kerf_width > 0., "Kerf compensation"),
+        (config.corner_radius > 0., "Corner rounding"),
+        (config.reverse_pushed_contours, "Reversing contours"),
+    ]
+    .into_iter()
+    .find_map(|(enabled, option)| enabled.then_some(option))
+}
+
+/// The first enabled output option which needs the whole program.
+fn unsupported_option(config: &OutputConfig) -> Option<&'static str> {
+    [
+        (config.gate_knife, "Knife gating"),
+        (config.dry_run, "Dry run"),
+        (config.check_clearance, "The clearance check"),
+        (config.check_soft_limits, "The soft limit check"),
+        (config.check_max_feedrate, "The feedrate check"),
+        (config.line_numbers || config.checksums, "Line numbering"),
+        (config.limit_line_length, "The line length limit"),
+        (config.incremental, "Incremental output"),
+        (config.absolute_arc_centers, "Absolute arc centers"),
+        (config.writes_radius_arcs(), "Radius arcs"),
+        (config.height_map.is_some(), "The height map"),
+        (config.writes_arcs_as_lines(), "Arcs as lines"),
+        (
+            config.dialect.converts_units(),
+            "A dialect without inches or inverse time",
+        ),
+        (config.original_comments, "Original line comments"),
+        (config.material_depth().is_some(), "Material depths"),
+        (config.probes(), "Probing"),
+        (config.split_files, "Split files"),
+    ]
+    .into_iter()
+    .find_map(|(enabled, option)| enabled.then_some(option))
+}
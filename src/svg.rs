@@ -0,0 +1,189 @@
+use std::{f64::consts::PI, fmt::Write};
+
+use crate::{
+    preview::{image_style, MoveKind},
+    toolpath::{Segment, SegmentShape, Toolpath},
+    types::GCodePlane,
+    vec3::Vec3,
+};
+
+/// Space around the path, in mm.
+const MARGIN: f64 = 2.;
+
+/// Width of the drawn lines, in mm.
+const STROKE_WIDTH: f64 = 0.2;
+
+/// The layers of the image, in drawing order: the id and Inkscape label of the group, and the
+/// kind of move drawn in it, which is also the class of its paths.
+const LAYERS: [(&str, &str, MoveKind); 3] = [
+    ("rapids", "Rapid moves", MoveKind::Rapid),
+    ("swivels", "Swivels", MoveKind::Swivel),
+    ("cuts", "Cuts", MoveKind::Cut),
+];
+
+/// Writes the toolpath as an SVG image at its real size, seen from above: cuts, rapid moves and
+/// swivels each in their own Inkscape layer, with the classes `cut`, `rapid` and `swivel` to
+/// style them. Arcs in the XY plane are kept as arcs, arcs in other planes are drawn as a line
+/// between their ends. Coordinates in the image are in mm, with Y flipped to point down.
+pub fn write_svg(toolpath: &Toolpath) -> String {
+    let (min, max) = bounds(toolpath);
+    let (left, top) = (min.0 - MARGIN, -max.1 - MARGIN);
+    let (width, height) = (max.0 - min.0 + 2. * MARGIN, max.1 - min.1 + 2. * MARGIN);
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg""#,
+            r#" xmlns:inkscape="http://www.inkscape.org/namespaces/inkscape""#,
+            r#" width="{:.3}mm" height="{:.3}mm" viewBox="{:.3} {:.3} {:.3} {:.3}">"#
+        ),
+        width, height, left, top, width, height
+    );
+    svg.push_str("<style>\n");
+    let _ = writeln!(
+        svg,
+        "path {{ fill: none; stroke-width: {STROKE_WIDTH}; stroke-linecap: round; }}"
+    );
+    for (_, _, kind) in LAYERS {
+        let Some(([r, g, b], dashed)) = image_style(kind) else {
+            continue;
+        };
+        let dash = if dashed {
+            format!(
+                " stroke-dasharray: {:.2} {:.2};",
+                4. * STROKE_WIDTH,
+                3. * STROKE_WIDTH
+            )
+        } else {
+            String::new()
+        };
+        let _ = writeln!(
+            svg,
+            ".{} {{ stroke: rgb({r},{g},{b});{dash} }}",
+            class(kind)
+        );
+    }
+    svg.push_str("</style>\n");
+    for (id, label, kind) in LAYERS {
+        let _ = writeln!(
+            svg,
+            r#"<g id="{id}" inkscape:groupmode="layer" inkscape:label="{label}">"#
+        );
+        for path in paths(toolpath, kind) {
+            let _ = writeln!(svg, r#"<path class="{}" d="{path}"/>"#, class(kind));
+        }
+        svg.push_str("</g>\n");
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn class(kind: MoveKind) -> &'static str {
+    match kind {
+        MoveKind::Cut => "cut",
+        MoveKind::Rapid => "rapid",
+        MoveKind::Swivel => "swivel",
+        MoveKind::Dwell => "dwell",
+    }
+}
+
+/// The path data of the segments of `kind`, joining segments which follow each other.
+fn paths(toolpath: &Toolpath, kind: MoveKind) -> Vec<String> {
+    let mut paths = vec![];
+    let mut path = String::new();
+    let mut position = None;
+    for segment in toolpath.segments.iter().filter(|s| s.kind == kind) {
+        let (start, end) = (to_image(segment.start), to_image(segment.end));
+        if start == end && !is_xy_arc(segment) {
+            // Lifts and plunges
+            continue;
+        }
+        if position != Some(start) {
+            if !path.is_empty() {
+                paths.push(std::mem::take(&mut path));
+            }
+            let _ = write!(path, "M{:.3} {:.3}", start.0, start.1);
+        }
+        match segment.shape {
+            SegmentShape::Arc {
+                center,
+                sweep,
+                plane: GCodePlane::XY,
+            } => {
+                let radius = (segment.start - center).project_plane(&GCodePlane::XY);
+                let radius = radius.magnitude();
+                // Y points down in SVG, so counterclockwise arcs have a negative sweep there
+                let sweep_flag = u8::from(sweep < 0.);
+                if start == end {
+                    // A full circle, as two halves
+                    let opposite = to_image(center * 2. - segment.start);
+                    let _ = write!(
+                        path,
+                        " A{radius:.3} {radius:.3} 0 0 {sweep_flag} {:.3} {:.3}",
+                        opposite.0, opposite.1
+                    );
+                }
+                let large_arc = u8::from(sweep.abs() > PI && start != end);
+                let _ = write!(
+                    path,
+                    " A{radius:.3} {radius:.3} 0 {large_arc} {sweep_flag} {:.3} {:.3}",
+                    end.0, end.1
+                );
+            }
+            _ => {
+                let _ = write!(path, " L{:.3} {:.3}", end.0, end.1);
+            }
+        }
+        position = Some(end);
+    }
+    if !path.is_empty() {
+        paths.push(path);
+    }
+    paths
+}
+
+fn is_xy_arc(segment: &Segment) -> bool {
+    matches!(
+        segment.shape,
+        SegmentShape::Arc {
+            plane: GCodePlane::XY,
+            ..
+        }
+    )
+}
+
+/// The X and Y of `point` in the image, rounded like they are written so ends which are written
+/// the same are joined. Adding `0.` avoids writing `-0`.
+fn to_image(point: Vec3) -> (f64, f64) {
+    let round = |mm: f64| (mm * 1000.).round() / 1000. + 0.;
+    (round(point.x), round(-point.y))
+}
+
+/// The lowest and highest X and Y of the segments, counting whole circles for XY arcs. An empty
+/// toolpath is at the origin.
+fn bounds(toolpath: &Toolpath) -> ((f64, f64), (f64, f64)) {
+    let mut points = toolpath.segments.iter().flat_map(|segment| {
+        let mut points = vec![segment.start, segment.end];
+        if let SegmentShape::Arc {
+            center,
+            plane: GCodePlane::XY,
+            ..
+        } = segment.shape
+        {
+            let radius = (segment.start - center).project_plane(&GCodePlane::XY);
+            let radius = radius.magnitude();
+            points.push(center - Vec3::new(radius, radius, 0.));
+            points.push(center + Vec3::new(radius, radius, 0.));
+        }
+        points
+    });
+    let Some(first) = points.next() else {
+        return ((0., 0.), (0., 0.));
+    };
+    points.fold(((first.x, first.y), (first.x, first.y)), |(min, max), p| {
+        (
+            (min.0.min(p.x), min.1.min(p.y)),
+            (max.0.max(p.x), max.1.max(p.y)),
+        )
+    })
+}
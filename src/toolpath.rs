@@ -0,0 +1,268 @@
+use serde::Serialize;
+
+use crate::{
+    hpgl::{self, HPGL_EXTENSION},
+    output::source_line,
+    preview::MoveKind,
+    stats::arc_sweep,
+    svg,
+    types::{Command, FixedGCode, GCodePlane, GCodeState, Movement},
+    vec3::Vec3,
+    DragknifePath,
+};
+
+/// The formats the toolpath can be written in besides G-code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// For vinyl cutters and plotters, see [`hpgl::write_hpgl`].
+    Hpgl,
+    /// An image at real size, see [`svg::write_svg`].
+    Svg,
+    /// The segments for other tools, see [`Toolpath::to_json`].
+    Json,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 3] = [ExportFormat::Hpgl, ExportFormat::Svg, ExportFormat::Json];
+
+    /// The name of the format on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportFormat::Hpgl => "hpgl",
+            ExportFormat::Svg => "svg",
+            ExportFormat::Json => "json",
+        }
+    }
+
+    /// The extension of the files written in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Hpgl => HPGL_EXTENSION,
+            ExportFormat::Svg => "svg",
+            ExportFormat::Json => "json",
+        }
+    }
+
+    /// The format of a file name ending in `extension`, ignoring case.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        let extension = extension.to_ascii_lowercase();
+        match extension.as_str() {
+            "hpgl" => Some(ExportFormat::Hpgl),
+            _ => Self::ALL.into_iter().find(|f| f.extension() == extension),
+        }
+    }
+
+    pub fn write(self, toolpath: &Toolpath) -> String {
+        match self {
+            ExportFormat::Hpgl => hpgl::write_hpgl(toolpath),
+            ExportFormat::Svg => svg::write_svg(toolpath),
+            ExportFormat::Json => toolpath.to_json(),
+        }
+    }
+}
+
+/// The shape of a segment of the toolpath.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SegmentShape {
+    Line,
+    /// An arc around `center` in `plane`, turning through `sweep` radians, positive for
+    /// counterclockwise. The ends may differ along the third axis for helixes.
+    Arc {
+        center: Vec3,
+        sweep: f64,
+        plane: GCodePlane,
+    },
+}
+
+/// A move of the repathed program, with the knife correction applied.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Segment {
+    /// Whether the segment is a cut of the input, a move between cuts or turns the knife.
+    pub kind: MoveKind,
+    /// Made at the rapid speed (`G0`), so the knife is lifted.
+    pub rapid: bool,
+    /// In mm in work coordinates.
+    pub start: Vec3,
+    /// In mm in work coordinates.
+    pub end: Vec3,
+    /// The direction the move starts in, in radians in its plane, if it has one.
+    pub start_angle: Option<f64>,
+    /// The direction the move ends in, in radians in its plane, if it has one.
+    pub end_angle: Option<f64>,
+    #[serde(flatten)]
+    pub shape: SegmentShape,
+}
+
+/// Appends the segments of a move, one for every line of a move to the home position through
+/// another point. `settings` is the state after the move.
+fn push_segments(
+    segments: &mut Vec<Segment>,
+    command: &Command,
+    settings: &GCodeState,
+    kind: MoveKind,
+    rapid: bool,
+) {
+    let (start_angle, end_angle) = (command.start_angle(), command.end_angle());
+    let mut segment = |start, end, shape| {
+        segments.push(Segment {
+            kind,
+            rapid,
+            start: settings.to_work_coords(start),
+            end: settings.to_work_coords(end),
+            start_angle,
+            end_angle,
+            shape,
+        })
+    };
+    match command {
+        Command::Arc(arc) => segment(
+            arc.start,
+            arc.end,
+            SegmentShape::Arc {
+                center: settings.to_work_coords(arc.center),
+                sweep: arc_sweep(command, settings),
+                plane: settings.plane,
+            },
+        ),
+        Command::Home(home) => {
+            let points = [Some(home.start), home.intermediate, Some(home.end)];
+            let points: Vec<_> = points.into_iter().flatten().collect();
+            for ends in points.windows(2) {
+                segment(ends[0], ends[1], SegmentShape::Line);
+            }
+        }
+        Command::Other(_) => {}
+        _ => segment(command.start_pos(), command.end_pos(), SegmentShape::Line),
+    }
+}
+
+/// A move of a [`DragknifePath`] as it is programmed, without the knife correction, see
+/// [`DragknifePath::segments`].
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PathSegment {
+    /// Index of the move in `DragknifePath::commands`.
+    pub command: usize,
+    /// Zero-based input line of the move.
+    pub line: Option<usize>,
+    /// A cut, or a rapid move.
+    #[serde(flatten)]
+    pub segment: Segment,
+}
+
+/// The segments of the moves of a [`DragknifePath`], in order.
+pub struct PathSegments<'p, 'a> {
+    path: &'p DragknifePath<'a>,
+    settings: GCodeState,
+    next: usize,
+    pending: std::vec::IntoIter<PathSegment>,
+}
+
+impl Iterator for PathSegments<'_, '_> {
+    type Item = PathSegment;
+
+    fn next(&mut self) -> Option<PathSegment> {
+        loop {
+            if let Some(segment) = self.pending.next() {
+                return Some(segment);
+            }
+            let index = self.next;
+            let command = self.path.commands.get(index)?;
+            self.next += 1;
+            command.update_settings(&mut self.settings);
+            let (kind, rapid) = match command {
+                Command::Linear(_) | Command::Arc(_) => (MoveKind::Cut, false),
+                _ => (MoveKind::Rapid, true),
+            };
+            let mut segments = vec![];
+            push_segments(&mut segments, command, &self.settings, kind, rapid);
+            let line = source_line(command.original().span());
+            self.pending = segments
+                .into_iter()
+                .map(|segment| PathSegment {
+                    command: index,
+                    line,
+                    segment,
+                })
+                .collect::<Vec<_>>()
+                .into_iter();
+        }
+    }
+}
+
+impl<'a> DragknifePath<'a> {
+    /// The moves of the path as lines and arcs, in mm in work coordinates, for analyzing or
+    /// drawing it. Commands which do not move are left out, and a move to the home position
+    /// through another point gives two lines.
+    pub fn segments(&self) -> PathSegments<'_, 'a> {
+        PathSegments {
+            path: self,
+            settings: self.initial_state,
+            next: 0,
+            pending: vec![].into_iter(),
+        }
+    }
+}
+
+/// The repathed program as lines and arcs, for writing it in other formats than G-code.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Toolpath {
+    pub segments: Vec<Segment>,
+}
+
+impl Toolpath {
+    /// Collects the moves of `fixed`, the output of [`DragknifePath::to_fixed_gcode`] for
+    /// `path`. Commands which do not move, like dwells, are left out.
+    pub fn new(path: &DragknifePath, fixed: &[FixedGCode]) -> Self {
+        let mut toolpath = Toolpath::default();
+        let mut settings = path.initial_state;
+        let mut degenerate_arcs = vec![];
+        let mut prev: Option<Command> = None;
+        for fixed in fixed {
+            let Ok(command) = Command::from_gcode(
+                &fixed.gcode,
+                prev.as_ref(),
+                &mut settings,
+                &mut degenerate_arcs,
+            ) else {
+                continue;
+            };
+            command.update_settings(&mut settings);
+            let inserted = fixed
+                .reason
+                .as_deref()
+                .is_some_and(|reason| reason != "knife offset");
+            let (kind, rapid) = match &command {
+                Command::Other(_) => {
+                    prev = Some(command);
+                    continue;
+                }
+                _ if inserted => (MoveKind::Swivel, matches!(command, Command::Rapid(_))),
+                Command::Linear(_) | Command::Arc(_) => (MoveKind::Cut, false),
+                Command::Rapid(_) | Command::Home(_) => (MoveKind::Rapid, true),
+            };
+            push_segments(&mut toolpath.segments, &command, &settings, kind, rapid);
+            prev = Some(command);
+        }
+        toolpath
+    }
+
+    /// The segments as JSON, for tools which work with the geometry instead of G-code: an object
+    /// with the `units` (`mm`) and the `segments`, each with its `kind` (`cut`, `rapid` or
+    /// `swivel`), whether it is `rapid`, its `start` and `end`, its `start_angle` and
+    /// `end_angle` and its `type`, `line` or `arc`. Arcs also have their `center`, their
+    /// `sweep` in radians, positive for counterclockwise, and their `plane`.
+    pub fn to_json(&self) -> String {
+        #[derive(Serialize)]
+        struct Json<'a> {
+            units: &'a str,
+            segments: &'a [Segment],
+        }
+        let json = Json {
+            units: "mm",
+            segments: &self.segments,
+        };
+        // Only fails for maps with keys which are not strings
+        serde_json::to_string_pretty(&json).unwrap_or_default() + "\n"
+    }
+}
@@ -1,10 +1,13 @@
-use std::{error::Error, fmt::Display};
+use std::{borrow::Cow, collections::HashMap, error::Error, f64::consts::PI, fmt::Display};
 
-use crate::vec3::Vec3;
-use gcode::GCode;
+use crate::{
+    dialect::CodeRange, input::parse_gcode, operations::Operation, registration::Registration,
+    vec3::Vec3,
+};
+use gcode::{GCode, Mnemonic, Span, Word};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
 pub enum GCodeUnit {
     #[default]
     Millimeters,
@@ -41,7 +44,7 @@ impl GCodeAxis {
     }
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum GCodePlane {
     #[default]
     XY,
@@ -73,19 +76,63 @@ impl GCodePlane {
     }
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
 pub enum GCodePositioning {
     Relative,
     #[default]
     Absolute,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum FeedMode {
+    /// `G94`: F is the speed in units per minute.
+    #[default]
+    UnitsPerMinute,
+    /// `G93`: F is one over the duration of the move in minutes, and is needed on every move.
+    InverseTime,
+}
+
+/// The offsets of the G54-G59 work coordinate systems from machine zero.
+pub type WorkOffsets = [Vec3; 6];
+
+/// Word values as `f64`, which is used for all calculations. The `gcode` crate stores `f32`.
+pub trait WordValues {
+    fn value(&self, letter: char) -> Option<f64>;
+}
+
+impl WordValues for GCode {
+    fn value(&self, letter: char) -> Option<f64> {
+        self.value_for(letter).map(f64::from)
+    }
+}
+
+/// A word with `value` reduced to the precision of the output.
+pub fn word(letter: char, value: f64, span: Span) -> Word {
+    Word::new(letter, value as f32, span)
+}
+
+/// The letters of the rotary axes, in the order of `GCodeState::rotary`.
+pub const ROTARY_AXES: [char; 3] = ['A', 'B', 'C'];
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct GCodeState {
     pub unit: GCodeUnit,
     pub plane: GCodePlane,
     pub positioning: GCodePositioning,
-    pub feedrate: f32,
+    /// How arc centers (`I`, `J`, `K`) are given: `G91.1` relative to the start of the arc (the
+    /// default) or `G90.1` absolute.
+    pub arc_positioning: GCodePositioning,
+    pub feed_mode: FeedMode,
+    /// The cutting speed in mm/min. Not updated in inverse time mode.
+    pub feedrate: f64,
+    pub work_offsets: WorkOffsets,
+    /// Index into `work_offsets` of the active coordinate system, 0 for G54.
+    pub coordinate_system: usize,
+    pub degenerate_arcs: DegenerateArcs,
+    /// Where the rotary axes `A`, `B` and `C` are, in degrees. The output writes them absolute,
+    /// like the other axes.
+    #[serde(default)]
+    pub rotary: [f64; 3],
 }
 
 impl Default for GCodeState {
@@ -94,63 +141,162 @@ impl Default for GCodeState {
             unit: Default::default(),
             plane: Default::default(),
             positioning: Default::default(),
+            arc_positioning: GCodePositioning::Relative,
+            feed_mode: FeedMode::UnitsPerMinute,
             feedrate: 3000.,
+            work_offsets: Default::default(),
+            coordinate_system: 0,
+            degenerate_arcs: Default::default(),
+            rotary: [0.; 3],
         }
     }
 }
 
 impl GCodeState {
-    pub fn unit_factor(&self) -> f32 {
+    pub fn with_work_offsets(work_offsets: WorkOffsets) -> Self {
+        GCodeState {
+            work_offsets,
+            ..Default::default()
+        }
+    }
+
+    /// The state before the first command for `config`.
+    pub fn from_config(config: &DragknifeConfig) -> Self {
+        GCodeState {
+            work_offsets: config.work_offsets,
+            degenerate_arcs: config.degenerate_arcs,
+            ..Default::default()
+        }
+    }
+
+    pub fn work_offset(&self) -> Vec3 {
+        self.work_offsets[self.coordinate_system]
+    }
+
+    /// Follows the rotary axes of a move. `G28` homes the ones it names, or all of them.
+    pub fn update_rotary(&mut self, gcode: &GCode) {
+        if gcode.mnemonic() != Mnemonic::General || gcode.minor_number() != 0 {
+            return;
+        }
+        match gcode.major_number() {
+            0..=3 => {
+                for (axis, letter) in ROTARY_AXES.into_iter().enumerate() {
+                    let Some(value) = gcode.value(letter) else {
+                        continue;
+                    };
+                    match self.positioning {
+                        GCodePositioning::Absolute => self.rotary[axis] = value,
+                        GCodePositioning::Relative => self.rotary[axis] += value,
+                    }
+                }
+            }
+            28 => {
+                let all = ROTARY_AXES
+                    .iter()
+                    .all(|&letter| gcode.value(letter).is_none())
+                    && ['X', 'Y', 'Z']
+                        .iter()
+                        .all(|&letter| gcode.value(letter).is_none());
+                for (axis, letter) in ROTARY_AXES.into_iter().enumerate() {
+                    if all || gcode.value(letter).is_some() {
+                        self.rotary[axis] = 0.;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Converts a machine position to coordinates in the active work coordinate system.
+    pub fn to_work_coords(&self, pos: Vec3) -> Vec3 {
+        pos - self.work_offset()
+    }
+
+    /// Converts a machine position in mm to the coordinates used in the program, i.e. in the
+    /// active work coordinate system and unit.
+    pub fn to_program_coords(&self, pos: Vec3) -> Vec3 {
+        self.to_work_coords(pos) / self.unit_factor()
+    }
+
+    /// The number of mm per program unit.
+    pub fn unit_factor(&self) -> f64 {
         match self.unit {
             GCodeUnit::Millimeters => 1.,
-            GCodeUnit::Inches => 2.54,
+            GCodeUnit::Inches => 25.4,
         }
     }
 
+    /// The F word for a move of `length` mm at `feedrate` mm/min in the active feed mode.
+    pub fn feedrate_word(&self, feedrate: f64, length: f64) -> Word {
+        let value = match self.feed_mode {
+            FeedMode::UnitsPerMinute => feedrate / self.unit_factor(),
+            FeedMode::InverseTime => feedrate / length.max(0.001),
+        };
+        word('F', value, Span::PLACEHOLDER)
+    }
+
     pub fn get_target(&mut self, mut pos: Vec3, gcode: &GCode) -> Vec3 {
         let unit = self.unit_factor();
         if let GCodePositioning::Absolute = self.positioning {
+            let offset = self.work_offset();
             pos.x = gcode
-                .value_for(GCodeAxis::X.main_name())
-                .map(|e| e * unit)
+                .value(GCodeAxis::X.main_name())
+                .map(|e| e * unit + offset.x)
                 .unwrap_or(pos.x);
             pos.y = gcode
-                .value_for(GCodeAxis::Y.main_name())
-                .map(|e| e * unit)
+                .value(GCodeAxis::Y.main_name())
+                .map(|e| e * unit + offset.y)
                 .unwrap_or(pos.y);
             pos.z = gcode
-                .value_for(GCodeAxis::Z.main_name())
-                .map(|e| e * unit)
+                .value(GCodeAxis::Z.main_name())
+                .map(|e| e * unit + offset.z)
                 .unwrap_or(pos.z);
         } else {
             pos.x += gcode
-                .value_for(GCodeAxis::X.main_name())
+                .value(GCodeAxis::X.main_name())
                 .map(|e| e * unit)
                 .unwrap_or(0.);
             pos.y += gcode
-                .value_for(GCodeAxis::Y.main_name())
+                .value(GCodeAxis::Y.main_name())
                 .map(|e| e * unit)
                 .unwrap_or(0.);
             pos.z += gcode
-                .value_for(GCodeAxis::Z.main_name())
+                .value(GCodeAxis::Z.main_name())
                 .map(|e| e * unit)
                 .unwrap_or(0.);
         }
         pos
     }
 
-    pub fn get_center_offset(&self, gcode: &GCode) -> Vec3 {
+    /// The offset of the arc center from `start`.
+    pub fn get_center_offset(&self, start: Vec3, gcode: &GCode) -> Vec3 {
         let unit = self.unit_factor();
+        if let GCodePositioning::Absolute = self.arc_positioning {
+            let offset = self.work_offset();
+            let x = gcode
+                .value(GCodeAxis::X.center_name())
+                .map(|e| e * unit + offset.x - start.x)
+                .unwrap_or(0.);
+            let y = gcode
+                .value(GCodeAxis::Y.center_name())
+                .map(|e| e * unit + offset.y - start.y)
+                .unwrap_or(0.);
+            let z = gcode
+                .value(GCodeAxis::Z.center_name())
+                .map(|e| e * unit + offset.z - start.z)
+                .unwrap_or(0.);
+            return Vec3 { x, y, z };
+        }
         let x = gcode
-            .value_for(GCodeAxis::X.center_name())
+            .value(GCodeAxis::X.center_name())
             .map(|e| e * unit)
             .unwrap_or(0.);
         let y = gcode
-            .value_for(GCodeAxis::Y.center_name())
+            .value(GCodeAxis::Y.center_name())
             .map(|e| e * unit)
             .unwrap_or(0.);
         let z = gcode
-            .value_for(GCodeAxis::Z.center_name())
+            .value(GCodeAxis::Z.center_name())
             .map(|e| e * unit)
             .unwrap_or(0.);
         Vec3 { x, y, z }
@@ -159,8 +305,8 @@ impl GCodeState {
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub enum LiftConfig {
-    AbsoluteHeight(f32),
-    RelativeHeight(f32),
+    AbsoluteHeight(f64),
+    RelativeHeight(f64),
 }
 
 impl Default for LiftConfig {
@@ -170,14 +316,14 @@ impl Default for LiftConfig {
 }
 
 impl LiftConfig {
-    pub fn calcute_height(&self, from: f32) -> f32 {
+    pub fn calcute_height(&self, from: f64) -> f64 {
         match self {
             LiftConfig::AbsoluteHeight(h) => *h,
             LiftConfig::RelativeHeight(h) => from + h,
         }
     }
 
-    pub fn get_height_mut(&mut self) -> &mut f32 {
+    pub fn get_height_mut(&mut self) -> &mut f64 {
         match self {
             LiftConfig::AbsoluteHeight(h) => h,
             LiftConfig::RelativeHeight(h) => h,
@@ -185,82 +331,626 @@ impl LiftConfig {
     }
 }
 
-#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+/// What to do with arcs which have no radius, or whose end is not on the circle they start on.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum DegenerateArcs {
+    /// Cut a line to the end of the arc instead.
+    #[default]
+    Linear,
+    /// Leave the arc out of the output.
+    Drop,
+    /// Stop with an error. Only `DragknifePath::try_from_gcode_with_state` does this; otherwise
+    /// the arc is dropped.
+    Error,
+}
+
+/// Which corners are rounded with `DragknifeConfig::corner_radius`, see
+/// [`crate::DragknifePath::round_corners`].
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum CornerRounding {
+    /// Every corner, instead of swiveling at the sharp ones.
+    #[default]
+    All,
+    /// The corners which are not sharp enough for a swivel, which the knife would otherwise be
+    /// pushed through. The sharp ones are still swiveled at.
+    Unswiveled,
+}
+
+/// What to do with G and M codes of the input which the repath does not understand, like a
+/// canned cycle (`G83`). They are copied to the output as they are, as they may do something the
+/// path does not show.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum UnsupportedCodes {
+    /// Copy them.
+    Pass,
+    /// Copy them and warn about them.
+    #[default]
+    Warn,
+    /// Stop with an error.
+    Error,
+}
+
+/// Why an arc can not be cut as programmed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ArcProblem {
+    /// The center is on the start point.
+    ZeroRadius,
+    /// The distances from the center to the start and end differ.
+    RadiusMismatch { start: f64, end: f64 },
+}
+
+/// An arc which was cut as a line or dropped, as it can not be cut as programmed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DegenerateArc {
+    /// Zero-based input line of the arc.
+    pub line: Option<usize>,
+    pub problem: ArcProblem,
+    pub handling: DegenerateArcs,
+}
+
+impl Display for DegenerateArc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Arc")?;
+        if let Some(line) = self.line {
+            write!(f, " at input line {}", line + 1)?;
+        }
+        match self.problem {
+            ArcProblem::ZeroRadius => write!(f, " has no radius")?,
+            ArcProblem::RadiusMismatch { start, end } => write!(
+                f,
+                " starts at radius {start:.4} mm but ends at radius {end:.4} mm"
+            )?,
+        }
+        match self.handling {
+            DegenerateArcs::Linear => write!(f, "; cut as a line"),
+            DegenerateArcs::Drop => write!(f, "; dropped"),
+            DegenerateArcs::Error => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 #[serde(default)]
 pub struct DragknifeConfig {
-    pub knife_offset: f32,
+    pub knife_offset: f64,
     pub lift_config: LiftConfig,
-    pub sharp_angle_threshold: f32,
-    pub swivel_feedrate: f32,
+    pub sharp_angle_threshold: f64,
+    pub swivel_feedrate: f64,
+    /// GCode emitted instead of the Z move when lifting the knife for a swivel. Empty for a Z move.
+    pub lift_template: String,
+    /// GCode emitted instead of the Z move when lowering the knife after a swivel. Empty for a Z
+    /// move.
+    pub lower_template: String,
+    pub work_offsets: WorkOffsets,
+    /// Seconds to wait with `G4` after lowering the knife after a swivel. 0 for no dwell.
+    pub swivel_dwell: f64,
+    /// Swivel at this percentage of the cutting feedrate instead of at `swivel_feedrate`.
+    pub swivel_feedrate_percentage: Option<f64>,
+    /// Threshold for right (clockwise) turns, if it differs from `sharp_angle_threshold`. Which
+    /// of these are the inside corners depends on the direction the contour is cut in.
+    pub right_turn_threshold: Option<f64>,
+    /// Corners sharper than this are direction reversals, where a swivel would sweep the knife
+    /// across the cut it just made. At these the knife is lifted to `reversal_lift`, swiveled
+    /// beside the cut at the swivel height, and lowered again at the start of the next cut. `None`
+    /// swivels around them as well.
+    pub reversal_angle: Option<f64>,
+    pub reversal_lift: LiftConfig,
+    /// Reverse closed contours which push the knife through fewer sharp corners when cut the
+    /// other way.
+    pub reverse_pushed_contours: bool,
+    pub degenerate_arcs: DegenerateArcs,
+    /// Cuts deeper than this many mm below the work zero are reported by `validate`. `None` to
+    /// allow any depth.
+    pub max_cut_depth: Option<f64>,
+    /// The lowest height in work coordinates the knife may reach, e.g. the surface of the
+    /// spoilboard. Cuts and swivels below it are reported by `validate`.
+    pub min_height: Option<f64>,
+    /// The radius `G41` and `G42` offset the path by, in mm, unless `G41.1` or `G42.1` give a
+    /// diameter with `D`, see [`crate::DragknifePath::apply_cutter_compensation`].
+    pub compensation_radius: f64,
+    /// The width of the cut the blade makes, in mm. Closed contours are offset by half of it, so
+    /// parts and holes come out at the size they were drawn, see
+    /// [`crate::DragknifePath::apply_kerf_compensation`]. 0 to cut the contours as programmed.
+    pub kerf_width: f64,
+    /// Corners between cuts are replaced by arcs of this radius in mm, which the blade follows
+    /// without a swivel. 0 to keep them as programmed.
+    pub corner_radius: f64,
+    pub corner_rounding: CornerRounding,
+    pub unsupported_codes: UnsupportedCodes,
+    /// Codes which are copied without a warning, though the repath does not understand them,
+    /// e.g. `M62-65` to switch the outputs of the machine.
+    pub allowed_codes: Vec<CodeRange>,
+    /// Codes which stop the repath wherever they are, also ones it understands, e.g. `M3` on a
+    /// machine where it starts a spindle.
+    pub blocked_codes: Vec<CodeRange>,
+    /// Lift the knife to this height instead of `lift_config` for swivels which would sweep it
+    /// across an earlier cut. `None` to only report them.
+    pub crossing_lift: Option<LiftConfig>,
+    /// A regex for the comments which start an operation, with the name of the operation as its
+    /// first group. Empty for `operations::DEFAULT_OPERATION_MARKER`.
+    pub operation_marker: String,
+    /// Parts of the program which are cut with other settings, e.g. crease lines. Comments are
+    /// only checked for markers if there are any.
+    pub operations: Vec<Operation>,
+    /// Marks which line the path up with a printed design, see [`Registration`].
+    pub registration: Registration,
 }
 
 impl DragknifeConfig {
+    /// The settings which matter most, with the others turned off. See
+    /// [`DragknifeConfig::builder`] for a checked config with any of the settings.
     pub fn new(
-        knife_offset: f32,
+        knife_offset: f64,
         lift_config: LiftConfig,
-        sharp_angle_threshold: f32,
-        swivel_feedrate: f32,
+        sharp_angle_threshold: f64,
+        swivel_feedrate: f64,
     ) -> Self {
         DragknifeConfig {
             knife_offset,
             lift_config,
             sharp_angle_threshold,
             swivel_feedrate,
+            lift_template: String::new(),
+            lower_template: String::new(),
+            work_offsets: Default::default(),
+            swivel_dwell: 0.,
+            swivel_feedrate_percentage: None,
+            right_turn_threshold: None,
+            reversal_angle: None,
+            reversal_lift: LiftConfig::RelativeHeight(5.0),
+            reverse_pushed_contours: false,
+            degenerate_arcs: Default::default(),
+            max_cut_depth: None,
+            min_height: None,
+            compensation_radius: 0.,
+            kerf_width: 0.,
+            corner_radius: 0.,
+            corner_rounding: CornerRounding::default(),
+            unsupported_codes: Default::default(),
+            allowed_codes: vec![],
+            blocked_codes: vec![],
+            crossing_lift: None,
+            operation_marker: String::new(),
+            operations: vec![],
+            registration: Default::default(),
+        }
+    }
+
+    pub fn builder() -> DragknifeConfigBuilder {
+        DragknifeConfigBuilder::default()
+    }
+
+    /// The angle above which a corner gets a swivel. `signed_angle` is positive for right turns.
+    pub fn angle_threshold(&self, signed_angle: f64) -> f64 {
+        match self.right_turn_threshold {
+            Some(threshold) if signed_angle > 0. => threshold,
+            _ => self.sharp_angle_threshold,
+        }
+    }
+
+    /// Whether a corner of `signed_angle` is handled as a direction reversal.
+    pub fn is_reversal(&self, signed_angle: f64) -> bool {
+        self.reversal_angle
+            .is_some_and(|angle| signed_angle.abs() > angle)
+    }
+
+    /// The swivel feedrate in mm/min. Inverse time mode does not track the cutting feedrate, so
+    /// there it is always `swivel_feedrate`.
+    pub fn effective_swivel_feedrate(&self, settings: &GCodeState) -> f64 {
+        match (self.swivel_feedrate_percentage, settings.feed_mode) {
+            (Some(percentage), FeedMode::UnitsPerMinute) => settings.feedrate * percentage / 100.,
+            _ => self.swivel_feedrate,
+        }
+    }
+
+    /// Checks that the settings can be cut with: a knife offset of 0 or more, angle thresholds
+    /// between 0 and 180°, feedrates above 0, no negative dwell, compensation radius, kerf
+    /// width or corner radius, and lift heights which are numbers.
+    pub fn check(&self) -> Result<(), ConfigError> {
+        if !(self.knife_offset >= 0. && self.knife_offset.is_finite()) {
+            return Err(ConfigError::KnifeOffset(self.knife_offset));
+        }
+        let angles = [
+            ("sharp angle threshold", Some(self.sharp_angle_threshold)),
+            ("right turn threshold", self.right_turn_threshold),
+            ("reversal angle", self.reversal_angle),
+        ];
+        for (setting, angle) in angles {
+            if let Some(angle) = angle.filter(|angle| !(0. ..=PI).contains(angle)) {
+                return Err(ConfigError::Angle { setting, angle });
+            }
+        }
+        let feedrates = [
+            ("swivel feedrate", Some(self.swivel_feedrate)),
+            (
+                "swivel feedrate percentage",
+                self.swivel_feedrate_percentage,
+            ),
+        ];
+        for (setting, value) in feedrates {
+            if let Some(value) = value.filter(|value| !(*value > 0. && value.is_finite())) {
+                return Err(ConfigError::Feedrate { setting, value });
+            }
+        }
+        if !(self.swivel_dwell >= 0. && self.swivel_dwell.is_finite()) {
+            return Err(ConfigError::SwivelDwell(self.swivel_dwell));
+        }
+        if !(self.compensation_radius >= 0. && self.compensation_radius.is_finite()) {
+            return Err(ConfigError::CompensationRadius(self.compensation_radius));
+        }
+        if !(self.kerf_width >= 0. && self.kerf_width.is_finite()) {
+            return Err(ConfigError::KerfWidth(self.kerf_width));
+        }
+        if !(self.corner_radius >= 0. && self.corner_radius.is_finite()) {
+            return Err(ConfigError::CornerRadius(self.corner_radius));
+        }
+        let lifts = [
+            ("swivel lift", Some(self.lift_config)),
+            ("reversal lift", Some(self.reversal_lift)),
+            ("crossing lift", self.crossing_lift),
+        ];
+        for (setting, lift) in lifts {
+            let height = match lift {
+                Some(LiftConfig::AbsoluteHeight(height) | LiftConfig::RelativeHeight(height)) => {
+                    height
+                }
+                None => continue,
+            };
+            if !height.is_finite() {
+                return Err(ConfigError::Lift { setting, height });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds a [`DragknifeConfig`] by name instead of by position, and checks it:
+///
+/// ```
+/// use dragknife_repath::types::{DragknifeConfig, LiftConfig};
+///
+/// let config = DragknifeConfig::builder()
+///     .knife_offset(0.35)
+///     .lift(LiftConfig::RelativeHeight(0.5))
+///     .sharp_angle_threshold(10f64.to_radians())
+///     .max_cut_depth(None)
+///     .build()
+///     .unwrap();
+/// ```
+///
+/// Settings which are not given keep the defaults of a config file, see
+/// [`crate::config_file::ConfigFile`].
+#[derive(Debug, Clone)]
+pub struct DragknifeConfigBuilder {
+    config: DragknifeConfig,
+}
+
+impl Default for DragknifeConfigBuilder {
+    fn default() -> Self {
+        DragknifeConfigBuilder {
+            config: crate::config_file::ConfigFile::default().knife,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+impl DragknifeConfigBuilder {
+    pub fn knife_offset(mut self, knife_offset: f64) -> Self {
+        self.config.knife_offset = knife_offset;
+        self
+    }
+
+    /// How the knife is lifted for a swivel.
+    pub fn lift(mut self, lift: LiftConfig) -> Self {
+        self.config.lift_config = lift;
+        self
+    }
+
+    /// In radians.
+    pub fn sharp_angle_threshold(mut self, threshold: f64) -> Self {
+        self.config.sharp_angle_threshold = threshold;
+        self
+    }
+
+    /// In mm/min.
+    pub fn swivel_feedrate(mut self, feedrate: f64) -> Self {
+        self.config.swivel_feedrate = feedrate;
+        self
+    }
+
+    pub fn lift_template(mut self, template: impl Into<String>) -> Self {
+        self.config.lift_template = template.into();
+        self
+    }
+
+    pub fn lower_template(mut self, template: impl Into<String>) -> Self {
+        self.config.lower_template = template.into();
+        self
+    }
+
+    pub fn work_offsets(mut self, work_offsets: WorkOffsets) -> Self {
+        self.config.work_offsets = work_offsets;
+        self
+    }
+
+    /// In seconds.
+    pub fn swivel_dwell(mut self, seconds: f64) -> Self {
+        self.config.swivel_dwell = seconds;
+        self
+    }
+
+    pub fn swivel_feedrate_percentage(mut self, percentage: impl Into<Option<f64>>) -> Self {
+        self.config.swivel_feedrate_percentage = percentage.into();
+        self
+    }
+
+    /// In radians.
+    pub fn right_turn_threshold(mut self, threshold: impl Into<Option<f64>>) -> Self {
+        self.config.right_turn_threshold = threshold.into();
+        self
+    }
+
+    /// In radians.
+    pub fn reversal_angle(mut self, angle: impl Into<Option<f64>>) -> Self {
+        self.config.reversal_angle = angle.into();
+        self
+    }
+
+    pub fn reversal_lift(mut self, lift: LiftConfig) -> Self {
+        self.config.reversal_lift = lift;
+        self
+    }
+
+    pub fn reverse_pushed_contours(mut self, reverse: bool) -> Self {
+        self.config.reverse_pushed_contours = reverse;
+        self
+    }
+
+    pub fn degenerate_arcs(mut self, handling: DegenerateArcs) -> Self {
+        self.config.degenerate_arcs = handling;
+        self
+    }
+
+    pub fn max_cut_depth(mut self, depth: impl Into<Option<f64>>) -> Self {
+        self.config.max_cut_depth = depth.into();
+        self
+    }
+
+    pub fn min_height(mut self, height: impl Into<Option<f64>>) -> Self {
+        self.config.min_height = height.into();
+        self
+    }
+
+    /// In mm.
+    pub fn compensation_radius(mut self, radius: f64) -> Self {
+        self.config.compensation_radius = radius;
+        self
+    }
+
+    /// In mm.
+    pub fn kerf_width(mut self, width: f64) -> Self {
+        self.config.kerf_width = width;
+        self
+    }
+
+    /// In mm.
+    pub fn corner_radius(mut self, radius: f64) -> Self {
+        self.config.corner_radius = radius;
+        self
+    }
+
+    pub fn corner_rounding(mut self, rounding: CornerRounding) -> Self {
+        self.config.corner_rounding = rounding;
+        self
+    }
+
+    pub fn unsupported_codes(mut self, handling: UnsupportedCodes) -> Self {
+        self.config.unsupported_codes = handling;
+        self
+    }
+
+    /// Adds codes which are copied without a warning.
+    pub fn allowed_codes(mut self, codes: impl IntoIterator<Item = CodeRange>) -> Self {
+        self.config.allowed_codes.extend(codes);
+        self
+    }
+
+    /// Adds codes which stop the repath.
+    pub fn blocked_codes(mut self, codes: impl IntoIterator<Item = CodeRange>) -> Self {
+        self.config.blocked_codes.extend(codes);
+        self
+    }
+
+    pub fn crossing_lift(mut self, lift: impl Into<Option<LiftConfig>>) -> Self {
+        self.config.crossing_lift = lift.into();
+        self
+    }
+
+    pub fn operation_marker(mut self, marker: impl Into<String>) -> Self {
+        self.config.operation_marker = marker.into();
+        self
+    }
+
+    /// Adds an operation.
+    pub fn operation(mut self, operation: Operation) -> Self {
+        self.config.operations.push(operation);
+        self
+    }
+
+    pub fn registration(mut self, registration: Registration) -> Self {
+        self.config.registration = registration;
+        self
+    }
+
+    /// The config, if [`DragknifeConfig::check`] finds nothing wrong with it.
+    pub fn build(self) -> Result<DragknifeConfig, ConfigError> {
+        self.config.check()?;
+        Ok(self.config)
+    }
+}
+
+/// A setting of a [`DragknifeConfig`] which can not be cut with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// The knife offset is negative or not a number.
+    KnifeOffset(f64),
+    /// An angle is not between 0 and 180°. In radians.
+    Angle { setting: &'static str, angle: f64 },
+    /// A feedrate or a percentage of one is not above 0.
+    Feedrate { setting: &'static str, value: f64 },
+    /// The dwell is negative or not a number.
+    SwivelDwell(f64),
+    /// The cutter compensation radius is negative or not a number.
+    CompensationRadius(f64),
+    /// The kerf width is negative or not a number.
+    KerfWidth(f64),
+    /// The radius corners are rounded with is negative or not a number.
+    CornerRadius(f64),
+    /// A height to lift to is not a number.
+    Lift { setting: &'static str, height: f64 },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::KnifeOffset(offset) => {
+                write!(f, "The knife offset must be 0 or more, not {offset}")
+            }
+            Self::Angle { setting, angle } => write!(
+                f,
+                "The {setting} must be between 0 and 180°, not {:.1}°",
+                angle.to_degrees()
+            ),
+            Self::Feedrate { setting, value } => {
+                write!(f, "The {setting} must be more than 0, not {value}")
+            }
+            Self::SwivelDwell(seconds) => {
+                write!(
+                    f,
+                    "The swivel dwell must be 0 or more seconds, not {seconds}"
+                )
+            }
+            Self::CompensationRadius(radius) => write!(
+                f,
+                "The cutter compensation radius must be 0 or more, not {radius}"
+            ),
+            Self::KerfWidth(width) => write!(f, "The kerf width must be 0 or more, not {width}"),
+            Self::CornerRadius(radius) => {
+                write!(f, "The corner radius must be 0 or more, not {radius}")
+            }
+            Self::Lift { setting, height } => {
+                write!(
+                    f,
+                    "The height of the {setting} must be a number, not {height}"
+                )
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+#[derive(Debug, Clone, Default)]
 pub struct DragknifeState {
-    pub next_feedrate: Option<f32>,
+    pub next_feedrate: Option<f64>,
+    /// The lift and lower templates parsed so far, by their text.
+    templates: HashMap<String, Vec<GCode>>,
 }
 
-#[derive(Debug, Clone)]
+impl DragknifeState {
+    /// The codes of a lift or lower template, which is parsed once for a whole path.
+    pub fn template_gcode(&mut self, template: &str) -> &[GCode] {
+        if !self.templates.contains_key(template) {
+            self.templates
+                .insert(template.to_string(), parse_gcode(template));
+        }
+        &self.templates[template]
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HomeMovement<'a> {
-    pub original: &'a GCode,
+    pub original: Cow<'a, GCode>,
     pub start: Vec3,
+    /// The point the machine moves through before homing, if any axes were given.
+    pub intermediate: Option<Vec3>,
+    pub end: Vec3,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RapidMovement<'a> {
-    pub original: &'a GCode,
+    pub original: Cow<'a, GCode>,
     pub start: Vec3,
     pub end: Vec3,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LinearMovement<'a> {
-    pub original: &'a GCode,
+    pub original: Cow<'a, GCode>,
     pub start: Vec3,
     pub end: Vec3,
-    pub angle: Option<f32>,
+    pub angle: Option<f64>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum ArcDirection {
     CW,
     CCW,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ArcMovement<'a> {
-    pub original: &'a GCode,
+    pub original: Cow<'a, GCode>,
     pub direction: ArcDirection,
     pub start: Vec3,
     pub end: Vec3,
     pub center: Vec3,
-    pub start_angle: f32,
-    pub end_angle: f32,
+    pub start_angle: f64,
+    pub end_angle: f64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OtherCommand<'a> {
-    pub original: &'a GCode,
+    pub original: Cow<'a, GCode>,
     pub pos: Vec3,
-    pub angle: Option<f32>,
+    pub angle: Option<f64>,
 }
 
 impl<'a> OtherCommand<'a> {
+    /// `G10 L2` sets the offset of a work coordinate system, `G10 L20` sets it so the current
+    /// position gets the given coordinates. `P1`-`P6` select G54-G59, `P0` the active one.
+    fn set_work_offset(&self, settings: &mut GCodeState) {
+        let gcode = &self.original;
+        let Some(index) = self.work_offset_index(settings) else {
+            return;
+        };
+        let unit = settings.unit_factor();
+        let offset = &mut settings.work_offsets[index];
+        let axes = [
+            (GCodeAxis::X, &mut offset.x, self.pos.x),
+            (GCodeAxis::Y, &mut offset.y, self.pos.y),
+            (GCodeAxis::Z, &mut offset.z, self.pos.z),
+        ];
+        for (axis, offset, pos) in axes {
+            let Some(value) = gcode.value(axis.main_name()) else {
+                continue;
+            };
+            match gcode.value('L').map(|l| l as u32) {
+                Some(2) => *offset = value * unit,
+                Some(20) => *offset = pos - value * unit,
+                _ => {}
+            }
+        }
+    }
+
+    /// The index into `work_offsets` changed by a `G10`.
+    pub fn work_offset_index(&self, settings: &GCodeState) -> Option<usize> {
+        match self.original.value('P').map(|p| p as usize) {
+            Some(0) | None => Some(settings.coordinate_system),
+            Some(p @ 1..=6) => Some(p - 1),
+            Some(_) => None,
+        }
+    }
+
     pub fn update_settings(&self, settings: &mut GCodeState) {
         match self.original.major_number() {
             17 /* Select XY plane */=> {
@@ -278,20 +968,41 @@ impl<'a> OtherCommand<'a> {
             21 /* Select mm */=> {
                 settings.unit = GCodeUnit::Millimeters;
             },
-            90 /* Select absolute positioning */=> {
+            90 /* Select absolute positioning */ if self.original.minor_number() == 0 => {
                 settings.positioning = GCodePositioning::Absolute;
             },
-            91 /* Select relative positioning */=> {
+            91 /* Select relative positioning */ if self.original.minor_number() == 0 => {
                 settings.positioning = GCodePositioning::Relative;
             },
-            40..=44 /* Tool compensation: NOOP */ => {},
-            54..=59 /* Set coord systems: NOOP */ => {},
+            90 /* Select absolute arc centers */ if self.original.minor_number() == 1 => {
+                settings.arc_positioning = GCodePositioning::Absolute;
+            },
+            91 /* Select relative arc centers */ if self.original.minor_number() == 1 => {
+                settings.arc_positioning = GCodePositioning::Relative;
+            },
+            10 /* Set work offsets */ => self.set_work_offset(settings),
+            40..=44 /* Tool compensation: applied to the path, or NOOP */ => {},
+            93 /* Select inverse time feed mode */ => {
+                settings.feed_mode = FeedMode::InverseTime;
+            },
+            94 /* Select units per minute feed mode */ => {
+                settings.feed_mode = FeedMode::UnitsPerMinute;
+            },
+            54..=59 /* Select work coordinate system */ if self.original.minor_number() == 0 => {
+                settings.coordinate_system = self.original.major_number() as usize - 54;
+            },
             _ => {},
         }
+        // A feedrate is modal, whichever command it is written on
+        if let (FeedMode::UnitsPerMinute, Some(feedrate)) =
+            (settings.feed_mode, self.original.value('F'))
+        {
+            settings.feedrate = feedrate * settings.unit_factor();
+        }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Command<'a> {
     Other(OtherCommand<'a>),
     Linear(LinearMovement<'a>),
@@ -301,21 +1012,66 @@ pub enum Command<'a> {
 }
 
 impl<'a> Command<'a> {
-    pub fn original(&self) -> &'a GCode {
+    pub fn original(&self) -> &GCode {
+        self.original_cow()
+    }
+
+    pub fn original_cow(&self) -> &Cow<'a, GCode> {
         match self {
-            Command::Other(command) => command.original,
-            Command::Linear(command) => command.original,
-            Command::Arc(command) => command.original,
-            Command::Home(command) => command.original,
-            Command::Rapid(command) => command.original,
+            Command::Other(command) => &command.original,
+            Command::Linear(command) => &command.original,
+            Command::Arc(command) => &command.original,
+            Command::Home(command) => &command.original,
+            Command::Rapid(command) => &command.original,
+        }
+    }
+
+    /// Copies the input command, so the command no longer borrows the parsed program.
+    pub fn into_owned(self) -> Command<'static> {
+        match self {
+            Command::Other(command) => Command::Other(OtherCommand {
+                original: Cow::Owned(command.original.into_owned()),
+                pos: command.pos,
+                angle: command.angle,
+            }),
+            Command::Linear(command) => Command::Linear(LinearMovement {
+                original: Cow::Owned(command.original.into_owned()),
+                start: command.start,
+                end: command.end,
+                angle: command.angle,
+            }),
+            Command::Arc(command) => Command::Arc(ArcMovement {
+                original: Cow::Owned(command.original.into_owned()),
+                direction: command.direction,
+                start: command.start,
+                end: command.end,
+                center: command.center,
+                start_angle: command.start_angle,
+                end_angle: command.end_angle,
+            }),
+            Command::Home(command) => Command::Home(HomeMovement {
+                original: Cow::Owned(command.original.into_owned()),
+                start: command.start,
+                intermediate: command.intermediate,
+                end: command.end,
+            }),
+            Command::Rapid(command) => Command::Rapid(RapidMovement {
+                original: Cow::Owned(command.original.into_owned()),
+                start: command.start,
+                end: command.end,
+            }),
         }
     }
 
     pub fn update_settings(&self, settings: &mut GCodeState) -> bool {
         match self {
             Command::Other(command) => command.update_settings(settings),
+            _ if settings.feed_mode == FeedMode::InverseTime => {
+                settings.update_rotary(self.original());
+            }
             _ => {
-                if let Some(feedrate) = self.original().value_for('F') {
+                settings.update_rotary(self.original());
+                if let Some(feedrate) = self.original().value('F') {
                     settings.feedrate = feedrate * settings.unit_factor();
                     return true;
                 };
@@ -328,8 +1084,8 @@ impl<'a> Command<'a> {
 pub trait Movement {
     fn start_pos(&self) -> Vec3;
     fn end_pos(&self) -> Vec3;
-    fn start_angle(&self) -> Option<f32>;
-    fn end_angle(&self) -> Option<f32>;
+    fn start_angle(&self) -> Option<f64>;
+    fn end_angle(&self) -> Option<f64>;
 }
 
 impl<'a> Movement for Command<'a> {
@@ -348,12 +1104,12 @@ impl<'a> Movement for Command<'a> {
             Command::Other(movement) => movement.pos,
             Command::Linear(movement) => movement.end,
             Command::Arc(movement) => movement.end,
-            Command::Home(_) => Vec3::zero(),
+            Command::Home(movement) => movement.end,
             Command::Rapid(movement) => movement.end,
         }
     }
 
-    fn start_angle(&self) -> Option<f32> {
+    fn start_angle(&self) -> Option<f64> {
         match self {
             Command::Other(movement) => movement.angle,
             Command::Linear(movement) => movement.angle,
@@ -363,7 +1119,7 @@ impl<'a> Movement for Command<'a> {
         }
     }
 
-    fn end_angle(&self) -> Option<f32> {
+    fn end_angle(&self) -> Option<f64> {
         match self {
             Command::Other(movement) => movement.angle,
             Command::Linear(movement) => movement.angle,
@@ -383,24 +1139,110 @@ impl<'a> Movement for Option<&Command<'a>> {
         self.map_or(Vec3::zero(), |c| c.end_pos())
     }
 
-    fn start_angle(&self) -> Option<f32> {
+    fn start_angle(&self) -> Option<f64> {
         self.map_or(None, |c| c.start_angle())
     }
 
-    fn end_angle(&self) -> Option<f32> {
+    fn end_angle(&self) -> Option<f64> {
         self.map_or(None, |c| c.end_angle())
     }
 }
 
+/// A command of the fixed program, with the reason it was added if it was not in the input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FixedGCode {
+    pub gcode: GCode,
+    pub reason: Option<String>,
+}
+
+impl FixedGCode {
+    pub fn inserted(gcode: GCode, reason: impl Into<String>) -> Self {
+        FixedGCode {
+            gcode,
+            reason: Some(reason.into()),
+        }
+    }
+
+    /// Changes the command, keeping the reason.
+    pub fn map(self, f: impl FnOnce(GCode) -> GCode) -> Self {
+        FixedGCode {
+            gcode: f(self.gcode),
+            reason: self.reason,
+        }
+    }
+}
+
+impl From<GCode> for FixedGCode {
+    fn from(gcode: GCode) -> Self {
+        FixedGCode {
+            gcode,
+            reason: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum RepathError {
     MissingArguments,
+    /// A word of the output is NaN or infinite, e.g. because of a zero-length arc.
+    NonFinite {
+        /// Zero-based input line of the command, or of the command the inserted code belongs to.
+        line: Option<usize>,
+        word: char,
+        /// What the word was computed for, e.g. `swivel: 90° corner` or `G2`.
+        computation: String,
+    },
+    DegenerateArc(DegenerateArc),
+    /// A code of the input which is not understood, with `UnsupportedCodes::Error`, or which is
+    /// one of `DragknifeConfig::blocked_codes`.
+    UnsupportedCode {
+        /// Zero-based input line of the code.
+        line: Option<usize>,
+        code: String,
+        blocked: bool,
+    },
+    /// The written program does not make a cut of the input, see `DragknifePath::verify_output`.
+    Verification {
+        /// Zero-based input line of the cut.
+        line: Option<usize>,
+        problem: String,
+    },
 }
 
 impl Display for RepathError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::MissingArguments => write!(f, "Expected multiple arguments where none were provided. This is likely a result of line duplication.")
+            Self::MissingArguments => write!(f, "Expected multiple arguments where none were provided. This is likely a result of line duplication."),
+            Self::NonFinite { line, word, computation } => {
+                write!(f, "Could not compute the {word} word of the {computation}")?;
+                if let Some(line) = line {
+                    write!(f, " for input line {}", line + 1)?;
+                }
+                write!(f, ": the result is not a finite number")
+            }
+            Self::DegenerateArc(arc) => arc.fmt(f),
+            Self::UnsupportedCode {
+                line,
+                code,
+                blocked,
+            } => {
+                if *blocked {
+                    write!(f, "{code} is blocked in the settings")?;
+                } else {
+                    write!(f, "{code} is not supported")?;
+                }
+                if let Some(line) = line {
+                    write!(f, " (input line {})", line + 1)?;
+                }
+                Ok(())
+            }
+            Self::Verification { line, problem } => {
+                write!(f, "The written program does not match the input")?;
+                if let Some(line) = line {
+                    write!(f, " at input line {}", line + 1)?;
+                }
+                write!(f, ": {problem}")
+            }
         }
     }
 }
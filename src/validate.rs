@@ -0,0 +1,233 @@
+use std::fmt::Display;
+
+use gcode::{GCode, Mnemonic, Span};
+
+use crate::{
+    dialect::CodeRange,
+    output::{command_number, mnemonic_letter, source_line},
+    signed_angle,
+    types::{
+        Command, DragknifeConfig, FeedMode, GCodeState, Movement, RepathError, UnsupportedCodes,
+        WordValues,
+    },
+    DragknifePath,
+};
+
+/// General commands which are understood, or which do not change the path.
+const SUPPORTED_CODES: [u32; 27] = [
+    0, 1, 2, 3, 4, 10, 17, 18, 19, 20, 21, 28, 40, 41, 42, 43, 49, 54, 55, 56, 57, 58, 59, 90, 91,
+    93, 94,
+];
+
+/// Miscellaneous commands which do not move the knife: stops, the spindle, tool changes, coolant,
+/// overrides and subprograms.
+const SUPPORTED_M_CODES: [u32; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 30, 48, 49, 60, 98, 99];
+
+/// How a G or M code of the input is handled if it is not understood or blocked, following
+/// `config`. `None` for codes which are understood or allowed.
+fn unsupported_code(gcode: &GCode, config: &DragknifeConfig) -> Option<UnsupportedCodes> {
+    let matches = |ranges: &[CodeRange]| ranges.iter().any(|range| range.matches(gcode));
+    if matches(&config.blocked_codes) {
+        return Some(UnsupportedCodes::Error);
+    }
+    let supported = match gcode.mnemonic() {
+        Mnemonic::General => SUPPORTED_CODES.contains(&gcode.major_number()),
+        Mnemonic::Miscellaneous => SUPPORTED_M_CODES.contains(&gcode.major_number()),
+        _ => true,
+    };
+    (!supported && !matches(&config.allowed_codes)).then_some(config.unsupported_codes)
+}
+
+/// Checks a code of the input against `config.unsupported_codes`, `allowed_codes` and
+/// `blocked_codes`: an error for one which stops the repath, and a warning for one which is
+/// copied with a warning.
+pub fn check_code(
+    gcode: &GCode,
+    config: &DragknifeConfig,
+) -> Result<Option<ValidationWarning>, RepathError> {
+    let code = format!(
+        "{}{}",
+        mnemonic_letter(gcode.mnemonic()),
+        command_number(gcode)
+    );
+    match unsupported_code(gcode, config) {
+        Some(UnsupportedCodes::Error) => Err(RepathError::UnsupportedCode {
+            line: source_line(gcode.span()),
+            code,
+            blocked: config
+                .blocked_codes
+                .iter()
+                .any(|range| range.matches(gcode)),
+        }),
+        Some(UnsupportedCodes::Warn) => Ok(Some(ValidationWarning::UnsupportedCode {
+            span: gcode.span(),
+            code,
+        })),
+        _ => Ok(None),
+    }
+}
+
+/// Arc ends which are moved less than this onto their circle are not reported.
+const ARC_END_TOLERANCE: f64 = 0.00001;
+
+/// Something in the input which may not be repathed as intended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationWarning {
+    /// A G or M code which is copied to the output without being understood, e.g. a canned
+    /// cycle, with `UnsupportedCodes::Warn`.
+    UnsupportedCode { span: Span, code: String },
+    /// The first cut comes before any feedrate is set, so it runs at the controller's default.
+    FeedrateNotSet { span: Span },
+    /// A cut goes deeper than `DragknifeConfig::max_cut_depth` below the work zero.
+    TooDeep { span: Span, depth: f64 },
+    /// The end of an arc was not on its circle and was moved onto it.
+    ArcEndAdjusted { span: Span, distance: f64 },
+    /// A cut, or the swivel before it, goes below `DragknifeConfig::min_height`.
+    BelowMinHeight {
+        span: Span,
+        height: f64,
+        swivel: bool,
+    },
+}
+
+impl ValidationWarning {
+    pub fn span(&self) -> Span {
+        match self {
+            ValidationWarning::UnsupportedCode { span, .. }
+            | ValidationWarning::FeedrateNotSet { span }
+            | ValidationWarning::TooDeep { span, .. }
+            | ValidationWarning::ArcEndAdjusted { span, .. }
+            | ValidationWarning::BelowMinHeight { span, .. } => *span,
+        }
+    }
+}
+
+impl Display for ValidationWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationWarning::UnsupportedCode { code, .. } => {
+                write!(f, "{code} is not supported and copied as it is")?
+            }
+            ValidationWarning::FeedrateNotSet { .. } => {
+                write!(f, "The first cut comes before any feedrate is set")?
+            }
+            ValidationWarning::TooDeep { depth, .. } => {
+                write!(f, "Cut {depth:.3} mm below the work zero")?
+            }
+            ValidationWarning::ArcEndAdjusted { distance, .. } => {
+                write!(f, "Arc end moved {distance:.4} mm to put it on the circle")?
+            }
+            ValidationWarning::BelowMinHeight { height, swivel, .. } => write!(
+                f,
+                "{} at height {height:.3} is below the lowest allowed height",
+                if *swivel { "Swivel" } else { "Cut" }
+            )?,
+        }
+        if let Some(line) = source_line(self.span()) {
+            write!(f, " (input line {})", line + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> DragknifePath<'a> {
+    /// Stops at the first code of the input which is not understood with
+    /// `UnsupportedCodes::Error`, or which is blocked.
+    pub fn check_codes(&self, config: &DragknifeConfig) -> Result<(), RepathError> {
+        for command in &self.commands {
+            check_code(command.original(), config)?;
+        }
+        Ok(())
+    }
+
+    /// Finds things in the input which are repathed, but may not give the intended result.
+    ///
+    /// This looks at the moves as they were parsed, so it is run before contours are reversed.
+    pub fn validate(&self, config: &DragknifeConfig) -> Vec<ValidationWarning> {
+        let mut warnings = vec![];
+        let mut settings = self.initial_state;
+        let mut feedrate_set = false;
+        let mut cut = false;
+        let mut prev_angle = None;
+        let below_min = |height: f64| config.min_height.is_some_and(|min| height < min);
+        for command in &self.commands {
+            let original = command.original();
+            let span = original.span();
+            feedrate_set |= original.value('F').is_some();
+            match command {
+                Command::Other(_) => {
+                    if let Ok(Some(warning)) = check_code(original, config) {
+                        warnings.push(warning);
+                    }
+                }
+                Command::Linear(_) | Command::Arc(_) => {
+                    if !cut && !feedrate_set && settings.feed_mode == FeedMode::UnitsPerMinute {
+                        warnings.push(ValidationWarning::FeedrateNotSet { span });
+                    }
+                    cut = true;
+                    let height = settings
+                        .to_work_coords(command.end_pos())
+                        .third_coord(&settings.plane);
+                    if config.max_cut_depth.is_some_and(|max| -height > max) {
+                        warnings.push(ValidationWarning::TooDeep {
+                            span,
+                            depth: -height,
+                        });
+                    }
+                    if below_min(height) {
+                        warnings.push(ValidationWarning::BelowMinHeight {
+                            span,
+                            height,
+                            swivel: false,
+                        });
+                    }
+                    if let Some(height) = swivel_height(prev_angle, command, &settings, config) {
+                        if below_min(height) {
+                            warnings.push(ValidationWarning::BelowMinHeight {
+                                span,
+                                height,
+                                swivel: true,
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+            if let Command::Arc(arc) = command {
+                let target = settings.get_target(arc.start, original);
+                let distance = (target - arc.end).magnitude();
+                if distance > ARC_END_TOLERANCE {
+                    warnings.push(ValidationWarning::ArcEndAdjusted { span, distance });
+                }
+            }
+            prev_angle = command.end_angle();
+            command.update_settings(&mut settings);
+        }
+        warnings
+    }
+}
+
+/// The height the knife is lifted to for the corner before `command`, if there is a swivel or
+/// reversal and the height is not set by a custom lift.
+fn swivel_height(
+    prev_angle: Option<f64>,
+    command: &Command,
+    settings: &GCodeState,
+    config: &DragknifeConfig,
+) -> Option<f64> {
+    let signed_angle = signed_angle(prev_angle?, command.start_angle()?);
+    if signed_angle.abs() <= config.angle_threshold(signed_angle)
+        || !config.lift_template.is_empty()
+    {
+        return None;
+    }
+    let lift = if config.is_reversal(signed_angle) {
+        config.reversal_lift
+    } else {
+        config.lift_config
+    };
+    let start_height = settings
+        .to_work_coords(command.start_pos())
+        .third_coord(&settings.plane);
+    Some(lift.calcute_height(start_height))
+}
@@ -1,16 +1,21 @@
-use std::{fmt::Display, ops::{Add, Div, Mul, Sub}};
+use std::{
+    fmt::Display,
+    ops::{Add, Div, Mul, Sub},
+};
+
+use serde::{Deserialize, Serialize};
 
 use crate::types::GCodePlane;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Vec3 {
-    pub x: f32,
-    pub y: f32,
-    pub z: f32,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
 }
 
 impl Vec3 {
-    pub fn new(x: f32, y: f32, z: f32) -> Vec3 {
+    pub fn new(x: f64, y: f64, z: f64) -> Vec3 {
         Vec3 { x, y, z }
     }
 
@@ -22,13 +27,13 @@ impl Vec3 {
         }
     }
 
-    pub fn unit_angle(angle: f32, plane: &GCodePlane) -> Vec3 {
+    pub fn unit_angle(angle: f64, plane: &GCodePlane) -> Vec3 {
         let axis_1 = angle.cos();
         let axis_2 = angle.sin();
         Vec3::from_2d(axis_1, axis_2, plane)
     }
 
-    pub fn from_2d(axis_1: f32, axis_2: f32, plane: &GCodePlane) -> Vec3 {
+    pub fn from_2d(axis_1: f64, axis_2: f64, plane: &GCodePlane) -> Vec3 {
         match plane {
             GCodePlane::XY => Vec3 {
                 x: axis_1,
@@ -48,7 +53,7 @@ impl Vec3 {
         }
     }
 
-    pub fn coords_for_plane(&self, plane: &GCodePlane) -> (f32, f32) {
+    pub fn coords_for_plane(&self, plane: &GCodePlane) -> (f64, f64) {
         match plane {
             GCodePlane::XY => (self.x, self.y),
             GCodePlane::ZX => (self.z, self.x),
@@ -56,7 +61,7 @@ impl Vec3 {
         }
     }
 
-    pub fn third_coord(&self, plane: &GCodePlane) -> f32 {
+    pub fn third_coord(&self, plane: &GCodePlane) -> f64 {
         match plane {
             GCodePlane::XY => self.z,
             GCodePlane::ZX => self.y,
@@ -74,13 +79,13 @@ impl Vec3 {
         out
     }
 
-    pub fn angle_to(&self, other: &Vec3, plane: &GCodePlane) -> f32 {
+    pub fn angle_to(&self, other: &Vec3, plane: &GCodePlane) -> f64 {
         let (a1, a2) = self.coords_for_plane(plane);
         let (b1, b2) = other.coords_for_plane(plane);
         (b2 - a2).atan2(b1 - a1)
     }
 
-    pub fn magnitude(&self) -> f32 {
+    pub fn magnitude(&self) -> f64 {
         (self.x.powi(2) + self.y.powi(2) + self.z.powi(2)).sqrt()
     }
 
@@ -126,10 +131,10 @@ impl Sub<Vec3> for Vec3 {
     }
 }
 
-impl Mul<f32> for Vec3 {
+impl Mul<f64> for Vec3 {
     type Output = Vec3;
 
-    fn mul(self, rhs: f32) -> Self::Output {
+    fn mul(self, rhs: f64) -> Self::Output {
         Vec3 {
             x: self.x * rhs,
             y: self.y * rhs,
@@ -138,10 +143,10 @@ impl Mul<f32> for Vec3 {
     }
 }
 
-impl Div<f32> for Vec3 {
+impl Div<f64> for Vec3 {
     type Output = Vec3;
 
-    fn div(self, rhs: f32) -> Self::Output {
+    fn div(self, rhs: f64) -> Self::Output {
         Vec3 {
             x: self.x / rhs,
             y: self.y / rhs,
@@ -154,4 +159,4 @@ impl Display for Vec3 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "({:.3}, {:.3}, {:.3})", self.x, self.y, self.z)
     }
-}
\ No newline at end of file
+}
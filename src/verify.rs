@@ -0,0 +1,168 @@
+use std::f64::consts::FRAC_PI_2;
+
+use crate::{
+    input,
+    operations::OperationConfigs,
+    output::source_line,
+    types::{
+        ArcDirection, Command, DragknifeConfig, GCodePlane, GCodeState, Movement, RepathError,
+    },
+    vec3::Vec3,
+    DragknifePath, REVERSAL_PIVOT,
+};
+
+/// How far in mm the written program may be from the repathed input, enough for coordinates
+/// rounded to 3 decimals in inches.
+pub const VERIFY_TOLERANCE: f64 = 0.05;
+
+/// A planar move of the input or of the reparsed output.
+struct Cut {
+    /// Index into `DragknifePath::commands`.
+    command: usize,
+    line: Option<usize>,
+    start: Vec3,
+    end: Vec3,
+    /// Center and direction, for arcs.
+    arc: Option<(Vec3, ArcDirection)>,
+    plane: GCodePlane,
+}
+
+impl<'a> DragknifePath<'a> {
+    /// Parses the written program again and checks that it makes every cut of this path, shifted
+    /// by the knife offset of its operation along the direction of the cut.
+    ///
+    /// The moves inserted between two cuts, like swivels, must stay within the knife offset of
+    /// the corner, or of the point beside it where the blade is turned at a reversal. Heights are
+    /// not compared, as dry runs and clearance change them on purpose.
+    pub fn verify_output(
+        &self,
+        text: &str,
+        config: &DragknifeConfig,
+        tolerance: f64,
+    ) -> Result<(), RepathError> {
+        let program = input::parse_program(text);
+        let output = DragknifePath::from_gcode_with_state(
+            program.gcodes.iter(),
+            GCodeState::from_config(config),
+        );
+        // Arc ends are moved onto their circle when parsing, which leaves short moves behind
+        let expected = cuts(self, tolerance);
+        let written = cuts(&output, tolerance);
+        let mut written = written.iter();
+        let operations = OperationConfigs::new(config);
+        for cut in expected {
+            let knife_offset = operations.get(self.operation_of(cut.command)).knife_offset;
+            let plane = cut.plane;
+            let distance = |a: Vec3, b: Vec3| (a - b).project_plane(&plane).magnitude();
+            let target = cut.end + Vec3::unit_angle(end_angle(&cut), &plane) * knife_offset;
+            let error = |problem: String| RepathError::Verification {
+                line: cut.line,
+                problem,
+            };
+            loop {
+                let Some(candidate) = written.next() else {
+                    let (x, y) = target.coords_for_plane(&plane);
+                    return Err(error(format!("no cut ends at {x:.3}, {y:.3}")));
+                };
+                if distance(candidate.end, target) <= tolerance {
+                    match (cut.arc, candidate.arc) {
+                        (Some((center, direction)), Some((written_center, written_direction)))
+                            if distance(center, written_center) > tolerance
+                                || direction != written_direction =>
+                        {
+                            return Err(error("the arc has a different center or direction".into()))
+                        }
+                        _ => break,
+                    }
+                }
+                // Arcs written as lines and moves split for a height map are cut in pieces
+                if candidate.arc.is_none() && on_cut(&cut, candidate.end, knife_offset, tolerance) {
+                    continue;
+                }
+                // Knife offset moves and swivels turn the knife around the start of the cut, and
+                // reversals around a point beside it
+                let reach = (REVERSAL_PIVOT + 1.) * knife_offset;
+                if distance(candidate.end, cut.start) > reach + tolerance {
+                    return Err(error(format!(
+                        "an inserted move ends {:.3} mm from the start of the cut",
+                        distance(candidate.end, cut.start)
+                    )));
+                }
+                if let Some((center, _)) = candidate.arc {
+                    let pivot = distance(center, cut.start);
+                    if pivot > tolerance
+                        && (pivot - REVERSAL_PIVOT * knife_offset).abs() > tolerance
+                    {
+                        return Err(error("a swivel does not turn around the corner".into()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The moves of `path` which cut at least `min_length` in the plane.
+fn cuts(path: &DragknifePath, min_length: f64) -> Vec<Cut> {
+    let mut cuts = vec![];
+    let mut settings = path.initial_state;
+    for (i, command) in path.commands.iter().enumerate() {
+        let arc = match command {
+            Command::Linear(_) => None,
+            Command::Arc(arc) => Some((arc.center, arc.direction)),
+            _ => {
+                command.update_settings(&mut settings);
+                continue;
+            }
+        };
+        let (start, end) = (command.start_pos(), command.end_pos());
+        if (end - start).project_plane(&settings.plane).magnitude() > min_length {
+            cuts.push(Cut {
+                command: i,
+                line: source_line(command.original().span()),
+                start,
+                end,
+                arc,
+                plane: settings.plane,
+            });
+        }
+        command.update_settings(&mut settings);
+    }
+    cuts
+}
+
+/// Whether `point` lies on `cut` as written, shifted by the knife offset: lines are moved along
+/// their direction, and arcs are cut at `hypot(radius, knife_offset)` from their center.
+fn on_cut(cut: &Cut, point: Vec3, knife_offset: f64, tolerance: f64) -> bool {
+    let plane = &cut.plane;
+    match cut.arc {
+        None => {
+            let shift = Vec3::unit_angle(end_angle(cut), plane) * knife_offset;
+            let (a1, a2) = (cut.start + shift).coords_for_plane(plane);
+            let (b1, b2) = (cut.end + shift).coords_for_plane(plane);
+            let (p1, p2) = point.coords_for_plane(plane);
+            let (d1, d2) = (b1 - a1, b2 - a2);
+            let length_squared = d1 * d1 + d2 * d2;
+            let t = if length_squared > 0. {
+                (((p1 - a1) * d1 + (p2 - a2) * d2) / length_squared).clamp(0., 1.)
+            } else {
+                0.
+            };
+            (p1 - a1 - t * d1).hypot(p2 - a2 - t * d2) <= tolerance
+        }
+        Some((center, _)) => {
+            let radius = (cut.end - center).project_plane(plane).magnitude();
+            let distance = (point - center).project_plane(plane).magnitude();
+            (distance - radius.hypot(knife_offset)).abs() <= tolerance
+        }
+    }
+}
+
+/// The direction of `cut` at its end, worked out from its geometry.
+fn end_angle(cut: &Cut) -> f64 {
+    match cut.arc {
+        None => cut.start.angle_to(&cut.end, &cut.plane),
+        Some((center, ArcDirection::CW)) => center.angle_to(&cut.end, &cut.plane) - FRAC_PI_2,
+        Some((center, ArcDirection::CCW)) => center.angle_to(&cut.end, &cut.plane) + FRAC_PI_2,
+    }
+}
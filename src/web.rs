@@ -0,0 +1,37 @@
+use std::sync::mpsc;
+
+use wasm_bindgen::{JsCast, JsValue};
+
+/// Lets the user pick a file in the browser and reads it in the background. Its name and contents
+/// are sent once it is read, or the sender is dropped if no file was picked.
+pub fn upload_file(ctx: &egui::Context) -> mpsc::Receiver<(String, String)> {
+    let (sender, receiver) = mpsc::channel();
+    let ctx = ctx.clone();
+    wasm_bindgen_futures::spawn_local(async move {
+        if let Some(file) = rfd::AsyncFileDialog::new().pick_file().await {
+            let contents = file.read().await;
+            let contents = String::from_utf8_lossy(&contents).into_owned();
+            // The app may have been closed in the meantime
+            let _ = sender.send((file.file_name(), contents));
+        }
+        ctx.request_repaint();
+    });
+    receiver
+}
+
+/// Makes the browser download `contents` as a file named `name`.
+pub fn download(name: &str, contents: &str) -> Result<(), JsValue> {
+    let parts = js_sys::Array::of1(&JsValue::from_str(contents));
+    let mut options = web_sys::BlobPropertyBag::new();
+    options.type_("text/plain");
+    let blob = web_sys::Blob::new_with_str_sequence_and_options(&parts, &options)?;
+    let url = web_sys::Url::create_object_url_with_blob(&blob)?;
+    let document = web_sys::window()
+        .and_then(|window| window.document())
+        .ok_or("no document")?;
+    let link: web_sys::HtmlAnchorElement = document.create_element("a")?.dyn_into()?;
+    link.set_href(&url);
+    link.set_download(name);
+    link.click();
+    web_sys::Url::revoke_object_url(&url)
+}
@@ -0,0 +1,51 @@
+//! Cutter compensation is turned on together with the first move by most CAM software, which
+//! has to be offset like a move on a line of its own.
+
+use dragknife_repath::{job::repath_source, output::OutputConfig, types::DragknifeConfig};
+
+fn repathed(program: &str) -> String {
+    let config = DragknifeConfig::builder()
+        .knife_offset(0.)
+        .compensation_radius(1.)
+        .build()
+        .unwrap();
+    let output_config = OutputConfig {
+        format_numbers: true,
+        ..Default::default()
+    };
+    repath_source(program, &config, &output_config, false, true)
+        .unwrap()
+        .text
+}
+
+fn cuts_to(output: &str, position: &str) -> bool {
+    let line = format!("G1 {position}");
+    output
+        .lines()
+        .any(|l| l == line || l.starts_with(&format!("{line} ")))
+}
+
+/// A square, cut counterclockwise from the origin with the cutter on the inside.
+fn square(start: &str, end: &str) -> String {
+    format!("G21 G90\nG0 X0 Y0 Z5\nG1 Z-1 F300\n{start}\nG1 X10 Y10\nX0 Y10\n{end}\nG0 Z5\nM30\n")
+}
+
+#[test]
+fn coordinates_on_the_compensation_line_are_a_move() {
+    for start in ["G1 G41 X10 Y0", "G41 X10 Y0", "G41.1 D2 G1 X10 Y0"] {
+        let output = repathed(&square(start, "G1 G40 X0 Y0"));
+        // The first move leads in to the corner of the offset path
+        for corner in ["X9 Y1", "X9 Y9", "X0 Y9"] {
+            assert!(cuts_to(&output, corner), "{start}, {corner}:\n{output}");
+        }
+        assert!(!output.contains("G41"), "{start}:\n{output}");
+    }
+}
+
+#[test]
+fn compensation_line_moves_like_a_line_of_its_own() {
+    assert_eq!(
+        repathed(&square("G1 G41 X10 Y0", "G1 G40 X0 Y0")),
+        repathed(&square("G41\nG1 X10 Y0", "G40\nG1 X0 Y0"))
+    );
+}
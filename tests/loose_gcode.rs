@@ -0,0 +1,127 @@
+//! Lowercase and loosely formatted G-code, as written by some senders and hand edits, has to be
+//! repathed exactly like the same program written the usual way.
+
+use dragknife_repath::{
+    input::parse_program, job::repath_source, output::OutputConfig, types::DragknifeConfig,
+};
+use regex::Regex;
+
+/// Programs from CAM software, as they were exported.
+const CORPUS: [(&str, &str); 5] = [
+    ("test_base.cnc", include_str!("../test_base.cnc")),
+    ("test_input.cnc", include_str!("../test_input.cnc")),
+    ("test_input2.cnc", include_str!("../test_input2.cnc")),
+    ("testpiece.cnc", include_str!("../testpiece.cnc")),
+    (
+        "testpiece_multidepth.cnc",
+        include_str!("../testpiece_multidepth.cnc"),
+    ),
+];
+
+/// Applies `change` to the code of every line, leaving the comments as they are.
+fn change_code(program: &str, change: impl Fn(&str) -> String) -> String {
+    let comment = Regex::new(r"\([^)]*\)|;.*").unwrap();
+    let mut out = String::new();
+    let mut end = 0;
+    for found in comment.find_iter(program) {
+        out += &change(&program[end..found.start()]);
+        out += found.as_str();
+        end = found.end();
+    }
+    out += &change(&program[end..]);
+    out
+}
+
+fn lowercase(program: &str) -> String {
+    change_code(program, str::to_lowercase)
+}
+
+/// Removes the spaces between words, but not the line breaks.
+fn without_spaces(program: &str) -> String {
+    change_code(program, |code| code.replace([' ', '\t'], ""))
+}
+
+/// Writes whole coordinates with a trailing dot (`X10.`) and drops the zero in front of
+/// fractions (`X-.5`), like Fanuc style posts.
+fn fanuc_numbers(program: &str) -> String {
+    let whole = Regex::new(r"([XYZIJKRF]-?\d+)([^\d.]|$)").unwrap();
+    let fraction = Regex::new(r"([XYZIJKRF]-?)0\.(\d)").unwrap();
+    change_code(program, |code| {
+        let code = whole.replace_all(code, "$1.$2");
+        fraction.replace_all(&code, "$1.$2").into_owned()
+    })
+}
+
+fn loose_variants(program: &str) -> [(&'static str, String); 4] {
+    [
+        ("lowercase", lowercase(program)),
+        ("without spaces", without_spaces(program)),
+        ("Fanuc numbers", fanuc_numbers(program)),
+        (
+            "all of them",
+            without_spaces(&lowercase(&fanuc_numbers(program))),
+        ),
+    ]
+}
+
+fn parsed(program: &str) -> (Vec<String>, usize) {
+    let program = parse_program(program);
+    let gcodes = program.gcodes.iter().map(ToString::to_string).collect();
+    (gcodes, program.issues.len())
+}
+
+fn repathed(program: &str) -> String {
+    let config = DragknifeConfig::builder().build().unwrap();
+    let output_config = OutputConfig {
+        normalize_original_text: true,
+        ..Default::default()
+    };
+    repath_source(program, &config, &output_config, false, false)
+        .unwrap()
+        .text
+}
+
+#[test]
+fn corpus_parses_the_same_when_loosely_formatted() {
+    for (name, program) in CORPUS {
+        let expected = parsed(program);
+        for (variant, loose) in loose_variants(program) {
+            assert_eq!(parsed(&loose), expected, "{name}, {variant}");
+        }
+    }
+}
+
+#[test]
+fn corpus_repaths_the_same_when_loosely_formatted() {
+    for (name, program) in CORPUS {
+        let expected = repathed(program);
+        for (variant, loose) in loose_variants(program) {
+            assert_eq!(repathed(&loose), expected, "{name}, {variant}");
+        }
+    }
+}
+
+#[test]
+fn sender_style_lines() {
+    let loose = "g21 g90\ng0 x0y0 z5\ng1z-1f300\ng1 x10.y5\nx20.y-.5\ng2x30y-.5i5j0\ng0z5\nm30\n";
+    let canonical = "G21 G90\nG0 X0 Y0 Z5\nG1 Z-1 F300\nG1 X10 Y5\nX20 Y-0.5\nG2 X30 Y-0.5 I5 J0\n\
+                     G0 Z5\nM30\n";
+    assert_eq!(parsed(loose), parsed(canonical));
+    assert_eq!(parsed(loose).1, 0);
+    assert_eq!(repathed(loose), repathed(canonical));
+}
+
+#[test]
+fn lowercase_words_are_not_dropped() {
+    let (gcodes, issues) = parsed("g1 x10.y5 f300\n");
+    assert_eq!(gcodes, ["G1 X10 Y5 F300"]);
+    assert_eq!(issues, 0);
+}
+
+#[test]
+fn words_without_a_number_are_skipped() {
+    // The `gcode` parser panics on these
+    let (gcodes, issues) = parsed("g1 x- y5\nG1 X. Y6 )\n");
+    assert_eq!(gcodes, ["G1 Y5", "G1 Y6"]);
+    assert_eq!(issues, 3);
+}